@@ -0,0 +1,50 @@
+use std::hash::{BuildHasher, Hash};
+use std::thread;
+use std::time::Duration;
+
+use crate::PQueue;
+
+// How long the receiver bridge waits for an item before checking whether the channel was dropped
+const RECEIVER_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+impl<T, S> PQueue<T, S>
+where
+    T: Eq + Hash + Clone + Send + Sync + 'static,
+    S: BuildHasher + Send + 'static,
+{
+    /// Returns a channel sender whose items are enqueued with default_score, as if passed to
+    /// `update`. A background thread feeds the queue until every clone of the sender is dropped.
+    /// Updates that fail under the `Checked` overflow policy are discarded.
+    pub fn sender(&self, default_score: i64) -> flume::Sender<T> {
+        let (tx, rx) = flume::unbounded();
+        let queue = self.clone();
+        thread::spawn(move || {
+            for item in rx.iter() {
+                let _ = queue.update(item, default_score);
+            }
+        });
+        tx
+    }
+
+    /// Returns a bounded channel receiver fed with items popped off the queue in priority order. A
+    /// background thread pops items as they become available and blocks while the channel is full,
+    /// so at most capacity items (plus the one waiting to be sent) are taken off the queue ahead of
+    /// the consumer. Once the receiver is dropped, the item in flight is put back and the thread exits
+    /// (items already buffered in the channel are dropped along with it).
+    pub fn receiver(&self, capacity: usize) -> flume::Receiver<T> {
+        let (tx, rx) = flume::bounded(capacity);
+        let queue = self.clone();
+        thread::spawn(move || {
+            while !tx.is_disconnected() {
+                let Some((item, score)) = queue.next_entry_timeout(RECEIVER_POLL_INTERVAL) else {
+                    continue;
+                };
+                if let Err(flume::SendError(item)) = tx.send(item) {
+                    let _ = queue.update(item, score);
+                    return;
+                }
+            }
+        });
+        rx
+    }
+}
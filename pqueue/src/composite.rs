@@ -0,0 +1,91 @@
+use std::hash::{BuildHasher, Hash};
+use std::sync::Arc;
+
+use crate::{OverflowPolicy, PQueue, PQueueError};
+
+/// A two part priority that is compared lexicographically: first by `primary`, then by `secondary`,
+/// e.g. `(severity, timestamp)`.
+///
+/// Queue scores are i64s, so a `CompositeScore` is packed into one with `i64::from` (and unpacked with
+/// `CompositeScore::from`). The packing keeps the ordering of the packed scores identical to the
+/// lexicographic ordering of the parts, so items are served by highest `primary` first and then by
+/// highest `secondary`. To serve the smallest `secondary` first (e.g. oldest timestamp), store its
+/// complement with `CompositeScore::new_inverted`.
+///
+/// `update` adds to the packed value, where adding to `secondary` can carry into `primary`. Add to
+/// composite scores with `PQueue::update_composite` instead, which adds to each part on its own.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct CompositeScore {
+    pub primary: i32,
+    pub secondary: u32,
+}
+
+impl CompositeScore {
+    pub fn new(primary: i32, secondary: u32) -> Self {
+        Self { primary, secondary }
+    }
+
+    /// Creates a score where, within the same primary, smaller secondary values have higher priority
+    pub fn new_inverted(primary: i32, secondary: u32) -> Self {
+        Self { primary, secondary: u32::MAX - secondary }
+    }
+
+    // Adds the deltas to each part on its own under the policy, so neither part spills into the
+    // other. None if a part overflows under the `Checked` policy.
+    fn add(self, primary: i32, secondary: i32, policy: OverflowPolicy) -> Option<Self> {
+        let (primary, secondary) = match policy {
+            OverflowPolicy::Checked => (self.primary.checked_add(primary)?, self.secondary.checked_add_signed(secondary)?),
+            OverflowPolicy::Saturating => (self.primary.saturating_add(primary), self.secondary.saturating_add_signed(secondary)),
+            OverflowPolicy::Wrapping => (self.primary.wrapping_add(primary), self.secondary.wrapping_add_signed(secondary)),
+        };
+        Some(Self { primary, secondary })
+    }
+}
+
+impl<T, S> PQueue<T, S>
+where
+    T: Eq + Hash + Clone,
+    S: BuildHasher,
+{
+    /// Like `update` for items scored with a `CompositeScore`, adding primary and secondary to the
+    /// parts of the item's score separately, under the queue's overflow policy, so that a change to
+    /// `secondary` never changes `primary`. A new item starts from a score of zero. Returns the
+    /// previous score (None if the item was inserted) and the resulting score (None if the item was
+    /// dropped by the `EvictLowest` capacity policy), or an error if either part overflows under the
+    /// `Checked` policy, with the item left untouched.
+    pub fn update_composite(&self, item: T, primary: i32, secondary: i32) -> Result<(Option<CompositeScore>, Option<CompositeScore>), PQueueError> {
+        let mut queue = self.lock();
+        let current = queue.score(&item).map(CompositeScore::from);
+        let score = current.unwrap_or_default()
+            .add(primary, secondary, queue.overflow_policy)
+            .ok_or(PQueueError::Overflow {
+                score: current.map_or(0, i64::from),
+                delta: ((primary as i64) << 32) + secondary as i64,
+            })?;
+        let item = Arc::new(item);
+        queue.set_score(item.clone(), score.into())?;
+        if !queue.contains(&*item) {
+            return Ok((current, None));
+        }
+        drop(queue);
+        if current.is_none() {
+            self.item_available();
+        }
+        Ok((current, Some(score)))
+    }
+}
+
+impl From<CompositeScore> for i64 {
+    fn from(value: CompositeScore) -> Self {
+        ((value.primary as i64) << 32) | value.secondary as i64
+    }
+}
+
+impl From<i64> for CompositeScore {
+    fn from(value: i64) -> Self {
+        Self {
+            primary: (value >> 32) as i32,
+            secondary: value as u32,
+        }
+    }
+}
@@ -0,0 +1,52 @@
+use std::sync::Arc;
+
+use tokio::sync::broadcast;
+
+// How many events a subscriber can fall behind by before it starts missing them
+const EVENT_CAPACITY: usize = 1024;
+
+/// A change to the queue, delivered to receivers created with `PQueue::subscribe`
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum QueueEvent<T> {
+    /// The item was inserted (previous is None) or its score changed
+    Updated { item: Arc<T>, previous: Option<i64>, score: i64 },
+    /// The item was popped with the given score
+    Popped { item: Arc<T>, score: i64 },
+    /// The item was removed, whether explicitly or for dropping to zero under auto removal
+    Removed { item: Arc<T>, score: i64 },
+    /// The item expired and was removed
+    Expired { item: Arc<T>, score: i64 },
+    /// The queue was cleared of count items
+    Cleared { count: usize },
+}
+
+// Publishes queue events to subscribers. Events are only built when someone is subscribed, so an
+// unobserved queue pays nothing for them.
+pub(crate) struct EventPublisher<T> {
+    sender: broadcast::Sender<QueueEvent<T>>,
+}
+
+impl<T: Clone> Default for EventPublisher<T> {
+    fn default() -> Self {
+        Self {
+            sender: broadcast::channel(EVENT_CAPACITY).0,
+        }
+    }
+}
+
+impl<T> EventPublisher<T> {
+    pub(crate) fn subscribe(&self) -> broadcast::Receiver<QueueEvent<T>> {
+        self.sender.subscribe()
+    }
+
+    pub(crate) fn publish<F>(&self, event: F)
+    where
+        F: FnOnce() -> QueueEvent<T>,
+    {
+        if self.sender.receiver_count() > 0 {
+            // Sending only fails when every receiver was dropped in the meantime
+            let _ = self.sender.send(event());
+        }
+    }
+}
@@ -0,0 +1,51 @@
+use std::borrow::Borrow;
+use std::hash::{Hash, Hasher};
+use std::sync::Arc;
+
+// The item index is keyed by Arc<T>, and HashMap lookups require the key to implement Borrow<Q> for
+// the type being looked up. Arc<T> only borrows as T, so to look up a &str in a String queue without
+// allocating, both the stored Arc<T> and the caller's &Q are viewed through this trait object, which
+// hashes and compares using the borrowed Q. The Borrow contract guarantees T and Q hash identically.
+pub(crate) trait KeyRef<Q: ?Sized> {
+    fn key(&self) -> &Q;
+}
+
+impl<T, Q> KeyRef<Q> for Arc<T>
+where
+    T: Borrow<Q>,
+    Q: ?Sized,
+{
+    fn key(&self) -> &Q {
+        (**self).borrow()
+    }
+}
+
+impl<Q: ?Sized> KeyRef<Q> for &Q {
+    fn key(&self) -> &Q {
+        self
+    }
+}
+
+impl<'a, T, Q> Borrow<dyn KeyRef<Q> + 'a> for Arc<T>
+where
+    T: Borrow<Q> + 'a,
+    Q: ?Sized + 'a,
+{
+    fn borrow(&self) -> &(dyn KeyRef<Q> + 'a) {
+        self
+    }
+}
+
+impl<Q: Hash + ?Sized> Hash for dyn KeyRef<Q> + '_ {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.key().hash(state)
+    }
+}
+
+impl<Q: PartialEq + ?Sized> PartialEq for dyn KeyRef<Q> + '_ {
+    fn eq(&self, other: &Self) -> bool {
+        self.key() == other.key()
+    }
+}
+
+impl<Q: Eq + ?Sized> Eq for dyn KeyRef<Q> + '_ {}
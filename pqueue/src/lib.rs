@@ -1,34 +1,74 @@
+#[cfg(feature = "channel")]
+mod channel;
+mod composite;
+#[cfg(feature = "async")]
+mod event;
+mod key;
+#[cfg(feature = "sled")]
+mod persistent;
+mod schedule;
+#[cfg(feature = "async")]
+mod stream;
+
+use std::borrow::Borrow;
 use std::collections::{BTreeMap, HashMap, VecDeque};
-use std::sync::{Arc, Mutex};
-use std::hash::Hash;
+use std::collections::hash_map::RandomState;
+use std::fmt;
+use std::mem::size_of;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::hash::{BuildHasher, Hash};
 use chrono::{NaiveDateTime, Duration, Utc};
 
+#[cfg(feature = "async")]
+use event::EventPublisher;
+use key::KeyRef;
+use schedule::Scheduler;
+
+pub use composite::CompositeScore;
+#[cfg(feature = "async")]
+pub use event::QueueEvent;
+#[cfg(feature = "sled")]
+pub use persistent::{PersistentItem, SledPQueue};
+pub use schedule::{Band, Schedule};
+#[cfg(feature = "async")]
+pub use stream::PQueueStream;
+
 // Priority queue wrapper with internal synchronization using Arc and Mutex for thread safety
 // You can clone this and pass it to multiple threads to share the same internal queue. Cloning
 // will not copy the data, but instead, each cloned instance will point to the same internal queue.
-pub struct PQueue<T>
+// The item index uses the hasher built by S, which defaults to the std RandomState (SipHash).
+pub struct PQueue<T, S = RandomState>
 where
     T: Eq + Hash + Clone,
+    S: BuildHasher,
 {
-    queue: Arc<Mutex<PriorityQueue<T>>>,
+    queue: Arc<Mutex<PriorityQueue<T, S>>>,
+    available: Arc<Condvar>,
+    #[cfg(feature = "async")]
+    notify: Arc<tokio::sync::Notify>,
 }
 
-impl<T> Default for PQueue<T>
+impl<T, S> Default for PQueue<T, S>
 where
     T: Eq + Hash + Clone,
+    S: BuildHasher + Default,
  {
     fn default() -> Self {
-        Self::new()
+        Self::with_hasher(S::default())
     }
 }
 
-impl<T> Clone for PQueue<T>
+impl<T, S> Clone for PQueue<T, S>
 where
     T: Eq + Hash + Clone,
+    S: BuildHasher,
 {
     fn clone(&self) -> Self {
         Self {
-            queue: self.queue.clone()
+            queue: self.queue.clone(),
+            available: self.available.clone(),
+            #[cfg(feature = "async")]
+            notify: self.notify.clone(),
         }
     }
 }
@@ -38,46 +78,630 @@ where
     T: Eq + Hash + Clone,
 {
     pub fn new() -> Self {
+        Self::with_hasher(RandomState::new())
+    }
+}
+
+impl<T, S> PQueue<T, S>
+where
+    T: Eq + Hash + Clone,
+    S: BuildHasher,
+{
+    /// Creates an empty queue whose item index hashes with the given hasher builder, e.g. a faster
+    /// hasher for small keys or a DoS-resistant one for untrusted identifiers
+    pub fn with_hasher(hasher: S) -> Self {
         Self {
             queue: Arc::new(Mutex::new(PriorityQueue {
                 scores: BTreeMap::new(),
-                items: HashMap::new(),
+                items: HashMap::with_hasher(hasher),
+                inserted: BTreeMap::new(),
+                expiries: BTreeMap::new(),
+                next_seq: 0,
                 stats: PQueueStatsTracker {
                     start_time: Utc::now().naive_utc(),
                     updates: 0,
                     items: 0,
                     pools: 0,
+                    evicted: 0,
+                    rejected: 0,
+                    expired: 0,
+                    enqueues: RateTracker::default(),
+                    dequeues: RateTracker::default(),
                 },
-            }))
+                overflow_policy: OverflowPolicy::default(),
+                max_items: None,
+                paused: false,
+                scheduler: Scheduler::default(),
+                auto_remove: false,
+                expiry_sweep_limit: None,
+                #[cfg(feature = "async")]
+                events: EventPublisher::default(),
+            })),
+            available: Arc::new(Condvar::new()),
+            #[cfg(feature = "async")]
+            notify: Arc::new(tokio::sync::Notify::new()),
         }
     }
 
-    pub fn update(&self, item: T, new_score: i64) {
-        let mut queue = self.queue.lock().unwrap();
-        queue.update(Arc::new(item), new_score);
+    /// Builds a queue from (score, item) pairs sorted by ascending score, with items sharing a score
+    /// in the order they should be popped. See `load_sorted`.
+    pub fn from_sorted_iter<I>(iter: I) -> Self
+    where
+        I: IntoIterator<Item = (i64, T)>,
+        S: Default,
+    {
+        let queue = Self::with_hasher(S::default());
+        queue.load_sorted(iter);
+        queue
+    }
+
+    /// Bulk loads (score, item) pairs sorted by ascending score, with items sharing a score in the
+    /// order they should be popped, e.g. when restoring a snapshot. Scores are set rather than added
+    /// and loading does not count towards the update stats. Loading sorted input into an empty queue
+    /// skips the per-item pool lookups; unsorted or repeated entries are still loaded correctly (a
+    /// repeated item takes its last score) but take the slower path. Returns the number of pairs loaded.
+    pub fn load_sorted<I>(&self, iter: I) -> usize
+    where
+        I: IntoIterator<Item = (i64, T)>,
+    {
+        let mut queue = self.lock();
+        let loaded = queue.load_sorted(iter.into_iter().map(|(score, item)| (score, Arc::new(item))));
+        drop(queue);
+        self.wake_all();
+        loaded
+    }
+
+    /// Adds new_score to the item's current score, or inserts the item with new_score if it is not
+    /// in the queue yet. Returns the previous score (None if the item was inserted) and the resulting
+    /// score, or an error if the addition overflows under the `Checked` overflow policy. The resulting
+    /// score is None if auto removal is enabled and the item was removed for dropping to zero or below.
+    pub fn update(&self, item: T, new_score: i64) -> Result<(Option<i64>, Option<i64>), PQueueError> {
+        let mut queue = self.lock();
+        let result = queue.update(Arc::new(item), new_score);
+        drop(queue);
+        if let Ok((None, Some(_))) = result {
+            self.item_available();
+        }
+        result
+    }
+
+    /// Sets the score of the item, or inserts it with that score, rather than adding to it. Returns the
+    /// score the item had before, None if it was inserted (or dropped by the `EvictLowest` capacity
+    /// policy), or an error if the queue is full under the `Reject` capacity policy.
+    pub fn set_score(&self, item: T, score: i64) -> Result<Option<i64>, PQueueError> {
+        let mut queue = self.lock();
+        let item = Arc::new(item);
+        let previous = queue.set_score(item.clone(), score)?;
+        let inserted = previous.is_none() && queue.contains(&*item);
+        drop(queue);
+        if inserted {
+            self.item_available();
+        }
+        Ok(previous)
+    }
+
+    /// Inserts the item with the given score only if it is not already in the queue (NX semantics).
+    /// Returns true if the item was inserted.
+    pub fn insert_if_absent(&self, item: T, score: i64) -> bool {
+        let mut queue = self.lock();
+        let item = Arc::new(item);
+        // Checked again after the insert, which a full queue may have dropped the item from
+        if queue.contains(&*item) || queue.set_score(item.clone(), score).is_err() || !queue.contains(&*item) {
+            return false;
+        }
+        drop(queue);
+        self.item_available();
+        true
+    }
+
+    /// Adds delta to the score of the item only if it is already in the queue (XX semantics).
+    /// Returns true if the item was updated.
+    pub fn update_if_exists(&self, item: T, delta: i64) -> Result<bool, PQueueError> {
+        let mut queue = self.lock();
+        if !queue.contains(&item) {
+            return Ok(false);
+        }
+        queue.update(Arc::new(item), delta)?;
+        Ok(true)
+    }
+
+    /// Sets the score of the item to new only if its current score still equals expected. On a
+    /// mismatch the queue is left untouched and the current score is returned as the error. An item
+    /// not in the queue fails with expected itself, which a mismatch can never return. Setting the
+    /// score it already has leaves the item where it is in its pool.
+    pub fn cas_score(&self, item: T, expected: i64, new: i64) -> Result<(), i64> {
+        let mut queue = self.lock();
+        match queue.score(&item) {
+            Some(current) if current == expected && current == new => Ok(()),
+            Some(current) if current == expected => {
+                // The item is in the queue, so the capacity can't turn it away
+                let _ = queue.set_score(Arc::new(item), new);
+                Ok(())
+            },
+            Some(current) => Err(current),
+            None => Err(expected),
+        }
     }
 
     pub fn peek(&self) -> Option<T> {
-        let queue = self.queue.lock().unwrap();
+        let queue = self.lock();
         queue.peek().map(|arc_item| (*arc_item).clone())
     }
 
     pub fn next(&self) -> Option<T> {
-        let mut queue = self.queue.lock().unwrap();
+        let mut queue = self.lock();
         queue.next().map(|arc_item| Arc::try_unwrap(arc_item).unwrap_or_else(|arc| (*arc).clone()))
     }
 
-    pub fn score(&self, item: &T) -> Option<i64> {
-        let queue = self.queue.lock().unwrap();
-        queue.score(&Arc::new(item.clone()))
+    /// Pops up to max items in priority order under a single lock
+    pub fn next_batch(&self, max: usize) -> Vec<T> {
+        let mut queue = self.lock();
+        std::iter::from_fn(|| queue.next())
+            .take(max)
+            .map(|arc_item| Arc::try_unwrap(arc_item).unwrap_or_else(|arc| (*arc).clone()))
+            .collect()
+    }
+
+    /// Returns up to count of the highest priority items with their scores, in the order `next` would
+    /// pop them under the strict schedule, without removing them
+    pub fn peek_n(&self, count: usize) -> Vec<(T, i64)> {
+        let queue = self.lock();
+        queue.peek_entries()
+            .take(count)
+            .map(|(arc_item, score)| ((*arc_item).clone(), score))
+            .collect()
+    }
+
+    /// Returns up to count of the items scored between min and max (inclusive) with their scores, from
+    /// the highest score down and in the order `next` would pop them within a score, without removing them
+    pub fn score_range(&self, min: i64, max: i64, count: usize) -> Vec<(T, i64)> {
+        // BTreeMap::range panics on an inverted range
+        if min > max {
+            return Vec::new();
+        }
+        let queue = self.lock();
+        queue.scores.range(min..=max)
+            .rev()
+            .flat_map(|(&score, items)| items.values().map(move |item| ((**item).clone(), score)))
+            .take(count)
+            .collect()
+    }
+
+    /// Returns every (score, item) pair sorted by ascending score, with items sharing a score in the
+    /// order they would be popped: the order `load_sorted` takes, so a snapshot loaded into an empty
+    /// queue recreates this one
+    pub fn snapshot(&self) -> Vec<(i64, T)> {
+        let queue = self.lock();
+        queue.scores.iter()
+            .flat_map(|(&score, items)| items.values().map(move |item| (score, (**item).clone())))
+            .collect()
+    }
+
+    /// Returns up to count items with their scores in the order they were first inserted, starting at
+    /// cursor (0 to start from the beginning), along with the cursor to pass to continue the scan, or 0
+    /// once every item has been returned. The queue is only locked for one batch at a time; items in the
+    /// queue for the whole scan are returned exactly once however their scores change in between.
+    pub fn scan(&self, cursor: u64, count: usize) -> (u64, Vec<(T, i64)>) {
+        let queue = self.lock();
+        let mut batch = queue.inserted.range(cursor..);
+        let entries: Vec<_> = batch.by_ref()
+            .take(count)
+            .map(|(_, item)| ((**item).clone(), queue.items[item].score))
+            .collect();
+        let cursor = batch.next().map_or(0, |(&seq, _)| seq);
+        (cursor, entries)
+    }
+
+    /// Like `peek`, but also returns the item's score, read under the same lock
+    pub fn peek_with_score(&self) -> Option<(T, i64)> {
+        let queue = self.lock();
+        queue.peek_entry().map(|(arc_item, score)| ((*arc_item).clone(), score))
+    }
+
+    /// Like `next`, but also returns the score the popped item had
+    pub fn next_with_score(&self) -> Option<(T, i64)> {
+        let mut queue = self.lock();
+        queue.next_entry().map(|(arc_item, score)| (Arc::try_unwrap(arc_item).unwrap_or_else(|arc| (*arc).clone()), score))
+    }
+
+    /// Pops the next item only if its score is at least threshold, atomically. Returns None, leaving
+    /// the queue untouched, when the item `next` would pop is below the threshold.
+    pub fn next_if_above(&self, threshold: i64) -> Option<T> {
+        let mut queue = self.lock();
+        queue.next_entry_above(threshold).map(|(arc_item, _)| Arc::try_unwrap(arc_item).unwrap_or_else(|arc| (*arc).clone()))
+    }
+
+    /// Returns the highest score in the queue without touching the item that holds it
+    pub fn top_score(&self) -> Option<i64> {
+        let queue = self.lock();
+        queue.scores.keys().next_back().cloned()
+    }
+
+    /// Returns the highest score in the queue along with the number of items sharing it
+    pub fn top_pool(&self) -> Option<(i64, usize)> {
+        let queue = self.lock();
+        queue.scores.iter().next_back().map(|(&score, pool)| (score, pool.len()))
+    }
+
+    /// Returns every score in the queue, highest first, along with the number of items sharing it
+    pub fn pools(&self) -> Vec<(i64, usize)> {
+        let queue = self.lock();
+        queue.scores.iter().rev().map(|(&score, pool)| (score, pool.len())).collect()
+    }
+
+    /// Like `peek`, but returns the shared reference to the head item instead of cloning it
+    pub fn peek_arc(&self) -> Option<Arc<T>> {
+        let queue = self.lock();
+        queue.peek()
+    }
+
+    /// Like `next`, but returns the popped item's Arc as is, so large items never have to be cloned
+    /// when other references to them are still alive
+    pub fn next_arc(&self) -> Option<Arc<T>> {
+        let mut queue = self.lock();
+        queue.next()
+    }
+
+    /// Looks up the score of an item by any borrowed form of the item type (e.g. &str for a String
+    /// queue), without cloning or allocating
+    pub fn score<Q>(&self, item: &Q) -> Option<i64>
+    where
+        T: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let queue = self.lock();
+        queue.score(item)
+    }
+
+    pub fn contains<Q>(&self, item: &Q) -> bool
+    where
+        T: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let queue = self.lock();
+        queue.contains(item)
+    }
+
+    /// Returns the item's score along with when it was first inserted and last updated
+    pub fn item_info<Q>(&self, item: &Q) -> Option<ItemInfo>
+    where
+        T: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let queue = self.lock();
+        queue.item_info(item)
+    }
+
+    /// Estimates the bytes the queue takes up, given the heap memory each item owns (e.g.
+    /// `String::capacity` for a String queue). Goes through every item, so takes time in proportion to
+    /// the length of the queue.
+    pub fn memory_usage(&self, heap_size: impl Fn(&T) -> usize) -> usize {
+        let queue = self.lock();
+        queue.memory_usage(heap_size)
+    }
+
+    /// Estimates the bytes an item takes up in the queue, given the heap memory it owns
+    pub fn item_memory_usage<Q>(&self, item: &Q, heap_size: impl Fn(&T) -> usize) -> Option<usize>
+    where
+        T: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let queue = self.lock();
+        queue.item_memory_usage(item, heap_size)
+    }
+
+    /// Puts the item in the queue with the score, timestamps and expiry in info, e.g. as read with
+    /// `item_info` from another queue, replacing it if it is in the queue already. Returns the score
+    /// the item had before. The item goes to the back of its pool whatever its insertion time.
+    pub fn restore(&self, item: T, info: ItemInfo) -> Option<i64> {
+        let mut queue = self.lock();
+        let previous = queue.restore(Arc::new(item), info);
+        drop(queue);
+        if previous.is_none() {
+            self.item_available();
+        }
+        previous
+    }
+
+    /// Removes the item from the queue, returning the score it had
+    pub fn remove<Q>(&self, item: &Q) -> Option<i64>
+    where
+        T: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let mut queue = self.lock();
+        queue.remove(item)
+    }
+
+    /// Runs f with a `Transaction` holding the queue's lock, so other users of the queue see either
+    /// none or all of the updates and removals made through it, e.g. to move score between items.
+    /// Returns what f returns.
+    pub fn transaction<R>(&self, f: impl FnOnce(&mut Transaction<'_, T, S>) -> R) -> R {
+        let mut queue = self.lock();
+        let result = f(&mut Transaction { queue: &mut queue });
+        drop(queue);
+        self.wake_all();
+        result
+    }
+
+    /// Makes the item expire once ttl has passed, replacing any expiry it had. Expired items are
+    /// removed the next time the queue is used, as if they were never there. A ttl reaching past the
+    /// latest time representable expires the item then. Returns false if the item is not in the queue.
+    pub fn expire<Q>(&self, item: &Q, ttl: Duration) -> bool
+    where
+        T: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let expires_at = Utc::now().naive_utc()
+            .checked_add_signed(ttl)
+            .unwrap_or(if ttl < Duration::zero() { NaiveDateTime::MIN } else { NaiveDateTime::MAX });
+        let mut queue = self.lock();
+        queue.set_expiry(item, Some(expires_at))
+    }
+
+    /// Removes the item's expiry, returning false if the item is not in the queue or had no expiry
+    pub fn persist<Q>(&self, item: &Q) -> bool
+    where
+        T: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let mut queue = self.lock();
+        queue.item_info(item).is_some_and(|info| info.expires_at.is_some()) && queue.set_expiry(item, None)
+    }
+
+    /// Caps how many expired items each use of the queue removes before going ahead, None (the default)
+    /// to remove them all. Bounds the time a single call can take when many items expire at once: the
+    /// rest are left to later calls or to `remove_expired`, and until then are skipped rather than
+    /// popped, though peeks and lookups still see them. Some(0) leaves them all to `remove_expired`.
+    pub fn set_expiry_sweep_limit(&self, limit: Option<usize>) {
+        let mut queue = self.lock();
+        queue.expiry_sweep_limit = limit;
+    }
+
+    /// Removes up to max of the items that have expired, returning how many were removed
+    pub fn remove_expired(&self, max: usize) -> usize {
+        let mut queue = self.queue.lock().unwrap();
+        queue.remove_expired(Utc::now().naive_utc(), max)
+    }
+
+    /// Removes every item from the queue, returning how many were removed
+    pub fn clear(&self) -> usize {
+        let mut queue = self.lock();
+        queue.clear()
     }
 
     pub fn stats(&self) -> PQueueStats {
-        let queue = self.queue.lock().unwrap();
-        queue.stats.clone().into()
+        let queue = self.lock();
+        let mut stats: PQueueStats = queue.stats.clone().into();
+        stats.oldest_item_age = queue.oldest_inserted_at().map(|inserted_at| Utc::now().naive_utc() - inserted_at);
+        stats
+    }
+
+    /// Zeroes the update count and the enqueue and dequeue rates, leaving the item and pool counts
+    /// (which describe the queue's contents) and the uptime alone
+    pub fn reset_stats(&self) {
+        let mut queue = self.lock();
+        queue.stats.updates = 0;
+        queue.stats.enqueues = RateTracker::default();
+        queue.stats.dequeues = RateTracker::default();
+    }
+
+    /// Stops dispatching items: while paused, `next` returns None, but updates are still accepted
+    pub fn pause(&self) {
+        let mut queue = self.lock();
+        queue.paused = true;
+    }
+
+    pub fn resume(&self) {
+        let mut queue = self.lock();
+        queue.paused = false;
+        drop(queue);
+        self.wake_all();
+    }
+
+    pub fn is_paused(&self) -> bool {
+        let queue = self.lock();
+        queue.paused
+    }
+
+    pub fn schedule(&self) -> Schedule {
+        let queue = self.lock();
+        queue.scheduler.schedule().clone()
+    }
+
+    /// Sets how `next` picks items: strictly by priority, or weighted across bands of scores so that
+    /// lower priority bands still get a share of the pops. Changing the schedule resets the weighted
+    /// round robin state.
+    pub fn set_schedule(&self, schedule: Schedule) {
+        let mut queue = self.lock();
+        queue.scheduler = Scheduler::new(schedule);
+    }
+
+    /// Waits until an item can be popped and returns it. Cancelling the returned future never loses
+    /// an item, as items are only taken off the queue when the future completes.
+    #[cfg(feature = "async")]
+    pub async fn next_async(&self) -> T {
+        self.next_with_score_async().await.0
+    }
+
+    /// Like `next_async`, but also returns the score the popped item had
+    #[cfg(feature = "async")]
+    pub async fn next_with_score_async(&self) -> (T, i64) {
+        loop {
+            let mut notified = std::pin::pin!(self.notify.notified());
+            // Register interest before checking the queue so a concurrent update can't be missed
+            notified.as_mut().enable();
+            if let Some(entry) = self.next_with_score() {
+                return entry;
+            }
+            notified.await;
+        }
+    }
+
+    /// Subscribes to changes made to the queue from then on. Events are published in the order the
+    /// changes were made; a receiver that falls too far behind misses the oldest events (receiving a
+    /// `Lagged` error). Bulk loads with `load_sorted` into an empty queue are not published.
+    #[cfg(feature = "async")]
+    pub fn subscribe(&self) -> tokio::sync::broadcast::Receiver<QueueEvent<T>> {
+        let queue = self.lock();
+        queue.events.subscribe()
+    }
+
+    /// Returns a never ending stream of popped items, waiting for new items whenever the queue is
+    /// empty or paused
+    #[cfg(feature = "async")]
+    pub fn stream(&self) -> PQueueStream<T, S>
+    where
+        T: Send + Sync + 'static,
+        S: Send + 'static,
+    {
+        PQueueStream::new(self.clone())
+    }
+
+    // Locks the queue, first removing the items that have expired
+    fn lock(&self) -> MutexGuard<'_, PriorityQueue<T, S>> {
+        let mut queue = self.queue.lock().unwrap();
+        if !queue.expiries.is_empty() {
+            let limit = queue.expiry_sweep_limit.unwrap_or(usize::MAX);
+            queue.remove_expired(Utc::now().naive_utc(), limit);
+        }
+        queue
+    }
+
+    // Wakes up consumers blocked waiting for an item after an item was added: one blocked thread,
+    // and every waiting future, as a future dropped after being picked (e.g. on a timeout) would take
+    // a single wakeup with it. Futures that lose the race for the item go back to waiting.
+    fn item_available(&self) {
+        self.available.notify_one();
+        #[cfg(feature = "async")]
+        self.notify.notify_waiters();
+    }
+
+    // Wakes up every consumer blocked waiting for items, e.g. after many items were added at once
+    fn wake_all(&self) {
+        self.available.notify_all();
+        #[cfg(feature = "async")]
+        self.notify.notify_waiters();
+    }
+
+    // Blocks the calling thread for up to timeout until an item can be popped, returning it along
+    // with the score it had
+    #[cfg_attr(not(feature = "channel"), allow(dead_code))]
+    fn next_entry_timeout(&self, timeout: std::time::Duration) -> Option<(T, i64)> {
+        let queue = self.lock();
+        let (mut queue, _) = self.available
+            .wait_timeout_while(queue, timeout, |queue| queue.paused || queue.scores.is_empty())
+            .unwrap();
+        let limit = queue.expiry_sweep_limit.unwrap_or(usize::MAX);
+        queue.remove_expired(Utc::now().naive_utc(), limit);
+        queue.next_entry().map(|(arc_item, score)| (Arc::try_unwrap(arc_item).unwrap_or_else(|arc| (*arc).clone()), score))
+    }
+
+    pub fn auto_remove(&self) -> bool {
+        let queue = self.lock();
+        queue.auto_remove
+    }
+
+    /// When enabled, an `update` that leaves an item with a score of zero or below removes the item
+    /// from the queue (or doesn't insert it, for a new item) and reports its resulting score as None
+    pub fn set_auto_remove(&self, enabled: bool) {
+        let mut queue = self.lock();
+        queue.auto_remove = enabled;
+    }
+
+    pub fn overflow_policy(&self) -> OverflowPolicy {
+        let queue = self.lock();
+        queue.overflow_policy
+    }
+
+    /// Sets how additive updates behave when the resulting score does not fit in an i64
+    pub fn set_overflow_policy(&self, policy: OverflowPolicy) {
+        let mut queue = self.lock();
+        queue.overflow_policy = policy;
+    }
+
+    /// Returns the most items the queue holds and what happens to items inserted past that, if bounded
+    pub fn max_items(&self) -> Option<(usize, CapacityPolicy)> {
+        let queue = self.lock();
+        queue.max_items
+    }
+
+    /// Bounds the queue to max_items, applying the policy to items inserted once it is full; None
+    /// lifts the bound. Updates of items already in the queue are never affected, nor are items loaded
+    /// with `load_sorted` or `restore`, so a queue can exceed its bound by loading more.
+    pub fn set_max_items(&self, max_items: Option<(usize, CapacityPolicy)>) {
+        let mut queue = self.lock();
+        queue.max_items = max_items;
+    }
+
+    /// Removes up to count items the way the policy makes room in a full queue, returning them in the
+    /// order they were evicted. Evicts nothing under `CapacityPolicy::Reject`.
+    pub fn evict(&self, count: usize, policy: CapacityPolicy) -> Vec<T> {
+        let mut queue = self.lock();
+        let mut evicted = Vec::new();
+        while evicted.len() < count {
+            let victim = match policy {
+                CapacityPolicy::Reject => None,
+                CapacityPolicy::EvictLowest => queue.scores.values().next().and_then(|pool| pool.values().next_back().cloned()),
+                CapacityPolicy::EvictOldest => queue.inserted.values().next().cloned(),
+            };
+            let Some(victim) = victim else {
+                break;
+            };
+            queue.remove(&*victim);
+            queue.stats.evicted += 1;
+            evicted.push(Arc::try_unwrap(victim).unwrap_or_else(|arc| (*arc).clone()));
+        }
+        evicted
     }
 }
 
+/// What happens to an item inserted into a queue holding as many items as it may
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CapacityPolicy {
+    /// Reject the insert with `PQueueError::Full`
+    Reject,
+    /// Remove the item `next` would pop last; when that would be the new item, it is dropped instead
+    EvictLowest,
+    /// Remove the item that was inserted the longest ago
+    EvictOldest,
+}
+
+/// Policy applied when an additive update would overflow an item's i64 score
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Clamp the resulting score to i64::MIN or i64::MAX
+    Saturating,
+    /// Reject the update with `PQueueError::Overflow`, leaving the item untouched
+    #[default]
+    Checked,
+    /// Wrap around the boundary of the i64 range
+    Wrapping,
+}
+
+/// Errors returned by queue operations
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PQueueError {
+    /// Adding delta to the item's current score overflowed under the `Checked` policy
+    Overflow { score: i64, delta: i64 },
+    /// The persistent storage backend failed
+    Storage(String),
+    /// The queue holds as many items as it may under the `Reject` capacity policy
+    Full { max_items: usize },
+}
+
+impl fmt::Display for PQueueError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PQueueError::Overflow { score, delta } => write!(f, "score overflow adding {} to {}", delta, score),
+            PQueueError::Storage(e) => write!(f, "storage error: {}", e),
+            PQueueError::Full { max_items } => write!(f, "queue is full ({} items)", max_items),
+        }
+    }
+}
+
+impl std::error::Error for PQueueError {}
+
 /// Statistics for the priority queue, returned by the `stats` method
 ///
 /// uptime: The time since the priority queue was instantiated
@@ -85,23 +709,63 @@ where
 /// updates: The count of update calls made to the queue since it was started
 /// items: The count of items currently in the queue
 /// pools: The count of separate score pools in the queue (a pool is just a set of items with the same score)
+/// enqueue_rate: The rate of update calls per second over sliding windows
+/// dequeue_rate: The rate of items popped per second over sliding windows
+/// oldest_item_age: How long the item that was inserted the longest ago has been waiting in the queue
+/// evicted: The count of items removed or dropped to keep the queue within its bound
+/// rejected: The count of inserts rejected for the queue being full
+/// expired: The count of items removed for having expired
 #[derive(Clone, Debug)]
 pub struct PQueueStats {
     pub uptime: Duration,
     pub version: String,
     pub updates: i64,
     pub items: i64,
-    pub pools: i64
+    pub pools: i64,
+    pub evicted: i64,
+    pub rejected: i64,
+    pub expired: i64,
+    pub enqueue_rate: Rates,
+    pub dequeue_rate: Rates,
+    pub oldest_item_age: Option<Duration>,
+}
+
+/// Details about an item in the queue, returned by the `item_info` method
+///
+/// inserted_at: When the item was added to the queue (updates to an item already in the queue keep it)
+/// last_updated: When the item's score was last set or added to
+/// expires_at: When the item expires, if it was given an expiry with `expire`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ItemInfo {
+    pub score: i64,
+    pub inserted_at: NaiveDateTime,
+    pub last_updated: NaiveDateTime,
+    pub expires_at: Option<NaiveDateTime>,
+}
+
+/// Operations per second averaged over the last 1 second, 1 minute and 5 minutes
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Rates {
+    pub last_1s: f64,
+    pub last_1m: f64,
+    pub last_5m: f64,
 }
 
 impl From<PQueueStatsTracker> for PQueueStats {
     fn from(value: PQueueStatsTracker) -> Self {
+        let now = Utc::now();
         Self {
-            uptime: Utc::now().naive_utc() - value.start_time,
+            uptime: now.naive_utc() - value.start_time,
             version: env!("CARGO_PKG_VERSION").to_string(),
             updates: value.updates,
             items: value.items,
             pools: value.pools,
+            evicted: value.evicted,
+            rejected: value.rejected,
+            expired: value.expired,
+            enqueue_rate: value.enqueues.rates(now.timestamp()),
+            dequeue_rate: value.dequeues.rates(now.timestamp()),
+            oldest_item_age: None,
         }
     }
 }
@@ -113,174 +777,951 @@ struct PQueueStatsTracker {
     updates: i64,
     items: i64,
     pools: i64,
+    evicted: i64,
+    rejected: i64,
+    expired: i64,
+    enqueues: RateTracker,
+    dequeues: RateTracker,
+}
+
+// Counts events in one second buckets covering the longest rate window, so the rate over any window
+// up to that length can be computed. Only completed seconds are counted, which keeps the 1s rate
+// from dipping at the start of every second.
+#[derive(Clone, Debug, Default)]
+struct RateTracker {
+    buckets: VecDeque<(i64, u64)>,
+}
+
+impl RateTracker {
+    const HORIZON_SECS: i64 = 300;
+
+    fn record(&mut self, now: i64) {
+        match self.buckets.back_mut() {
+            // Clock steps backwards are folded into the latest bucket rather than reordering them
+            Some((second, count)) if *second >= now => *count += 1,
+            _ => self.buckets.push_back((now, 1)),
+        }
+        while self.buckets.front().is_some_and(|&(second, _)| second < now - Self::HORIZON_SECS) {
+            self.buckets.pop_front();
+        }
+    }
+
+    fn rate(&self, now: i64, window_secs: i64) -> f64 {
+        let total: u64 = self.buckets.iter()
+            .filter(|&&(second, _)| second >= now - window_secs && second < now)
+            .map(|&(_, count)| count)
+            .sum();
+        total as f64 / window_secs as f64
+    }
+
+    fn rates(&self, now: i64) -> Rates {
+        Rates {
+            last_1s: self.rate(now, 1),
+            last_1m: self.rate(now, 60),
+            last_5m: self.rate(now, Self::HORIZON_SECS),
+        }
+    }
+}
+
+/// Updates and removals applied together under the queue's lock, see `PQueue::transaction`
+pub struct Transaction<'a, T, S>
+where
+    T: Eq + Hash,
+{
+    queue: &'a mut PriorityQueue<T, S>,
+}
+
+impl<T, S> Transaction<'_, T, S>
+where
+    T: Eq + Hash + Clone,
+    S: BuildHasher,
+{
+    /// Like `PQueue::update`
+    pub fn update(&mut self, item: T, new_score: i64) -> Result<(Option<i64>, Option<i64>), PQueueError> {
+        self.queue.update(Arc::new(item), new_score)
+    }
+
+    /// Like `PQueue::remove`
+    pub fn remove<Q>(&mut self, item: &Q) -> Option<i64>
+    where
+        T: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.queue.remove(item)
+    }
+
+    /// Like `PQueue::next_with_score`
+    pub fn next_with_score(&mut self) -> Option<(T, i64)> {
+        self.queue.next_entry().map(|(arc_item, score)| (Arc::try_unwrap(arc_item).unwrap_or_else(|arc| (*arc).clone()), score))
+    }
+
+    /// Like `PQueue::peek_with_score`, seeing the changes made so far
+    pub fn peek_with_score(&self) -> Option<(T, i64)> {
+        self.queue.peek_entry().map(|(arc_item, score)| ((*arc_item).clone(), score))
+    }
+
+    /// Like `PQueue::score`, seeing the changes made so far
+    pub fn score<Q>(&self, item: &Q) -> Option<i64>
+    where
+        T: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.queue.score(item)
+    }
 }
 
 // The core priority queue structure
 
-struct PriorityQueue<T>
+// The items sharing a score, keyed by the sequence number they were placed in the pool with. Keying by
+// sequence keeps the pool in FIFO order while letting an item be taken out of the middle of the pool in
+// O(log n) using the position recorded in its index entry, however many items share the score.
+type Pool<T> = BTreeMap<u64, Arc<T>>;
+
+struct PriorityQueue<T, S>
 where
     T: Eq + Hash,
 {
-    scores: BTreeMap<i64, VecDeque<Arc<T>>>,
-    items: HashMap<Arc<T>, i64>,
+    scores: BTreeMap<i64, Pool<T>>,
+    items: HashMap<Arc<T>, ItemEntry, S>,
+    // Items in the order they were first inserted, keyed by their insertion sequence number
+    inserted: BTreeMap<u64, Arc<T>>,
+    // Items that expire, keyed by when they expire and their insertion sequence number
+    expiries: BTreeMap<(NaiveDateTime, u64), Arc<T>>,
+    // Source of both insertion sequence numbers and pool positions
+    next_seq: u64,
     stats: PQueueStatsTracker,
+    overflow_policy: OverflowPolicy,
+    max_items: Option<(usize, CapacityPolicy)>,
+    paused: bool,
+    scheduler: Scheduler,
+    auto_remove: bool,
+    // How many expired items each use of the queue removes, None for all of them
+    expiry_sweep_limit: Option<usize>,
+    #[cfg(feature = "async")]
+    events: EventPublisher<T>,
+}
+
+// Index entry for an item in the queue
+struct ItemEntry {
+    score: i64,
+    // The item's key within the pool for its score
+    position: u64,
+    inserted_at: NaiveDateTime,
+    last_updated: NaiveDateTime,
+    expires_at: Option<NaiveDateTime>,
+    seq: u64,
 }
 
-impl<T> PriorityQueue<T>
+impl<T, S> PriorityQueue<T, S>
 where
     T: Eq + Hash + Clone,
+    S: BuildHasher,
 {
-    pub fn update(&mut self, item: Arc<T>, new_score: i64) {
-        let mut new_score = new_score;
-        self.stats.updates += 1;
-        if let Some(&current_score) = self.items.get(&item) {
-            self.remove_item(&item, current_score);
-            new_score += current_score;
-        } else {
-            self.stats.items += 1;
+    pub fn update(&mut self, item: Arc<T>, new_score: i64) -> Result<(Option<i64>, Option<i64>), PQueueError> {
+        let current_score = self.items.get(&item).map(|entry| entry.score);
+        let new_score = match current_score {
+            Some(current_score) => self.add_scores(current_score, new_score)?,
+            None => new_score,
+        };
+
+        if self.auto_remove && new_score <= 0 {
+            self.record_update();
+            self.remove(&*item);
+            return Ok((current_score, None));
+        }
+        if current_score.is_none() && !self.make_room(new_score)? {
+            return Ok((None, None));
+        }
+        self.record_update();
+        self.place_item(item, new_score);
+        Ok((current_score, Some(new_score)))
+    }
+
+    // Sets the item's score to an absolute value rather than adding to it, returning the previous score
+    pub fn set_score(&mut self, item: Arc<T>, new_score: i64) -> Result<Option<i64>, PQueueError> {
+        if !self.items.contains_key(&item) && !self.make_room(new_score)? {
+            return Ok(None);
         }
+        self.record_update();
+        Ok(self.place_item(item, new_score))
+    }
 
-        self.items.insert(item.clone(), new_score);
-        if !self.scores.contains_key(&new_score) {
-            self.stats.pools += 1;
+    // Counts an update that goes ahead, once the item is sure to be placed (or auto removed)
+    fn record_update(&mut self) {
+        self.stats.updates += 1;
+        self.stats.enqueues.record(Utc::now().timestamp());
+    }
+
+    // Applies the capacity policy before inserting an item with the given score into a full queue.
+    // Returns whether to go ahead with the insert, false when the new item is the one evicted.
+    fn make_room(&mut self, score: i64) -> Result<bool, PQueueError> {
+        let Some((max_items, policy)) = self.max_items else {
+            return Ok(true);
+        };
+        if self.items.len() < max_items {
+            return Ok(true);
+        }
+        let evicted = match policy {
+            CapacityPolicy::Reject => {
+                self.stats.rejected += 1;
+                return Err(PQueueError::Full { max_items });
+            },
+            // Within a pool items pop oldest first, so the newest of the lowest pool pops last, after
+            // which a new item with the same score would pop
+            CapacityPolicy::EvictLowest => match self.scores.iter().next() {
+                Some((&lowest, pool)) if lowest < score => pool.values().next_back().cloned(),
+                _ => None,
+            },
+            CapacityPolicy::EvictOldest => self.inserted.values().next().cloned(),
+        };
+        self.stats.evicted += 1;
+        match evicted {
+            Some(evicted) => {
+                self.remove(&*evicted);
+                Ok(true)
+            },
+            None => Ok(false),
         }
-        self.scores.entry(new_score).or_default().push_back(item);
     }
 
     pub fn peek(&self) -> Option<Arc<T>> {
-        self.scores.iter().next_back().and_then(|(_, items)| items.iter().next().cloned())
+        self.peek_entry().map(|(item, _)| item)
+    }
+
+    pub fn peek_entry(&self) -> Option<(Arc<T>, i64)> {
+        self.peek_entries().next()
+    }
+
+    // Every item with its score, from the highest score down and FIFO within a score
+    pub fn peek_entries(&self) -> impl Iterator<Item = (Arc<T>, i64)> + '_ {
+        self.scores.iter().rev().flat_map(|(&score, items)| items.values().map(move |item| (item.clone(), score)))
     }
 
     pub fn next(&mut self) -> Option<Arc<T>> {
-        if let Some((&score, items)) = self.scores.iter_mut().next_back() {
-            let item = items.pop_front();
-            if let Some(item) = item {
-                if items.is_empty() {
-                    self.scores.remove(&score);
-                    self.stats.pools -= 1;
-                }
-                self.items.remove(&item);
-                self.stats.items -= 1;
-                Some(item)
-            } else {
-                self.scores.remove(&score);
-                self.stats.pools -= 1;
-                None
-            }
-        } else {
-            None
-        }
+        self.next_entry().map(|(item, _)| item)
     }
 
-    pub fn score(&self, item: &Arc<T>) -> Option<i64> {
-        self.items.get(item).cloned()
+    // Pops the next item along with the score it had
+    pub fn next_entry(&mut self) -> Option<(Arc<T>, i64)> {
+        self.next_entry_above(i64::MIN)
     }
 
-    fn remove_item(&mut self, item: &Arc<T>, score: i64) {
-        if let Some(items) = self.scores.get_mut(&score) {
-            items.retain(|i| i != item);
-            if items.is_empty() {
-                self.scores.remove(&score);
-                self.stats.pools -= 1;
+    // Pops the next item only if its score is at least threshold
+    pub fn next_entry_above(&mut self, threshold: i64) -> Option<(Arc<T>, i64)> {
+        if self.paused {
+            return None;
+        }
+        loop {
+            let (score, band) = self.scheduler.select(&self.scores)?;
+            if score < threshold {
+                return None;
+            }
+            // Expired items a limited sweep left behind are dropped rather than popped
+            if self.expiry_sweep_limit.is_some() && self.expire_first(score) {
+                continue;
             }
+            if let Some(band) = band {
+                self.scheduler.charge(band, &self.scores);
+            }
+            return self.pop_from_pool(score).map(|item| (item, score));
         }
     }
-}
 
-pub trait PQueueOperations<T> {
-    fn new() -> Self;
-    fn update(&self, item: T, new_score: i64);
-    fn peek(&self) -> Option<T>;
-    fn next(&self) -> Option<T>;
-    fn score(&self, item: &T) -> Option<i64>;
-    fn stats(&self) -> PQueueStats;
-}
+    pub fn score<Q>(&self, item: &Q) -> Option<i64>
+    where
+        T: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.items.get(&item as &dyn KeyRef<Q>).map(|entry| entry.score)
+    }
 
+    pub fn item_info<Q>(&self, item: &Q) -> Option<ItemInfo>
+    where
+        T: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.items.get(&item as &dyn KeyRef<Q>).map(|entry| ItemInfo {
+            score: entry.score,
+            inserted_at: entry.inserted_at,
+            last_updated: entry.last_updated,
+            expires_at: entry.expires_at,
+        })
+    }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    pub fn contains<Q>(&self, item: &Q) -> bool
+    where
+        T: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.items.contains_key(&item as &dyn KeyRef<Q>)
+    }
 
-    #[test]
-    fn test_update_and_peek() {
-        let queue = PQueue::<String>::new();
-        queue.update("item1".to_string(), 10);
-        queue.update("item2".to_string(), 20);
-        assert_eq!(queue.peek(), Some("item2".to_string()));
+    pub fn restore(&mut self, item: Arc<T>, info: ItemInfo) -> Option<i64> {
+        let previous = self.place_item(item.clone(), info.score);
+        self.set_expiry(&*item, info.expires_at);
+        if let Some(entry) = self.items.get_mut(&item) {
+            entry.inserted_at = info.inserted_at;
+            entry.last_updated = info.last_updated;
+        }
+        previous
     }
 
-    #[test]
-    fn test_next() {
-        let queue = PQueue::<String>::new();
-        queue.update("item1".to_string(), 10);
-        queue.update("item2".to_string(), 20);
-        assert_eq!(queue.next(), Some("item2".to_string()));
-        assert_eq!(queue.peek(), Some("item1".to_string()));
+    pub fn memory_usage(&self, heap_size: impl Fn(&T) -> usize) -> usize {
+        let items: usize = self.items.iter().map(|(item, entry)| Self::footprint(entry) + heap_size(item)).sum();
+        size_of::<Self>() + items + self.scores.len() * (size_of::<i64>() + size_of::<Pool<T>>())
     }
 
-    #[test]
-    fn test_update_existing_item() {
-        let queue = PQueue::<String>::new();
-        queue.update("item1".to_string(), 10);
-        queue.update("item1".to_string(), 20);
-        assert_eq!(queue.score(&"item1".to_string()), Some(30));
+    pub fn item_memory_usage<Q>(&self, item: &Q, heap_size: impl Fn(&T) -> usize) -> Option<usize>
+    where
+        T: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let (item, entry) = self.items.get_key_value(&item as &dyn KeyRef<Q>)?;
+        Some(Self::footprint(entry) + heap_size(item))
     }
 
-    #[test]
-    fn test_next_on_empty() {
-        let queue = PQueue::<String>::new();
-        assert_eq!(queue.next(), None);
+    // Bytes taken up by an item's allocation and its entries in the queue's maps, leaving out the heap
+    // memory the item owns and the maps' spare capacity
+    fn footprint(entry: &ItemEntry) -> usize {
+        let allocation = 2 * size_of::<usize>() + size_of::<T>();
+        let index = size_of::<Arc<T>>() + size_of::<ItemEntry>() + 1;
+        // Its place in its pool and in the insertion order
+        let positions = 2 * (size_of::<u64>() + size_of::<Arc<T>>());
+        let expiry = entry.expires_at.map_or(0, |_| size_of::<(NaiveDateTime, u64)>() + size_of::<Arc<T>>());
+        allocation + index + positions + expiry
     }
 
-    #[test]
-    fn test_score_retrieval() {
-        let queue = PQueue::<String>::new();
-        queue.update("item1".to_string(), 10);
-        queue.update("item2".to_string(), 20);
-        assert_eq!(queue.score(&"item1".to_string()), Some(10));
-        assert_eq!(queue.score(&"item2".to_string()), Some(20));
+    pub fn remove<Q>(&mut self, item: &Q) -> Option<i64>
+    where
+        T: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let (_item, score) = self.unlink(item)?;
+        #[cfg(feature = "async")]
+        self.events.publish(|| QueueEvent::Removed { item: _item, score });
+        Some(score)
     }
 
-    #[test]
-    fn test_score_after_update() {
-        let queue = PQueue::<String>::new();
-        queue.update("item1".to_string(), 10);
-        queue.update("item1".to_string(), 20); // Updating the same item
-        assert_eq!(queue.score(&"item1".to_string()), Some(30)); // Expect the score to be cumulative
+    pub fn clear(&mut self) -> usize {
+        let removed = self.items.len();
+        #[cfg(feature = "async")]
+        self.events.publish(|| QueueEvent::Cleared { count: removed });
+        self.scores.clear();
+        self.items.clear();
+        self.inserted.clear();
+        self.expiries.clear();
+        self.stats.items = 0;
+        self.stats.pools = 0;
+        removed
     }
 
-    #[test]
-    fn test_stats_after_operations() {
-        let queue = PQueue::<String>::new();
-        queue.update("item1".to_string(), 10);
-        queue.update("item2".to_string(), 20);
-        queue.next();
-        let stats = queue.stats();
-        assert_eq!(stats.updates, 2);
+    // Loads (score, item) pairs sorted by ascending score. Into an empty queue the pools are built in
+    // order and the score tree is bulk built from them, instead of looking up a pool per item. Pairs
+    // that break the ordering, repeat an item, or target a non-empty queue are placed individually.
+    pub fn load_sorted<I>(&mut self, iter: I) -> usize
+    where
+        I: Iterator<Item = (i64, Arc<T>)>,
+    {
+        let now = Utc::now().naive_utc();
+        let mut leftovers = Vec::new();
+        let mut loaded = 0;
+        if self.items.is_empty() {
+            let (lower, _) = iter.size_hint();
+            self.items.reserve(lower);
+            let mut pools: Vec<(i64, Pool<T>)> = Vec::new();
+            for (score, item) in iter {
+                let in_order = pools.last().map_or(true, |&(last, _)| score >= last);
+                if !in_order || self.items.contains_key(&item) {
+                    leftovers.push((score, item));
+                    continue;
+                }
+                let position = self.take_seq();
+                self.index_item(item.clone(), score, position, now);
+                match pools.last_mut() {
+                    Some((last, pool)) if *last == score => {
+                        pool.insert(position, item);
+                    },
+                    _ => pools.push((score, Pool::from([(position, item)]))),
+                }
+                loaded += 1;
+            }
+            self.stats.pools += pools.len() as i64;
+            self.scores = pools.into_iter().collect();
+        } else {
+            leftovers.extend(iter);
+        }
+
+        loaded += leftovers.len();
+        for (score, item) in leftovers {
+            self.place_item(item, score);
+        }
+        loaded
+    }
+
+    // Sets or clears when the item expires, returning false if the item is not in the queue
+    pub fn set_expiry<Q>(&mut self, item: &Q, expires_at: Option<NaiveDateTime>) -> bool
+    where
+        T: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let Some((item, entry)) = self.items.get_key_value(&item as &dyn KeyRef<Q>) else {
+            return false;
+        };
+        let (item, seq, previous) = (item.clone(), entry.seq, entry.expires_at);
+        if let Some(previous) = previous {
+            self.expiries.remove(&(previous, seq));
+        }
+        if let Some(expires_at) = expires_at {
+            self.expiries.insert((expires_at, seq), item.clone());
+        }
+        if let Some(entry) = self.items.get_mut(&item) {
+            entry.expires_at = expires_at;
+        }
+        true
+    }
+
+    // Removes up to max of the items that expired by now, returning how many were removed
+    pub fn remove_expired(&mut self, now: NaiveDateTime, max: usize) -> usize {
+        let mut removed = 0;
+        while removed < max {
+            let Some(entry) = self.expiries.first_entry() else {
+                break;
+            };
+            if entry.key().0 > now {
+                break;
+            }
+            let item = entry.remove();
+            self.unlink_expired(&item);
+            removed += 1;
+        }
+        removed
+    }
+
+    // Removes the first item of the pool for score if it has expired, returning whether it had
+    fn expire_first(&mut self, score: i64) -> bool {
+        let Some((_, item)) = self.scores.get(&score).and_then(|pool| pool.first_key_value()) else {
+            return false;
+        };
+        let expired = self.items.get(item)
+            .and_then(|entry| entry.expires_at)
+            .is_some_and(|expires_at| expires_at <= Utc::now().naive_utc());
+        if expired {
+            let item = item.clone();
+            self.unlink_expired(&item);
+        }
+        expired
+    }
+
+    fn unlink_expired(&mut self, item: &Arc<T>) {
+        if let Some((_item, _score)) = self.unlink(&**item) {
+            self.stats.expired += 1;
+            #[cfg(feature = "async")]
+            self.events.publish(|| QueueEvent::Expired { item: _item, score: _score });
+        }
+    }
+
+    // The insertion time of the item that has been in the queue the longest
+    pub fn oldest_inserted_at(&self) -> Option<NaiveDateTime> {
+        self.inserted.values().next().and_then(|item| self.items.get(item)).map(|entry| entry.inserted_at)
+    }
+
+    fn pop_from_pool(&mut self, score: i64) -> Option<Arc<T>> {
+        let items = self.scores.get_mut(&score)?;
+        let item = items.pop_first();
+        if items.is_empty() {
+            self.scores.remove(&score);
+            self.stats.pools -= 1;
+        }
+        let (_, item) = item?;
+        if let Some(entry) = self.items.remove(&item) {
+            self.forget(&entry);
+        }
+        self.stats.items -= 1;
+        self.stats.dequeues.record(Utc::now().timestamp());
+        #[cfg(feature = "async")]
+        self.events.publish(|| QueueEvent::Popped { item: item.clone(), score });
+        Some(item)
+    }
+
+    // Takes the item out of the queue, returning it along with the score it had
+    fn unlink<Q>(&mut self, item: &Q) -> Option<(Arc<T>, i64)>
+    where
+        T: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let (item, entry) = self.items.remove_entry(&item as &dyn KeyRef<Q>)?;
+        self.forget(&entry);
+        self.stats.items -= 1;
+        self.remove_from_pool(entry.score, entry.position);
+        Some((item, entry.score))
+    }
+
+    fn add_scores(&self, score: i64, delta: i64) -> Result<i64, PQueueError> {
+        match self.overflow_policy {
+            OverflowPolicy::Saturating => Ok(score.saturating_add(delta)),
+            OverflowPolicy::Checked => score.checked_add(delta).ok_or(PQueueError::Overflow { score, delta }),
+            OverflowPolicy::Wrapping => Ok(score.wrapping_add(delta)),
+        }
+    }
+
+    // Puts the item at the back of the pool for score, indexing it if it is new or moving it out of
+    // its current pool otherwise. Returns the score the item had before.
+    fn place_item(&mut self, item: Arc<T>, score: i64) -> Option<i64> {
+        let now = Utc::now().naive_utc();
+        let position = self.take_seq();
+        let (item, current) = match self.items.get_key_value(&item) {
+            // Keep using the Arc the index already holds so the item is only stored once
+            Some((indexed, entry)) => (indexed.clone(), Some((entry.score, entry.position))),
+            None => (item, None),
+        };
+        match current {
+            Some((current_score, current_position)) => {
+                if let Some(entry) = self.items.get_mut(&item) {
+                    entry.score = score;
+                    entry.position = position;
+                    entry.last_updated = now;
+                }
+                self.remove_from_pool(current_score, current_position);
+            },
+            None => self.index_item(item.clone(), score, position, now),
+        }
+
+        if !self.scores.contains_key(&score) {
+            self.stats.pools += 1;
+        }
+        #[cfg(feature = "async")]
+        self.events.publish(|| QueueEvent::Updated {
+            item: item.clone(),
+            previous: current.map(|(current_score, _)| current_score),
+            score,
+        });
+        self.scores.entry(score).or_default().insert(position, item);
+        current.map(|(current_score, _)| current_score)
+    }
+
+    // Adds a new item to the index; the caller is responsible for putting it in its pool at seq
+    fn index_item(&mut self, item: Arc<T>, score: i64, seq: u64, now: NaiveDateTime) {
+        self.items.insert(item.clone(), ItemEntry { score, position: seq, inserted_at: now, last_updated: now, expires_at: None, seq });
+        self.inserted.insert(seq, item);
+        self.stats.items += 1;
+    }
+
+    // Drops the bookkeeping kept for an item besides its index entry and its place in a pool
+    fn forget(&mut self, entry: &ItemEntry) {
+        self.inserted.remove(&entry.seq);
+        if let Some(expires_at) = entry.expires_at {
+            self.expiries.remove(&(expires_at, entry.seq));
+        }
+    }
+
+    fn take_seq(&mut self) -> u64 {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        seq
+    }
+
+    fn remove_from_pool(&mut self, score: i64, position: u64) {
+        if let Some(items) = self.scores.get_mut(&score) {
+            items.remove(&position);
+            if items.is_empty() {
+                self.scores.remove(&score);
+                self.stats.pools -= 1;
+            }
+        }
+    }
+}
+
+pub trait PQueueOperations<T> {
+    fn new() -> Self;
+    fn update(&self, item: T, new_score: i64) -> Result<(Option<i64>, Option<i64>), PQueueError>;
+    fn set_score(&self, item: T, score: i64) -> Result<Option<i64>, PQueueError>;
+    fn insert_if_absent(&self, item: T, score: i64) -> bool;
+    fn update_if_exists(&self, item: T, delta: i64) -> Result<bool, PQueueError>;
+    fn cas_score(&self, item: T, expected: i64, new: i64) -> Result<(), i64>;
+    fn peek(&self) -> Option<T>;
+    fn next(&self) -> Option<T>;
+    fn peek_with_score(&self) -> Option<(T, i64)>;
+    fn next_with_score(&self) -> Option<(T, i64)>;
+    fn next_batch(&self, max: usize) -> Vec<T>;
+    fn peek_n(&self, count: usize) -> Vec<(T, i64)>;
+    fn score_range(&self, min: i64, max: i64, count: usize) -> Vec<(T, i64)>;
+    fn snapshot(&self) -> Vec<(i64, T)>;
+    fn scan(&self, cursor: u64, count: usize) -> (u64, Vec<(T, i64)>);
+    fn peek_arc(&self) -> Option<Arc<T>>;
+    fn top_score(&self) -> Option<i64>;
+    fn top_pool(&self) -> Option<(i64, usize)>;
+    fn pools(&self) -> Vec<(i64, usize)>;
+    fn next_if_above(&self, threshold: i64) -> Option<T>;
+    fn next_arc(&self) -> Option<Arc<T>>;
+    fn score(&self, item: &T) -> Option<i64>;
+    fn contains(&self, item: &T) -> bool;
+    fn item_info(&self, item: &T) -> Option<ItemInfo>;
+    fn remove(&self, item: &T) -> Option<i64>;
+    fn expire(&self, item: &T, ttl: Duration) -> bool;
+    fn persist(&self, item: &T) -> bool;
+    fn clear(&self) -> usize;
+    fn stats(&self) -> PQueueStats;
+    fn reset_stats(&self);
+    fn pause(&self);
+    fn resume(&self);
+    fn is_paused(&self) -> bool;
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_update_and_peek() {
+        let queue = PQueue::<String>::new();
+        queue.update("item1".to_string(), 10).unwrap();
+        queue.update("item2".to_string(), 20).unwrap();
+        assert_eq!(queue.peek(), Some("item2".to_string()));
+    }
+
+    #[test]
+    fn test_next() {
+        let queue = PQueue::<String>::new();
+        queue.update("item1".to_string(), 10).unwrap();
+        queue.update("item2".to_string(), 20).unwrap();
+        assert_eq!(queue.next(), Some("item2".to_string()));
+        assert_eq!(queue.peek(), Some("item1".to_string()));
+    }
+
+    #[test]
+    fn test_update_existing_item() {
+        let queue = PQueue::<String>::new();
+        queue.update("item1".to_string(), 10).unwrap();
+        queue.update("item1".to_string(), 20).unwrap();
+        assert_eq!(queue.score(&"item1".to_string()), Some(30));
+    }
+
+    #[test]
+    fn test_next_on_empty() {
+        let queue = PQueue::<String>::new();
+        assert_eq!(queue.next(), None);
+    }
+
+    #[test]
+    fn test_score_retrieval() {
+        let queue = PQueue::<String>::new();
+        queue.update("item1".to_string(), 10).unwrap();
+        queue.update("item2".to_string(), 20).unwrap();
+        assert_eq!(queue.score(&"item1".to_string()), Some(10));
+        assert_eq!(queue.score(&"item2".to_string()), Some(20));
+    }
+
+    #[test]
+    fn test_score_after_update() {
+        let queue = PQueue::<String>::new();
+        queue.update("item1".to_string(), 10).unwrap();
+        queue.update("item1".to_string(), 20).unwrap(); // Updating the same item
+        assert_eq!(queue.score(&"item1".to_string()), Some(30)); // Expect the score to be cumulative
+    }
+
+    #[test]
+    fn test_stats_after_operations() {
+        let queue = PQueue::<String>::new();
+        queue.update("item1".to_string(), 10).unwrap();
+        queue.update("item2".to_string(), 20).unwrap();
+        queue.next();
+        let stats = queue.stats();
+        assert_eq!(stats.updates, 2);
         assert_eq!(stats.items, 1); // One item should have been removed
         assert_eq!(stats.pools, 1); // Pools count after one removal
     }
 
+    #[test]
+    fn test_transaction() {
+        let queue = PQueue::<String>::new();
+        queue.update("item1".to_string(), 10).unwrap();
+        queue.update("item2".to_string(), 5).unwrap();
+        let moved = queue.transaction(|tx| {
+            let score = tx.remove("item1").unwrap();
+            tx.update("item2".to_string(), score).unwrap();
+            tx.score("item2")
+        });
+        assert_eq!(moved, Some(15));
+        assert!(!queue.contains("item1"));
+        assert_eq!(queue.stats().items, 1);
+        let halved = queue.transaction(|tx| {
+            let (item, score) = tx.next_with_score().unwrap();
+            tx.update(item, score / 2).unwrap();
+            tx.peek_with_score()
+        });
+        assert_eq!(halved, Some(("item2".to_string(), 7)));
+        assert_eq!(queue.next(), Some("item2".to_string()));
+    }
+
+    #[test]
+    fn test_expire() {
+        let queue = PQueue::<String>::new();
+        queue.update("item1".to_string(), 10).unwrap();
+        queue.update("item2".to_string(), 5).unwrap();
+        assert!(queue.expire("item1", Duration::zero()));
+        assert!(!queue.expire("missing", Duration::zero()));
+        // Too far off to add to the current time
+        queue.update("item3".to_string(), 5).unwrap();
+        assert!(queue.expire("item3", Duration::max_value()));
+        assert_eq!(queue.item_info("item3").unwrap().expires_at, Some(NaiveDateTime::MAX));
+        assert!(queue.expire("item3", Duration::min_value()));
+        assert!(!queue.contains("item3"));
+        assert!(!queue.contains("item1"));
+        assert_eq!(queue.stats().items, 1);
+        assert_eq!(queue.next(), Some("item2".to_string()));
+    }
+
+    #[test]
+    fn test_expiry_sweep_limit() {
+        let queue = PQueue::<String>::new();
+        queue.set_expiry_sweep_limit(Some(0));
+        queue.update("item1".to_string(), 10).unwrap();
+        queue.update("item2".to_string(), 10).unwrap();
+        queue.update("item3".to_string(), 5).unwrap();
+        queue.expire("item1", Duration::zero());
+        queue.expire("item2", Duration::zero());
+        // Left in place by the sweeps, but never popped
+        assert!(queue.contains("item1"));
+        assert_eq!(queue.next(), Some("item3".to_string()));
+        assert_eq!(queue.stats().expired, 2);
+        queue.update("item4".to_string(), 1).unwrap();
+        queue.expire("item4", Duration::zero());
+        assert_eq!(queue.remove_expired(10), 1);
+        assert_eq!(queue.stats().items, 0);
+        assert_eq!(queue.stats().expired, 3);
+    }
+
+    #[test]
+    fn test_persist() {
+        let queue = PQueue::<String>::new();
+        queue.update("item1".to_string(), 10).unwrap();
+        assert!(!queue.persist("item1"));
+        assert!(queue.expire("item1", Duration::hours(1)));
+        assert!(queue.item_info("item1").unwrap().expires_at.is_some());
+        assert!(queue.persist("item1"));
+        assert_eq!(queue.item_info("item1").unwrap().expires_at, None);
+        // A popped item doesn't keep its expiry when it is added again
+        queue.expire("item1", Duration::hours(1));
+        assert_eq!(queue.next(), Some("item1".to_string()));
+        queue.update("item1".to_string(), 1).unwrap();
+        assert_eq!(queue.item_info("item1").unwrap().expires_at, None);
+    }
+
+    #[test]
+    fn test_reset_stats() {
+        let queue = PQueue::<String>::new();
+        queue.update("item1".to_string(), 1).unwrap();
+        queue.update("item2".to_string(), 2).unwrap();
+        queue.reset_stats();
+        let stats = queue.stats();
+        assert_eq!(stats.updates, 0);
+        assert_eq!(stats.items, 2);
+        assert_eq!(stats.pools, 2);
+        queue.update("item1".to_string(), 1).unwrap();
+        assert_eq!(queue.stats().updates, 1);
+    }
+
     #[test]
     fn test_removal_of_items() {
         let queue = PQueue::<String>::new();
-        queue.update("item1".to_string(), 10);
-        queue.update("item2".to_string(), 20);
+        queue.update("item1".to_string(), 10).unwrap();
+        queue.update("item2".to_string(), 20).unwrap();
         queue.next(); // This should remove "item2"
         assert_eq!(queue.score(&"item2".to_string()), None); // "item2" should not be in the queue
     }
 
+    #[test]
+    fn test_with_score() {
+        let queue = PQueue::<String>::new();
+        assert_eq!(queue.peek_with_score(), None);
+        queue.update("item1".to_string(), 10).unwrap();
+        queue.update("item2".to_string(), 20).unwrap();
+        assert_eq!(queue.peek_with_score(), Some(("item2".to_string(), 20)));
+        assert_eq!(queue.next_with_score(), Some(("item2".to_string(), 20)));
+        assert_eq!(queue.next_with_score(), Some(("item1".to_string(), 10)));
+        assert_eq!(queue.next_with_score(), None);
+    }
+
+    #[test]
+    fn test_next_batch() {
+        let queue = PQueue::<String>::new();
+        queue.update("item1".to_string(), 10).unwrap();
+        queue.update("item2".to_string(), 20).unwrap();
+        queue.update("item3".to_string(), 10).unwrap();
+        assert_eq!(queue.next_batch(2), vec!["item2".to_string(), "item1".to_string()]);
+        assert_eq!(queue.next_batch(5), vec!["item3".to_string()]);
+        assert!(queue.next_batch(5).is_empty());
+    }
+
+    #[test]
+    fn test_peek_n() {
+        let queue = PQueue::<String>::new();
+        queue.update("item1".to_string(), 10).unwrap();
+        queue.update("item2".to_string(), 20).unwrap();
+        queue.update("item3".to_string(), 10).unwrap();
+        assert_eq!(queue.peek_n(2), vec![("item2".to_string(), 20), ("item1".to_string(), 10)]);
+        assert_eq!(queue.peek_n(5).len(), 3);
+        assert_eq!(queue.stats().items, 3);
+    }
+
+    #[test]
+    fn test_score_range() {
+        let queue = PQueue::<String>::new();
+        queue.update("item1".to_string(), 10).unwrap();
+        queue.update("item2".to_string(), 20).unwrap();
+        queue.update("item3".to_string(), 10).unwrap();
+        queue.update("item4".to_string(), 5).unwrap();
+        assert_eq!(
+            queue.score_range(10, 20, usize::MAX),
+            vec![("item2".to_string(), 20), ("item1".to_string(), 10), ("item3".to_string(), 10)]
+        );
+        assert_eq!(queue.score_range(6, 15, 1), vec![("item1".to_string(), 10)]);
+        assert!(queue.score_range(20, 10, usize::MAX).is_empty());
+        assert_eq!(queue.stats().items, 4);
+    }
+
+    #[test]
+    fn test_set_score() {
+        let pqueue = PQueue::<String>::new();
+        assert_eq!(pqueue.set_score("item1".to_string(), 5), Ok(None));
+        assert_eq!(pqueue.set_score("item1".to_string(), 5), Ok(Some(5)));
+        assert_eq!(pqueue.score("item1"), Some(5));
+
+        pqueue.update("item2".to_string(), 3).unwrap();
+        assert_eq!(pqueue.set_score("item1".to_string(), 1), Ok(Some(5)));
+        assert_eq!(pqueue.next(), Some("item2".to_string()));
+        assert_eq!(pqueue.next(), Some("item1".to_string()));
+    }
+
+    #[test]
+    fn test_max_items() {
+        let pqueue = PQueue::<String>::new();
+        pqueue.set_max_items(Some((2, CapacityPolicy::Reject)));
+        pqueue.update("a".to_string(), 1).unwrap();
+        pqueue.update("b".to_string(), 2).unwrap();
+        assert_eq!(pqueue.update("c".to_string(), 3), Err(PQueueError::Full { max_items: 2 }));
+        assert_eq!(pqueue.set_score("c".to_string(), 3), Err(PQueueError::Full { max_items: 2 }));
+        assert!(!pqueue.insert_if_absent("c".to_string(), 3));
+        // Items already in the queue can still be updated
+        assert_eq!(pqueue.update("a".to_string(), 1), Ok((Some(1), Some(2))));
+        assert_eq!(pqueue.stats().rejected, 3);
+        // Rejected inserts aren't counted as updates or enqueues
+        assert_eq!(pqueue.stats().updates, 3);
+        assert_eq!(pqueue.queue.lock().unwrap().stats.enqueues.buckets.iter().map(|b| b.1).sum::<u64>(), 3);
+
+        pqueue.set_max_items(Some((2, CapacityPolicy::EvictLowest)));
+        pqueue.update("c".to_string(), 3).unwrap();
+        assert_eq!(pqueue.peek_n(3), vec![("c".to_string(), 3), ("b".to_string(), 2)]);
+        // An item that would pop last is the one dropped
+        assert_eq!(pqueue.update("d".to_string(), 2), Ok((None, None)));
+        assert!(!pqueue.insert_if_absent("d".to_string(), 1));
+        assert!(!pqueue.contains("d"));
+
+        pqueue.set_max_items(Some((2, CapacityPolicy::EvictOldest)));
+        pqueue.update("e".to_string(), 1).unwrap();
+        assert_eq!(pqueue.peek_n(3), vec![("c".to_string(), 3), ("e".to_string(), 1)]);
+        assert_eq!(pqueue.stats().evicted, 4);
+
+        pqueue.set_max_items(None);
+        pqueue.update("f".to_string(), 1).unwrap();
+        assert_eq!(pqueue.stats().items, 3);
+    }
+
+    #[test]
+    fn test_evict() {
+        let pqueue = PQueue::<String>::new();
+        for (item, score) in [("a", 3), ("b", 1), ("c", 1), ("d", 2)] {
+            pqueue.update(item.to_string(), score).unwrap();
+        }
+        assert_eq!(pqueue.evict(2, CapacityPolicy::Reject), Vec::<String>::new());
+        assert_eq!(pqueue.evict(2, CapacityPolicy::EvictLowest), vec!["c".to_string(), "b".to_string()]);
+        assert_eq!(pqueue.evict(1, CapacityPolicy::EvictOldest), vec!["a".to_string()]);
+        assert_eq!(pqueue.evict(5, CapacityPolicy::EvictOldest), vec!["d".to_string()]);
+        assert_eq!(pqueue.stats().evicted, 4);
+    }
+
+    #[test]
+    fn test_scan() {
+        let pqueue = PQueue::<String>::new();
+        for i in 0..5 {
+            pqueue.update(format!("item{}", i), i).unwrap();
+        }
+
+        let (cursor, first) = pqueue.scan(0, 2);
+        assert_eq!(first, vec![("item0".to_string(), 0), ("item1".to_string(), 1)]);
+        assert_ne!(cursor, 0);
+
+        // Items moving between scores or leaving don't upset the scan
+        pqueue.update("item0".to_string(), 10).unwrap();
+        pqueue.remove("item3");
+        pqueue.update("item5".to_string(), 5).unwrap();
+        let (cursor, second) = pqueue.scan(cursor, 2);
+        assert_eq!(second, vec![("item2".to_string(), 2), ("item4".to_string(), 4)]);
+        let (cursor, last) = pqueue.scan(cursor, 2);
+        assert_eq!(last, vec![("item5".to_string(), 5)]);
+        assert_eq!(cursor, 0);
+
+        assert_eq!(PQueue::<String>::new().scan(0, 10), (0, Vec::new()));
+    }
+
+    #[test]
+    fn test_restore() {
+        let source = PQueue::<String>::new();
+        source.update("item1".to_string(), 10).unwrap();
+        source.expire("item1", Duration::seconds(60));
+        let info = source.item_info("item1").unwrap();
+
+        let queue = PQueue::<String>::new();
+        queue.update("item2".to_string(), 10).unwrap();
+        assert_eq!(queue.restore("item1".to_string(), info.clone()), None);
+        assert_eq!(queue.item_info("item1"), Some(info.clone()));
+        assert_eq!(queue.next(), Some("item2".to_string()));
+
+        let replaced = ItemInfo { score: 20, ..info };
+        assert_eq!(queue.restore("item1".to_string(), replaced.clone()), Some(10));
+        assert_eq!(queue.item_info("item1"), Some(replaced));
+        assert_eq!(queue.stats().items, 1);
+    }
+
+    #[test]
+    fn test_memory_usage() {
+        let queue = PQueue::<String>::new();
+        let empty = queue.memory_usage(String::capacity);
+        queue.update("item1".to_string(), 10).unwrap();
+        let short = queue.item_memory_usage("item1", String::capacity).unwrap();
+        queue.update("a much longer item name".to_string(), 10).unwrap();
+        let long = queue.item_memory_usage("a much longer item name", String::capacity).unwrap();
+        assert_eq!(long - short, "a much longer item name".len() - "item1".len());
+        assert!(queue.memory_usage(String::capacity) >= empty + short + long);
+        assert_eq!(queue.item_memory_usage("missing", String::capacity), None);
+    }
+
+    #[test]
+    fn test_clear() {
+        let queue = PQueue::<String>::new();
+        queue.update("item1".to_string(), 10).unwrap();
+        queue.update("item2".to_string(), 20).unwrap();
+        assert_eq!(queue.clear(), 2);
+        assert_eq!(queue.next(), None);
+        assert_eq!(queue.stats().items, 0);
+        assert_eq!(queue.stats().pools, 0);
+        assert_eq!(queue.clear(), 0);
+        queue.update("item1".to_string(), 5).unwrap();
+        assert_eq!(queue.score("item1"), Some(5));
+    }
+
     #[test]
     fn test_complex_scenario() {
         let queue = PQueue::<String>::new();
         let queue2 = queue.clone();
-        queue.update("item1".to_string(), 10);
-        queue.update("item2".to_string(), 15);
+        queue.update("item1".to_string(), 10).unwrap();
+        queue.update("item2".to_string(), 15).unwrap();
         // ensure that queue and it clone share the same internal queue by adding an item to queue2
         // and checking if it comes back when we peek from queue
-        queue2.update("item3".to_string(), 22);
-        queue2.update("item4".to_string(), 15);
-        queue.update("item1".to_string(), 6); // Increment item1's score
+        queue2.update("item3".to_string(), 22).unwrap();
+        queue2.update("item4".to_string(), 15).unwrap();
+        queue.update("item1".to_string(), 6).unwrap(); // Increment item1's score
         assert_eq!(queue.peek(), Some("item3".to_string())); // "item3" should have the highest score
         queue.next(); // Remove "item3"
         assert_eq!(queue.peek(), Some("item1".to_string())); // "item1" should have the highest score now
@@ -291,4 +1732,595 @@ mod tests {
 
     }
 
-}
\ No newline at end of file
+    #[test]
+    fn test_insert_if_absent() {
+        let queue = PQueue::<String>::new();
+        assert!(queue.insert_if_absent("item1".to_string(), 10));
+        assert!(!queue.insert_if_absent("item1".to_string(), 20));
+        assert_eq!(queue.score(&"item1".to_string()), Some(10)); // Existing score is left untouched
+        assert_eq!(queue.stats().updates, 1);
+    }
+
+    #[test]
+    fn test_update_if_exists() {
+        let queue = PQueue::<String>::new();
+        assert_eq!(queue.update_if_exists("item1".to_string(), 10), Ok(false));
+        assert!(!queue.contains(&"item1".to_string())); // Missing items are not created
+        queue.update("item1".to_string(), 10).unwrap();
+        assert_eq!(queue.update_if_exists("item1".to_string(), 5), Ok(true));
+        assert_eq!(queue.score(&"item1".to_string()), Some(15));
+    }
+
+    #[test]
+    fn test_cas_score() {
+        let queue = PQueue::<String>::new();
+        assert_eq!(queue.cas_score("item1".to_string(), 10, 20), Err(10));
+        queue.update("item1".to_string(), 10).unwrap();
+        queue.update("item2".to_string(), 15).unwrap();
+        assert_eq!(queue.cas_score("item1".to_string(), 5, 20), Err(10));
+        assert_eq!(queue.score(&"item1".to_string()), Some(10));
+        assert_eq!(queue.cas_score("item1".to_string(), 10, 20), Ok(()));
+        assert_eq!(queue.score(&"item1".to_string()), Some(20)); // Score is replaced, not added to
+        assert_eq!(queue.peek(), Some("item1".to_string()));
+        queue.update("item3".to_string(), 20).unwrap();
+        // Setting the same score keeps the item ahead of those that joined its pool since
+        assert_eq!(queue.cas_score("item1".to_string(), 20, 20), Ok(()));
+        assert_eq!(queue.peek(), Some("item1".to_string()));
+        let stats = queue.stats();
+        assert_eq!(stats.items, 3);
+        assert_eq!(stats.pools, 2);
+    }
+
+    #[test]
+    fn test_update_returns_scores() {
+        let queue = PQueue::<String>::new();
+        assert_eq!(queue.update("item1".to_string(), 10), Ok((None, Some(10))));
+        assert_eq!(queue.update("item1".to_string(), -4), Ok((Some(10), Some(6))));
+    }
+
+    #[test]
+    fn test_overflow_checked() {
+        let queue = PQueue::<String>::new();
+        queue.update("item1".to_string(), i64::MAX).unwrap();
+        assert_eq!(queue.update("item1".to_string(), 1), Err(PQueueError::Overflow { score: i64::MAX, delta: 1 }));
+        assert_eq!(queue.score(&"item1".to_string()), Some(i64::MAX)); // Rejected update leaves the item untouched
+        assert_eq!(queue.stats().updates, 1);
+    }
+
+    #[test]
+    fn test_overflow_saturating_and_wrapping() {
+        let queue = PQueue::<String>::new();
+        queue.set_overflow_policy(OverflowPolicy::Saturating);
+        queue.update("item1".to_string(), i64::MIN).unwrap();
+        assert_eq!(queue.update("item1".to_string(), -1), Ok((Some(i64::MIN), Some(i64::MIN))));
+
+        queue.set_overflow_policy(OverflowPolicy::Wrapping);
+        assert_eq!(queue.overflow_policy(), OverflowPolicy::Wrapping);
+        assert_eq!(queue.update("item1".to_string(), -1), Ok((Some(i64::MIN), Some(i64::MAX))));
+        assert_eq!(queue.stats().pools, 1);
+    }
+
+    #[test]
+    fn test_custom_hasher() {
+        use std::collections::hash_map::DefaultHasher;
+        use std::hash::BuildHasherDefault;
+
+        let queue = PQueue::<String, BuildHasherDefault<DefaultHasher>>::default();
+        queue.update("item1".to_string(), 10).unwrap();
+        queue.update("item2".to_string(), 20).unwrap();
+        queue.update("item1".to_string(), 15).unwrap();
+        assert_eq!(queue.score(&"item1".to_string()), Some(25));
+        assert_eq!(queue.next(), Some("item1".to_string()));
+        assert_eq!(queue.next(), Some("item2".to_string()));
+    }
+
+    #[test]
+    fn test_borrowed_lookups() {
+        let queue = PQueue::<String>::new();
+        queue.update("item1".to_string(), 10).unwrap();
+        queue.update("item2".to_string(), 20).unwrap();
+        assert_eq!(queue.score("item1"), Some(10));
+        assert!(queue.contains("item2"));
+        assert!(!queue.contains("item3"));
+        assert_eq!(queue.remove("item2"), Some(20));
+        assert_eq!(queue.remove("item2"), None);
+        assert_eq!(queue.peek(), Some("item1".to_string()));
+        let stats = queue.stats();
+        assert_eq!(stats.items, 1);
+        assert_eq!(stats.pools, 1);
+    }
+
+    #[test]
+    fn test_arc_accessors() {
+        let queue = PQueue::<String>::new();
+        queue.update("item1".to_string(), 10).unwrap();
+        queue.update("item2".to_string(), 20).unwrap();
+        let head = queue.peek_arc().unwrap();
+        assert_eq!(*head, "item2");
+        let popped = queue.next_arc().unwrap();
+        assert!(Arc::ptr_eq(&head, &popped)); // Both point at the same allocation, nothing was cloned
+        assert_eq!(queue.next_arc().as_deref(), Some(&"item1".to_string()));
+        assert_eq!(queue.next_arc(), None);
+    }
+
+    #[test]
+    fn test_rate_tracker_windows() {
+        let mut tracker = RateTracker::default();
+        for _ in 0..30 {
+            tracker.record(1_000);
+        }
+        for _ in 0..6 {
+            tracker.record(1_059);
+        }
+        tracker.record(1_060); // The current second is still in progress and is not counted
+        let rates = tracker.rates(1_060);
+        assert_eq!(rates.last_1s, 6.0);
+        assert_eq!(rates.last_1m, 0.6);
+        assert_eq!(rates.last_5m, 36.0 / 300.0);
+        // Buckets older than the longest window are dropped
+        tracker.record(1_350);
+        assert_eq!(tracker.buckets.len(), 3);
+        assert_eq!(tracker.rates(1_351).last_5m, 8.0 / 300.0);
+    }
+
+    #[test]
+    fn test_stats_rates() {
+        let queue = PQueue::<String>::new();
+        queue.update("item1".to_string(), 10).unwrap();
+        queue.next();
+        let stats = queue.stats();
+        assert_eq!(queue.queue.lock().unwrap().stats.enqueues.buckets.iter().map(|b| b.1).sum::<u64>(), 1);
+        assert_eq!(queue.queue.lock().unwrap().stats.dequeues.buckets.iter().map(|b| b.1).sum::<u64>(), 1);
+        assert!(stats.enqueue_rate.last_5m <= 1.0 / 300.0);
+    }
+
+    #[test]
+    fn test_pause_and_resume() {
+        let queue = PQueue::<String>::new();
+        queue.update("item1".to_string(), 10).unwrap();
+        queue.pause();
+        assert!(queue.is_paused());
+        queue.update("item2".to_string(), 20).unwrap(); // Producers are still accepted while paused
+        assert_eq!(queue.next(), None);
+        assert_eq!(queue.next_arc(), None);
+        assert_eq!(queue.peek(), Some("item2".to_string()));
+        assert_eq!(queue.stats().items, 2);
+        queue.resume();
+        assert!(!queue.is_paused());
+        assert_eq!(queue.next(), Some("item2".to_string()));
+    }
+
+    #[test]
+    fn test_composite_score_ordering() {
+        let scores = [
+            CompositeScore::new(i32::MIN, 0),
+            CompositeScore::new(-1, u32::MAX),
+            CompositeScore::new(0, 0),
+            CompositeScore::new(0, 7),
+            CompositeScore::new(1, 0),
+            CompositeScore::new(i32::MAX, u32::MAX),
+        ];
+        for pair in scores.windows(2) {
+            assert!(i64::from(pair[0]) < i64::from(pair[1]));
+        }
+        for score in scores {
+            assert_eq!(CompositeScore::from(i64::from(score)), score);
+        }
+    }
+
+    #[test]
+    fn test_composite_score_in_queue() {
+        let queue = PQueue::<String>::new();
+        queue.insert_if_absent("minor-old".to_string(), CompositeScore::new_inverted(1, 100).into());
+        queue.insert_if_absent("major-new".to_string(), CompositeScore::new_inverted(2, 200).into());
+        queue.insert_if_absent("major-old".to_string(), CompositeScore::new_inverted(2, 100).into());
+        assert_eq!(queue.next(), Some("major-old".to_string()));
+        assert_eq!(queue.next(), Some("major-new".to_string()));
+        let score = queue.score("minor-old").map(CompositeScore::from);
+        assert_eq!(score, Some(CompositeScore::new_inverted(1, 100)));
+    }
+
+    #[test]
+    fn test_composite_score_update() {
+        let queue = PQueue::<String>::new();
+        let full = CompositeScore::new(1, u32::MAX);
+        assert_eq!(queue.update_composite("item1".to_string(), 1, 10), Ok((None, Some(CompositeScore::new(1, 10)))));
+        assert_eq!(queue.update_composite("item1".to_string(), 2, -5), Ok((Some(CompositeScore::new(1, 10)), Some(CompositeScore::new(3, 5)))));
+        queue.set_score("item2".to_string(), full.into()).unwrap();
+        // Adding to a full secondary would carry into the primary as a packed update
+        assert!(queue.update_composite("item2".to_string(), 0, 1).is_err());
+        assert_eq!(queue.score("item2").map(CompositeScore::from), Some(full));
+        queue.set_overflow_policy(OverflowPolicy::Saturating);
+        assert_eq!(queue.update_composite("item2".to_string(), 0, 1), Ok((Some(full), Some(full))));
+        queue.set_overflow_policy(OverflowPolicy::Wrapping);
+        assert_eq!(queue.update_composite("item2".to_string(), 0, 1), Ok((Some(full), Some(CompositeScore::new(1, 0)))));
+        assert!(queue.update_composite("item3".to_string(), 1, -1).is_ok());
+        queue.set_overflow_policy(OverflowPolicy::Checked);
+        assert!(queue.update_composite("item4".to_string(), 1, -1).is_err());
+        assert!(!queue.contains("item4"));
+    }
+
+    #[test]
+    fn test_item_info_timestamps() {
+        let queue = PQueue::<String>::new();
+        assert_eq!(queue.item_info("item1"), None);
+        assert!(queue.stats().oldest_item_age.is_none());
+        queue.update("item1".to_string(), 10).unwrap();
+        queue.update("item2".to_string(), 20).unwrap();
+        let info = queue.item_info("item1").unwrap();
+        assert_eq!(info.score, 10);
+        assert_eq!(info.inserted_at, info.last_updated);
+        std::thread::sleep(std::time::Duration::from_millis(5));
+        queue.update("item1".to_string(), 5).unwrap();
+        let updated = queue.item_info("item1").unwrap();
+        assert_eq!(updated.score, 15);
+        assert_eq!(updated.inserted_at, info.inserted_at);
+        assert!(updated.last_updated > info.last_updated);
+        // item1 stays the oldest item after the newer item2 is popped
+        let age = queue.stats().oldest_item_age.unwrap();
+        assert!(age >= Duration::milliseconds(5));
+        assert_eq!(queue.next(), Some("item2".to_string()));
+        assert!(queue.stats().oldest_item_age.unwrap() >= age);
+        queue.remove("item1");
+        assert!(queue.stats().oldest_item_age.is_none());
+    }
+
+    #[test]
+    fn test_from_sorted_iter() {
+        let queue = PQueue::<String>::from_sorted_iter(vec![
+            (-5, "low".to_string()),
+            (10, "first".to_string()),
+            (10, "second".to_string()),
+            (20, "top".to_string()),
+        ]);
+        let stats = queue.stats();
+        assert_eq!(stats.items, 4);
+        assert_eq!(stats.pools, 3);
+        assert_eq!(stats.updates, 0);
+        assert_eq!(queue.score("second"), Some(10));
+        let popped: Vec<String> = std::iter::from_fn(|| queue.next()).collect();
+        assert_eq!(popped, vec!["top", "first", "second", "low"]);
+    }
+
+    #[test]
+    fn test_snapshot_round_trip() {
+        let queue = PQueue::<String>::new();
+        queue.update("item1".to_string(), 10).unwrap();
+        queue.update("item2".to_string(), 20).unwrap();
+        queue.update("item3".to_string(), 10).unwrap();
+        queue.update("item1".to_string(), 0).unwrap(); // Moves behind item3
+        let snapshot = queue.snapshot();
+        assert_eq!(snapshot, vec![(10, "item3".to_string()), (10, "item1".to_string()), (20, "item2".to_string())]);
+        let restored = PQueue::<String>::from_sorted_iter(snapshot);
+        let popped: Vec<String> = std::iter::from_fn(|| restored.next()).collect();
+        assert_eq!(popped, vec!["item2", "item3", "item1"]);
+    }
+
+    #[test]
+    fn test_load_sorted_fallbacks() {
+        let queue = PQueue::<String>::new();
+        let loaded = queue.load_sorted(vec![
+            (10, "item1".to_string()),
+            (5, "item2".to_string()), // Out of order
+            (20, "item1".to_string()), // Repeated item takes its last score
+        ]);
+        assert_eq!(loaded, 3);
+        assert_eq!(queue.score("item1"), Some(20));
+        assert_eq!(queue.score("item2"), Some(5));
+        assert_eq!(queue.stats().pools, 2);
+        // Loading into a non-empty queue places every item individually
+        queue.load_sorted(vec![(20, "item3".to_string())]);
+        let popped: Vec<String> = std::iter::from_fn(|| queue.next()).collect();
+        assert_eq!(popped, vec!["item1", "item3", "item2"]);
+        assert_eq!(queue.stats().items, 0);
+    }
+
+    #[test]
+    fn test_rescore_within_large_pool() {
+        let queue = PQueue::<String>::new();
+        for i in 0..1000 {
+            queue.update(format!("item{}", i), 1).unwrap();
+        }
+        // Moving items out of the middle of a pool keeps the remaining items in FIFO order
+        queue.update("item500".to_string(), 1).unwrap();
+        assert_eq!(queue.remove("item1"), Some(1));
+        assert_eq!(queue.next(), Some("item500".to_string()));
+        assert_eq!(queue.next(), Some("item0".to_string()));
+        assert_eq!(queue.next(), Some("item2".to_string()));
+        let stats = queue.stats();
+        assert_eq!(stats.items, 996);
+        assert_eq!(stats.pools, 1);
+    }
+
+    #[test]
+    fn test_next_if_above() {
+        let queue = PQueue::<String>::new();
+        queue.update("item1".to_string(), 10).unwrap();
+        queue.update("item2".to_string(), 20).unwrap();
+        assert_eq!(queue.next_if_above(25), None);
+        assert_eq!(queue.next_if_above(20), Some("item2".to_string())); // The threshold is inclusive
+        assert_eq!(queue.next_if_above(15), None);
+        assert_eq!(queue.stats().items, 1);
+        assert_eq!(queue.next_if_above(i64::MIN), Some("item1".to_string()));
+        assert_eq!(queue.next_if_above(i64::MIN), None);
+    }
+
+    #[test]
+    fn test_top_score() {
+        let queue = PQueue::<String>::new();
+        assert_eq!(queue.top_score(), None);
+        assert_eq!(queue.top_pool(), None);
+        queue.update("item1".to_string(), 10).unwrap();
+        queue.update("item2".to_string(), 20).unwrap();
+        queue.update("item3".to_string(), 20).unwrap();
+        assert_eq!(queue.top_score(), Some(20));
+        assert_eq!(queue.top_pool(), Some((20, 2)));
+        assert_eq!(queue.pools(), vec![(20, 2), (10, 1)]);
+        queue.next();
+        queue.next();
+        assert_eq!(queue.top_pool(), Some((10, 1)));
+    }
+
+    #[test]
+    fn test_auto_remove() {
+        let queue = PQueue::<String>::new();
+        queue.update("item1".to_string(), 10).unwrap();
+        assert_eq!(queue.update("item1".to_string(), -10), Ok((Some(10), Some(0)))); // Disabled by default
+        queue.set_auto_remove(true);
+        assert!(queue.auto_remove());
+        queue.update("item1".to_string(), 10).unwrap();
+        assert_eq!(queue.update("item1".to_string(), -4), Ok((Some(10), Some(6))));
+        assert_eq!(queue.update("item1".to_string(), -7), Ok((Some(6), None)));
+        assert!(!queue.contains("item1"));
+        assert_eq!(queue.update("item2".to_string(), -1), Ok((None, None))); // Not inserted at all
+        let stats = queue.stats();
+        assert_eq!(stats.items, 0);
+        assert_eq!(stats.pools, 0);
+        assert_eq!(stats.updates, 6);
+    }
+
+    #[test]
+    fn test_weighted_schedule() {
+        let queue = PQueue::<String>::new();
+        queue.set_schedule(Schedule::Weighted(vec![Band::new(0, 10), Band::new(100, 70), Band::new(50, 20)]));
+        for i in 0..10 {
+            queue.update(format!("high{}", i), 150).unwrap();
+            queue.update(format!("mid{}", i), 75).unwrap();
+            queue.update(format!("low{}", i), -5).unwrap(); // The lowest band also takes scores below its minimum
+        }
+        let popped: Vec<String> = (0..10).filter_map(|_| queue.next()).collect();
+        assert_eq!(popped.iter().filter(|item| item.starts_with("high")).count(), 7);
+        assert_eq!(popped.iter().filter(|item| item.starts_with("mid")).count(), 2);
+        assert_eq!(popped.iter().filter(|item| item.starts_with("low")).count(), 1);
+        assert_eq!(popped[0], "high0"); // Within a band items keep their priority and FIFO order
+        assert_eq!(queue.schedule(), Schedule::Weighted(vec![Band::new(100, 70), Band::new(50, 20), Band::new(0, 10)]));
+    }
+
+    #[test]
+    fn test_weighted_schedule_skips_empty_bands() {
+        let queue = PQueue::<String>::new();
+        queue.set_schedule(Schedule::Weighted(vec![Band::new(100, 90), Band::new(0, 10)]));
+        queue.update("low1".to_string(), 1).unwrap();
+        queue.update("low2".to_string(), 2).unwrap();
+        assert_eq!(queue.next(), Some("low2".to_string()));
+        assert_eq!(queue.next(), Some("low1".to_string()));
+        assert_eq!(queue.next(), None);
+        queue.set_schedule(Schedule::Strict);
+        queue.update("a".to_string(), 1).unwrap();
+        queue.update("b".to_string(), 200).unwrap();
+        assert_eq!(queue.next(), Some("b".to_string()));
+    }
+
+    #[cfg(feature = "async")]
+    #[tokio::test]
+    async fn test_next_async_waits_for_items() {
+        let queue = PQueue::<String>::new();
+        let producer = queue.clone();
+        let consumer = tokio::spawn(async move { queue.next_async().await });
+        tokio::task::yield_now().await;
+        producer.update("item1".to_string(), 10).unwrap();
+        assert_eq!(consumer.await.unwrap(), "item1");
+    }
+
+    #[cfg(feature = "async")]
+    #[tokio::test]
+    async fn test_next_async_survives_cancelled_waiters() {
+        let queue = PQueue::<String>::new();
+        let (first, second) = (queue.clone(), queue.clone());
+        let cancelled = tokio::spawn(async move { first.next_async().await });
+        let consumer = tokio::spawn(async move { second.next_async().await });
+        tokio::task::yield_now().await;
+        // The waiter woken first goes away without taking the item
+        queue.update("item1".to_string(), 10).unwrap();
+        cancelled.abort();
+        let popped = tokio::time::timeout(std::time::Duration::from_secs(1), consumer).await;
+        assert_eq!(popped.unwrap().unwrap(), "item1");
+    }
+
+    #[cfg(feature = "async")]
+    #[tokio::test]
+    async fn test_stream() {
+        use futures_core::Stream;
+        use std::pin::Pin;
+
+        let queue = PQueue::<String>::new();
+        queue.update("item1".to_string(), 10).unwrap();
+        queue.update("item2".to_string(), 20).unwrap();
+        queue.pause();
+        let mut stream = queue.stream();
+        async fn next<St: Stream + Unpin>(stream: &mut St) -> Option<St::Item> {
+            std::future::poll_fn(|cx| Pin::new(&mut *stream).poll_next(cx)).await
+        }
+        let paused = tokio::time::timeout(std::time::Duration::from_millis(20), next(&mut stream)).await;
+        assert!(paused.is_err()); // Nothing is dispatched while the queue is paused
+        queue.resume();
+        assert_eq!(next(&mut stream).await, Some("item2".to_string()));
+        assert_eq!(next(&mut stream).await, Some("item1".to_string()));
+    }
+
+    #[cfg(feature = "async")]
+    #[test]
+    fn test_subscribe() {
+        let queue = PQueue::<String>::new();
+        queue.update("item1".to_string(), 10).unwrap(); // Not seen, nothing is subscribed yet
+        let mut events = queue.subscribe();
+        queue.update("item1".to_string(), 5).unwrap();
+        queue.update("item2".to_string(), 20).unwrap();
+        queue.next();
+        queue.remove("item1");
+        assert_eq!(events.try_recv(), Ok(QueueEvent::Updated { item: Arc::new("item1".to_string()), previous: Some(10), score: 15 }));
+        assert_eq!(events.try_recv(), Ok(QueueEvent::Updated { item: Arc::new("item2".to_string()), previous: None, score: 20 }));
+        assert_eq!(events.try_recv(), Ok(QueueEvent::Popped { item: Arc::new("item2".to_string()), score: 20 }));
+        assert_eq!(events.try_recv(), Ok(QueueEvent::Removed { item: Arc::new("item1".to_string()), score: 15 }));
+        assert!(events.try_recv().is_err());
+    }
+
+    #[cfg(feature = "channel")]
+    #[test]
+    fn test_channel_bridges() {
+        let queue = PQueue::<String>::new();
+        let sender = queue.sender(5);
+        sender.send("item1".to_string()).unwrap();
+        sender.send("item1".to_string()).unwrap();
+        drop(sender);
+        // The bridge thread enqueues the items in the background
+        while queue.score("item1") != Some(10) {
+            std::thread::yield_now();
+        }
+        queue.update("item2".to_string(), 20).unwrap();
+
+        let receiver = queue.receiver(1);
+        assert_eq!(receiver.recv_timeout(std::time::Duration::from_secs(1)), Ok("item2".to_string()));
+        assert_eq!(receiver.recv_timeout(std::time::Duration::from_secs(1)), Ok("item1".to_string()));
+        queue.update("item3".to_string(), 1).unwrap(); // Wakes the bridge thread blocked on the empty queue
+        assert_eq!(receiver.recv_timeout(std::time::Duration::from_secs(1)), Ok("item3".to_string()));
+    }
+
+    #[cfg(feature = "channel")]
+    #[test]
+    fn test_receiver_requeues_on_drop() {
+        let queue = PQueue::<String>::new();
+        queue.update("item1".to_string(), 10).unwrap();
+        queue.update("item2".to_string(), 20).unwrap();
+        let receiver = queue.receiver(1);
+        // One item fills the channel and the bridge blocks sending the next one
+        while queue.stats().items > 0 {
+            std::thread::yield_now();
+        }
+        drop(receiver);
+        while !queue.contains("item1") {
+            std::thread::yield_now();
+        }
+        assert_eq!(queue.score("item1"), Some(10));
+    }
+
+    #[cfg(feature = "sled")]
+    fn temporary_sled_queue() -> SledPQueue<String> {
+        SledPQueue::with_db(sled::Config::new().temporary(true).open().unwrap()).unwrap()
+    }
+
+    #[cfg(feature = "sled")]
+    #[test]
+    fn test_sled_queue_ordering() {
+        let queue = temporary_sled_queue();
+        queue.update("item1".to_string(), 10).unwrap();
+        queue.update("item2".to_string(), 20).unwrap();
+        queue.update("item3".to_string(), 10).unwrap();
+        assert_eq!(queue.update("item1".to_string(), 5).unwrap(), (Some(10), 15));
+        assert_eq!(queue.update("item4".to_string(), -5).unwrap(), (None, -5));
+        assert_eq!(queue.len(), 4);
+        assert_eq!(queue.peek(), Some("item2".to_string()));
+        assert_eq!(queue.next_with_score().unwrap(), Some(("item2".to_string(), 20)));
+        assert_eq!(queue.next().unwrap(), Some("item1".to_string()));
+        assert_eq!(queue.next().unwrap(), Some("item3".to_string()));
+        assert_eq!(queue.next().unwrap(), Some("item4".to_string()));
+        assert_eq!(queue.next().unwrap(), None);
+        assert!(queue.is_empty());
+    }
+
+    #[cfg(feature = "sled")]
+    #[test]
+    fn test_sled_queue_remove_and_overflow() {
+        let queue = temporary_sled_queue();
+        queue.update("item1".to_string(), i64::MAX).unwrap();
+        assert_eq!(queue.update("item1".to_string(), 1), Err(PQueueError::Overflow { score: i64::MAX, delta: 1 }));
+        assert_eq!(queue.score(&"item1".to_string()).unwrap(), Some(i64::MAX));
+        assert_eq!(queue.set_score("item1".to_string(), 3).unwrap(), Some(i64::MAX));
+        assert_eq!(queue.remove(&"item1".to_string()).unwrap(), Some(3));
+        assert!(!queue.contains(&"item1".to_string()).unwrap());
+        assert_eq!(queue.remove(&"item1".to_string()).unwrap(), None);
+        assert!(queue.is_empty());
+    }
+
+    #[cfg(feature = "sled")]
+    #[test]
+    fn test_sled_queue_beyond_head() {
+        let queue = temporary_sled_queue();
+        for i in 0..5000 {
+            queue.update(format!("item{}", i), i % 7).unwrap();
+        }
+        let mut last = i64::MAX;
+        let mut count = 0;
+        while let Some((_, score)) = queue.next_with_score().unwrap() {
+            assert!(score <= last);
+            last = score;
+            count += 1;
+        }
+        assert_eq!(count, 5000);
+    }
+
+    #[cfg(feature = "sled")]
+    #[test]
+    fn test_sled_queue_head_drained() {
+        let queue = temporary_sled_queue();
+        for i in 0..3000 {
+            queue.update(format!("item{}", i), i).unwrap();
+        }
+        // Moving every item of the head behind the rest drains it
+        for i in 1024..3000 {
+            queue.set_score(format!("item{}", i), -1).unwrap();
+        }
+        assert_eq!(queue.peek(), Some("item1023".to_string()));
+        // As does removing them
+        for i in 1024..3000 {
+            queue.remove(&format!("item{}", i)).unwrap();
+        }
+        assert_eq!(queue.len(), 1024);
+        assert_eq!(queue.peek(), Some("item1023".to_string()));
+        assert_eq!(queue.next().unwrap(), Some("item1023".to_string()));
+    }
+
+    #[cfg(feature = "sled")]
+    #[test]
+    fn test_sled_queue_snapshot() {
+        let queue = temporary_sled_queue();
+        queue.update("item1".to_string(), 10).unwrap();
+        queue.update("item2".to_string(), 20).unwrap();
+        queue.update("item3".to_string(), 10).unwrap();
+        let entries = queue.snapshot().unwrap();
+        assert_eq!(entries, vec![(10, "item1".to_string()), (10, "item3".to_string()), (20, "item2".to_string())]);
+        let loaded = PQueue::<String>::new();
+        assert_eq!(loaded.load_sorted(entries), 3);
+        assert_eq!(loaded.next(), Some("item2".to_string()));
+        assert_eq!(loaded.next(), Some("item1".to_string()));
+    }
+
+    #[cfg(feature = "sled")]
+    #[test]
+    fn test_sled_queue_reopen() {
+        let path = std::env::temp_dir().join(format!("pqueue-sled-test-{}", std::process::id()));
+        {
+            let queue = SledPQueue::<String>::open(&path).unwrap();
+            queue.update("item1".to_string(), 10).unwrap();
+            queue.update("item2".to_string(), 20).unwrap();
+            queue.flush().unwrap();
+        }
+        let queue = SledPQueue::<String>::open(&path).unwrap();
+        assert_eq!(queue.len(), 2);
+        assert_eq!(queue.next().unwrap(), Some("item2".to_string()));
+        assert_eq!(queue.clear().unwrap(), 1);
+        drop(queue);
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+}
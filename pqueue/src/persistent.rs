@@ -0,0 +1,341 @@
+use std::collections::BTreeMap;
+use std::marker::PhantomData;
+use std::ops::Bound;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use sled::transaction::{ConflictableTransactionError, TransactionError, Transactional};
+use sled::Tree;
+
+use crate::PQueueError;
+
+// How many of the highest priority entries are kept in the in-memory head index
+const HEAD_CAPACITY: usize = 1024;
+
+/// Item types that can be stored in a `SledPQueue`. An item is stored as its byte representation,
+/// which also serves as its identity: two items are the same item if their bytes are equal.
+pub trait PersistentItem: Sized {
+    fn to_bytes(&self) -> Vec<u8>;
+    fn from_bytes(bytes: &[u8]) -> Option<Self>;
+}
+
+impl PersistentItem for String {
+    fn to_bytes(&self) -> Vec<u8> {
+        self.as_bytes().to_vec()
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        String::from_utf8(bytes.to_vec()).ok()
+    }
+}
+
+impl PersistentItem for Vec<u8> {
+    fn to_bytes(&self) -> Vec<u8> {
+        self.clone()
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        Some(bytes.to_vec())
+    }
+}
+
+/// A priority queue stored in a sled database, so its contents survive restarts and can grow beyond
+/// the available memory. It follows the semantics of `PQueue` (additive updates, highest score first,
+/// FIFO within a score) with the `Checked` overflow policy.
+///
+/// Two trees are kept: one mapping items to their score and pool position, and one ordering items by
+/// score and position. Every change to both trees is applied in a single transaction. The highest
+/// priority entries of the ordering tree are also held in memory, so `peek` and `next` don't have to
+/// search the tree on disk; the in-memory head is refilled from disk as it is drained.
+///
+/// Like `PQueue`, cloning gives another handle to the same queue.
+pub struct SledPQueue<T: PersistentItem> {
+    state: Arc<Mutex<SledState>>,
+    _item: PhantomData<fn() -> T>,
+}
+
+impl<T: PersistentItem> Clone for SledPQueue<T> {
+    fn clone(&self) -> Self {
+        Self {
+            state: self.state.clone(),
+            _item: PhantomData,
+        }
+    }
+}
+
+struct SledState {
+    db: sled::Db,
+    items: Tree,
+    order: Tree,
+    // Order keys (and the item they belong to) of every entry up to and including bound; with no
+    // bound, every entry in the queue
+    head: BTreeMap<Vec<u8>, Vec<u8>>,
+    bound: Option<Vec<u8>>,
+    len: usize,
+}
+
+impl<T: PersistentItem> SledPQueue<T> {
+    /// Opens (or creates) the queue stored in the sled database at path
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, PQueueError> {
+        Self::with_db(sled::open(path).map_err(storage_error)?)
+    }
+
+    /// Uses an already opened sled database to store the queue, e.g. one opened with a custom
+    /// `sled::Config`
+    pub fn with_db(db: sled::Db) -> Result<Self, PQueueError> {
+        let items = db.open_tree("pqueue_items").map_err(storage_error)?;
+        let order = db.open_tree("pqueue_order").map_err(storage_error)?;
+        let len = items.len();
+        let mut state = SledState { db, items, order, head: BTreeMap::new(), bound: None, len };
+        state.refill_head(None)?;
+        Ok(Self {
+            state: Arc::new(Mutex::new(state)),
+            _item: PhantomData,
+        })
+    }
+
+    /// Adds delta to the item's score, or inserts the item with a score of delta. Returns the
+    /// previous score (None if the item was inserted) and the resulting score.
+    pub fn update(&self, item: T, delta: i64) -> Result<(Option<i64>, i64), PQueueError> {
+        let mut state = self.state.lock().unwrap();
+        state.place(item.to_bytes(), |current| match current {
+            Some(score) => score.checked_add(delta).ok_or(PQueueError::Overflow { score, delta }),
+            None => Ok(delta),
+        })
+    }
+
+    /// Sets the item's score, returning the score it had before
+    pub fn set_score(&self, item: T, score: i64) -> Result<Option<i64>, PQueueError> {
+        let mut state = self.state.lock().unwrap();
+        state.place(item.to_bytes(), |_| Ok(score)).map(|(current, _)| current)
+    }
+
+    pub fn peek(&self) -> Option<T> {
+        let state = self.state.lock().unwrap();
+        state.head.values().next().and_then(|item| T::from_bytes(item))
+    }
+
+    pub fn next(&self) -> Result<Option<T>, PQueueError> {
+        let mut state = self.state.lock().unwrap();
+        Ok(state.pop()?.and_then(|(item, _)| T::from_bytes(&item)))
+    }
+
+    /// Pops the next item along with the score it had
+    pub fn next_with_score(&self) -> Result<Option<(T, i64)>, PQueueError> {
+        let mut state = self.state.lock().unwrap();
+        Ok(state.pop()?.and_then(|(item, score)| T::from_bytes(&item).map(|item| (item, score))))
+    }
+
+    pub fn score(&self, item: &T) -> Result<Option<i64>, PQueueError> {
+        let state = self.state.lock().unwrap();
+        let entry = state.items.get(item.to_bytes()).map_err(storage_error)?;
+        Ok(entry.map(|entry| decode_entry(&entry).0))
+    }
+
+    pub fn contains(&self, item: &T) -> Result<bool, PQueueError> {
+        let state = self.state.lock().unwrap();
+        state.items.contains_key(item.to_bytes()).map_err(storage_error)
+    }
+
+    /// Removes the item from the queue, returning the score it had
+    pub fn remove(&self, item: &T) -> Result<Option<i64>, PQueueError> {
+        let mut state = self.state.lock().unwrap();
+        state.remove(item.to_bytes())
+    }
+
+    pub fn len(&self) -> usize {
+        let state = self.state.lock().unwrap();
+        state.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns every (score, item) pair sorted by ascending score, with items sharing a score in the
+    /// order they would be popped, like `PQueue::snapshot`, so the queue can be loaded into a `PQueue`
+    /// with `load_sorted`
+    pub fn snapshot(&self) -> Result<Vec<(i64, T)>, PQueueError> {
+        let state = self.state.lock().unwrap();
+        let mut entries = Vec::with_capacity(state.len);
+        for entry in state.order.iter() {
+            let (key, item) = entry.map_err(storage_error)?;
+            if let Some(item) = T::from_bytes(&item) {
+                entries.push((decode_order_score(&key), item));
+            }
+        }
+        // The order tree goes from the highest score down; a stable sort keeps each pool in order
+        entries.sort_by_key(|&(score, _)| score);
+        Ok(entries)
+    }
+
+    /// Removes every item, returning how many were removed
+    pub fn clear(&self) -> Result<usize, PQueueError> {
+        let mut state = self.state.lock().unwrap();
+        state.items.clear().map_err(storage_error)?;
+        state.order.clear().map_err(storage_error)?;
+        state.head.clear();
+        state.bound = None;
+        Ok(std::mem::take(&mut state.len))
+    }
+
+    /// Flushes all pending writes to disk. sled also flushes in the background periodically.
+    pub fn flush(&self) -> Result<(), PQueueError> {
+        let state = self.state.lock().unwrap();
+        state.db.flush().map(|_| ()).map_err(storage_error)
+    }
+}
+
+impl SledState {
+    // Moves the item (inserting it if needed) to the back of the pool for the score computed from its
+    // current score
+    fn place<F>(&mut self, item: Vec<u8>, new_score: F) -> Result<(Option<i64>, i64), PQueueError>
+    where
+        F: Fn(Option<i64>) -> Result<i64, PQueueError>,
+    {
+        let (current, score, position) = (&self.items, &self.order)
+            .transaction(|(items, order)| {
+                let current = items.get(&item)?.map(|entry| decode_entry(&entry));
+                let score = new_score(current.map(|(score, _)| score)).map_err(ConflictableTransactionError::Abort)?;
+                if let Some((current_score, current_position)) = current {
+                    order.remove(order_key(current_score, current_position))?;
+                }
+                let position = order.generate_id()?;
+                items.insert(item.as_slice(), encode_entry(score, position).as_slice())?;
+                order.insert(order_key(score, position), item.as_slice())?;
+                Ok((current, score, position))
+            })
+            .map_err(transaction_error)?;
+
+        match current {
+            Some((current_score, current_position)) => {
+                self.head.remove(&order_key(current_score, current_position));
+            },
+            None => self.len += 1,
+        }
+        self.cache(order_key(score, position), item);
+        self.fill_head()?;
+        Ok((current.map(|(current_score, _)| current_score), score))
+    }
+
+    fn pop(&mut self) -> Result<Option<(Vec<u8>, i64)>, PQueueError> {
+        self.fill_head()?;
+        // Only taken out of the head once it is gone from disk, so a failed transaction leaves both as
+        // they were
+        let Some((key, item)) = self.head.first_key_value().map(|(key, item)| (key.clone(), item.clone())) else {
+            return Ok(None);
+        };
+        (&self.items, &self.order)
+            .transaction(|(items, order)| {
+                items.remove(item.as_slice())?;
+                order.remove(key.as_slice())?;
+                Ok(())
+            })
+            .map_err(transaction_error)?;
+        self.head.remove(&key);
+        self.len -= 1;
+        Ok(Some((item, decode_order_score(&key))))
+    }
+
+    fn remove(&mut self, item: Vec<u8>) -> Result<Option<i64>, PQueueError> {
+        let removed = (&self.items, &self.order)
+            .transaction(|(items, order)| {
+                let Some(entry) = items.remove(item.as_slice())? else {
+                    return Ok(None);
+                };
+                let (score, position) = decode_entry(&entry);
+                order.remove(order_key(score, position))?;
+                Ok(Some((score, position)))
+            })
+            .map_err(transaction_error)?;
+        let Some((score, position)) = removed else {
+            return Ok(None);
+        };
+        self.head.remove(&order_key(score, position));
+        self.len -= 1;
+        self.fill_head()?;
+        Ok(Some(score))
+    }
+
+    // Adds an entry to the in-memory head if it falls within it, trimming the head back down to its
+    // capacity once it has grown to twice that
+    fn cache(&mut self, key: Vec<u8>, item: Vec<u8>) {
+        if self.bound.as_ref().is_some_and(|bound| key > *bound) {
+            return;
+        }
+        self.head.insert(key, item);
+        if self.head.len() >= 2 * HEAD_CAPACITY {
+            while self.head.len() > HEAD_CAPACITY {
+                self.head.pop_last();
+            }
+            self.bound = self.head.keys().next_back().cloned();
+        }
+    }
+
+    // Refills the head from disk once it has been drained while entries remain beyond it, so it always
+    // holds the next entry to pop
+    fn fill_head(&mut self) -> Result<(), PQueueError> {
+        if self.head.is_empty() && self.bound.is_some() {
+            let bound = self.bound.take();
+            self.refill_head(bound)?;
+        }
+        Ok(())
+    }
+
+    // Loads the highest priority entries after the given key (or overall, with no key) into the head
+    fn refill_head(&mut self, after: Option<Vec<u8>>) -> Result<(), PQueueError> {
+        let entries = match &after {
+            Some(after) => self.order.range::<&[u8], _>((Bound::Excluded(after.as_slice()), Bound::Unbounded)),
+            None => self.order.iter(),
+        };
+        for (loaded, entry) in entries.enumerate() {
+            let (key, item) = entry.map_err(storage_error)?;
+            if loaded == HEAD_CAPACITY {
+                // There is more on disk than fits in the head
+                self.bound = self.head.keys().next_back().cloned();
+                return Ok(());
+            }
+            self.head.insert(key.to_vec(), item.to_vec());
+        }
+        self.bound = None;
+        Ok(())
+    }
+}
+
+// Order keys sort by descending score and then by position, so the first key in the order tree is the
+// earliest placed item of the highest score
+fn order_key(score: i64, position: u64) -> Vec<u8> {
+    let mut key = Vec::with_capacity(16);
+    key.extend_from_slice(&(!((score as u64) ^ (1 << 63))).to_be_bytes());
+    key.extend_from_slice(&position.to_be_bytes());
+    key
+}
+
+fn decode_order_score(key: &[u8]) -> i64 {
+    (!u64::from_be_bytes(key[..8].try_into().unwrap()) ^ (1 << 63)) as i64
+}
+
+fn encode_entry(score: i64, position: u64) -> [u8; 16] {
+    let mut entry = [0; 16];
+    entry[..8].copy_from_slice(&score.to_be_bytes());
+    entry[8..].copy_from_slice(&position.to_be_bytes());
+    entry
+}
+
+fn decode_entry(entry: &[u8]) -> (i64, u64) {
+    (
+        i64::from_be_bytes(entry[..8].try_into().unwrap()),
+        u64::from_be_bytes(entry[8..16].try_into().unwrap()),
+    )
+}
+
+fn storage_error(e: sled::Error) -> PQueueError {
+    PQueueError::Storage(e.to_string())
+}
+
+fn transaction_error(e: TransactionError<PQueueError>) -> PQueueError {
+    match e {
+        TransactionError::Abort(e) => e,
+        TransactionError::Storage(e) => storage_error(e),
+    }
+}
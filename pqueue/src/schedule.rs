@@ -0,0 +1,113 @@
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+use std::ops::Bound;
+
+/// How `next` picks the item to pop
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum Schedule {
+    /// Always pop the highest scored item
+    #[default]
+    Strict,
+    /// Group scores into bands and serve the bands in proportion to their weights, popping the
+    /// highest scored item within the chosen band. Bands with no items are skipped, so a lone
+    /// non-empty band is served on every pop.
+    Weighted(Vec<Band>),
+}
+
+/// A band of scores used by weighted scheduling. A band holds every score from `min_score` up to the
+/// `min_score` of the next higher band; the lowest band also holds every score below its `min_score`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Band {
+    pub min_score: i64,
+    pub weight: u32,
+}
+
+impl Band {
+    pub fn new(min_score: i64, weight: u32) -> Self {
+        Self { min_score, weight }
+    }
+}
+
+// Smooth weighted round robin over the non-empty bands (the algorithm nginx uses to balance
+// upstreams): on every pop each eligible band gains its weight in credit, the band with the most
+// credit is served and pays back the total weight of the eligible bands. Over a cycle every band is
+// served in proportion to its weight, interleaved rather than in bursts.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Scheduler {
+    schedule: Schedule,
+    credits: Vec<i64>,
+}
+
+impl Scheduler {
+    pub(crate) fn new(schedule: Schedule) -> Self {
+        let schedule = match schedule {
+            Schedule::Weighted(mut bands) if !bands.is_empty() => {
+                bands.sort_by_key(|band| Reverse(band.min_score));
+                bands.dedup_by_key(|band| band.min_score);
+                Schedule::Weighted(bands)
+            },
+            _ => Schedule::Strict,
+        };
+        let credits = match &schedule {
+            Schedule::Weighted(bands) => vec![0; bands.len()],
+            Schedule::Strict => Vec::new(),
+        };
+        Self { schedule, credits }
+    }
+
+    pub(crate) fn schedule(&self) -> &Schedule {
+        &self.schedule
+    }
+
+    // Picks the score pool the next item should be popped from, along with the band it belongs to
+    // when scheduling by weight. This does not advance the scheduler; call `charge` once the item
+    // has actually been popped.
+    pub(crate) fn select<V>(&self, scores: &BTreeMap<i64, V>) -> Option<(i64, Option<usize>)> {
+        let bands = match &self.schedule {
+            Schedule::Strict => return scores.keys().next_back().map(|&score| (score, None)),
+            Schedule::Weighted(bands) => bands,
+        };
+        let mut total = 0;
+        let mut best: Option<(i64, usize, i64)> = None;
+        for (band, top) in self.band_tops(scores) {
+            let credit = self.credits[band] + bands[band].weight as i64;
+            total += bands[band].weight as i64;
+            if best.map_or(true, |(best_credit, _, _)| credit > best_credit) {
+                best = Some((credit, band, top));
+            }
+        }
+        let (_, band, top) = best?;
+        if total == 0 {
+            // Every eligible band has a weight of zero, so fall back to strict priority
+            return scores.keys().next_back().map(|&score| (score, None));
+        }
+        Some((top, Some(band)))
+    }
+
+    pub(crate) fn charge<V>(&mut self, served: usize, scores: &BTreeMap<i64, V>) {
+        let bands = match &self.schedule {
+            Schedule::Strict => return,
+            Schedule::Weighted(bands) => bands,
+        };
+        let eligible: Vec<usize> = self.band_tops(scores).map(|(band, _)| band).collect();
+        let mut total = 0;
+        for band in eligible {
+            self.credits[band] += bands[band].weight as i64;
+            total += bands[band].weight as i64;
+        }
+        self.credits[served] -= total;
+    }
+
+    // The highest score held by each non-empty band
+    fn band_tops<'a, V>(&'a self, scores: &'a BTreeMap<i64, V>) -> impl Iterator<Item = (usize, i64)> + 'a {
+        let bands: &[Band] = match &self.schedule {
+            Schedule::Weighted(bands) => bands,
+            Schedule::Strict => &[],
+        };
+        bands.iter().enumerate().filter_map(move |(i, band)| {
+            let upper = if i == 0 { Bound::Unbounded } else { Bound::Excluded(bands[i - 1].min_score) };
+            let lower = if i == bands.len() - 1 { Bound::Unbounded } else { Bound::Included(band.min_score) };
+            scores.range((lower, upper)).next_back().map(|(&score, _)| (i, score))
+        })
+    }
+}
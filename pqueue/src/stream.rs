@@ -0,0 +1,52 @@
+use std::future::Future;
+use std::hash::{BuildHasher, Hash};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use futures_core::Stream;
+
+use crate::PQueue;
+
+/// A `Stream` of items popped off a `PQueue`, created with `PQueue::stream`. The stream never ends;
+/// while the queue is empty or paused it waits for more items.
+pub struct PQueueStream<T, S>
+where
+    T: Eq + Hash + Clone,
+    S: BuildHasher,
+{
+    queue: PQueue<T, S>,
+    pending: Option<Pin<Box<dyn Future<Output = T> + Send>>>,
+}
+
+impl<T, S> PQueueStream<T, S>
+where
+    T: Eq + Hash + Clone + Send + Sync + 'static,
+    S: BuildHasher + Send + 'static,
+{
+    pub(crate) fn new(queue: PQueue<T, S>) -> Self {
+        Self { queue, pending: None }
+    }
+}
+
+impl<T, S> Stream for PQueueStream<T, S>
+where
+    T: Eq + Hash + Clone + Send + Sync + 'static,
+    S: BuildHasher + Send + 'static,
+{
+    type Item = T;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<T>> {
+        let this = &mut *self;
+        let pending = this.pending.get_or_insert_with(|| {
+            let queue = this.queue.clone();
+            Box::pin(async move { queue.next_async().await })
+        });
+        match pending.as_mut().poll(cx) {
+            Poll::Ready(item) => {
+                this.pending = None;
+                Poll::Ready(Some(item))
+            },
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
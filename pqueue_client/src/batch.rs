@@ -0,0 +1,104 @@
+// Batch mode, --file: streams a file of commands to the server, one per line, without waiting for the
+// replies in between, and prints the replies as they come back followed by a summary of the commands
+// that failed. Blank lines and lines starting with # are skipped.
+//
+// The replies are numbered with SEQ ON so a reply spanning several lines is told from the next, and a
+// PING sent after the last command marks the end: once its number comes back every command has been
+// answered. Replies are printed in the --output format, and with --command-timeout the run fails once
+// the server goes that long without replying.
+
+use std::path::Path;
+use std::time::Duration;
+
+use tokio::io::{self, AsyncBufReadExt as _, AsyncWriteExt as _, BufReader, BufWriter};
+
+use crate::connection::Stream;
+use crate::output::{self, Expect, Format, Formatted, Formatter};
+
+/// Runs the commands in the file at path over stream, returning how many of them failed
+pub async fn run(stream: Box<dyn Stream>, path: &Path, format: Format, timeout: Option<Duration>) -> io::Result<usize> {
+    let contents = std::fs::read_to_string(path)?;
+    let commands: Vec<(usize, String)> = contents.lines().enumerate()
+        .map(|(index, line)| (index + 1, line.trim()))
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+        .map(|(number, line)| (number, line.to_string()))
+        .collect();
+
+    let (reader, writer) = io::split(stream);
+    let requests: Vec<String> = commands.iter().map(|(_, command)| command.clone()).collect();
+    // Written from a task of its own so the replies are read meanwhile, as neither side could make
+    // progress with both directions' buffers full. The writer is handed back rather than dropped, which
+    // would end the connection before the last replies are sent.
+    let writing = tokio::spawn(async move {
+        let mut writer = BufWriter::new(writer);
+        writer.write_all(b"SEQ ON\r\n").await?;
+        for request in requests {
+            writer.write_all(request.as_bytes()).await?;
+            writer.write_all(b"\r\n").await?;
+        }
+        writer.write_all(b"PING\r\n").await?;
+        writer.flush().await?;
+        Ok::<_, io::Error>(writer)
+    });
+
+    let mut lines = BufReader::new(reader).lines();
+    let mut stdout = BufWriter::new(io::stdout());
+    let mut formatter = Formatter::new(format);
+    let mut failures = Vec::new();
+    // Number of the reply being read, 1 being SEQ ON's, and whether its first line is still to come
+    let mut seq = 0;
+    let mut first_line = false;
+    let last = commands.len() + 2;
+    loop {
+        let line = match timeout {
+            Some(timeout) => tokio::time::timeout(timeout, lines.next_line()).await
+                .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, format!("no reply from the server for {:?}", timeout)))?,
+            None => lines.next_line().await,
+        };
+        let Some(line) = line? else {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "the server closed the connection before replying to every command"));
+        };
+        if let Some(number) = output::seq(&line) {
+            seq = number;
+            first_line = true;
+            let expect = if seq == 1 || seq == last { Expect::Hidden } else { Expect::Reply };
+            if let Some(rendered) = formatter.start(expect) {
+                stdout.write_all(rendered.as_bytes()).await?;
+                stdout.write_all(b"\n").await?;
+            }
+            if seq == last {
+                break;
+            }
+            continue;
+        }
+        match seq {
+            // Anything before the first number is a greeting, unless SEQ was refused
+            0 if line.starts_with('-') => {
+                return Err(io::Error::new(io::ErrorKind::Unsupported, format!("the server doesn't number replies: {}", line)));
+            },
+            0 | 1 => continue,
+            _ => {},
+        }
+        if first_line && line.starts_with('-') {
+            let (number, command) = &commands[seq - 2];
+            failures.push(format!("line {}: {}: {}", number, command, line));
+        }
+        first_line = false;
+        match formatter.format(&line) {
+            Formatted::Line(line) => {
+                stdout.write_all(line.as_bytes()).await?;
+                stdout.write_all(b"\n").await?;
+            },
+            Formatted::Error(line) => eprintln!("{}", line),
+            Formatted::Nothing => {},
+        }
+    }
+    stdout.flush().await?;
+    writing.await.map_err(io::Error::other)??;
+
+    for failure in &failures {
+        eprintln!("{}", failure);
+    }
+    eprintln!("{} commands, {} errors", commands.len(), failures.len());
+    Ok(failures.len())
+}
@@ -0,0 +1,126 @@
+// Tab completion for the interactive client: the first word completes to a command, the words after
+// some commands to the keywords they take (CONFIG GET, INFO stats, ...), and identifiers to those
+// named in commands earlier in the session. Completions keep the case of what was typed, as commands
+// are case insensitive.
+
+use std::collections::VecDeque;
+
+use rustyline::completion::Completer;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::validate::Validator;
+use rustyline::{Context, Helper};
+
+// Every command the server understands
+const COMMANDS: &[&str] = &[
+    "ACK", "AUTH", "BNEXT", "CLEAR", "CLIENT", "CLUSTER", "CONFIG", "CONSUME", "CREDIT", "DELAY", "DISCARD",
+    "DRAIN", "DUMP", "EVAL", "EXEC", "EXPIRE", "EXPORT", "GETDATA", "HELLO", "HELP", "INFO", "LATENCY",
+    "MEMORY", "MONITOR", "MULTI", "MUPDATE", "NACK", "NEXT", "NEXTSCORE", "PEEK", "PEEKSCORE", "PERSIST",
+    "PING", "PROTOCOL", "READONLY", "REMOVE", "RESERVE", "RESETSTATS", "RESTORE", "SAVE", "SCAN", "SCORE",
+    "SCORERANGE", "SEQ", "SETDATA", "SETSCORE", "SHUTDOWN", "SLOWLOG", "SUBSCRIBE", "TTL", "UNSUBSCRIBE",
+    "UPDATE", "WAITEMPTY",
+];
+
+// The keywords taken by the command's first argument
+const KEYWORDS: &[(&str, &[&str])] = &[
+    ("CLIENT", &["LIST", "KILL"]),
+    ("CLUSTER", &["NODES", "NODE"]),
+    ("CONFIG", &["GET", "SET"]),
+    ("CONSUME", &["STOP"]),
+    ("DRAIN", &["EXIT", "OFF"]),
+    ("EXPORT", &["JSON", "CSV"]),
+    ("INFO", &["all", "server", "clients", "memory", "persistence", "stats", "commandstats", "latency", "queue"]),
+    ("MEMORY", &["USAGE"]),
+    ("MONITOR", &["STOP"]),
+    ("PROTOCOL", &["TEXT", "JSON", "BINARY"]),
+    ("READONLY", &["ON", "OFF"]),
+    ("SEQ", &["ON", "OFF"]),
+    ("SHUTDOWN", &["SAVE", "NOSAVE"]),
+    ("SLOWLOG", &["GET", "LEN", "RESET"]),
+    ("SUBSCRIBE", &["updates"]),
+    ("UNSUBSCRIBE", &["updates"]),
+];
+
+// The commands whose first argument is an identifier
+const ITEM_COMMANDS: &[&str] = &[
+    "DELAY", "DUMP", "EXPIRE", "GETDATA", "MUPDATE", "PERSIST", "REMOVE", "RESTORE", "SCORE", "SETDATA",
+    "SETSCORE", "TTL", "UPDATE",
+];
+
+// How many of the identifiers named last are offered
+const MAX_ITEMS: usize = 1000;
+
+/// Completes commands, their keywords and the identifiers seen in the session
+#[derive(Default)]
+pub struct Completion {
+    // Most recently named first
+    items: VecDeque<String>,
+}
+
+impl Completion {
+    /// Remembers the identifier named in a command entered, if any
+    pub fn remember(&mut self, line: &str) {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let item = match words.as_slice() {
+            [command, item, ..] if is_one_of(command, ITEM_COMMANDS) => item,
+            [command, subcommand, item, ..] if command.eq_ignore_ascii_case("MEMORY") && subcommand.eq_ignore_ascii_case("USAGE") => item,
+            [command, subcommand, item, ..] if command.eq_ignore_ascii_case("CLUSTER") && subcommand.eq_ignore_ascii_case("NODE") => item,
+            _ => return,
+        };
+        self.items.retain(|seen| seen != item);
+        self.items.push_front(item.to_string());
+        self.items.truncate(MAX_ITEMS);
+    }
+
+    fn candidates(&self, words: &[&str], prefix: &str) -> Vec<String> {
+        match words {
+            [] => matching(COMMANDS.iter().copied(), prefix),
+            [command] if is_one_of(command, ITEM_COMMANDS) => self.matching_items(prefix),
+            [command] => KEYWORDS.iter()
+                .find(|(name, _)| name.eq_ignore_ascii_case(command))
+                .map(|(_, keywords)| matching(keywords.iter().copied(), prefix))
+                .unwrap_or_default(),
+            [command, subcommand] if (command.eq_ignore_ascii_case("MEMORY") && subcommand.eq_ignore_ascii_case("USAGE"))
+                || (command.eq_ignore_ascii_case("CLUSTER") && subcommand.eq_ignore_ascii_case("NODE")) => self.matching_items(prefix),
+            _ => Vec::new(),
+        }
+    }
+
+    // Identifiers are case sensitive, so they are matched and completed as they are
+    fn matching_items(&self, prefix: &str) -> Vec<String> {
+        self.items.iter().filter(|item| item.starts_with(prefix)).cloned().collect()
+    }
+}
+
+impl Completer for Completion {
+    type Candidate = String;
+
+    fn complete(&self, line: &str, pos: usize, _ctx: &Context<'_>) -> rustyline::Result<(usize, Vec<String>)> {
+        let start = line[..pos].rfind(char::is_whitespace).map_or(0, |i| i + 1);
+        let words: Vec<&str> = line[..start].split_whitespace().collect();
+        Ok((start, self.candidates(&words, &line[start..pos])))
+    }
+}
+
+impl Hinter for Completion {
+    type Hint = String;
+}
+
+impl Highlighter for Completion {}
+
+impl Validator for Completion {}
+
+impl Helper for Completion {}
+
+fn is_one_of(word: &str, names: &[&str]) -> bool {
+    names.iter().any(|name| name.eq_ignore_ascii_case(word))
+}
+
+// The keywords starting with prefix, ignoring case, in lower case when that is how prefix is typed
+fn matching<'a>(keywords: impl Iterator<Item = &'a str>, prefix: &str) -> Vec<String> {
+    let lower = prefix.chars().any(|c| c.is_ascii_lowercase());
+    keywords
+        .filter(|keyword| keyword.len() >= prefix.len() && keyword[..prefix.len()].eq_ignore_ascii_case(prefix))
+        .map(|keyword| if lower { keyword.to_ascii_lowercase() } else { keyword.to_string() })
+        .collect()
+}
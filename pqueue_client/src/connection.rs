@@ -0,0 +1,91 @@
+// Connecting to the server, over TCP in the clear or over TLS (see tls), or over a Unix socket with
+// --unix-socket: every attempt is bounded by --connect-timeout when given, the TLS handshake included,
+// and a failed connection is retried with the delay between attempts doubling, --retries times when
+// connecting and --reconnect-attempts times when the connection drops.
+
+use std::path::PathBuf;
+use std::time::Duration;
+
+use tokio::io::{self, AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio_rustls::rustls::pki_types::ServerName;
+use tokio_rustls::TlsConnector;
+
+// Delay before the first retry, doubling with every retry up to the max
+const RETRY_DELAY: Duration = Duration::from_millis(250);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(8);
+
+/// A connection to the server, in the clear or over TLS
+pub trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Stream for T {}
+
+/// Makes connections to the server
+pub struct Connector {
+    host: String,
+    address: String,
+    // The Unix socket connected to instead of the address
+    socket: Option<PathBuf>,
+    timeout: Option<Duration>,
+    tls: Option<TlsConnector>,
+}
+
+impl Connector {
+    /// Connects to port on host, over TLS when tls is given
+    pub fn new(host: &str, port: &str, timeout: Option<Duration>, tls: Option<TlsConnector>) -> Self {
+        Self { host: host.to_string(), address: format!("{}:{}", host, port), socket: None, timeout, tls }
+    }
+
+    /// Connects to the Unix socket at path
+    pub fn unix(path: PathBuf, timeout: Option<Duration>) -> Self {
+        Self { host: String::new(), address: path.display().to_string(), socket: Some(path), timeout, tls: None }
+    }
+
+    /// Where connections are made to
+    pub fn address(&self) -> &str {
+        &self.address
+    }
+
+    /// Connects, giving up after the timeout if any, the TLS handshake included
+    pub async fn connect(&self) -> io::Result<Box<dyn Stream>> {
+        match self.timeout {
+            Some(timeout) => tokio::time::timeout(timeout, self.connect_once()).await
+                .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, format!("timed out after {:?}", timeout)))?,
+            None => self.connect_once().await,
+        }
+    }
+
+    /// Connects, retrying up to retries times after the first attempt fails, and reporting each
+    /// failure retried. With delay set it waits before the first attempt too.
+    pub async fn connect_retrying(&self, retries: u32, delay: bool, mut report: impl FnMut(String)) -> io::Result<Box<dyn Stream>> {
+        let mut wait = RETRY_DELAY;
+        if delay {
+            tokio::time::sleep(wait).await;
+            wait = (wait * 2).min(MAX_RETRY_DELAY);
+        }
+        for retry in 1..=retries {
+            match self.connect().await {
+                Ok(stream) => return Ok(stream),
+                Err(e) => report(format!("Failed to connect to {}: {}, retrying ({} of {})", self.address, e, retry, retries)),
+            }
+            tokio::time::sleep(wait).await;
+            wait = (wait * 2).min(MAX_RETRY_DELAY);
+        }
+        self.connect().await
+    }
+
+    async fn connect_once(&self) -> io::Result<Box<dyn Stream>> {
+        if let Some(path) = &self.socket {
+            #[cfg(unix)]
+            return Ok(Box::new(tokio::net::UnixStream::connect(path).await?));
+            #[cfg(not(unix))]
+            return Err(io::Error::new(io::ErrorKind::Unsupported, format!("{}: Unix sockets are only supported on Unix", path.display())));
+        }
+        let stream = TcpStream::connect(&self.address).await?;
+        let Some(tls) = &self.tls else {
+            return Ok(Box::new(stream));
+        };
+        let name = ServerName::try_from(self.host.clone()).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        Ok(Box::new(tls.connect(name, stream).await?))
+    }
+}
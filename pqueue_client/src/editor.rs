@@ -0,0 +1,70 @@
+// Line editing for the interactive client: arrow keys move through the line and the history, Ctrl-R
+// searches it, Tab completes commands and identifiers, and the history is kept in ~/.pqueue_history
+// across sessions. The editor blocks while reading a line, so it runs on a thread of its own, handing
+// each line to the client as it is entered, while responses are printed above the prompt without
+// disturbing what is being typed.
+
+use std::path::PathBuf;
+
+use rustyline::config::{CompletionType, Config};
+use rustyline::error::ReadlineError;
+use rustyline::history::DefaultHistory;
+use rustyline::{Editor, ExternalPrinter};
+use tokio::sync::mpsc::{self, UnboundedReceiver};
+
+use crate::completion::Completion;
+
+const HISTORY_FILE: &str = ".pqueue_history";
+const HISTORY_SIZE: usize = 1000;
+
+/// Starts reading lines with prompt, returning the lines entered and the printer for output. The
+/// lines end at Ctrl-D.
+pub fn spawn(prompt: String) -> rustyline::Result<(UnboundedReceiver<String>, Box<dyn ExternalPrinter + Send>)> {
+    let config = Config::builder()
+        .max_history_size(HISTORY_SIZE)?
+        .history_ignore_dups(true)?
+        .history_ignore_space(true)
+        .completion_type(CompletionType::List)
+        .build();
+    let mut editor = Editor::<Completion, DefaultHistory>::with_config(config)?;
+    editor.set_helper(Some(Completion::default()));
+    let printer = editor.create_external_printer()?;
+    let history = history_path();
+    if let Some(path) = &history {
+        // A missing file is only a first session
+        let _ = editor.load_history(path);
+    }
+
+    let (sender, receiver) = mpsc::unbounded_channel();
+    std::thread::spawn(move || loop {
+        match editor.readline(&prompt) {
+            Ok(line) => {
+                if let Some(completion) = editor.helper_mut() {
+                    completion.remember(&line);
+                }
+                if editor.add_history_entry(line.as_str()).unwrap_or(false) {
+                    if let Some(path) = &history {
+                        if let Err(e) = editor.append_history(path) {
+                            eprintln!("Failed to save history to {}: {}", path.display(), e);
+                        }
+                    }
+                }
+                if sender.send(line).is_err() {
+                    return;
+                }
+            },
+            // Ctrl-C abandons the line being typed, as in a shell
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => return,
+            Err(e) => {
+                eprintln!("Failed to read a line: {}", e);
+                return;
+            },
+        }
+    });
+    Ok((receiver, Box::new(printer)))
+}
+
+fn history_path() -> Option<PathBuf> {
+    std::env::var_os("HOME").map(|home| PathBuf::from(home).join(HISTORY_FILE))
+}
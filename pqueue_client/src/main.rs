@@ -1,70 +1,269 @@
-use tokio::{io::{self, AsyncWriteExt, AsyncBufReadExt as _}, net::TcpStream, select};
-use clap::{Arg, Command, ArgAction};
+mod batch;
+mod completion;
+mod connection;
+mod editor;
+mod output;
+mod pretty;
+mod tls;
+
+use tokio::{io::{self, AsyncWrite, AsyncWriteExt, AsyncBufReadExt as _}, select, sync::mpsc::{self, UnboundedReceiver}, time::Instant};
+use clap::{value_parser, Arg, Command, ArgAction};
+use std::collections::VecDeque;
+use std::path::PathBuf;
+use std::time::Duration;
+use connection::{Connector, Stream};
+use output::{Expect, Format, Formatted, Formatter};
+use rustyline::ExternalPrinter;
 
 #[tokio::main]
 async fn main() {
     let matches = Command::new("PQueue Interactive Client")
         .arg(Arg::new("host").long("host").default_value("localhost"))
         .arg(Arg::new("port").long("port").default_value("8002"))
+        .arg(Arg::new("unix-socket").short('s').long("unix-socket").value_name("PATH").value_parser(value_parser!(PathBuf)).conflicts_with_all(["host", "port", "tls"])
+            .help("Connect to the server's Unix socket at this path rather than over TCP"))
+        .arg(Arg::new("file").short('f').long("file").value_name("PATH").value_parser(value_parser!(PathBuf))
+            .help("Stream the commands in a file to the server, one per line, printing the replies and a summary of the errors, then exit"))
+        .arg(Arg::new("output").short('o').long("output").value_name("FORMAT").value_parser(["auto", "raw", "pretty", "csv", "tsv"]).default_value("auto")
+            .help("Print replies as the server sends them (raw), with INFO and HELP rendered as colored tables (pretty), or as rows of comma (csv) or tab (tsv) separated fields; auto is pretty at a terminal and raw otherwise"))
+        .arg(Arg::new("connect-timeout").long("connect-timeout").value_name("SECONDS").value_parser(value_parser!(f64))
+            .help("Give up on an attempt to connect after this long"))
+        .arg(Arg::new("command-timeout").long("command-timeout").value_name("SECONDS").value_parser(value_parser!(f64))
+            .help("Treat the connection as lost when the server goes this long without replying to the commands sent, which blocking commands such as BNEXT and WAITEMPTY need to be given time for"))
+        .arg(Arg::new("retries").long("retries").value_name("COUNT").value_parser(value_parser!(u32)).default_value("0")
+            .help("Attempts made to connect again when connecting fails, backing off exponentially between them"))
+        .arg(Arg::new("reconnect-attempts").long("reconnect-attempts").value_name("COUNT").value_parser(value_parser!(u32)).default_value("5")
+            .help("Attempts made to reconnect when the connection drops, backing off exponentially between them; 0 exits instead"))
+        .arg(Arg::new("tls").long("tls").help("Connect over TLS, verifying the server's certificate").action(ArgAction::SetTrue))
+        .arg(Arg::new("cacert").long("cacert").value_name("FILE").value_parser(value_parser!(PathBuf)).requires("tls")
+            .help("Verify the server's certificate against the CAs in this PEM file rather than the system's"))
+        .arg(Arg::new("cert").long("cert").value_name("FILE").value_parser(value_parser!(PathBuf)).requires_all(["tls", "key"])
+            .help("Present this PEM certificate chain to servers verifying client certificates"))
+        .arg(Arg::new("key").long("key").value_name("FILE").value_parser(value_parser!(PathBuf)).requires("cert")
+            .help("The PEM private key of --cert"))
         .arg(Arg::new("debug").short('d').long("debug").help("Output extra debugging info to stdout").action(ArgAction::SetTrue))
         .get_matches();
 
     let host = matches.get_one::<String>("host").unwrap();
     let port = matches.get_one::<String>("port").unwrap();
-    let debug = matches.get_flag("debug");
-    let server_address = format!("{}:{}", host, port);
+    let seconds = |name: &str| matches.get_one::<f64>(name).map(|&seconds| Duration::from_secs_f64(seconds));
+    let options = Options {
+        format: Format::from_name(matches.get_one::<String>("output").unwrap(), atty::is(atty::Stream::Stdin) && atty::is(atty::Stream::Stdout)),
+        connect_timeout: seconds("connect-timeout"),
+        command_timeout: seconds("command-timeout"),
+        debug: matches.get_flag("debug"),
+    };
+    let retries = *matches.get_one::<u32>("retries").unwrap();
+    let reconnect_attempts = *matches.get_one::<u32>("reconnect-attempts").unwrap();
+    let tls = matches.get_flag("tls").then(|| {
+        let ca = matches.get_one::<PathBuf>("cacert").map(PathBuf::as_path);
+        let identity = matches.get_one::<PathBuf>("cert").map(|cert| (cert.as_path(), matches.get_one::<PathBuf>("key").unwrap().as_path()));
+        tls::connector(ca, identity).unwrap_or_else(|e| {
+            eprintln!("Failed to set up TLS: {}", e);
+            std::process::exit(1);
+        })
+    });
+    let connector = match matches.get_one::<PathBuf>("unix-socket") {
+        Some(path) => Connector::unix(path.clone(), options.connect_timeout),
+        None => Connector::new(host, port, options.connect_timeout, tls),
+    };
 
-    let mut stream = TcpStream::connect(server_address).await.unwrap();
-    // let (mut reader, mut writer) = stream.split();
+    let mut stream = match connector.connect_retrying(retries, false, |failure| eprintln!("{}", failure)).await {
+        Ok(stream) => stream,
+        Err(e) => {
+            eprintln!("Failed to connect to {}: {}", connector.address(), e);
+            std::process::exit(1);
+        },
+    };
 
-    let mut stdin = io::BufReader::new(io::stdin()).lines();
+    if let Some(path) = matches.get_one::<PathBuf>("file") {
+        match batch::run(stream, path, options.format, options.command_timeout).await {
+            Ok(0) => return,
+            Ok(_) => std::process::exit(1),
+            Err(e) => {
+                eprintln!("Failed to run {}: {}", path.display(), e);
+                std::process::exit(1);
+            },
+        }
+    }
 
     let is_interactive = atty::is(atty::Stream::Stdin);
 
-    let (reader, writer) = stream.split();
-    let mut reader = io::BufReader::new(reader).lines();
-    let mut writer = io::BufWriter::new(writer);
-    let mut stdout = io::stdout();
+    // At a terminal commands are read with line editing and history, and everything printed goes
+    // through the editor so it lands above the prompt; otherwise stdin is read line by line as is
+    let (mut commands, printer) = if is_interactive {
+        let (commands, printer) = editor::spawn(format!("pqueue::{}> ", connector.address())).unwrap();
+        (commands, Some(printer))
+    } else {
+        let (sender, commands) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            let mut stdin = io::BufReader::new(io::stdin()).lines();
+            while let Ok(Some(command)) = stdin.next_line().await {
+                if sender.send(command).is_err() {
+                    return;
+                }
+            }
+        });
+        (commands, None)
+    };
 
+    let mut console = Console { printer, stdout: io::stdout() };
     loop {
-        if is_interactive {
-            print!("pqueue::{}:{}> ", host, port);
-            io::stdout().flush().await.unwrap(); // Ensure the prompt is displayed immediately
+        if let Ended::Quit = session(stream, &mut commands, &mut console, &options).await {
+            return;
+        }
+        if reconnect_attempts == 0 {
+            console.error(&format!("Lost the connection to {}", connector.address()));
+            std::process::exit(1);
         }
+        console.error(&format!("Lost the connection to {}, reconnecting", connector.address()));
+        let reconnected = connector.connect_retrying(reconnect_attempts - 1, true, |failure| console.error(&failure)).await;
+        match reconnected {
+            Ok(reconnected) => {
+                // Whatever was in flight is not sent again, as it may have run already
+                console.error(&format!("Reconnected to {}, replies to commands sent before are lost", connector.address()));
+                stream = reconnected;
+            },
+            Err(e) => {
+                console.error(&format!("Gave up reconnecting to {}: {}", connector.address(), e));
+                std::process::exit(1);
+            },
+        }
+    }
+}
 
-        select! {
-            command = stdin.next_line() => {
-                let command = command.unwrap();
+// Settings for the session over each connection
+struct Options {
+    format: Format,
+    connect_timeout: Option<Duration>,
+    command_timeout: Option<Duration>,
+    debug: bool,
+}
+
+// How a session over a connection ended
+enum Ended {
+    // No more commands
+    Quit,
+    Disconnected,
+}
+
+// Sends the commands to the server over stream and prints its replies until either ends
+async fn session(stream: Box<dyn Stream>, commands: &mut UnboundedReceiver<String>, console: &mut Console, options: &Options) -> Ended {
+    let (reader, writer) = io::split(stream);
+    let mut reader = io::BufReader::new(reader).lines();
+    let mut writer = io::BufWriter::new(writer);
+    let mut formatter = Formatter::new(options.format);
+    // Numbered replies tell where one ends and the next starts (see output), and so how many commands
+    // are still to be answered
+    let numbered = options.format != Format::Raw || options.command_timeout.is_some();
+    // What is expected of the replies to the requests sent and not answered yet, and when the server
+    // is late replying to them if it isn't heard from before
+    let mut unanswered = VecDeque::new();
+    let mut deadline = None;
+    if numbered {
+        if send(&mut writer, "SEQ ON").await.is_err() {
+            return Ended::Disconnected;
+        }
+        unanswered.push_back(Expect::Hidden);
+        deadline = options.command_timeout.map(|timeout| Instant::now() + timeout);
+    }
 
+    loop {
+        select! {
+            command = commands.recv() => {
                 if let Some(command) = command {
                     let command = command.trim();
                     if !command.is_empty() {
-                        if debug { println!("read command: {}", command); }
+                        if options.debug { console.print(&format!("read command: {}", command)).await; }
 
-                        writer.write_all(command.as_bytes()).await.unwrap();
-                        writer.write_all(b"\r\n").await.unwrap();
-                        writer.flush().await.unwrap();
+                        if send(&mut writer, command).await.is_err() {
+                            return Ended::Disconnected;
+                        }
+                        // Tables are rendered from whole replies, which the reply to a PING sent after
+                        // tells are all in
+                        let name = command.split_whitespace().next().unwrap_or_default();
+                        if options.format == Format::Pretty && (name.eq_ignore_ascii_case("INFO") || name.eq_ignore_ascii_case("HELP")) {
+                            if send(&mut writer, "PING").await.is_err() {
+                                return Ended::Disconnected;
+                            }
+                            unanswered.extend([Expect::WholeReply, Expect::Hidden]);
+                        } else {
+                            unanswered.push_back(Expect::Reply);
+                        }
+                        if deadline.is_none() {
+                            deadline = options.command_timeout.map(|timeout| Instant::now() + timeout);
+                        }
                     }
                 } else {
-                    // if user sends ctrl + d or an EOF is streamed in over stdin, the stdin reader will have
-                    // a None value and we can break out
-                    return;
+                    // if user sends ctrl + d or an EOF is streamed in over stdin, the commands end and we
+                    // can break out
+                    return Ended::Quit;
                 }
             }
             response = reader.next_line() => {
-                let response = response.unwrap();
-                if let Some(response) = response {
-                    if debug { println!("received response: {}", response); }
+                if let Ok(Some(response)) = response {
+                    if options.debug { console.print(&format!("received response: {}", response)).await; }
 
-                    stdout.write_all(&response.as_bytes()).await.unwrap();
-                    stdout.write_all(b"\n").await.unwrap();
-                    stdout.flush().await.unwrap();
+                    let seq = if numbered { output::seq(&response) } else { None };
+                    let expect = seq.map(|_| unanswered.pop_front().unwrap_or(Expect::Reply));
+                    // Any line heard gives the server more time, until every command is answered
+                    if !unanswered.is_empty() {
+                        deadline = options.command_timeout.map(|timeout| Instant::now() + timeout);
+                    } else {
+                        deadline = None;
+                    }
+                    if let Some(expect) = expect {
+                        if let Some(rendered) = formatter.start(expect) {
+                            console.print(&rendered).await;
+                        }
+                        continue;
+                    }
+                    match formatter.format(&response) {
+                        Formatted::Line(line) => console.print(&line).await,
+                        Formatted::Error(line) => console.error(&line),
+                        Formatted::Nothing => {},
+                    }
                 } else {
                     // If we get an EOF or the socket is disconnected, flow ends up here and we can break out
-                    return;
+                    return Ended::Disconnected;
                 }
-
             }
+            _ = tokio::time::sleep_until(deadline.unwrap_or_else(Instant::now)), if deadline.is_some() => {
+                console.error("Timed out waiting for a reply from the server");
+                return Ended::Disconnected;
+            }
+        }
+    }
+}
+
+async fn send(writer: &mut (impl AsyncWrite + Unpin), command: &str) -> io::Result<()> {
+    writer.write_all(command.as_bytes()).await?;
+    writer.write_all(b"\r\n").await?;
+    writer.flush().await
+}
+
+// Where output goes: above the prompt when editing, otherwise stdout and stderr
+struct Console {
+    printer: Option<Box<dyn ExternalPrinter + Send>>,
+    stdout: io::Stdout,
+}
+
+impl Console {
+    async fn print(&mut self, line: &str) {
+        match &mut self.printer {
+            Some(printer) => printer.print(format!("{}\n", line)).unwrap(),
+            None => {
+                self.stdout.write_all(line.as_bytes()).await.unwrap();
+                self.stdout.write_all(b"\n").await.unwrap();
+                self.stdout.flush().await.unwrap();
+            },
+        }
+    }
+
+    fn error(&mut self, line: &str) {
+        match &mut self.printer {
+            Some(printer) => printer.print(format!("{}\n", line)).unwrap(),
+            None => eprintln!("{}", line),
         }
     }
 }
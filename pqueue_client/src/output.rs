@@ -0,0 +1,247 @@
+// Output formats, --output: raw prints replies as the server sends them, pretty does too but for INFO
+// and HELP, rendered as colored tables (see pretty), and is what a terminal gets by default, while csv
+// and tsv turn replies into rows of fields to pipe into spreadsheets, awk and the like:
+//
+//   PEEK 2, NEXT 2, SCORERANGE   a row of <item>,<score> per entry
+//   SCAN                         a row holding the cursor, then a row per entry
+//   INFO                         a row of <section>,<field>,<value> per statistic
+//   HELLO                        a row of <field>,<value> per field
+//   HELP                         a row of <usage>,<description> per command
+//   anything else                a row of the reply's words, identifiers unquoted
+//
+// Errors aren't rows, and are printed to stderr instead. Telling which reply a line belongs to takes
+// the replies being numbered, so the client turns on SEQ for these formats. A table is only rendered
+// once the whole reply is in, which the reply to a PING the client sends after the command tells.
+
+use crate::pretty;
+
+/// How replies are printed
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    Raw,
+    Pretty,
+    Csv,
+    Tsv,
+}
+
+impl Format {
+    /// The format named by --output, auto being pretty when printing to a terminal
+    pub fn from_name(name: &str, terminal: bool) -> Self {
+        match name {
+            "auto" if terminal => Format::Pretty,
+            "pretty" => Format::Pretty,
+            "csv" => Format::Csv,
+            "tsv" => Format::Tsv,
+            _ => Format::Raw,
+        }
+    }
+}
+
+/// What is expected of the reply to a request sent
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Expect {
+    // The reply to a request the client sent itself, such as SEQ ON, which isn't printed
+    Hidden,
+    Reply,
+    // A reply followed by one that is hidden, so it can be held until it is all in
+    WholeReply,
+}
+
+/// A line of a reply, formatted
+pub enum Formatted {
+    Line(String),
+    Error(String),
+    Nothing,
+}
+
+// The kind of reply being formatted, as told by its first line
+enum Reply {
+    // Not the reply to a command entered, such as a greeting or the reply to SEQ ON
+    Ignored,
+    // Yet to see its first line, and whether it is followed by a hidden one
+    Unknown { whole: bool },
+    Rows,
+    Info { section: String },
+    Hello,
+    Help,
+    // The lines of an INFO or HELP reply, rendered once it is all in
+    Held(Vec<String>),
+}
+
+/// Formats the lines of replies one by one
+pub struct Formatter {
+    format: Format,
+    reply: Reply,
+}
+
+impl Formatter {
+    pub fn new(format: Format) -> Self {
+        // Raw output holds whatever comes before the first numbered reply, such as a greeting
+        let reply = if matches!(format, Format::Raw | Format::Pretty) { Reply::Rows } else { Reply::Ignored };
+        Self { format, reply }
+    }
+
+    /// Starts the next reply, returning the previous one rendered if it was held until it was all in
+    pub fn start(&mut self, expect: Expect) -> Option<String> {
+        let reply = match expect {
+            Expect::Hidden => Reply::Ignored,
+            Expect::Reply => Reply::Unknown { whole: false },
+            Expect::WholeReply => Reply::Unknown { whole: true },
+        };
+        match std::mem::replace(&mut self.reply, reply) {
+            Reply::Held(lines) => Some(pretty::render(&lines)),
+            _ => None,
+        }
+    }
+
+    pub fn format(&mut self, line: &str) -> Formatted {
+        if line.starts_with('-') && matches!(self.reply, Reply::Ignored) {
+            return Formatted::Error(line.to_string());
+        }
+        if matches!(self.format, Format::Raw | Format::Pretty) {
+            return match &mut self.reply {
+                Reply::Ignored => Formatted::Nothing,
+                Reply::Unknown { whole: true } if self.format == Format::Pretty && pretty::renders(line) => {
+                    self.reply = Reply::Held(vec![line.to_string()]);
+                    Formatted::Nothing
+                },
+                Reply::Held(lines) => {
+                    lines.push(line.to_string());
+                    Formatted::Nothing
+                },
+                reply => {
+                    *reply = Reply::Rows;
+                    Formatted::Line(line.to_string())
+                },
+            };
+        }
+        if line.starts_with('-') {
+            return Formatted::Error(line.to_string());
+        }
+        // Pushes aren't numbered, so they can turn up within any reply
+        if let Some(push) = line.strip_prefix('>') {
+            return Formatted::Line(self.row(&words(push)));
+        }
+        if let Reply::Unknown { .. } = self.reply {
+            self.reply = match line {
+                "+INFO" => Reply::Info { section: String::new() },
+                "+HELLO" => Reply::Hello,
+                line if line.starts_with("USAGE") => Reply::Help,
+                _ => Reply::Rows,
+            };
+            if !matches!(self.reply, Reply::Rows) {
+                return Formatted::Nothing;
+            }
+        }
+        let Some(content) = line.strip_prefix('+') else {
+            // Counts of the lines following
+            return match &self.reply {
+                Reply::Rows if !line.starts_with('*') => Formatted::Line(self.row(&[line.to_string()])),
+                _ => Formatted::Nothing,
+            };
+        };
+        let fields = match &mut self.reply {
+            Reply::Ignored | Reply::Unknown { .. } | Reply::Held(_) => return Formatted::Nothing,
+            Reply::Rows => words(content),
+            Reply::Info { section } => match content.strip_prefix("# ") {
+                Some(name) => {
+                    *section = name.to_string();
+                    return Formatted::Nothing;
+                },
+                None => {
+                    let (name, value) = content.split_once(':').unwrap_or((content, ""));
+                    vec![section.clone(), name.to_string(), value.to_string()]
+                },
+            },
+            Reply::Hello => {
+                let (name, value) = content.split_once(':').unwrap_or((content, ""));
+                vec![name.to_string(), value.to_string()]
+            },
+            Reply::Help => {
+                let (usage, description) = split_help(content);
+                vec![usage.to_string(), description.to_string()]
+            },
+        };
+        Formatted::Line(self.row(&fields))
+    }
+
+    fn row(&self, fields: &[String]) -> String {
+        match self.format {
+            Format::Tsv => fields.iter().map(|field| escape_tsv(field)).collect::<Vec<_>>().join("\t"),
+            _ => fields.iter().map(|field| escape_csv(field)).collect::<Vec<_>>().join(","),
+        }
+    }
+}
+
+/// The number of the reply a SEQ line starts, None for any other line
+pub fn seq(line: &str) -> Option<usize> {
+    line.strip_prefix('#').and_then(|number| number.parse().ok())
+}
+
+/// Splits a line of HELP into the usage and the description, the bracketed end of the line: as
+/// usages hold brackets of their own, the bracket the description opens with is found by matching the
+/// last
+pub fn split_help(line: &str) -> (&str, &str) {
+    let mut depth = 0;
+    for (index, c) in line.char_indices().rev() {
+        match c {
+            ']' => depth += 1,
+            '[' if depth == 1 => return (line[..index].trim_end(), &line[index + 1..line.len() - 1]),
+            '[' => depth -= 1,
+            _ => {},
+        }
+    }
+    (line, "")
+}
+
+// Splits a line of a reply into its words, unquoting identifiers the way the server quotes them
+fn words(line: &str) -> Vec<String> {
+    let mut words = Vec::new();
+    let mut chars = line.chars().peekable();
+    loop {
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        let Some(first) = chars.next() else {
+            return words;
+        };
+        let mut word = String::new();
+        if first == '"' {
+            while let Some(c) = chars.next() {
+                match c {
+                    '"' => break,
+                    '\\' => match chars.next() {
+                        Some('n') => word.push('\n'),
+                        Some('r') => word.push('\r'),
+                        Some('t') => word.push('\t'),
+                        Some('x') => {
+                            let code: String = chars.by_ref().take(2).collect();
+                            word.extend(u8::from_str_radix(&code, 16).ok().map(char::from));
+                        },
+                        Some(c) => word.push(c),
+                        None => break,
+                    },
+                    c => word.push(c),
+                }
+            }
+        } else {
+            word.push(first);
+            while let Some(c) = chars.next_if(|c| !c.is_whitespace()) {
+                word.push(c);
+            }
+        }
+        words.push(word);
+    }
+}
+
+// Quotes a field holding a comma, quote or line break, doubling its quotes
+fn escape_csv(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+// Escapes the tabs, line breaks and backslashes of a field
+fn escape_tsv(field: &str) -> String {
+    field.replace('\\', "\\\\").replace('\t', "\\t").replace('\n', "\\n").replace('\r', "\\r")
+}
@@ -0,0 +1,65 @@
+// Rendering of INFO and HELP replies for a terminal, --output pretty: INFO as a table of each section's
+// statistics, names aligned and colored, and HELP with the command names highlighted and the
+// descriptions aligned.
+
+use crate::output::split_help;
+
+const RESET: &str = "\x1b[0m";
+const BOLD_YELLOW: &str = "\x1b[1;33m";
+const BOLD_GREEN: &str = "\x1b[1;32m";
+const CYAN: &str = "\x1b[36m";
+const DIM: &str = "\x1b[2m";
+
+/// Whether the reply starting with line is rendered
+pub fn renders(line: &str) -> bool {
+    line == "+INFO" || line.starts_with("USAGE")
+}
+
+/// Renders the lines of an INFO or HELP reply
+pub fn render(lines: &[String]) -> String {
+    match lines.first() {
+        Some(line) if line == "+INFO" => info(&lines[1..]),
+        Some(line) => format!("{}{}{}\n{}", DIM, line.trim_end(), RESET, help(&lines[1..])),
+        None => String::new(),
+    }
+}
+
+fn info(lines: &[String]) -> String {
+    let width = lines.iter()
+        .filter_map(|line| line.strip_prefix('+'))
+        .filter(|content| !content.starts_with("# "))
+        .map(|content| content.split_once(':').map_or(content, |(name, _)| name).len())
+        .max()
+        .unwrap_or(0);
+    let mut rendered = Vec::new();
+    for content in lines.iter().map(|line| line.strip_prefix('+').unwrap_or(line)) {
+        match content.strip_prefix("# ") {
+            Some(section) => {
+                if !rendered.is_empty() {
+                    rendered.push(String::new());
+                }
+                rendered.push(format!("{}# {}{}", BOLD_YELLOW, section, RESET));
+            },
+            None => {
+                let (name, value) = content.split_once(':').unwrap_or((content, ""));
+                rendered.push(format!("{}{:<width$}{}  {}", CYAN, name, RESET, value, width = width));
+            },
+        }
+    }
+    rendered.join("\n")
+}
+
+fn help(lines: &[String]) -> String {
+    let commands: Vec<(&str, &str)> = lines.iter().map(|line| split_help(line.strip_prefix('+').unwrap_or(line))).collect();
+    let width = commands.iter().map(|(usage, _)| usage.len()).max().unwrap_or(0);
+    commands.iter().map(|(usage, description)| {
+        // The command's name is its leading upper case words, such as CLIENT KILL, before the arguments
+        let name = usage.split(' ')
+            .take_while(|word| !word.is_empty() && word.chars().all(|c| c.is_ascii_uppercase()))
+            .map(|word| word.len() + 1)
+            .sum::<usize>()
+            .saturating_sub(1);
+        let padded = format!("{:<width$}", usage, width = width);
+        format!("{}{}{}{}  {}", BOLD_GREEN, &padded[..name], RESET, &padded[name..], description)
+    }).collect::<Vec<_>>().join("\n")
+}
@@ -0,0 +1,61 @@
+// TLS for connections to a server started with --tls-cert, enabled with --tls. The server's
+// certificate is verified against the CAs in --cacert, or the system's when not given, and with --cert
+// and --key the client presents a certificate of its own, for servers started with --tls-ca.
+
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use std::sync::Arc;
+
+use tokio_rustls::rustls::crypto::ring;
+use tokio_rustls::rustls::pki_types::CertificateDer;
+use tokio_rustls::rustls::{ClientConfig, RootCertStore};
+use tokio_rustls::TlsConnector;
+
+/// Loads the CAs the server's certificate is verified against, and the client's certificate chain
+/// and key if given, all PEM files
+pub fn connector(ca: Option<&Path>, identity: Option<(&Path, &Path)>) -> Result<TlsConnector, String> {
+    let mut roots = RootCertStore::empty();
+    match ca {
+        Some(ca) => {
+            for cert in certificates(ca)? {
+                roots.add(cert).map_err(|e| format!("{}: {}", ca.display(), e))?;
+            }
+        },
+        None => {
+            let native = rustls_native_certs::load_native_certs();
+            if native.certs.is_empty() {
+                return Err(format!("no CA certificates found on the system, pass --cacert: {:?}", native.errors));
+            }
+            roots.add_parsable_certificates(native.certs);
+        },
+    }
+    let builder = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .map_err(|e| e.to_string())?
+        .with_root_certificates(roots);
+    let config = match identity {
+        Some((cert, key)) => {
+            let private_key = rustls_pemfile::private_key(&mut open(key)?)
+                .map_err(|e| format!("{}: {}", key.display(), e))?
+                .ok_or_else(|| format!("{}: no private key found", key.display()))?;
+            builder.with_client_auth_cert(certificates(cert)?, private_key).map_err(|e| format!("{}: {}", cert.display(), e))?
+        },
+        None => builder.with_no_client_auth(),
+    };
+    Ok(TlsConnector::from(Arc::new(config)))
+}
+
+fn certificates(path: &Path) -> Result<Vec<CertificateDer<'static>>, String> {
+    let certificates = rustls_pemfile::certs(&mut open(path)?)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| format!("{}: {}", path.display(), e))?;
+    if certificates.is_empty() {
+        return Err(format!("{}: no certificate found", path.display()));
+    }
+    Ok(certificates)
+}
+
+fn open(path: &Path) -> Result<BufReader<File>, String> {
+    File::open(path).map(BufReader::new).map_err(|e| format!("{}: {}", path.display(), e))
+}
@@ -0,0 +1,5 @@
+fn main() {
+    // Use the vendored protoc so building doesn't depend on one being installed
+    std::env::set_var("PROTOC", protoc_bin_vendored::protoc_bin_path().unwrap());
+    tonic_build::compile_protos("proto/pqueue.proto").unwrap();
+}
@@ -0,0 +1,113 @@
+// Users and the commands they may run, loaded from the file given with --acl-file. Each line defines
+// a user as a name, a password and the commands it may run, separated by whitespace:
+//
+//   # name     password  commands
+//   producer   s3cret    UPDATE SCORE
+//   consumer   hunter2   @consumer
+//   admin      letmein   @all
+//
+// Commands are named as in HELP (case insensitive) or given as one of the roles:
+//
+//   @producer  UPDATE MUPDATE SETSCORE SETDATA GETDATA REMOVE MULTI EXEC DISCARD DELAY SCORE EXPIRE TTL PERSIST
+//   @consumer  NEXT BNEXT RESERVE ACK NACK PEEK GETDATA SCORERANGE SCAN NEXTSCORE PEEKSCORE SCORE TTL CONSUME CREDIT SUBSCRIBE UNSUBSCRIBE
+//   @all       every command
+//
+// Users authenticate with AUTH <name> <password>. Blank lines and lines starting with # are ignored.
+
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use std::sync::Arc;
+
+use subtle::ConstantTimeEq as _;
+
+const ROLES: &[(&str, &[&str])] = &[
+    ("@producer", &["UPDATE", "MUPDATE", "SETSCORE", "SETDATA", "GETDATA", "REMOVE", "MULTI", "EXEC", "DISCARD", "DELAY", "SCORE", "EXPIRE", "TTL", "PERSIST"]),
+    ("@consumer", &["NEXT", "BNEXT", "RESERVE", "ACK", "NACK", "PEEK", "GETDATA", "SCORERANGE", "SCAN", "NEXTSCORE", "PEEKSCORE", "SCORE", "TTL", "CONSUME", "CREDIT", "SUBSCRIBE", "UNSUBSCRIBE"]),
+];
+
+/// What a client may run once authenticated: Some user's commands, or None for every command
+pub type Access = Option<Arc<User>>;
+
+/// Whether access allows running the command with the given name
+pub fn allows(access: &Access, command: &str) -> bool {
+    access.as_ref().is_none_or(|user| user.allows(command))
+}
+
+pub struct User {
+    password: String,
+    // None when the user may run every command
+    commands: Option<HashSet<String>>,
+}
+
+impl User {
+    pub fn allows(&self, command: &str) -> bool {
+        self.commands.as_ref().is_none_or(|commands| commands.contains(command))
+    }
+}
+
+pub struct Acl {
+    users: HashMap<String, Arc<User>>,
+}
+
+impl Acl {
+    /// Loads the ACL file at path, failing with a message naming the offending line
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let contents = std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        Self::parse(&contents).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    pub fn parse(contents: &str) -> Result<Self, String> {
+        let mut users = HashMap::new();
+        for (number, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut fields = line.split_whitespace();
+            let (Some(name), Some(password)) = (fields.next(), fields.next()) else {
+                return Err(format!("line {}: expected a name, a password and commands", number + 1));
+            };
+            let mut commands = Some(HashSet::new());
+            for field in fields {
+                let field = field.to_ascii_uppercase();
+                match field.as_str() {
+                    "@ALL" => commands = None,
+                    role if role.starts_with('@') => {
+                        let Some((_, role_commands)) = ROLES.iter().find(|(name, _)| name.eq_ignore_ascii_case(role)) else {
+                            return Err(format!("line {}: unknown role {}", number + 1, role.to_ascii_lowercase()));
+                        };
+                        if let Some(commands) = &mut commands {
+                            commands.extend(role_commands.iter().map(|command| command.to_string()));
+                        }
+                    },
+                    _ => {
+                        if let Some(commands) = &mut commands {
+                            commands.insert(field);
+                        }
+                    },
+                }
+            }
+            let user = User { password: password.to_string(), commands };
+            if users.insert(name.to_string(), Arc::new(user)).is_some() {
+                return Err(format!("line {}: user {} is already defined", number + 1, name));
+            }
+        }
+        Ok(Self { users })
+    }
+
+    /// The user with the given name, if there is one
+    pub fn user(&self, name: &str) -> Option<Arc<User>> {
+        self.users.get(name).cloned()
+    }
+
+    /// The user with the given name and password, if there is one
+    pub fn authenticate(&self, name: &str, password: &str) -> Option<Arc<User>> {
+        self.users.get(name).filter(|user| passwords_match(&user.password, password)).cloned()
+    }
+}
+
+/// Compares passwords in time that doesn't depend on where they differ, so timing AUTH doesn't give
+/// away how much of a guess was right
+pub fn passwords_match(expected: &str, given: &str) -> bool {
+    expected.as_bytes().ct_eq(given.as_bytes()).into()
+}
@@ -0,0 +1,306 @@
+// Binary protocol, selected with PROTOCOL BINARY. Every request and response is a frame: a u32
+// length (big endian, like every number here) followed by that many bytes of body. A request body is
+// an opcode followed by the command's fields:
+//
+//   0x00 COMMAND     any text protocol command as a name and arguments, each a bytes field
+//   0x01 UPDATE      item: bytes, delta: i64
+//   0x02 NEXT
+//   0x03 NEXT        count: u32
+//   0x04 PEEK
+//   0x05 PEEK        count: u32
+//   0x06 NEXTSCORE
+//   0x07 PEEKSCORE
+//   0x08 SCORE       item: bytes
+//   0x09 INFO
+//   0x0A CLEAR
+//   0x0B BNEXT       timeout in milliseconds: u32 (0 waits forever)
+//   0x0C AUTH        password: bytes
+//
+// where a bytes field is a u32 length followed by that many bytes, so items may hold any character.
+// A response body is a type followed by its payload:
+//
+//   0x00 OK
+//   0x01 NIL         no value, or with protocol version 1 no such item or no item to return
+//   0x02 INTEGER     i64
+//   0x03 ITEM        the item's bytes (the rest of the frame)
+//   0x04 ENTRY       score: i64, then the item's bytes
+//   0x05 ITEMS       count: u32, then count bytes fields
+//   0x06 ENTRIES     count: u32, then count (score: i64, item: bytes) pairs
+//   0x07 JSON        the JSON protocol's response, for INFO, HELLO, HELP, SCAN, pushed events and items with data
+//   0x08 MULTI       count: u32, then count responses, each framed like a response (for EXEC)
+//   0x09 SEQ         seq: u64, the sequence number of the request the next frame replies to (after SEQ ON)
+//   0xFF ERROR       the error message, starting with EMPTY when there is no item to return and
+//                    NOTFOUND when there is no such item
+
+use std::time::Duration;
+
+use crate::protocol::{Command, Response, EMPTY_ERROR, NOT_FOUND_ERROR};
+use crate::rename::CommandNames;
+
+// Reads the fields of a request body
+struct Fields<'a> {
+    body: &'a [u8],
+}
+
+impl<'a> Fields<'a> {
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.body.len() < len {
+            return None;
+        }
+        let (field, rest) = self.body.split_at(len);
+        self.body = rest;
+        Some(field)
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        self.take(4).map(|field| u32::from_be_bytes(field.try_into().unwrap()))
+    }
+
+    fn i64(&mut self) -> Option<i64> {
+        self.take(8).map(|field| i64::from_be_bytes(field.try_into().unwrap()))
+    }
+
+    fn string(&mut self) -> Option<String> {
+        let len = self.u32()? as usize;
+        self.take(len).and_then(|field| String::from_utf8(field.to_vec()).ok())
+    }
+
+    fn is_empty(&self) -> bool {
+        self.body.is_empty()
+    }
+}
+
+/// Decodes a request body (the frame without its length)
+pub fn decode_command(body: &[u8], names: &CommandNames) -> Command {
+    let Some((&opcode, body)) = body.split_first() else {
+        return Command::Error { msg: "Empty frame".to_string() };
+    };
+    let mut fields = Fields { body };
+    let command = match opcode {
+        0x00 => {
+            let mut parts = Vec::new();
+            while !fields.is_empty() {
+                match fields.string() {
+                    Some(part) => parts.push(part),
+                    None => return Command::Error { msg: "Malformed frame".to_string() },
+                }
+            }
+            let parts: Vec<&str> = parts.iter().map(String::as_str).collect();
+            return Command::from_named_parts(&parts, names);
+        },
+        0x01 => fields.string().zip(fields.i64()).map(|(item_id, value)| Command::Update { item_id, value, data: None }),
+        0x02 => Some(Command::Next),
+        0x03 => fields.u32().map(|count| Command::NextBatch { count: count as usize }),
+        0x04 => Some(Command::Peek),
+        0x05 => fields.u32().map(|count| Command::PeekMany { count: count as usize }),
+        0x06 => Some(Command::NextScore),
+        0x07 => Some(Command::PeekScore),
+        0x08 => fields.string().map(|item_id| Command::Score { item_id }),
+        0x09 => Some(Command::Info { section: None }),
+        0x0A => Some(Command::Clear),
+        0x0B => fields.u32().map(|millis| Command::BlockingNext { timeout: Duration::from_millis(millis as u64) }),
+        0x0C => fields.string().map(|password| Command::Auth { user: None, password }),
+        _ => return Command::Error { msg: format!("Unknown opcode {:#04x}", opcode) },
+    };
+    match command {
+        Some(command) if fields.is_empty() => command.unless_hidden(names),
+        _ => Command::Error { msg: "Malformed frame".to_string() },
+    }
+}
+
+/// Encodes a response as a complete frame
+pub fn encode_response(response: &Response) -> Vec<u8> {
+    let mut body = Vec::new();
+    match response {
+        Response::Ok => body.push(0x00),
+        Response::Nil => body.push(0x01),
+        Response::Score(score) | Response::Updated(Some(score)) => {
+            body.push(0x02);
+            body.extend_from_slice(&score.to_be_bytes());
+        },
+        Response::Count(count) => {
+            body.push(0x02);
+            body.extend_from_slice(&(*count as i64).to_be_bytes());
+        },
+        Response::Item(item) | Response::Line(item) => {
+            body.push(0x03);
+            body.extend_from_slice(item.as_bytes());
+        },
+        Response::Entry(item, score) => {
+            body.push(0x04);
+            body.extend_from_slice(&score.to_be_bytes());
+            body.extend_from_slice(item.as_bytes());
+        },
+        Response::Items(items) | Response::Lines(items) => {
+            body.push(0x05);
+            body.extend_from_slice(&(items.len() as u32).to_be_bytes());
+            items.iter().for_each(|item| put_bytes(&mut body, item.as_bytes()));
+        },
+        Response::Entries(entries) => {
+            body.push(0x06);
+            body.extend_from_slice(&(entries.len() as u32).to_be_bytes());
+            for (item, score) in entries {
+                body.extend_from_slice(&score.to_be_bytes());
+                put_bytes(&mut body, item.as_bytes());
+            }
+        },
+        Response::Multi(responses) => {
+            body.push(0x08);
+            body.extend_from_slice(&(responses.len() as u32).to_be_bytes());
+            responses.iter().for_each(|response| body.extend_from_slice(&encode_response(response)));
+        },
+        Response::Error(msg) => {
+            body.push(0xFF);
+            body.extend_from_slice(msg.as_bytes());
+        },
+        Response::Empty => {
+            body.push(0xFF);
+            body.extend_from_slice(format!("EMPTY {}", EMPTY_ERROR).as_bytes());
+        },
+        Response::NotFound | Response::Updated(None) => {
+            body.push(0xFF);
+            body.extend_from_slice(format!("NOTFOUND {}", NOT_FOUND_ERROR).as_bytes());
+        },
+        Response::Info(_) | Response::Hello { .. } | Response::SlowLog(_) | Response::Latency(_) | Response::Scanned { .. } | Response::Exported { .. } | Response::ItemData { .. } | Response::ItemsData(_) | Response::EntryData { .. } | Response::Reserved { .. } | Response::Queued | Response::Help | Response::Event { .. } | Response::Cleared(_) | Response::Lagged(_) | Response::Monitored { .. }
+        | Response::Consumed { .. } => {
+            body.push(0x07);
+            body.extend_from_slice(response.to_json().to_string().as_bytes());
+        },
+    }
+    let mut frame = Vec::with_capacity(4 + body.len());
+    put_bytes(&mut frame, &body);
+    frame
+}
+
+/// Frames the header preceding a reply to the request numbered seq, see SEQ
+pub fn encode_seq(seq: u64) -> Vec<u8> {
+    let mut body = vec![0x09];
+    body.extend_from_slice(&seq.to_be_bytes());
+    let mut frame = Vec::with_capacity(4 + body.len());
+    put_bytes(&mut frame, &body);
+    frame
+}
+
+fn put_bytes(buffer: &mut Vec<u8>, bytes: &[u8]) {
+    buffer.extend_from_slice(&(bytes.len() as u32).to_be_bytes());
+    buffer.extend_from_slice(bytes);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn body(opcode: u8, fields: &[&[u8]]) -> Vec<u8> {
+        let mut body = vec![opcode];
+        fields.iter().for_each(|field| body.extend_from_slice(field));
+        body
+    }
+
+    fn bytes(field: &str) -> Vec<u8> {
+        let mut buffer = Vec::new();
+        put_bytes(&mut buffer, field.as_bytes());
+        buffer
+    }
+
+    fn decode(body: &[u8]) -> Command {
+        decode_command(body, &CommandNames::default())
+    }
+
+    fn error(command: Command) -> String {
+        match command {
+            Command::Error { msg } => msg,
+            command => panic!("expected an error, got {}", command),
+        }
+    }
+
+    #[test]
+    fn test_decode_opcodes() {
+        let item = bytes("my item");
+        let cases: Vec<(Vec<u8>, &str)> = vec![
+            (body(0x00, &[&bytes("SCORE"), &bytes("my item")]), "SCORE \"my item\""),
+            (body(0x01, &[&item, &(-5i64).to_be_bytes()]), "UPDATE \"my item\" -5"),
+            (body(0x02, &[]), "NEXT"),
+            (body(0x03, &[&3u32.to_be_bytes()]), "NEXT 3"),
+            (body(0x04, &[]), "PEEK"),
+            (body(0x05, &[&2u32.to_be_bytes()]), "PEEK 2"),
+            (body(0x06, &[]), "NEXTSCORE"),
+            (body(0x07, &[]), "PEEKSCORE"),
+            (body(0x08, &[&item]), "SCORE \"my item\""),
+            (body(0x09, &[]), "INFO"),
+            (body(0x0A, &[]), "CLEAR"),
+            (body(0x0B, &[&1500u32.to_be_bytes()]), "BNEXT 1.5"),
+        ];
+        for (body, expected) in cases {
+            assert_eq!(decode(&body).to_string(), expected);
+        }
+        match decode(&body(0x0C, &[&bytes("secret")])) {
+            Command::Auth { user: None, password } => assert_eq!(password, "secret"),
+            command => panic!("expected AUTH, got {}", command),
+        }
+    }
+
+    #[test]
+    fn test_decode_malformed() {
+        // Truncated fields
+        assert_eq!(error(decode(&body(0x01, &[&bytes("item"), &[0, 0, 0]]))), "Malformed frame");
+        assert_eq!(error(decode(&body(0x03, &[&[0, 0]]))), "Malformed frame");
+        assert_eq!(error(decode(&body(0x08, &[&[0, 0, 0, 9], b"item"]))), "Malformed frame");
+        assert_eq!(error(decode(&body(0x00, &[&bytes("PING"), &[0, 0]]))), "Malformed frame");
+        // Trailing bytes
+        assert_eq!(error(decode(&body(0x02, &[&[0]]))), "Malformed frame");
+        assert_eq!(error(decode(&body(0x05, &[&2u32.to_be_bytes(), &[1]]))), "Malformed frame");
+        assert_eq!(error(decode(&[])), "Empty frame");
+    }
+
+    #[test]
+    fn test_decode_unknown_opcode() {
+        assert_eq!(error(decode(&[0x42])), "Unknown opcode 0x42");
+    }
+
+    #[test]
+    fn test_decode_hidden_opcode() {
+        let names = CommandNames::new([("NEXT".to_string(), Some("POP".to_string())), ("CLEAR".to_string(), None)]);
+        assert_eq!(error(decode_command(&body(0x02, &[]), &names)), "Invalid command or arguments");
+        assert_eq!(error(decode_command(&body(0x03, &[&2u32.to_be_bytes()]), &names)), "Invalid command or arguments");
+        assert_eq!(error(decode_command(&body(0x0A, &[]), &names)), "Invalid command or arguments");
+        // Under its new name through 0x00
+        assert_eq!(decode_command(&body(0x00, &[&bytes("POP")]), &names).to_string(), "NEXT");
+        assert_eq!(decode_command(&body(0x04, &[]), &names).to_string(), "PEEK");
+    }
+
+    #[test]
+    fn test_encode_responses() {
+        let frame = |body: &[u8]| {
+            let mut frame = Vec::new();
+            put_bytes(&mut frame, body);
+            frame
+        };
+        assert_eq!(encode_response(&Response::Ok), frame(&[0x00]));
+        assert_eq!(encode_response(&Response::Nil), frame(&[0x01]));
+        assert_eq!(encode_response(&Response::Score(-2)), frame(&body(0x02, &[&(-2i64).to_be_bytes()])));
+        assert_eq!(encode_response(&Response::Updated(Some(7))), frame(&body(0x02, &[&7i64.to_be_bytes()])));
+        assert_eq!(encode_response(&Response::Count(3)), frame(&body(0x02, &[&3i64.to_be_bytes()])));
+        assert_eq!(encode_response(&Response::Item("a b".to_string())), frame(b"\x03a b"));
+        assert_eq!(encode_response(&Response::Entry("a".to_string(), 4)), frame(&body(0x04, &[&4i64.to_be_bytes(), b"a"])));
+        assert_eq!(
+            encode_response(&Response::Items(vec!["a".to_string(), "bc".to_string()])),
+            frame(&body(0x05, &[&2u32.to_be_bytes(), &bytes("a"), &bytes("bc")])),
+        );
+        assert_eq!(
+            encode_response(&Response::Entries(vec![("a".to_string(), 1)])),
+            frame(&body(0x06, &[&1u32.to_be_bytes(), &1i64.to_be_bytes(), &bytes("a")])),
+        );
+        assert_eq!(
+            encode_response(&Response::ItemData { item: "a".to_string(), data: "d".to_string() }),
+            frame(&body(0x07, &[br#"{"data":"d","item":"a"}"#])),
+        );
+        assert_eq!(
+            encode_response(&Response::Multi(vec![Response::Ok, Response::Score(1)])),
+            frame(&body(0x08, &[&2u32.to_be_bytes(), &frame(&[0x00]), &frame(&body(0x02, &[&1i64.to_be_bytes()]))])),
+        );
+        assert_eq!(encode_response(&Response::Error("oops".to_string())), frame(b"\xFFoops"));
+        assert_eq!(encode_response(&Response::Empty), frame(&body(0xFF, &[format!("EMPTY {}", EMPTY_ERROR).as_bytes()])));
+        assert_eq!(encode_response(&Response::NotFound), frame(&body(0xFF, &[format!("NOTFOUND {}", NOT_FOUND_ERROR).as_bytes()])));
+        assert_eq!(encode_seq(5), frame(&body(0x09, &[&5u64.to_be_bytes()])));
+    }
+}
@@ -0,0 +1,102 @@
+use std::collections::HashMap;
+use std::ops::Deref;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use tokio::sync::Notify;
+use uuid::Uuid;
+
+/// The clients connected over TCP and WebSockets, listed by CLIENT LIST
+#[derive(Default)]
+pub struct Clients {
+    clients: Mutex<HashMap<Uuid, Arc<Client>>>,
+}
+
+pub struct Client {
+    pub id: Uuid,
+    address: String,
+    // The transport the client connected over, "tcp", "tls", "unix" or "ws"
+    kind: &'static str,
+    connected: Instant,
+    last_command: Mutex<(Option<&'static str>, Instant)>,
+    killed: Notify,
+}
+
+impl Client {
+    /// Notes the command the client ran last, for CLIENT LIST
+    pub fn record_command(&self, name: &'static str) {
+        *self.last_command.lock().unwrap() = (Some(name), Instant::now());
+    }
+
+    /// Completes once the client was killed with CLIENT KILL
+    pub async fn killed(&self) {
+        self.killed.notified().await
+    }
+
+    fn describe(&self) -> String {
+        let (last_command, last_active) = *self.last_command.lock().unwrap();
+        format!(
+            "id={} addr={} type={} age={} idle={} cmd={}",
+            self.id,
+            self.address,
+            self.kind,
+            self.connected.elapsed().as_secs(),
+            last_active.elapsed().as_secs(),
+            last_command.unwrap_or("NULL"),
+        )
+    }
+}
+
+/// Keeps a client listed until dropped
+pub struct Registration<'a> {
+    clients: &'a Clients,
+    client: Arc<Client>,
+}
+
+impl Deref for Registration<'_> {
+    type Target = Arc<Client>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.client
+    }
+}
+
+impl Drop for Registration<'_> {
+    fn drop(&mut self) {
+        self.clients.clients.lock().unwrap().remove(&self.client.id);
+    }
+}
+
+impl Clients {
+    pub fn register(&self, id: Uuid, address: String, kind: &'static str) -> Registration<'_> {
+        let now = Instant::now();
+        let client = Arc::new(Client {
+            id,
+            address,
+            kind,
+            connected: now,
+            last_command: Mutex::new((None, now)),
+            killed: Notify::new(),
+        });
+        self.clients.lock().unwrap().insert(id, client.clone());
+        Registration { clients: self, client }
+    }
+
+    /// A line describing each client, oldest first
+    pub fn list(&self) -> Vec<String> {
+        let mut clients: Vec<Arc<Client>> = self.clients.lock().unwrap().values().cloned().collect();
+        clients.sort_by_key(|client| client.connected);
+        clients.iter().map(|client| client.describe()).collect()
+    }
+
+    /// Disconnects the client with the given id, returning false if there is none
+    pub fn kill(&self, id: &Uuid) -> bool {
+        match self.clients.lock().unwrap().get(id) {
+            Some(client) => {
+                client.killed.notify_one();
+                true
+            },
+            None => false,
+        }
+    }
+}
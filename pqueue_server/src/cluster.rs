@@ -0,0 +1,47 @@
+// Cluster mode: every node is started with the same list of node addresses and its own position in
+// it, and owns the items whose identifier hashes to that position. Commands naming an item owned by
+// another node are answered with "MOVED <index> <address>" so the client can retry there; commands
+// acting on the whole queue (NEXT, PEEK, INFO, ...) only see the node's own shard, so consumers connect
+// to every node.
+
+/// The nodes of a cluster and which of them this server is
+pub struct Cluster {
+    nodes: Vec<String>,
+    index: usize,
+}
+
+impl Cluster {
+    /// Creates the cluster of nodes (the addresses clients connect to) where this server is the node
+    /// at index. Panics if index is out of range.
+    pub fn new(nodes: Vec<String>, index: usize) -> Self {
+        assert!(index < nodes.len(), "cluster index {} is out of range for {} nodes", index, nodes.len());
+        Self { nodes, index }
+    }
+
+    /// Index of the node owning item. FNV-1a keeps the mapping stable across builds and platforms,
+    /// which the std hashers don't promise.
+    pub fn node_for(&self, item: &str) -> usize {
+        let hash = item.bytes().fold(0xcbf29ce484222325_u64, |hash, byte| (hash ^ byte as u64).wrapping_mul(0x100000001b3));
+        (hash % self.nodes.len() as u64) as usize
+    }
+
+    /// The redirect for item when another node owns it, as sent to clients
+    pub fn redirect(&self, item: &str) -> Option<String> {
+        let node = self.node_for(item);
+        (node != self.index).then(|| format!("MOVED {} {}", node, self.nodes[node]))
+    }
+
+    /// "<index> <address>" for every node, with " myself" after this one
+    pub fn describe(&self) -> Vec<String> {
+        self.nodes.iter().enumerate().map(|(index, address)| {
+            let myself = if index == self.index { " myself" } else { "" };
+            format!("{} {}{}", index, address, myself)
+        }).collect()
+    }
+
+    /// "<index> <address>" of the node owning item
+    pub fn describe_owner(&self, item: &str) -> String {
+        let node = self.node_for(item);
+        format!("{} {}", node, self.nodes[node])
+    }
+}
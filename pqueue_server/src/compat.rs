@@ -0,0 +1,154 @@
+// The wire protocol of the original pqueued, served on --compat-port so its clients and tooling work
+// against this server unchanged while migrating. Only its commands are understood, parsed the way it
+// parsed them: split on whitespace with no quoting, and nothing like HELLO or AUTH to negotiate.
+//
+//   UPDATE <identifier> <score>   +OK
+//   NEXT                          +<identifier>, or +-1 when the queue is empty
+//   PEEK                          +<identifier>, or +-1 when the queue is empty
+//   SCORE <identifier>            +<score>, or +-1 when there is no such item
+//   INFO                          +INFO followed by a +name:value line per statistic
+//   HELP                          The usage of the commands above
+//
+// Errors are a single -<message> line. The commands run like any other client's, so they show in the
+// metrics, the slow log and MONITOR and are refused while the server is read only or draining.
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use tokio::io::{AsyncWriteExt as _, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{debug, error, warn};
+use uuid::Uuid;
+
+use crate::protocol::{Command, Protocol, Response};
+use crate::rename::CommandNames;
+use crate::{execute, read_request, Read, Server, Session, Skip, MAX_CLIENTS_ERROR};
+
+const HELP: &str = "USAGE (note: commands are case insensitive, identifiers are case sensitive): \r\n\
+    +UPDATE <identifier> <score> [Updates the priority of <identifier> by adding <score> to its priority or inserts it with priority of <score>]\r\n \
+    +NEXT                        [Pops the highest priority item (item that has had that priority the longest if multiple) off the queue]\r\n \
+    +PEEK                        [Returns the highest priority item without removing it from the queue]\r\n \
+    +SCORE <identifier>          [Fetch the current priority score for <identifier>]\r\n \
+    +INFO                        [Fetch statistics about the server]\r\n \
+    +HELP                        [Get this help]\r\n";
+
+/// Serves the original protocol to the clients connecting to listener
+pub async fn serve(listener: TcpListener, server: Arc<Server>) {
+    loop {
+        match listener.accept().await {
+            Ok((socket, address)) => {
+                tokio::spawn(handle_connection(socket, address, server.clone(), Uuid::new_v4()));
+            },
+            Err(e) => error!("Failed to accept a connection: {}", e),
+        }
+    }
+}
+
+#[tracing::instrument(name = "connection", skip_all, fields(client_id = %id))]
+async fn handle_connection(mut socket: TcpStream, address: SocketAddr, server: Arc<Server>, id: Uuid) {
+    // The original protocol is version 1: UPDATE replies OK, and nothing found is +-1
+    let mut session = Session::new(&server.config);
+    session.version = 1;
+    let Some(_client) = server.metrics.client_connected(server.config.max_clients()) else {
+        warn!("refusing client, max clients reached");
+        let _ = socket.write_all(render(&Response::Error(MAX_CLIENTS_ERROR.to_string()), &server).as_bytes()).await;
+        return;
+    };
+    let client = server.clients.register(id, address.to_string(), "compat");
+    session.client = Some(client.clone());
+    debug!("client connected");
+    let (reader, mut writer) = socket.split();
+    let mut reader = BufReader::with_capacity(server.config.read_buffer_size, reader);
+    let mut buffer = Vec::new();
+    let mut skip = Skip::default();
+    let mut requests = 0_u64;
+
+    loop {
+        let limits = server.config.limits();
+        let response = tokio::select! {
+            request = read_request(&mut reader, Protocol::Text, limits.max_request(Protocol::Text), &mut buffer, &mut skip) => match request {
+                Ok(Read::Request) => {
+                    requests += 1;
+                    debug!(seq = requests, request = %String::from_utf8_lossy(&buffer), "rcv");
+                    let command = parse(&String::from_utf8_lossy(&buffer), &server.config.command_names);
+                    buffer.clear();
+                    match command {
+                        Command::Help => Response::Help,
+                        command => execute(command, &server, &mut session).await,
+                    }
+                },
+                Ok(Read::Oversized) if !limits.disconnect => {
+                    requests += 1;
+                    limits.too_long(Protocol::Text)
+                },
+                Ok(Read::Oversized) => {
+                    warn!("disconnecting client, request over the limit");
+                    let _ = writer.write_all(render(&limits.too_long(Protocol::Text), &server).as_bytes()).await;
+                    return;
+                },
+                Ok(Read::Closed) | Err(_) => {
+                    debug!("client disconnected");
+                    return;
+                },
+            },
+            _ = client.killed() => {
+                debug!("client killed");
+                return;
+            },
+        };
+
+        let response = render(&response, &server);
+        debug!(seq = requests, response = %response, "snd");
+        if let Err(e) = writer.write_all(response.as_bytes()).await {
+            warn!("Failed to write to socket: {}", e);
+            return;
+        }
+    }
+}
+
+fn parse(line: &str, names: &CommandNames) -> Command {
+    let mut parts: Vec<&str> = line.split_whitespace().collect();
+    let renamed = parts.first().and_then(|name| names.renamed_from(name));
+    if let Some(command) = renamed {
+        parts[0] = command;
+    }
+    let command = match parts.as_slice() {
+        [command, item_id, value] if command.eq_ignore_ascii_case("UPDATE") => match value.parse() {
+            Ok(value) => Command::Update { item_id: item_id.to_string(), value, data: None },
+            Err(_) => Command::Error { msg: "Invalid value for UPDATE".to_string() },
+        },
+        [command] if command.eq_ignore_ascii_case("NEXT") => Command::Next,
+        [command] if command.eq_ignore_ascii_case("PEEK") => Command::Peek,
+        [command, item_id] if command.eq_ignore_ascii_case("SCORE") => Command::Score { item_id: item_id.to_string() },
+        [command] if command.eq_ignore_ascii_case("INFO") => Command::Info { section: None },
+        [command] if command.eq_ignore_ascii_case("HELP") => Command::Help,
+        _ => Command::Error { msg: "Invalid command or arguments".to_string() },
+    };
+    if renamed.is_some() { command } else { command.unless_hidden(names) }
+}
+
+// Renders the response to one of the original commands the way the original daemon did: items as
+// they are, without quoting, and INFO with the statistics it reported
+fn render(response: &Response, server: &Server) -> String {
+    match response {
+        Response::Ok => "+OK\r\n".to_string(),
+        Response::Nil | Response::Empty | Response::NotFound => "+-1\r\n".to_string(),
+        Response::Score(score) => format!("+{}\r\n", score),
+        Response::Item(item) | Response::ItemData { item, .. } => format!("+{}\r\n", item),
+        Response::Info(_) => {
+            let stats = server.pqueue.stats();
+            format!(
+                "+INFO\r\n+uptime:{}\r\n+version:{}\r\n+updates:{}\r\n+items:{}\r\n+pools:{}\r\n",
+                stats.uptime.num_seconds(),
+                stats.version,
+                stats.updates,
+                stats.items,
+                stats.pools,
+            )
+        },
+        Response::Help => HELP.to_string(),
+        Response::Error(msg) => format!("-{}\r\n", msg),
+        // None of the original commands reply with anything else
+        response => format!("-Unexpected response {:?}\r\n", response),
+    }
+}
@@ -0,0 +1,145 @@
+// The configuration file given with --config. Each line sets one of the command line options by its
+// long name, followed by its value, or alone for an on/off option:
+//
+//   # name              value
+//   port                8002
+//   requirepass         s3cret
+//   max-line-length     131072
+//   disconnect-oversized
+//
+// Blank lines and lines starting with # are ignored. Options given on the command line win over the
+// file; options that may be repeated, like bind, add to those on the command line.
+//
+// On SIGHUP the file is read again and the settings that can change while running are applied: the
+// log level, the request limits, the password, the ACL file, the client limit, the idle timeout and
+// the save interval. Everything else only takes effect on a restart.
+//
+// A few of those can be looked at and changed with CONFIG GET and CONFIG SET as well, see SETTINGS. A
+// change made that way lasts until the next restart or SIGHUP.
+
+use std::ffi::OsString;
+use std::path::Path;
+use std::sync::atomic::Ordering;
+
+use clap::{error::ErrorKind, ArgMatches, Command};
+
+use crate::{glob, Server, LOG_LEVELS};
+
+// The settings CONFIG works with, named like their command line options
+const SETTINGS: &[&str] = &["log-level", "idle-timeout", "max-clients", "save-interval"];
+
+/// Parses the command line along with the --config file it names, if any, exiting on errors like
+/// clap does
+pub fn matches(cli: fn() -> Command) -> ArgMatches {
+    let matches = cli().get_matches();
+    let Some(path) = matches.get_one::<String>("config") else {
+        return matches;
+    };
+    let options = read(path.as_ref()).unwrap_or_else(|e| cli().error(ErrorKind::Io, e).exit());
+    cli().get_matches_from(args(options))
+}
+
+/// Parses the command line and the --config file again for a reload, returning errors rather than
+/// exiting
+pub fn reload(cli: fn() -> Command) -> Result<ArgMatches, String> {
+    let matches = cli().try_get_matches().map_err(|e| e.to_string())?;
+    let options = matches.get_one::<String>("config").map(|path| read(path.as_ref())).transpose()?.unwrap_or_default();
+    cli().try_get_matches_from(args(options)).map_err(|e| e.to_string())
+}
+
+// The command line with the file's options inserted before the user's own, so they override the file
+fn args(options: Vec<String>) -> Vec<OsString> {
+    let mut args = std::env::args_os();
+    args.next().into_iter().chain(options.into_iter().map(OsString::from)).chain(args).collect()
+}
+
+fn read(path: &Path) -> Result<Vec<String>, String> {
+    let contents = std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    parse(&contents).map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+// Turns the lines of the file into command line arguments
+fn parse(contents: &str) -> Result<Vec<String>, String> {
+    let mut options = Vec::new();
+    for (number, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (name, value) = line.split_once(char::is_whitespace).map_or((line, ""), |(name, value)| (name, value.trim()));
+        if name.starts_with('-') || name == "config" {
+            return Err(format!("line {}: invalid option {}", number + 1, name));
+        }
+        // Joined with = so values starting with a dash aren't taken for options
+        options.push(if value.is_empty() { format!("--{}", name) } else { format!("--{}={}", name, value) });
+    }
+    Ok(options)
+}
+
+/// The settings matching the glob pattern, as "<name> <value>" lines
+pub fn get(server: &Server, pattern: &str) -> Vec<String> {
+    let config = &server.config;
+    SETTINGS.iter()
+        .filter(|name| glob::matches(pattern, name))
+        .map(|&name| {
+            let value = match name {
+                "log-level" => config.log_level.read().unwrap().clone(),
+                "idle-timeout" => config.idle_timeout.load(Ordering::Relaxed).to_string(),
+                "max-clients" => config.max_clients().unwrap_or(0).to_string(),
+                _ => config.save_interval.load(Ordering::Relaxed).to_string(),
+            };
+            format!("{} {}", name, value)
+        })
+        .collect()
+}
+
+/// Changes the named setting
+pub fn set(server: &Server, name: &str, value: &str) -> Result<(), String> {
+    let config = &server.config;
+    let number = || value.parse::<u64>().map_err(|_| format!("Invalid value for {}, expected a number", name));
+    match name.to_ascii_lowercase().as_str() {
+        "log-level" if LOG_LEVELS.contains(&value) => config.set_log_level(value),
+        "log-level" => return Err(format!("Invalid value for log-level, expected one of {}", LOG_LEVELS.join(", "))),
+        "idle-timeout" => config.idle_timeout.store(number()?, Ordering::Relaxed),
+        "max-clients" => *config.max_clients.write().unwrap() = Some(number()? as usize).filter(|&max_clients| max_clients > 0),
+        "save-interval" => {
+            config.save_interval.store(number()?, Ordering::Relaxed);
+            // The save task waits out the new interval
+            server.reloaded.notify_waiters();
+        },
+        _ => return Err(format!("Unknown setting {}, expected one of {}", name, SETTINGS.join(", "))),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let contents = "# name  value\n\n  port   8003  \nrequirepass s3cret word\n\t# indented comment\ndisconnect-oversized\n";
+        assert_eq!(parse(contents), Ok(vec![
+            "--port=8003".to_string(),
+            "--requirepass=s3cret word".to_string(),
+            "--disconnect-oversized".to_string(),
+        ]));
+        assert_eq!(parse(""), Ok(vec![]));
+    }
+
+    #[test]
+    fn test_parse_dash_value() {
+        let options = parse("requirepass -s3cret\ndisconnect-oversized").unwrap();
+        assert_eq!(options[0], "--requirepass=-s3cret");
+        let matches = crate::cli().try_get_matches_from(std::iter::once("pqueue_server".to_string()).chain(options)).unwrap();
+        assert_eq!(matches.get_one::<String>("requirepass").map(String::as_str), Some("-s3cret"));
+        assert!(matches.get_flag("disconnect-oversized"));
+    }
+
+    #[test]
+    fn test_parse_rejected() {
+        assert_eq!(parse("port 8002\nconfig other.conf"), Err("line 2: invalid option config".to_string()));
+        assert_eq!(parse("--port 8002"), Err("line 1: invalid option --port".to_string()));
+        assert_eq!(parse("-p 8002"), Err("line 1: invalid option -p".to_string()));
+    }
+}
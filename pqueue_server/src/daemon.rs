@@ -0,0 +1,65 @@
+// Running as a classic daemon, for init systems that expect the server to detach and leave a PID file
+
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Detaches the process from its terminal: forks twice around a new session so the daemon can never
+/// reacquire a terminal, then points stdin at /dev/null and stdout and stderr at log_file (or
+/// /dev/null). Must run before any threads are started, so before the tokio runtime is built.
+#[cfg(unix)]
+pub fn daemonize(log_file: Option<&Path>) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    let stdin = File::open("/dev/null")?;
+    let output = match log_file {
+        Some(path) => OpenOptions::new().create(true).append(true).open(path)?,
+        None => OpenOptions::new().write(true).open("/dev/null")?,
+    };
+    // SAFETY: the process is still single threaded, so forking can't leave another thread's locks held
+    unsafe {
+        fork_and_exit_parent()?;
+        if libc::setsid() == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        fork_and_exit_parent()?;
+        for (from, to) in [(stdin.as_raw_fd(), libc::STDIN_FILENO), (output.as_raw_fd(), libc::STDOUT_FILENO), (output.as_raw_fd(), libc::STDERR_FILENO)] {
+            if libc::dup2(from, to) == -1 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn daemonize(_log_file: Option<&Path>) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "daemonizing is only supported on Unix"))
+}
+
+#[cfg(unix)]
+unsafe fn fork_and_exit_parent() -> io::Result<()> {
+    match libc::fork() {
+        -1 => Err(io::Error::last_os_error()),
+        0 => Ok(()),
+        _ => libc::_exit(0),
+    }
+}
+
+/// Holds the process's id in a file until dropped
+pub struct PidFile {
+    path: PathBuf,
+}
+
+impl PidFile {
+    pub fn create(path: &Path) -> io::Result<Self> {
+        fs::write(path, format!("{}\n", std::process::id()))?;
+        Ok(Self { path: path.to_path_buf() })
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
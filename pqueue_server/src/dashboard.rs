@@ -0,0 +1,52 @@
+// The web admin dashboard, served along with the HTTP API when the server runs with --dashboard. The
+// page is static and public; it asks for the password when the server requires one and sends it with
+// the requests it polls the API with, which are authenticated like any other.
+
+use std::sync::atomic::Ordering;
+
+use serde_json::{json, Value};
+
+use crate::protocol::stats_json;
+use crate::Server;
+
+/// The dashboard's page, served at /dashboard
+pub const PAGE: &str = include_str!("dashboard.html");
+
+// Items listed as the top of the queue
+const TOP_ITEMS: usize = 20;
+
+// Buckets the score histogram splits the range of scores into
+const HISTOGRAM_BUCKETS: i128 = 20;
+
+/// What the dashboard shows: the queue's stats, its top items, how its items spread over scores and
+/// the connected clients
+pub fn data(server: &Server) -> Value {
+    json!({
+        "stats": stats_json(&server.pqueue.stats()),
+        "read_only": server.read_only.load(Ordering::Relaxed),
+        "connected_clients": server.metrics.connected_clients(),
+        "top_items": server.pqueue.peek_n(TOP_ITEMS).into_iter()
+            .map(|(item, score)| json!({ "item": item, "score": score }))
+            .collect::<Vec<_>>(),
+        "histogram": histogram(&server.pqueue.pools()),
+        "clients": server.clients.list(),
+    })
+}
+
+// Spreads the items of the pools (highest score first) over buckets of equal width
+fn histogram(pools: &[(i64, usize)]) -> Vec<Value> {
+    let (Some(&(max, _)), Some(&(min, _))) = (pools.first(), pools.last()) else {
+        return Vec::new();
+    };
+    // i128 as the range of scores can outgrow an i64
+    let (min, max) = (min as i128, max as i128);
+    let width = ((max - min + 1) + HISTOGRAM_BUCKETS - 1) / HISTOGRAM_BUCKETS;
+    let mut counts = vec![0; ((max - min) / width + 1) as usize];
+    for &(score, items) in pools {
+        counts[((score as i128 - min) / width) as usize] += items;
+    }
+    counts.into_iter().enumerate().map(|(bucket, count)| {
+        let low = min + bucket as i128 * width;
+        json!({ "min": low as i64, "max": (low + width - 1).min(max) as i64, "count": count })
+    }).collect()
+}
@@ -0,0 +1,81 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use tokio::sync::Notify;
+use tokio::time::Instant;
+use tracing::warn;
+
+use pqueue::PQueue;
+
+/// Items added with DELAY, held back until they are due and then added to the queue as UPDATE would
+#[derive(Default)]
+pub struct Delayed {
+    items: Mutex<DelayedItems>,
+    // Signalled when an item is delayed, in case it is due before the ones waited for
+    changed: Notify,
+}
+
+#[derive(Default)]
+struct DelayedItems {
+    // Items with their scores, by when they are due and the order they were delayed in
+    due: BTreeMap<(Instant, u64), (String, i64)>,
+    // When each item is due, so delaying an item again replaces its earlier delay
+    keys: HashMap<String, (Instant, u64)>,
+    next_seq: u64,
+}
+
+impl Delayed {
+    /// Adds the item with score once delay has passed, replacing any delay the item already had.
+    /// Returns false, leaving the item as it was, if delay is too long to tell when that is.
+    pub fn delay(&self, item: String, score: i64, delay: Duration) -> bool {
+        let Some(due) = Instant::now().checked_add(delay) else {
+            return false;
+        };
+        let mut items = self.items.lock().unwrap();
+        let key = (due, items.next_seq);
+        items.next_seq += 1;
+        if let Some(previous) = items.keys.insert(item.clone(), key) {
+            items.due.remove(&previous);
+        }
+        items.due.insert(key, (item, score));
+        drop(items);
+        self.changed.notify_one();
+        true
+    }
+
+    /// The number of items waiting to become due
+    pub fn len(&self) -> usize {
+        self.items.lock().unwrap().due.len()
+    }
+
+    /// Adds items to pqueue as they become due, forever
+    pub async fn run(&self, pqueue: &PQueue<String>) {
+        loop {
+            let next_due = {
+                let mut items = self.items.lock().unwrap();
+                let now = Instant::now();
+                while let Some(entry) = items.due.first_entry() {
+                    if entry.key().0 > now {
+                        break;
+                    }
+                    let (item, score) = entry.remove();
+                    items.keys.remove(&item);
+                    if let Err(e) = pqueue.update(item, score) {
+                        warn!("Failed to add a delayed item: {}", e);
+                    }
+                }
+                items.due.keys().next().map(|&(due, _)| due)
+            };
+            match next_due {
+                Some(due) => {
+                    tokio::select! {
+                        _ = tokio::time::sleep_until(due) => {},
+                        _ = self.changed.notified() => {},
+                    }
+                },
+                None => self.changed.notified().await,
+            }
+        }
+    }
+}
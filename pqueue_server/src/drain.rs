@@ -0,0 +1,63 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use tokio::sync::Notify;
+use tracing::{error, info};
+
+use crate::{snapshot, Server};
+
+// How often a drain that ends in shutting down checks whether the queue has emptied
+const CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Draining, for decommissioning a server: new items are refused while consumers keep popping until
+/// the queue is empty, after which the server may shut down
+#[derive(Default)]
+pub struct Drain {
+    draining: AtomicBool,
+    // Whether the server shuts down once drained
+    exit: AtomicBool,
+    // Signalled when a drain that ends in shutting down starts
+    started: Notify,
+}
+
+impl Drain {
+    pub fn start(&self, exit: bool) {
+        self.draining.store(true, Ordering::Relaxed);
+        self.exit.store(exit, Ordering::Relaxed);
+        if exit {
+            self.started.notify_one();
+        }
+    }
+
+    pub fn stop(&self) {
+        self.draining.store(false, Ordering::Relaxed);
+        self.exit.store(false, Ordering::Relaxed);
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::Relaxed)
+    }
+
+    /// Shuts the server down once a drain asked to exit has emptied the queue, with no item reserved
+    /// or delayed left to come back into it
+    pub async fn run(&self, server: &Server) {
+        loop {
+            if !self.exit.load(Ordering::Relaxed) {
+                self.started.notified().await;
+                continue;
+            }
+            if server.pqueue.stats().items == 0 && server.leases.len() == 0 && server.delayed.len() == 0 {
+                info!("Drained, shutting down");
+                // An empty snapshot, so a restart doesn't bring back the drained items
+                if server.config.data_dir.is_some() {
+                    if let Err(e) = snapshot::save(server).await {
+                        error!("Failed to save a snapshot: {}", e);
+                    }
+                }
+                server.shutdown.notify_one();
+                return;
+            }
+            tokio::time::sleep(CHECK_INTERVAL).await;
+        }
+    }
+}
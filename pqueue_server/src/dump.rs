@@ -0,0 +1,66 @@
+// DUMP and RESTORE, moving single items between servers. An item is dumped as a compact JSON object
+// holding everything about it but its id: its score, when it was inserted and last updated, when it
+// expires and the data attached to it, with times in milliseconds since the Unix epoch.
+
+use chrono::NaiveDateTime;
+use serde::{Deserialize, Serialize};
+
+use pqueue::ItemInfo;
+
+use crate::Server;
+
+// Raised whenever the format changes, so older dumps can still be told apart
+const DUMP_VERSION: u32 = 1;
+
+#[derive(Deserialize, Serialize)]
+struct Dump {
+    version: u32,
+    score: i64,
+    inserted_at: i64,
+    last_updated: i64,
+    expires_at: Option<i64>,
+    data: Option<String>,
+}
+
+/// Serializes the item, or None if it is not in the queue
+pub fn dump(server: &Server, item: &str) -> Option<String> {
+    let info = server.pqueue.item_info(item)?;
+    let dump = Dump {
+        version: DUMP_VERSION,
+        score: info.score,
+        inserted_at: info.inserted_at.and_utc().timestamp_millis(),
+        last_updated: info.last_updated.and_utc().timestamp_millis(),
+        expires_at: info.expires_at.map(|expires_at| expires_at.and_utc().timestamp_millis()),
+        data: server.payloads.get(item),
+    };
+    Some(serde_json::to_string(&dump).unwrap())
+}
+
+/// Recreates an item from its dump, failing if the item is already in the queue unless replace is set
+pub fn restore(server: &Server, item: String, dump: &str, replace: bool) -> Result<(), String> {
+    let dump: Dump = serde_json::from_str(dump).map_err(|_| "Invalid DUMP payload".to_string())?;
+    if dump.version != DUMP_VERSION {
+        return Err(format!("Unsupported DUMP version {}", dump.version));
+    }
+    let info = ItemInfo {
+        score: dump.score,
+        inserted_at: from_millis(dump.inserted_at)?,
+        last_updated: from_millis(dump.last_updated)?,
+        expires_at: dump.expires_at.map(from_millis).transpose()?,
+    };
+    if !replace && server.pqueue.contains(item.as_str()) {
+        return Err("Item already exists, RESTORE with REPLACE to overwrite it".to_string());
+    }
+    match dump.data {
+        Some(data) => server.payloads.set(item.clone(), data),
+        None => {
+            server.payloads.take(&item);
+        },
+    }
+    server.pqueue.restore(item, info);
+    Ok(())
+}
+
+fn from_millis(millis: i64) -> Result<NaiveDateTime, String> {
+    NaiveDateTime::from_timestamp_millis(millis).ok_or_else(|| "Invalid DUMP payload".to_string())
+}
@@ -0,0 +1,68 @@
+// Glob-style patterns, as taken by SCAN's MATCH:
+//
+//   *       any run of characters, including none
+//   ?       any one character
+//   [abc]   one of the characters listed, which may include ranges like [a-z]
+//   [^abc]  any one character but those listed
+//   \x      the character x itself, for matching a literal *, ?, [ or \
+
+/// Whether the pattern matches the whole of text
+pub fn matches(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    // Where to resume after the last *: the pattern past it, and the text it has consumed up to
+    let mut backtrack = None;
+    while t < text.len() {
+        if pattern.get(p) == Some(&'*') {
+            p += 1;
+            backtrack = Some((p, t));
+            continue;
+        }
+        if let Some(len) = match_one(&pattern[p..], text[t]) {
+            p += len;
+            t += 1;
+            continue;
+        }
+        // Let the last * swallow one more character, or give up if there wasn't one
+        let Some((star_p, star_t)) = backtrack else {
+            return false;
+        };
+        p = star_p;
+        t = star_t + 1;
+        backtrack = Some((star_p, star_t + 1));
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+// Matches c against the element at the start of the pattern, returning the element's length
+fn match_one(pattern: &[char], c: char) -> Option<usize> {
+    match pattern {
+        ['?', ..] => Some(1),
+        ['\\', escaped, ..] => (*escaped == c).then_some(2),
+        ['[', rest @ ..] => {
+            let (negated, rest) = match rest {
+                ['^', rest @ ..] => (true, rest),
+                rest => (false, rest),
+            };
+            // An unclosed [ is taken literally
+            let Some(end) = rest.iter().skip(1).position(|&c| c == ']').map(|end| end + 1) else {
+                return (c == '[').then_some(1);
+            };
+            let mut found = false;
+            let mut i = 0;
+            while i < end {
+                if i + 2 < end && rest[i + 1] == '-' {
+                    found |= (rest[i]..=rest[i + 2]).contains(&c);
+                    i += 3;
+                } else {
+                    found |= rest[i] == c;
+                    i += 1;
+                }
+            }
+            (found != negated).then_some(pattern.len() - rest.len() + end + 1)
+        },
+        [literal, ..] => (*literal == c).then_some(1),
+        [] => None,
+    }
+}
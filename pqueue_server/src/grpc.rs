@@ -0,0 +1,180 @@
+use std::sync::Arc;
+
+use tokio::{net::TcpListener, sync::mpsc};
+use tokio_stream::wrappers::{ReceiverStream, TcpListenerStream};
+use tonic::{service::Interceptor, Request, Response, Status};
+
+use pqueue::PQueue;
+
+use crate::acl::{self, Access};
+use crate::Server;
+
+pub mod proto {
+    tonic::include_proto!("pqueue");
+}
+
+use proto::p_queue_server::{PQueue as PQueueService, PQueueServer};
+use proto::*;
+
+struct GrpcService {
+    pqueue: PQueue<String>,
+    server: Arc<Server>,
+}
+
+impl GrpcService {
+    // The status failing a call for an item owned by another cluster node
+    fn misdirected(&self, item: &str) -> Option<Status> {
+        let redirect = self.server.config.cluster.as_ref()?.redirect(item)?;
+        Some(Status::failed_precondition(redirect))
+    }
+}
+
+/// Serves the gRPC service defined in proto/pqueue.proto on listener. When the server requires a
+/// password, calls must carry it as `authorization: Bearer <password>` metadata, or
+/// `Bearer <user>:<password>` for a user of the ACL file, who may only make the calls of the commands
+/// they may run (PERMISSION_DENIED otherwise). In cluster mode, calls
+/// for an item owned by another node fail with FAILED_PRECONDITION and a "MOVED <index> <address>" message.
+/// While the server is read only, Update, Next and Consume fail with FAILED_PRECONDITION.
+pub async fn serve(listener: TcpListener, server: Arc<Server>) -> Result<(), tonic::transport::Error> {
+    let authenticator = Authenticator { server: server.clone() };
+    let service = PQueueServer::with_interceptor(GrpcService { pqueue: server.pqueue.clone(), server: server.clone() }, authenticator);
+    tonic::transport::Server::builder()
+        .add_service(service)
+        .serve_with_incoming(TcpListenerStream::new(listener))
+        .await
+}
+
+// Rejects calls without valid authorization metadata when the server requires it, and passes on what
+// the caller may run as a request extension
+#[derive(Clone)]
+struct Authenticator {
+    server: Arc<Server>,
+}
+
+impl Interceptor for Authenticator {
+    fn call(&mut self, mut request: Request<()>) -> Result<Request<()>, Status> {
+        let token = request.metadata()
+            .get("authorization")
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+        let access = self.server.config.authenticate_token(token).map_err(Status::unauthenticated)?;
+        request.extensions_mut().insert(access);
+        Ok(request)
+    }
+}
+
+// The status failing a call of a user who may not run the command behind it
+fn forbidden<R>(request: &Request<R>, command: &str) -> Option<Status> {
+    let access = request.extensions().get::<Access>()?;
+    (!acl::allows(access, command)).then(|| Status::permission_denied(format!("No permission to run {}", command)))
+}
+
+// The status failing a call changing the queue while the server is read only, or adding items while
+// it drains
+fn refused(server: &Server, command: &str) -> Option<Status> {
+    server.refuses(command).map(Status::failed_precondition)
+}
+
+#[tonic::async_trait]
+impl PQueueService for GrpcService {
+    async fn update(&self, request: Request<UpdateRequest>) -> Result<Response<UpdateReply>, Status> {
+        if let Some(status) = forbidden(&request, "UPDATE").or_else(|| refused(&self.server, "UPDATE")) {
+            return Err(status);
+        }
+        let UpdateRequest { item, delta } = request.into_inner();
+        if let Some(status) = self.misdirected(&item) {
+            return Err(status);
+        }
+        match self.pqueue.update(item, delta) {
+            Ok((previous_score, score)) => Ok(Response::new(UpdateReply { previous_score, score })),
+            Err(e) => Err(Status::out_of_range(e.to_string())),
+        }
+    }
+
+    async fn next(&self, request: Request<NextRequest>) -> Result<Response<EntryReply>, Status> {
+        if let Some(status) = forbidden(&request, "NEXT").or_else(|| refused(&self.server, "NEXT")) {
+            return Err(status);
+        }
+        let entry = self.pqueue.next_with_score().map(|(item, score)| {
+            let data = self.server.payloads.take(&item);
+            Entry { item, score, data }
+        });
+        Ok(Response::new(EntryReply { entry }))
+    }
+
+    async fn peek(&self, request: Request<PeekRequest>) -> Result<Response<EntryReply>, Status> {
+        if let Some(status) = forbidden(&request, "PEEK") {
+            return Err(status);
+        }
+        let entry = self.pqueue.peek_with_score().map(|(item, score)| {
+            let data = self.server.payloads.get(&item);
+            Entry { item, score, data }
+        });
+        Ok(Response::new(EntryReply { entry }))
+    }
+
+    async fn score(&self, request: Request<ScoreRequest>) -> Result<Response<ScoreReply>, Status> {
+        if let Some(status) = forbidden(&request, "SCORE") {
+            return Err(status);
+        }
+        let item = request.into_inner().item;
+        if let Some(status) = self.misdirected(&item) {
+            return Err(status);
+        }
+        let score = self.pqueue.score(&item);
+        Ok(Response::new(ScoreReply { score }))
+    }
+
+    async fn info(&self, request: Request<InfoRequest>) -> Result<Response<InfoReply>, Status> {
+        if let Some(status) = forbidden(&request, "INFO") {
+            return Err(status);
+        }
+        let stats = self.pqueue.stats();
+        Ok(Response::new(InfoReply {
+            uptime: stats.uptime.num_seconds(),
+            version: stats.version,
+            updates: stats.updates,
+            items: stats.items,
+            pools: stats.pools,
+            enqueue_rate: Some(rates(&stats.enqueue_rate)),
+            dequeue_rate: Some(rates(&stats.dequeue_rate)),
+            oldest_item_age: stats.oldest_item_age.map_or(0, |age| age.num_seconds()),
+        }))
+    }
+
+    type ConsumeStream = ReceiverStream<Result<Entry, Status>>;
+
+    async fn consume(&self, request: Request<ConsumeRequest>) -> Result<Response<Self::ConsumeStream>, Status> {
+        if let Some(status) = forbidden(&request, "CONSUME").or_else(|| refused(&self.server, "CONSUME")) {
+            return Err(status);
+        }
+        let (tx, rx) = mpsc::channel(1);
+        let pqueue = self.pqueue.clone();
+        let server = self.server.clone();
+        tokio::spawn(async move {
+            loop {
+                // Only pop an item once there is room to send it, and stop waiting as soon as the
+                // client goes away so no item is popped for a closed stream
+                let Ok(permit) = tx.reserve().await else {
+                    return;
+                };
+                let (item, score) = tokio::select! {
+                    entry = pqueue.next_with_score_async() => entry,
+                    _ = tx.closed() => return,
+                };
+                let data = server.payloads.take(&item);
+                permit.send(Ok(Entry { item, score, data }));
+            }
+        });
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+}
+
+
+fn rates(rates: &pqueue::Rates) -> Rates {
+    Rates {
+        last_1s: rates.last_1s,
+        last_1m: rates.last_1m,
+        last_5m: rates.last_5m,
+    }
+}
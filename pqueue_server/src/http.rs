@@ -0,0 +1,319 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use axum::{
+    extract::{ws::{Message, WebSocket, WebSocketUpgrade}, ConnectInfo, Path, Query, Request, State},
+    http::{header, StatusCode},
+    middleware::{self, Next},
+    response::{Html, IntoResponse, Response},
+    routing::{get, post},
+    Extension, Json, Router,
+};
+use serde::Deserialize;
+use serde_json::json;
+use tokio::net::TcpListener;
+use tracing::{debug, warn};
+use uuid::Uuid;
+
+use crate::acl::{self, Access};
+use crate::{dashboard, snapshot};
+use crate::clients::Client;
+use crate::protocol::{stats_json, Command, Protocol, Response as ProtocolResponse};
+use crate::{execute, Server, Session, MAX_CLIENTS_ERROR};
+
+type HttpState = Arc<Server>;
+
+#[derive(Deserialize)]
+struct ScoreUpdate {
+    delta: i64,
+}
+
+#[derive(Deserialize)]
+struct WebSocketParams {
+    #[serde(default)]
+    notify: bool,
+}
+
+/// Serves the REST API on listener:
+///
+/// POST /items/{id}/score  Adds the "delta" from the JSON body to the item's score, inserting it if needed
+/// GET  /items/{id}/score  Fetches the item's score
+/// GET  /next              Pops the highest priority item
+/// GET  /peek              Returns the highest priority item without removing it
+/// GET  /stats             Fetches statistics about the server
+/// GET  /ws                WebSocket carrying the TCP protocol, see `handle_websocket`
+/// GET  /healthz           Health check for load balancers, needing no password: {"status": "ok"}, or
+///                         503 Service Unavailable with {"status": "draining"} while the server drains
+///
+/// Items are returned as {"item": <id>, "score": <score>}; an empty queue returns 204 No Content. When the
+/// server requires a password, requests must carry it as an `Authorization: Bearer <password>` header,
+/// or `Bearer <user>:<password>` for a user of the ACL file, who may only use the endpoints of the
+/// commands they may run (403 Forbidden otherwise). WebSockets (which browsers can't add headers to)
+/// authenticate with AUTH instead. In
+/// cluster mode, requests for an item owned by another node get 421 Misdirected Request with a
+/// "MOVED <index> <address>" error.
+/// While the server is read only, updates and pops get 503 Service Unavailable.
+///
+/// With --dashboard, the web admin dashboard is served at /dashboard too, see the dashboard module:
+///
+/// GET  /dashboard/data    Fetches what the dashboard shows (requires INFO)
+/// POST /dashboard/save    Saves a snapshot (requires SAVE)
+/// POST /dashboard/clear   Removes every item (requires CLEAR)
+pub async fn serve(listener: TcpListener, state: Arc<Server>) -> std::io::Result<()> {
+    let mut api = Router::new()
+        .route("/items/:id/score", post(update_score).get(get_score))
+        .route("/next", get(next))
+        .route("/peek", get(peek))
+        .route("/stats", get(stats));
+    if state.config.dashboard {
+        api = api
+            .route("/dashboard/data", get(dashboard_data))
+            .route("/dashboard/save", post(dashboard_save))
+            .route("/dashboard/clear", post(dashboard_clear));
+    }
+    let mut app = api
+        .route_layer(middleware::from_fn_with_state(state.clone(), authenticate))
+        .route("/ws", get(websocket))
+        .route("/healthz", get(healthz));
+    if state.config.dashboard {
+        app = app.route("/dashboard", get(|| async { Html(dashboard::PAGE) }));
+    }
+    let app = app.with_state(state);
+    axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>()).await
+}
+
+async fn authenticate(State(state): State<HttpState>, mut request: Request, next: Next) -> Response {
+    let token = request.headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    match state.config.authenticate_token(token) {
+        Ok(access) => {
+            request.extensions_mut().insert(access);
+            next.run(request).await
+        },
+        Err(e) => error(StatusCode::UNAUTHORIZED, &e),
+    }
+}
+
+// Rejects requests of users who may not run the command behind the endpoint
+fn forbidden(access: &Access, command: &str) -> Option<Response> {
+    (!acl::allows(access, command)).then(|| error(StatusCode::FORBIDDEN, &format!("No permission to run {}", command)))
+}
+
+// Rejects requests changing the queue while the server is read only, or adding items while it drains
+fn refused(state: &HttpState, command: &str) -> Option<Response> {
+    state.refuses(command).map(|msg| error(StatusCode::SERVICE_UNAVAILABLE, msg))
+}
+
+async fn healthz(State(state): State<HttpState>) -> Response {
+    if state.drain.is_draining() {
+        return (StatusCode::SERVICE_UNAVAILABLE, Json(json!({ "status": "draining" }))).into_response();
+    }
+    Json(json!({ "status": "ok" })).into_response()
+}
+
+async fn update_score(State(state): State<HttpState>, Extension(access): Extension<Access>, Path(id): Path<String>, Json(update): Json<ScoreUpdate>) -> Response {
+    if let Some(forbidden) = forbidden(&access, "UPDATE").or_else(|| refused(&state, "UPDATE")) {
+        return forbidden;
+    }
+    if let Some(redirect) = redirect(&state, &id) {
+        return redirect;
+    }
+    match state.pqueue.update(id.clone(), update.delta) {
+        Ok((_, score)) => Json(json!({ "item": id, "score": score })).into_response(),
+        Err(e) => error(StatusCode::UNPROCESSABLE_ENTITY, &e.to_string()),
+    }
+}
+
+async fn get_score(State(state): State<HttpState>, Extension(access): Extension<Access>, Path(id): Path<String>) -> Response {
+    if let Some(forbidden) = forbidden(&access, "SCORE") {
+        return forbidden;
+    }
+    if let Some(redirect) = redirect(&state, &id) {
+        return redirect;
+    }
+    match state.pqueue.score(&id) {
+        Some(score) => Json(json!({ "item": id, "score": score })).into_response(),
+        None => error(StatusCode::NOT_FOUND, "Item not found"),
+    }
+}
+
+async fn next(State(state): State<HttpState>, Extension(access): Extension<Access>) -> Response {
+    if let Some(forbidden) = forbidden(&access, "NEXT").or_else(|| refused(&state, "NEXT")) {
+        return forbidden;
+    }
+    let entry = state.pqueue.next_with_score();
+    let data = entry.as_ref().and_then(|(item, _)| state.payloads.take(item));
+    item_response(entry, data)
+}
+
+async fn peek(State(state): State<HttpState>, Extension(access): Extension<Access>) -> Response {
+    if let Some(forbidden) = forbidden(&access, "PEEK") {
+        return forbidden;
+    }
+    let entry = state.pqueue.peek_with_score();
+    let data = entry.as_ref().and_then(|(item, _)| state.payloads.get(item));
+    item_response(entry, data)
+}
+
+async fn stats(State(state): State<HttpState>, Extension(access): Extension<Access>) -> Response {
+    if let Some(forbidden) = forbidden(&access, "INFO") {
+        return forbidden;
+    }
+    Json(stats_json(&state.pqueue.stats())).into_response()
+}
+
+async fn dashboard_data(State(state): State<HttpState>, Extension(access): Extension<Access>) -> Response {
+    if let Some(forbidden) = forbidden(&access, "INFO") {
+        return forbidden;
+    }
+    Json(dashboard::data(&state)).into_response()
+}
+
+async fn dashboard_save(State(state): State<HttpState>, Extension(access): Extension<Access>) -> Response {
+    if let Some(forbidden) = forbidden(&access, "SAVE") {
+        return forbidden;
+    }
+    match snapshot::save(&state).await {
+        Ok(saved) => Json(json!({ "count": saved })).into_response(),
+        Err(e) => error(StatusCode::INTERNAL_SERVER_ERROR, &e.to_string()),
+    }
+}
+
+async fn dashboard_clear(State(state): State<HttpState>, Extension(access): Extension<Access>) -> Response {
+    if let Some(forbidden) = forbidden(&access, "CLEAR").or_else(|| refused(&state, "CLEAR")) {
+        return forbidden;
+    }
+    state.payloads.clear();
+    Json(json!({ "count": state.pqueue.clear() })).into_response()
+}
+
+async fn websocket(
+    State(state): State<HttpState>,
+    ConnectInfo(address): ConnectInfo<SocketAddr>,
+    Query(params): Query<WebSocketParams>,
+    upgrade: WebSocketUpgrade,
+) -> Response {
+    upgrade.on_upgrade(move |socket| handle_websocket(socket, address, state, params.notify, Uuid::new_v4()))
+}
+
+// Each text message is a command of the TCP protocol, answered with a message holding the reply the
+// TCP protocol would give. Connecting with ?notify=true subscribes to updates like SUBSCRIBE updates,
+// pushing a ">updated <item> <score>" message whenever an item is added or rescored (once authenticated).
+#[tracing::instrument(name = "websocket", skip_all, fields(client_id = %id))]
+async fn handle_websocket(mut socket: WebSocket, address: SocketAddr, state: HttpState, notify: bool, id: Uuid) {
+    let mut session = Session::new(&state.config);
+    let Some(_client) = state.metrics.client_connected(state.config.max_clients()) else {
+        warn!("refusing client, max clients reached");
+        let error = session.protocol.render(&ProtocolResponse::Error(MAX_CLIENTS_ERROR.to_string()));
+        let _ = socket.send(Message::Text(String::from_utf8_lossy(&error).into_owned())).await;
+        return;
+    };
+    let client = state.clients.register(id, address.to_string(), "ws");
+    session.client = Some(client.clone());
+    debug!("client connected");
+    if notify {
+        session.events = Some(state.pqueue.subscribe());
+    }
+    // A message received while a blocking command waited, served next
+    let mut received = None;
+    loop {
+        let limits = state.config.limits();
+        let mut oversized = false;
+        let next_message = async {
+            match received.take() {
+                Some(message) => Some(Ok(message)),
+                None => socket.recv().await,
+            }
+        };
+        let response = tokio::select! {
+            message = next_message => match message {
+                Some(Ok(Message::Text(text))) if text.len() > limits.max_request(session.protocol) => {
+                    oversized = true;
+                    limits.too_long(session.protocol)
+                },
+                Some(Ok(Message::Binary(frame))) if frame.len().saturating_sub(4) > limits.max_request(session.protocol) => {
+                    oversized = true;
+                    limits.too_long(session.protocol)
+                },
+                Some(Ok(Message::Text(text))) => {
+                    let command = session.protocol.parse(text.trim_end_matches(['\r', '\n']).as_bytes(), &state.config.command_names);
+                    match execute_watched(command, &state, &mut session, &mut socket, &mut received, &client).await {
+                        Some(response) => response,
+                        None => return,
+                    }
+                },
+                // Binary messages carry a binary protocol frame, length included
+                Some(Ok(Message::Binary(frame))) => {
+                    let command = session.protocol.parse(frame.get(4..).unwrap_or_default(), &state.config.command_names);
+                    match execute_watched(command, &state, &mut session, &mut socket, &mut received, &client).await {
+                        Some(response) => response,
+                        None => return,
+                    }
+                },
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => return,
+                // Pings are answered by axum
+                Some(Ok(_)) => continue,
+            },
+            push = session.next_push(&state) => push,
+            _ = client.killed() => return,
+        };
+        let message = match session.protocol {
+            Protocol::Binary => Message::Binary(session.protocol.render(&response)),
+            _ => Message::Text(String::from_utf8_lossy(&session.protocol.render(&response)).into_owned()),
+        };
+        let sent = socket.send(message).await.is_ok();
+        session.reply_sent(&state, sent);
+        if !sent || (oversized && limits.disconnect) {
+            return;
+        }
+    }
+}
+
+// Runs a command, and for a blocking one keeps watching the socket while it waits, giving up on it
+// should the client hang up or be killed, which returns None. The first message received meanwhile is
+// kept in received, and reading further messages waits for the command.
+async fn execute_watched(command: Command, state: &HttpState, session: &mut Session, socket: &mut WebSocket, received: &mut Option<Message>, client: &Client) -> Option<ProtocolResponse> {
+    if !command.blocks() {
+        return Some(execute(command, state, session).await);
+    }
+    let hung_up = async {
+        loop {
+            match socket.recv().await {
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => return,
+                // Pings are answered by axum
+                Some(Ok(Message::Ping(_) | Message::Pong(_))) => continue,
+                Some(Ok(message)) => {
+                    *received = Some(message);
+                    return std::future::pending().await;
+                },
+            }
+        }
+    };
+    tokio::select! {
+        response = execute(command, state, session) => Some(response),
+        _ = hung_up => {
+            debug!("client disconnected");
+            None
+        },
+        _ = client.killed() => None,
+    }
+}
+
+fn item_response(entry: Option<(String, i64)>, data: Option<String>) -> Response {
+    match (entry, data) {
+        (Some((item, score)), None) => Json(json!({ "item": item, "score": score })).into_response(),
+        (Some((item, score)), Some(data)) => Json(json!({ "item": item, "score": score, "data": data })).into_response(),
+        (None, _) => StatusCode::NO_CONTENT.into_response(),
+    }
+}
+
+fn redirect(state: &HttpState, item: &str) -> Option<Response> {
+    let redirect = state.config.cluster.as_ref()?.redirect(item)?;
+    Some(error(StatusCode::MISDIRECTED_REQUEST, &redirect))
+}
+
+fn error(status: StatusCode, msg: &str) -> Response {
+    (status, Json(json!({ "error": msg }))).into_response()
+}
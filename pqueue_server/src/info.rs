@@ -0,0 +1,153 @@
+// INFO's report, in sections that can be asked for one at a time with INFO <section>
+
+use std::sync::atomic::Ordering;
+
+use serde_json::{json, Value};
+
+use pqueue::CapacityPolicy;
+
+use crate::memory::MemoryLimit;
+use crate::Server;
+
+/// Names of the sections INFO reports, in order
+pub const SECTIONS: &[&str] = &["server", "clients", "memory", "persistence", "stats", "commandstats", "latency", "queue"];
+
+/// A named group of INFO fields
+#[derive(Clone, Debug)]
+pub struct InfoSection {
+    pub name: &'static str,
+    pub fields: Vec<(&'static str, Value)>,
+}
+
+/// Builds the named section, or every section when section is None. Errors for an unknown section.
+pub fn report(server: &Server, section: Option<&str>) -> Result<Vec<InfoSection>, String> {
+    match section {
+        None => Ok(SECTIONS.iter().map(|&name| build(server, name)).collect()),
+        Some(section) if section.eq_ignore_ascii_case("all") => report(server, None),
+        Some(section) => SECTIONS.iter()
+            .find(|name| name.eq_ignore_ascii_case(section))
+            .map(|&name| vec![build(server, name)])
+            .ok_or_else(|| format!("Unknown INFO section {}, expected one of {}", section, SECTIONS.join(", "))),
+    }
+}
+
+fn build(server: &Server, name: &'static str) -> InfoSection {
+    let stats = server.pqueue.stats();
+    let fields = match name {
+        "server" => {
+            let uptime = stats.uptime.num_seconds();
+            vec![
+                ("version", json!(stats.version)),
+                ("process_id", json!(std::process::id())),
+                ("uptime_in_seconds", json!(uptime)),
+                ("uptime_human", json!(human_duration(uptime))),
+                ("cluster_enabled", json!(server.config.cluster.is_some())),
+                ("read_only", json!(server.read_only.load(Ordering::Relaxed))),
+                ("draining", json!(server.drain.is_draining())),
+            ]
+        },
+        "clients" => vec![
+            ("connected_clients", json!(server.metrics.connected_clients())),
+            ("max_clients", json!(server.config.max_clients())),
+        ],
+        "memory" => {
+            let rss = resident_memory();
+            vec![
+                ("used_memory_rss", json!(rss)),
+                ("used_memory_rss_human", json!(rss.map(human_bytes))),
+                // Estimated, with --max-memory
+                ("used_memory", json!(server.memory.as_ref().map(MemoryLimit::used))),
+                ("maxmemory", json!(server.memory.as_ref().map(|memory| memory.max_memory))),
+                ("maxmemory_human", json!(server.memory.as_ref().map(|memory| human_bytes(memory.max_memory as u64)))),
+                ("maxmemory_policy", json!(server.memory.as_ref().map(|memory| policy_name(memory.policy)))),
+            ]
+        },
+        "persistence" => {
+            let last_save = server.last_save.lock().unwrap();
+            vec![
+                ("data_dir", json!(server.config.data_dir.as_ref().map(|dir| dir.display().to_string()))),
+                ("save_in_progress", json!(server.saving.try_lock().is_err())),
+                ("last_save_time", json!(last_save.as_ref().map(|save| save.at.timestamp()))),
+                ("last_save_status", json!(last_save.as_ref().map(|save| if save.result.is_ok() { "ok" } else { "err" }))),
+                ("last_save_items", json!(last_save.as_ref().and_then(|save| save.result.as_ref().ok()))),
+            ]
+        },
+        "stats" => vec![
+            ("total_commands_processed", json!(server.metrics.commands_processed())),
+            ("updates", json!(stats.updates)),
+            ("evicted_items", json!(stats.evicted)),
+            ("rejected_items", json!(stats.rejected)),
+            ("expired_items", json!(stats.expired)),
+            ("enqueue_rate_1s", rate(stats.enqueue_rate.last_1s)),
+            ("enqueue_rate_1m", rate(stats.enqueue_rate.last_1m)),
+            ("enqueue_rate_5m", rate(stats.enqueue_rate.last_5m)),
+            ("dequeue_rate_1s", rate(stats.dequeue_rate.last_1s)),
+            ("dequeue_rate_1m", rate(stats.dequeue_rate.last_1m)),
+            ("dequeue_rate_5m", rate(stats.dequeue_rate.last_5m)),
+            ("slowlog_len", json!(server.slowlog.len())),
+        ],
+        // Calls that replied with an error count as errors, and times are in microseconds
+        "commandstats" => server.metrics.command_stats().into_iter().map(|stats| {
+            let usec = stats.total.as_micros();
+            let usec_per_call = usec as f64 / stats.calls.max(1) as f64;
+            (stats.command, json!(format!("calls={},errors={},usec={},usec_per_call={:.2}", stats.calls, stats.errors, usec, usec_per_call)))
+        }).collect(),
+        // Percentiles in microseconds, like LATENCY
+        "latency" => server.metrics.latencies().into_iter().map(|latency| {
+            (latency.command, json!(format!("p50={},p95={},p99={}", latency.p50, latency.p95, latency.p99)))
+        }).collect(),
+        _ => vec![
+            ("items", json!(stats.items)),
+            ("pools", json!(stats.pools)),
+            ("max_items", json!(server.pqueue.max_items().map(|(max_items, _)| max_items))),
+            ("top_score", json!(server.pqueue.top_score())),
+            ("oldest_item_age", json!(stats.oldest_item_age.map_or(0, |age| age.num_seconds()))),
+            ("paused", json!(server.pqueue.is_paused())),
+            ("delayed_items", json!(server.delayed.len())),
+            ("reserved_items", json!(server.leases.len())),
+        ],
+    };
+    InfoSection { name, fields }
+}
+
+// The process's resident set size in bytes, where /proc is available
+fn resident_memory() -> Option<u64> {
+    let statm = std::fs::read_to_string("/proc/self/statm").ok()?;
+    let pages: u64 = statm.split_whitespace().nth(1)?.parse().ok()?;
+    // The page size is 4KiB on the platforms with /proc/self/statm this runs on
+    Some(pages * 4096)
+}
+
+fn policy_name(policy: CapacityPolicy) -> &'static str {
+    match policy {
+        CapacityPolicy::Reject => "reject",
+        CapacityPolicy::EvictLowest => "evict-lowest",
+        CapacityPolicy::EvictOldest => "evict-oldest",
+    }
+}
+
+// Rates are reported to two decimals, as INFO always has
+fn rate(rate: f64) -> Value {
+    json!((rate * 100.0).round() / 100.0)
+}
+
+fn human_bytes(bytes: u64) -> String {
+    let mut value = bytes as f64;
+    for unit in ["B", "K", "M", "G"] {
+        if value < 1024.0 {
+            return format!("{:.2}{}", value, unit);
+        }
+        value /= 1024.0;
+    }
+    format!("{:.2}T", value)
+}
+
+fn human_duration(secs: i64) -> String {
+    let (days, hours, minutes, secs) = (secs / 86400, secs / 3600 % 24, secs / 60 % 60, secs % 60);
+    match (days, hours, minutes) {
+        (0, 0, 0) => format!("{}s", secs),
+        (0, 0, _) => format!("{}m {}s", minutes, secs),
+        (0, _, _) => format!("{}h {}m {}s", hours, minutes, secs),
+        _ => format!("{}d {}h {}m {}s", days, hours, minutes, secs),
+    }
+}
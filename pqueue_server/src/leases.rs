@@ -0,0 +1,134 @@
+use std::collections::{BTreeSet, HashMap};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use tokio::sync::Notify;
+use tokio::time::Instant;
+use uuid::Uuid;
+
+use pqueue::PQueue;
+
+use crate::payloads::Payloads;
+
+/// Items handed out with RESERVE, held until acknowledged with ACK or put back on the queue with NACK
+/// or once their lease runs out. A leased item's data is held with the lease rather than in the
+/// payloads, so it can't be mistaken for the data of the item added again meanwhile. Put back items
+/// keep their score and data, unless the item was added to the queue again in the meantime.
+#[derive(Default)]
+pub struct Leases {
+    leases: Mutex<LeasedItems>,
+    // Signalled when a lease is taken, in case it runs out before the ones waited for
+    changed: Notify,
+}
+
+#[derive(Default)]
+struct LeasedItems {
+    leases: HashMap<Uuid, Lease>,
+    // Lease tokens by when the lease runs out
+    deadlines: BTreeSet<(Instant, Uuid)>,
+}
+
+/// An item handed out with RESERVE, along with the token acknowledging it
+pub struct Reservation {
+    pub token: Uuid,
+    pub item: String,
+    pub score: i64,
+    pub data: Option<String>,
+}
+
+struct Lease {
+    item: String,
+    score: i64,
+    data: Option<String>,
+    deadline: Instant,
+}
+
+impl Lease {
+    // Puts the item back on pqueue with its data, unless it was added again meanwhile
+    fn put_back(self, pqueue: &PQueue<String>, payloads: &Payloads) {
+        if pqueue.insert_if_absent(self.item.clone(), self.score) {
+            if let Some(data) = self.data {
+                payloads.set(self.item, data);
+            }
+        }
+    }
+}
+
+impl Leases {
+    /// Pops the next item and leases it for timeout, returning the reservation, None if the queue is
+    /// empty, or an error, popping nothing, if timeout is too long to tell when the lease runs out
+    pub fn reserve(&self, pqueue: &PQueue<String>, payloads: &Payloads, timeout: Duration) -> Result<Option<Reservation>, String> {
+        let deadline = Instant::now().checked_add(timeout).ok_or_else(|| "Invalid timeout for RESERVE".to_string())?;
+        let Some((item, score)) = pqueue.next_with_score() else {
+            return Ok(None);
+        };
+        let data = payloads.take(&item);
+        let token = Uuid::new_v4();
+        let mut leases = self.leases.lock().unwrap();
+        leases.leases.insert(token, Lease { item: item.clone(), score, data: data.clone(), deadline });
+        leases.deadlines.insert((deadline, token));
+        drop(leases);
+        self.changed.notify_one();
+        Ok(Some(Reservation { token, item, score, data }))
+    }
+
+    /// Completes the lease, dropping the item's data, and returns false if there is no such lease (or
+    /// it already ran out)
+    pub fn ack(&self, token: &Uuid) -> bool {
+        self.take(token).is_some()
+    }
+
+    /// Puts the leased item back on the queue, returning false if there is no such lease
+    pub fn nack(&self, pqueue: &PQueue<String>, payloads: &Payloads, token: &Uuid) -> bool {
+        match self.take(token) {
+            Some(lease) => {
+                lease.put_back(pqueue, payloads);
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// The number of items reserved and not yet acknowledged or put back
+    pub fn len(&self) -> usize {
+        self.leases.lock().unwrap().leases.len()
+    }
+
+    /// Puts items back on pqueue as their leases run out, forever
+    pub async fn run(&self, pqueue: &PQueue<String>, payloads: &Payloads) {
+        loop {
+            let mut expired = Vec::new();
+            let next_deadline = {
+                let mut leases = self.leases.lock().unwrap();
+                let now = Instant::now();
+                while let Some(&(deadline, token)) = leases.deadlines.first() {
+                    if deadline > now {
+                        break;
+                    }
+                    leases.deadlines.pop_first();
+                    expired.extend(leases.leases.remove(&token));
+                }
+                leases.deadlines.first().map(|&(deadline, _)| deadline)
+            };
+            for lease in expired {
+                lease.put_back(pqueue, payloads);
+            }
+            match next_deadline {
+                Some(deadline) => {
+                    tokio::select! {
+                        _ = tokio::time::sleep_until(deadline) => {},
+                        _ = self.changed.notified() => {},
+                    }
+                },
+                None => self.changed.notified().await,
+            }
+        }
+    }
+
+    fn take(&self, token: &Uuid) -> Option<Lease> {
+        let mut leases = self.leases.lock().unwrap();
+        let lease = leases.leases.remove(token)?;
+        leases.deadlines.remove(&(lease.deadline, *token));
+        Some(lease)
+    }
+}
@@ -0,0 +1,133 @@
+// The file given with --load, seeding the queue at startup, in the formats EXPORT writes. Files
+// ending in .json hold an array of entries as the JSON protocol lists them:
+//
+//   [{"item": "job-1", "score": 10}, {"item": "job-2", "score": 5}]
+//
+// Any other file is CSV, a line per item with its score, after an optional identifier,score header.
+// Identifiers holding commas or quotes are quoted, with quotes inside doubled:
+//
+//   identifier,score
+//   job-1,10
+//   "job,2",5
+//
+// Items are given the score in the file, replacing the score of any restored from a snapshot.
+
+use std::path::Path;
+
+use serde::Deserialize;
+use serde_json::json;
+
+#[derive(Deserialize)]
+struct Entry {
+    item: String,
+    score: i64,
+}
+
+/// Reads the (item, score) pairs in the file at path
+pub fn read(path: &Path) -> Result<Vec<(String, i64)>, String> {
+    let contents = std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    let entries = if path.extension().is_some_and(|extension| extension.eq_ignore_ascii_case("json")) {
+        serde_json::from_str::<Vec<Entry>>(&contents)
+            .map(|entries| entries.into_iter().map(|entry| (entry.item, entry.score)).collect())
+            .map_err(|e| e.to_string())
+    } else {
+        parse_csv(&contents)
+    };
+    entries.map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+/// The entries as a JSON array
+pub fn to_json(entries: &[(String, i64)]) -> String {
+    json!(entries.iter().map(|(item, score)| json!({ "item": item, "score": score })).collect::<Vec<_>>()).to_string()
+}
+
+/// The entries as CSV lines, header first
+pub fn to_csv(entries: &[(String, i64)]) -> Vec<String> {
+    let mut lines = vec!["identifier,score".to_string()];
+    lines.extend(entries.iter().map(|(item, score)| {
+        if item.contains([',', '"']) {
+            format!("\"{}\",{}", item.replace('"', "\"\""), score)
+        } else {
+            format!("{},{}", item, score)
+        }
+    }));
+    lines
+}
+
+fn parse_csv(contents: &str) -> Result<Vec<(String, i64)>, String> {
+    let mut entries = Vec::new();
+    for (number, line) in contents.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let (item, score) = split_csv(line).ok_or_else(|| format!("line {}: expected identifier,score", number + 1))?;
+        match score.trim().parse() {
+            Ok(score) => entries.push((item, score)),
+            Err(_) if number == 0 => continue,
+            Err(_) => return Err(format!("line {}: invalid score {}", number + 1, score)),
+        }
+    }
+    Ok(entries)
+}
+
+// Splits a line into its identifier, unquoted, and the score after it
+fn split_csv(line: &str) -> Option<(String, &str)> {
+    let Some(quoted) = line.strip_prefix('"') else {
+        return line.split_once(',').map(|(item, score)| (item.to_string(), score));
+    };
+    let mut item = String::new();
+    let mut chars = quoted.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' if quoted[i + 1..].starts_with('"') => {
+                item.push('"');
+                chars.next();
+            },
+            '"' => return quoted[i + 1..].strip_prefix(',').map(|score| (item, score)),
+            c => item.push(c),
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_csv_quoted() {
+        assert_eq!(split_csv("\"job,2\",5"), Some(("job,2".to_string(), "5")));
+        assert_eq!(split_csv("\"say \"\"hi\"\"\",3"), Some(("say \"hi\"".to_string(), "3")));
+        assert_eq!(split_csv("\"\"\"\",1"), Some(("\"".to_string(), "1")));
+        assert_eq!(split_csv("\"job,2,5"), None);
+        assert_eq!(split_csv("\"job\"5"), None);
+        assert_eq!(
+            parse_csv("identifier,score\njob-1,10\n\n\"job,2\",5\n"),
+            Ok(vec![("job-1".to_string(), 10), ("job,2".to_string(), 5)]),
+        );
+        assert_eq!(parse_csv("job-1,10\n\"job,2,5\n"), Err("line 2: expected identifier,score".to_string()));
+    }
+
+    #[test]
+    fn test_parse_csv_scores() {
+        // A first line that isn't an entry is taken for a header
+        assert_eq!(parse_csv("item,priority\njob,-3"), Ok(vec![("job".to_string(), -3)]));
+        assert_eq!(parse_csv("job, 7 "), Ok(vec![("job".to_string(), 7)]));
+        assert_eq!(parse_csv("identifier,score\njob,high"), Err("line 2: invalid score high".to_string()));
+        assert_eq!(parse_csv("job"), Err("line 1: expected identifier,score".to_string()));
+    }
+
+    #[test]
+    fn test_csv_round_trip() {
+        let entries = vec![
+            ("job-1".to_string(), 10),
+            ("job,2".to_string(), 5),
+            ("say \"hi\"".to_string(), -1),
+            ("\"".to_string(), 0),
+        ];
+        let lines = to_csv(&entries);
+        assert_eq!(lines[0], "identifier,score");
+        assert_eq!(lines[2], "\"job,2\",5");
+        assert_eq!(parse_csv(&lines.join("\n")), Ok(entries));
+    }
+}
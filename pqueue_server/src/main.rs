@@ -1,20 +1,98 @@
+mod acl;
+mod binary;
+mod clients;
+mod cluster;
+mod compat;
+mod config;
+mod daemon;
+mod dashboard;
+mod drain;
+mod delayed;
+mod dump;
+mod glob;
+mod grpc;
+mod http;
+mod info;
+mod leases;
+mod load;
+mod memory;
+mod metrics;
+mod notifications;
+mod payloads;
 mod protocol;
+mod rename;
+mod scripting;
+mod slowlog;
+mod snapshot;
+mod statsd;
+mod storage;
+mod systemd;
+mod tls;
 
-use clap::{Arg, Command as ClapCommand, ArgAction};
-use tokio::{net::{TcpListener, TcpStream}, io::{AsyncWriteExt as _, AsyncReadExt as _}};
-use std::sync::Arc;
+use clap::{Arg, ArgMatches, Command as ClapCommand, ArgAction};
+use tokio::{net::{TcpListener, TcpSocket}, io::{AsyncBufRead, AsyncBufReadExt as _, AsyncRead, AsyncWrite, AsyncWriteExt as _, BufReader, BufWriter}, sync::broadcast::{self, error::RecvError}};
+use std::hash::BuildHasher;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use tracing::{debug, error, info, warn};
+use tracing_subscriber::{fmt, prelude::*, reload, EnvFilter, Registry};
 use uuid::Uuid;
 
 use protocol::*;
-use pqueue::PQueue;
+use acl::{Access, Acl};
+use clients::{Client, Clients};
+use cluster::Cluster;
+use daemon::PidFile;
+use delayed::Delayed;
+use drain::Drain;
+use leases::Leases;
+use memory::MemoryLimit;
+use metrics::Metrics;
+use notifications::NotifyEvents;
+use payloads::Payloads;
+use rename::CommandNames;
+use slowlog::SlowLog;
+use snapshot::LastSave;
+use storage::Storage;
+use tls::Tls;
+use pqueue::{CapacityPolicy, PQueue, QueueEvent, Transaction};
 
 
-#[tokio::main]
-async fn main() {
-    let matches = ClapCommand::new("PQueue Server")
+fn main() {
+    let matches = config::matches(cli);
+
+    if matches.get_flag("daemonize") {
+        let log_file = matches.get_one::<String>("logfile").map(PathBuf::from);
+        daemon::daemonize(log_file.as_deref()).unwrap_or_else(|e| panic!("Failed to daemonize: {}", e));
+    }
+    let _pidfile = matches.get_one::<String>("pidfile")
+        .map(|path| PidFile::create(path.as_ref()).unwrap_or_else(|e| panic!("Failed to write the PID file {}: {}", path, e)));
+    let mut runtime = match matches.get_one::<String>("runtime").unwrap().as_str() {
+        "current-thread" => tokio::runtime::Builder::new_current_thread(),
+        _ => tokio::runtime::Builder::new_multi_thread(),
+    };
+    if let Some(&worker_threads) = matches.get_one::<usize>("worker-threads") {
+        runtime.worker_threads(worker_threads);
+    }
+    runtime.enable_all().build().unwrap().block_on(run(matches));
+}
+
+// The command line options, which the --config file may also set
+fn cli() -> ClapCommand {
+    ClapCommand::new("PQueue Server")
         .version("0.1.0")
         .author("Your Name")
         .about("Asynchronous priority queue server")
+        // Lets the command line override the options read from --config
+        .args_override_self(true)
+        .arg(
+            Arg::new("config")
+                .long("config")
+                .value_name("FILE")
+                .help("Reads options from this file, reloading the log level, limits, auth and save interval on SIGHUP"),
+        )
         .arg(
             Arg::new("host")
                 .long("host")
@@ -30,101 +108,1908 @@ async fn main() {
                 .help("Sets the port to bind")
                 .default_value("8002"),
         )
+        .arg(
+            Arg::new("bind")
+                .long("bind")
+                .value_name("ADDRESS")
+                .help("Listens on this address (such as 127.0.0.1:8002 or [::1]:8002) instead of --host and --port, may be repeated")
+                .action(ArgAction::Append)
+                .conflicts_with("port"),
+        )
+        .arg(
+            Arg::new("acceptors")
+                .long("acceptors")
+                .value_name("COUNT")
+                .help("Opens this many listening sockets per address with SO_REUSEPORT, so the kernel spreads connections over their accept loops (Unix only)")
+                .value_parser(clap::value_parser!(u16).range(1..))
+                .default_value("1"),
+        )
+        .arg(
+            Arg::new("unix-socket")
+                .long("unix-socket")
+                .value_name("PATH")
+                .help("Also listens on a Unix socket at this path, replacing a socket left there by a previous run (Unix only)"),
+        )
+        .arg(
+            Arg::new("http-port")
+                .long("http-port")
+                .value_name("PORT")
+                .help("Also serves the HTTP API on this port"),
+        )
+        .arg(
+            Arg::new("dashboard")
+                .long("dashboard")
+                .help("Serves a web admin dashboard at /dashboard on the HTTP API's port")
+                .action(ArgAction::SetTrue)
+                .requires("http-port"),
+        )
+        .arg(
+            Arg::new("grpc-port")
+                .long("grpc-port")
+                .value_name("PORT")
+                .help("Also serves the gRPC API on this port"),
+        )
+        .arg(
+            Arg::new("compat-port")
+                .long("compat-port")
+                .value_name("PORT")
+                .help("Also serves the original pqueued protocol on this port, for clients that haven't moved to this server's own, see compat.rs")
+                .conflicts_with_all(["requirepass", "acl-file"]),
+        )
+        .arg(
+            Arg::new("metrics-port")
+                .long("metrics-port")
+                .value_name("PORT")
+                .help("Serves Prometheus metrics at /metrics on this port"),
+        )
+        .arg(
+            Arg::new("statsd-addr")
+                .long("statsd-addr")
+                .value_name("HOST:PORT")
+                .help("Pushes metrics to the StatsD server at this address over UDP, see statsd.rs"),
+        )
+        .arg(
+            Arg::new("statsd-interval")
+                .long("statsd-interval")
+                .value_name("SECONDS")
+                .help("How often metrics are pushed to --statsd-addr")
+                .value_parser(clap::value_parser!(u64).range(1..))
+                .default_value("10"),
+        )
+        .arg(
+            Arg::new("statsd-prefix")
+                .long("statsd-prefix")
+                .value_name("PREFIX")
+                .help("The prefix of the names of the metrics pushed to --statsd-addr")
+                .default_value("pqueue"),
+        )
+        .arg(
+            Arg::new("requirepass")
+                .long("requirepass")
+                .value_name("PASSWORD")
+                .help("Requires clients to AUTH with this password before running any other command"),
+        )
+        .arg(
+            Arg::new("acl-file")
+                .long("acl-file")
+                .value_name("FILE")
+                .help("Loads users and the commands they may run from this file, see acl.rs for the format"),
+        )
+        .arg(
+            Arg::new("rename-command")
+                .long("rename-command")
+                .value_name("COMMAND=NAME")
+                .help("Makes a command only run under a new name, or not at all when the name is empty, may be repeated; see rename.rs")
+                .value_parser(rename::parse_rename)
+                .action(ArgAction::Append),
+        )
+        .arg(
+            Arg::new("tls-cert")
+                .long("tls-cert")
+                .value_name("FILE")
+                .help("Serves the TCP protocol over TLS with this PEM certificate chain")
+                .requires("tls-key"),
+        )
+        .arg(
+            Arg::new("tls-key")
+                .long("tls-key")
+                .value_name("FILE")
+                .help("The PEM private key of --tls-cert")
+                .requires("tls-cert"),
+        )
+        .arg(
+            Arg::new("tls-ca")
+                .long("tls-ca")
+                .value_name("FILE")
+                .help("Verifies the certificates clients present against the CAs in this PEM file")
+                .requires("tls-cert"),
+        )
+        .arg(
+            Arg::new("tls-require-client-cert")
+                .long("tls-require-client-cert")
+                .help("Refuses clients that don't present a certificate signed by --tls-ca")
+                .action(ArgAction::SetTrue)
+                .requires("tls-ca"),
+        )
+        .arg(
+            Arg::new("tls-cert-users")
+                .long("tls-cert-users")
+                .help("Authenticates clients as the ACL user named by the common name of their certificate")
+                .action(ArgAction::SetTrue)
+                .requires_all(["tls-ca", "acl-file"]),
+        )
+        .arg(
+            Arg::new("notify-events")
+                .long("notify-events")
+                .value_name("EVENTS")
+                .help("Queue events pushed to clients after SUBSCRIBE: all, or a comma separated list of added, updated, popped, expired and removed")
+                .default_value("added,updated")
+                .value_parser(clap::value_parser!(NotifyEvents)),
+        )
+        .arg(
+            Arg::new("max-clients")
+                .long("max-clients")
+                .value_name("COUNT")
+                .help("Refuses TCP and WebSocket connections beyond this many, replying with an error before closing them; 0 for no limit")
+                .value_parser(clap::value_parser!(usize)),
+        )
+        .arg(
+            Arg::new("idle-timeout")
+                .long("idle-timeout")
+                .value_name("SECONDS")
+                .help("Disconnects TCP clients that send no request for this long, unless subscribed, monitoring or consuming; 0 to never")
+                .value_parser(clap::value_parser!(u64))
+                .default_value("0"),
+        )
+        .arg(
+            Arg::new("max-items")
+                .long("max-items")
+                .value_name("COUNT")
+                .help("Bounds the queue to this many items, applying --max-items-policy to items added past it")
+                .value_parser(clap::value_parser!(usize)),
+        )
+        .arg(
+            Arg::new("max-items-policy")
+                .long("max-items-policy")
+                .value_name("POLICY")
+                .help("Rejects items added to a full queue, or makes room by evicting the item NEXT would pop last or the oldest item")
+                .value_parser(["reject", "evict-lowest", "evict-oldest"])
+                .default_value("reject")
+                .requires("max-items"),
+        )
+        .arg(
+            Arg::new("max-memory")
+                .long("max-memory")
+                .value_name("BYTES")
+                .help("Bounds the estimated memory taken up by the queue and its items' data, e.g. 512mb, applying --max-memory-policy past it")
+                .value_parser(memory::parse_bytes),
+        )
+        .arg(
+            Arg::new("max-memory-policy")
+                .long("max-memory-policy")
+                .value_name("POLICY")
+                .help("Past --max-memory, refuses commands adding items, or evicts the items NEXT would pop last or the oldest items")
+                .value_parser(["reject", "evict-lowest", "evict-oldest"])
+                .default_value("reject")
+                .requires("max-memory"),
+        )
+        .arg(
+            Arg::new("max-line-length")
+                .long("max-line-length")
+                .value_name("BYTES")
+                .help("Longest request line accepted in the text and JSON protocols")
+                .value_parser(clap::value_parser!(usize))
+                .default_value("65536"),
+        )
+        .arg(
+            Arg::new("max-item-length")
+                .long("max-item-length")
+                .value_name("BYTES")
+                .help("Longest item id accepted")
+                .value_parser(clap::value_parser!(usize))
+                .default_value("1024"),
+        )
+        .arg(
+            Arg::new("max-buffer-size")
+                .long("max-buffer-size")
+                .value_name("BYTES")
+                .help("Most a connection buffers for one request, binary frames included")
+                .value_parser(clap::value_parser!(usize))
+                .default_value("16777216"),
+        )
+        .arg(
+            Arg::new("disconnect-oversized")
+                .long("disconnect-oversized")
+                .help("Disconnects clients sending a request over the limits, instead of only replying with an error")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("ttl-mode")
+                .long("ttl-mode")
+                .value_name("MODE")
+                .help("Removes expired items as commands use the queue (lazy), or only in sweeps every --ttl-sweep-interval (active), in which case they may show to PEEK and SCORE until swept but are never popped")
+                .value_parser(["lazy", "active"])
+                .default_value("lazy"),
+        )
+        .arg(
+            Arg::new("ttl-sweep-interval")
+                .long("ttl-sweep-interval")
+                .value_name("MILLISECONDS")
+                .help("Time between sweeps of expired items with --ttl-mode active")
+                .value_parser(clap::value_parser!(u64).range(1..))
+                .default_value("100"),
+        )
+        .arg(
+            Arg::new("ttl-sweep-max")
+                .long("ttl-sweep-max")
+                .value_name("COUNT")
+                .help("The most expired items removed per command, or per sweep with --ttl-mode active, bounding the time spent when many expire at once; 0 for no limit")
+                .value_parser(clap::value_parser!(usize))
+                .default_value("0"),
+        )
+        .arg(
+            Arg::new("data-dir")
+                .long("data-dir")
+                .value_name("DIR")
+                .help("Restores the queue from a snapshot in this directory on startup and saves snapshots to it"),
+        )
+        .arg(
+            Arg::new("storage")
+                .long("storage")
+                .value_name("BACKEND")
+                .help("Keeps the queue only in memory, or also in a database in --data-dir that every change is written to, see storage.rs")
+                .value_parser(["memory", "persistent"])
+                .default_value("memory")
+                .requires_if("persistent", "data-dir"),
+        )
+        .arg(
+            Arg::new("load")
+                .long("load")
+                .value_name("FILE")
+                .help("Seeds the queue at startup with the items and scores in this JSON (.json) or CSV file, see load.rs for the formats"),
+        )
+        .arg(
+            Arg::new("save-interval")
+                .long("save-interval")
+                .value_name("SECONDS")
+                .help("Seconds between snapshots when --data-dir is set (0 only saves on SAVE)")
+                .value_parser(clap::value_parser!(u64))
+                .default_value("60"),
+        )
+        .arg(
+            Arg::new("cluster-nodes")
+                .long("cluster-nodes")
+                .value_name("ADDRESSES")
+                .help("Runs as a node of a cluster, given the comma separated addresses of every node (this one included), partitioning items between them by hash")
+                .value_delimiter(',')
+                .requires("cluster-index"),
+        )
+        .arg(
+            Arg::new("cluster-index")
+                .long("cluster-index")
+                .value_name("INDEX")
+                .help("Position of this node's address in --cluster-nodes, starting from 0")
+                .value_parser(clap::value_parser!(usize))
+                .requires("cluster-nodes"),
+        )
+        .arg(
+            Arg::new("slowlog-threshold")
+                .long("slowlog-threshold")
+                .value_name("MICROSECONDS")
+                .help("Logs commands taking longer than this to the slowlog, read with SLOWLOG GET")
+                .value_parser(clap::value_parser!(u64))
+                .default_value("10000"),
+        )
+        .arg(
+            Arg::new("slowlog-max-len")
+                .long("slowlog-max-len")
+                .value_name("COUNT")
+                .help("Number of commands kept in the slowlog (0 disables it)")
+                .value_parser(clap::value_parser!(usize))
+                .default_value("128"),
+        )
+        .arg(
+            Arg::new("read-only")
+                .long("read-only")
+                .help("Starts read only, rejecting commands that change the queue until READONLY OFF")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("legacy-replies")
+                .long("legacy-replies")
+                .help("Replies as protocol version 1 did, with +-1 rather than -EMPTY or -NOTFOUND and OK to UPDATE, unless a client asks for a later version with HELLO")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("greeting")
+                .long("greeting")
+                .help("Greets TCP clients on connecting with +PQUEUE <server version> <protocol version>, before they send anything")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("daemonize")
+                .long("daemonize")
+                .help("Detaches from the terminal and runs in the background, logging to --logfile")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("pidfile")
+                .long("pidfile")
+                .value_name("PATH")
+                .help("Writes the server's process id to this file, removed again when the server shuts down"),
+        )
+        .arg(
+            Arg::new("logfile")
+                .long("logfile")
+                .value_name("PATH")
+                .help("Appends the log to this file when daemonized, instead of discarding it")
+                .requires("daemonize"),
+        )
+        .arg(
+            Arg::new("log-level")
+                .long("log-level")
+                .value_name("LEVEL")
+                .help("Sets the log level (error, warn, info, debug or trace), overridden by RUST_LOG")
+                .value_parser(LOG_LEVELS)
+                .default_value("info"),
+        )
+        .arg(
+            Arg::new("log-format")
+                .long("log-format")
+                .value_name("FORMAT")
+                .help("Sets the log format")
+                .value_parser(["text", "json"])
+                .default_value("text"),
+        )
+        .arg(
+            Arg::new("runtime")
+                .long("runtime")
+                .value_name("FLAVOR")
+                .help("Runs every connection on the main thread, or spreads them over worker threads")
+                .value_parser(["current-thread", "multi-thread"])
+                .default_value("multi-thread"),
+        )
+        .arg(
+            Arg::new("worker-threads")
+                .long("worker-threads")
+                .value_name("COUNT")
+                .help("Worker threads of the multi-thread runtime (defaults to one per CPU core)")
+                .value_parser(clap::builder::RangedU64ValueParser::<usize>::new().range(1..)),
+        )
+        .arg(
+            Arg::new("read-buffer-size")
+                .long("read-buffer-size")
+                .value_name("BYTES")
+                .help("Size of the buffer each TCP connection reads requests through")
+                .value_parser(clap::builder::RangedU64ValueParser::<usize>::new().range(1..))
+                .default_value("8192"),
+        )
+        .arg(
+            Arg::new("flush-interval")
+                .long("flush-interval")
+                .value_name("MICROSECONDS")
+                .help("How long replies to pipelined requests may wait for more to be sent along with them; with 0 they are sent once no more requests have arrived")
+                .value_parser(clap::value_parser!(u64))
+                .default_value("0"),
+        )
+        .arg(
+            Arg::new("flush-responses")
+                .long("flush-responses")
+                .value_name("COUNT")
+                .help("Sends the replies waiting to go out once there are this many, however soon")
+                .value_parser(clap::builder::RangedU64ValueParser::<usize>::new().range(1..))
+                .default_value("64"),
+        )
         .arg(
             Arg::new("debug")
                 .short('d')
                 .long("debug")
-                .help("Output extra debugging info to stdout")
+                .help("Logs at the debug level, including every command and response (same as --log-level debug)")
                 .action(ArgAction::SetTrue),
         )
-        .get_matches();
+}
 
+async fn run(matches: ArgMatches) {
         let host = matches.get_one::<String>("host").unwrap();
         let port = matches.get_one::<String>("port").unwrap();
-        let debug = matches.get_flag("debug");
-        let address = format!("{}:{}", host, port);
+        let addresses = match matches.get_many::<String>("bind") {
+            Some(addresses) => addresses.cloned().collect(),
+            None => vec![format!("{}:{}", host, port)],
+        };
+        // A daemon logs to a file, where colors would only get in the way
+        let log_filter = init_logging(log_level(&matches), matches.get_one::<String>("log-format").unwrap() == "json", !matches.get_flag("daemonize"));
+        let config = ServerConfig {
+            auth: RwLock::new(Auth::new(&matches).unwrap_or_else(|e| panic!("Failed to load the ACL file {}", e))),
+            max_clients: RwLock::new(max_clients(&matches)),
+            idle_timeout: AtomicU64::new(*matches.get_one::<u64>("idle-timeout").unwrap()),
+            log_level: RwLock::new(log_level(&matches).to_string()),
+            log_filter,
+            notify_events: *matches.get_one::<NotifyEvents>("notify-events").unwrap(),
+            dashboard: matches.get_flag("dashboard"),
+            read_buffer_size: *matches.get_one::<usize>("read-buffer-size").unwrap(),
+            legacy_replies: matches.get_flag("legacy-replies"),
+            greeting: matches.get_flag("greeting"),
+            command_names: CommandNames::new(matches.get_many::<(String, Option<String>)>("rename-command").into_iter().flatten().cloned()),
+            flush_interval: Duration::from_micros(*matches.get_one::<u64>("flush-interval").unwrap()),
+            flush_responses: *matches.get_one::<usize>("flush-responses").unwrap(),
+            limits: RwLock::new(Limits::new(&matches)),
+            save_interval: AtomicU64::new(*matches.get_one::<u64>("save-interval").unwrap()),
+            data_dir: matches.get_one::<String>("data-dir").map(PathBuf::from),
+            tls: matches.get_one::<String>("tls-cert").map(|cert| {
+                let key = matches.get_one::<String>("tls-key").unwrap();
+                let ca = matches.get_one::<String>("tls-ca").map(PathBuf::from);
+                Tls::load(cert.as_ref(), key.as_ref(), ca.as_deref(), matches.get_flag("tls-require-client-cert"), matches.get_flag("tls-cert-users"))
+                    .unwrap_or_else(|e| panic!("Failed to set up TLS: {}", e))
+            }),
+            cluster: matches.get_many::<String>("cluster-nodes").map(|nodes| {
+                Cluster::new(nodes.cloned().collect(), *matches.get_one::<usize>("cluster-index").unwrap())
+            }),
+        };
+
+    // Under systemd socket activation the listening sockets are inherited rather than bound, so they stay
+    // open across restarts
+    let mut listeners = systemd::listeners().unwrap_or_else(|e| panic!("Failed to use the sockets passed by systemd: {}", e))
+        .into_iter()
+        .map(|listener| TcpListener::from_std(listener).unwrap())
+        .collect::<Vec<_>>();
+    if listeners.is_empty() {
+        let acceptors = *matches.get_one::<u16>("acceptors").unwrap();
+        for address in &addresses {
+            listeners.extend(bind(address, acceptors).await.unwrap_or_else(|e| panic!("Failed to bind {}: {}", address, e)));
+        }
+    }
+    let mut bound = listeners.iter().map(|listener| listener.local_addr().unwrap()).collect::<Vec<_>>();
+    bound.dedup();
+    for address in bound {
+        info!("Server running on {}", address);
+    }
+    #[cfg(unix)]
+    let unix_listener = matches.get_one::<String>("unix-socket").map(|path| {
+        let listener = bind_unix(path).unwrap_or_else(|e| panic!("Failed to bind {}: {}", path, e));
+        info!("Server running on {}", path);
+        (listener, path.clone())
+    });
+    #[cfg(not(unix))]
+    if matches.contains_id("unix-socket") {
+        panic!("--unix-socket is only supported on Unix");
+    }
+
+    let (storage, stored) = match (matches.get_one::<String>("storage").unwrap().as_str(), &config.data_dir) {
+        ("persistent", Some(data_dir)) => {
+            std::fs::create_dir_all(data_dir).unwrap();
+            let (storage, entries) = Storage::open(&storage::path(data_dir))
+                .unwrap_or_else(|e| panic!("Failed to open the database in {}: {}", data_dir.display(), e));
+            (Some(storage), entries)
+        },
+        _ => (None, Vec::new()),
+    };
+
+    let server = Arc::new(Server {
+        pqueue: PQueue::<String>::new(), // Replace String with your item type
+        config,
+        metrics: Metrics::default(),
+        saving: tokio::sync::Mutex::new(()),
+        last_save: std::sync::Mutex::new(None),
+        clients: Clients::default(),
+        delayed: Delayed::default(),
+        leases: Leases::default(),
+        payloads: Payloads::default(),
+        monitor: broadcast::channel(MONITOR_CAPACITY).0,
+        shutdown: tokio::sync::Notify::new(),
+        reloaded: tokio::sync::Notify::new(),
+        read_only: AtomicBool::new(matches.get_flag("read-only")),
+        drain: Drain::default(),
+        storage,
+        memory: matches.get_one::<usize>("max-memory").map(|&max_memory| MemoryLimit::new(max_memory, capacity_policy(&matches, "max-memory-policy"))),
+        slowlog: SlowLog::new(
+            Duration::from_micros(*matches.get_one::<u64>("slowlog-threshold").unwrap()),
+            *matches.get_one::<usize>("slowlog-max-len").unwrap(),
+        ),
+    });
+
+    let delayed_server = server.clone();
+    tokio::spawn(async move {
+        delayed_server.delayed.run(&delayed_server.pqueue).await;
+    });
+    server.pqueue.set_max_items(matches.get_one::<usize>("max-items").map(|&max_items| {
+        (max_items, capacity_policy(&matches, "max-items-policy"))
+    }));
+    if server.memory.is_some() {
+        let memory_server = server.clone();
+        tokio::spawn(async move {
+            memory_server.memory.as_ref().unwrap().run(&memory_server).await;
+        });
+    }
+
+    let sweep_max = Some(*matches.get_one::<usize>("ttl-sweep-max").unwrap()).filter(|&max| max > 0);
+    if matches.get_one::<String>("ttl-mode").unwrap() == "active" {
+        // Commands leave expired items to the sweeps
+        server.pqueue.set_expiry_sweep_limit(Some(0));
+        let interval = Duration::from_millis(*matches.get_one::<u64>("ttl-sweep-interval").unwrap());
+        let server = server.clone();
+        tokio::spawn(async move {
+            let mut sweeps = tokio::time::interval(interval);
+            loop {
+                sweeps.tick().await;
+                server.pqueue.remove_expired(sweep_max.unwrap_or(usize::MAX));
+            }
+        });
+    } else {
+        server.pqueue.set_expiry_sweep_limit(sweep_max);
+    }
+
+    let drain_server = server.clone();
+    tokio::spawn(async move {
+        drain_server.drain.run(&drain_server).await;
+    });
+    let leases_server = server.clone();
+    tokio::spawn(async move {
+        leases_server.leases.run(&leases_server.pqueue, &leases_server.payloads).await;
+    });
+
+    if let Some(data_dir) = &server.config.data_dir {
+        std::fs::create_dir_all(data_dir).unwrap();
+        if server.storage.is_some() {
+            info!("Restored {} items from {}", server.pqueue.load_sorted(stored), storage::path(data_dir).display());
+        } else {
+            match snapshot::read(&snapshot::path(data_dir)) {
+                Ok(Some(entries)) => info!("Restored {} items from {}", server.pqueue.load_sorted(entries), data_dir.display()),
+                Ok(None) => info!("No snapshot in {}, starting empty", data_dir.display()),
+                Err(e) => panic!("Failed to restore the snapshot in {}: {}", data_dir.display(), e),
+            }
+        }
+        let server = server.clone();
+        tokio::spawn(async move {
+            loop {
+                // Waits out the interval again whenever a reload may have changed it
+                let save_interval = server.config.save_interval.load(Ordering::Relaxed);
+                let wait = async {
+                    match save_interval {
+                        0 => std::future::pending().await,
+                        seconds => tokio::time::sleep(Duration::from_secs(seconds)).await,
+                    }
+                };
+                tokio::select! {
+                    _ = wait => match snapshot::save(&server).await {
+                        Ok(saved) => debug!("Saved {} items", saved),
+                        Err(e) => error!("Failed to save a snapshot: {}", e),
+                    },
+                    _ = server.reloaded.notified() => {},
+                }
+            }
+        });
+    }
 
-    let listener = TcpListener::bind(&address).await.unwrap();
-    println!("Server running on {}", address);
+    if server.storage.is_some() {
+        // Subscribed before anything else changes the queue, so the database sees every change
+        let events = server.pqueue.subscribe();
+        let server = server.clone();
+        tokio::spawn(async move {
+            server.storage.as_ref().unwrap().run(&server, events).await;
+        });
+    }
 
-    let pqueue = Arc::new(PQueue::<String>::new()); // Replace String with your item type
+    let payloads_events = server.pqueue.subscribe();
+    let payloads_server = server.clone();
+    tokio::spawn(async move {
+        payloads_server.payloads.run(&payloads_server, payloads_events).await;
+    });
+
+    if let Some(path) = matches.get_one::<String>("load") {
+        let entries = load::read(path.as_ref()).unwrap_or_else(|e| panic!("Failed to load {}", e));
+        let count = entries.len();
+        let rejected = entries.into_iter().map(|(item, score)| server.pqueue.set_score(item, score)).filter(Result::is_err).count();
+        info!("Loaded {} items from {}", count - rejected, path);
+        if rejected > 0 {
+            warn!("{} items from {} did not fit in the queue, see --max-items", rejected, path);
+        }
+    }
+
+    if let Some(http_port) = matches.get_one::<String>("http-port") {
+        let http_address = format!("{}:{}", host, http_port);
+        let http_listener = TcpListener::bind(&http_address).await.unwrap();
+        info!("HTTP API running on {}", http_address);
+        let server = server.clone();
+        tokio::spawn(async move {
+            if let Err(e) = http::serve(http_listener, server).await {
+                error!("HTTP API stopped: {}", e);
+            }
+        });
+    }
+
+    if let Some(grpc_port) = matches.get_one::<String>("grpc-port") {
+        let grpc_address = format!("{}:{}", host, grpc_port);
+        let grpc_listener = TcpListener::bind(&grpc_address).await.unwrap();
+        info!("gRPC API running on {}", grpc_address);
+        let server = server.clone();
+        tokio::spawn(async move {
+            if let Err(e) = grpc::serve(grpc_listener, server).await {
+                error!("gRPC API stopped: {}", e);
+            }
+        });
+    }
+
+    if let Some(compat_port) = matches.get_one::<String>("compat-port") {
+        let compat_address = format!("{}:{}", host, compat_port);
+        let compat_listener = TcpListener::bind(&compat_address).await.unwrap();
+        info!("Original pqueued protocol running on {}", compat_address);
+        tokio::spawn(compat::serve(compat_listener, server.clone()));
+    }
+
+    if let Some(metrics_port) = matches.get_one::<String>("metrics-port") {
+        let metrics_address = format!("{}:{}", host, metrics_port);
+        let metrics_listener = TcpListener::bind(&metrics_address).await.unwrap();
+        info!("Metrics running on {}", metrics_address);
+        let server = server.clone();
+        tokio::spawn(async move {
+            if let Err(e) = metrics::serve(metrics_listener, server).await {
+                error!("Metrics stopped: {}", e);
+            }
+        });
+    }
+
+    if let Some(statsd_address) = matches.get_one::<String>("statsd-addr") {
+        info!("Pushing metrics to StatsD at {}", statsd_address);
+        let statsd_address = statsd_address.clone();
+        let prefix = matches.get_one::<String>("statsd-prefix").unwrap().clone();
+        let interval = Duration::from_secs(*matches.get_one::<u64>("statsd-interval").unwrap());
+        let server = server.clone();
+        tokio::spawn(async move {
+            if let Err(e) = statsd::run(&server, &statsd_address, &prefix, interval).await {
+                error!("StatsD metrics stopped: {}", e);
+            }
+        });
+    }
+
+    #[cfg(unix)]
+    tokio::spawn(reload_on_sighup(server.clone()));
+    #[cfg(unix)]
+    tokio::spawn(drain_on_sigusr1(server.clone()));
+
+    if let Err(e) = systemd::notify("READY=1") {
+        warn!("Failed to notify systemd: {}", e);
+    }
+    for listener in listeners {
+        tokio::spawn(accept(listener, server.clone()));
+    }
+    #[cfg(unix)]
+    let unix_socket = unix_listener.map(|(listener, path)| {
+        tokio::spawn(accept_unix(listener, path.clone(), server.clone()));
+        path
+    });
+    server.shutdown.notified().await;
+    info!("Shutting down");
+    #[cfg(unix)]
+    if let Some(path) = &unix_socket {
+        let _ = std::fs::remove_file(path);
+    }
+    if let Some(storage) = &server.storage {
+        storage.stop().await;
+    }
+    let _ = systemd::notify("STOPPING=1");
+}
+
+// Changes the log level of a running server
+type LogFilter = reload::Handle<EnvFilter, Registry>;
+
+// Binds address, with as many listening sockets as acceptors sharing it through SO_REUSEPORT when
+// there is more than one
+async fn bind(address: &str, acceptors: u16) -> std::io::Result<Vec<TcpListener>> {
+    if acceptors == 1 {
+        return Ok(vec![TcpListener::bind(address).await?]);
+    }
+    let mut address = tokio::net::lookup_host(address).await?.next()
+        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput, "no address to bind"))?;
+    let mut listeners = Vec::new();
+    for _ in 0..acceptors {
+        let socket = if address.is_ipv4() { TcpSocket::new_v4()? } else { TcpSocket::new_v6()? };
+        socket.set_reuseaddr(true)?;
+        #[cfg(unix)]
+        socket.set_reuseport(true)?;
+        #[cfg(not(unix))]
+        return Err(std::io::Error::new(std::io::ErrorKind::Unsupported, "--acceptors needs SO_REUSEPORT"));
+        socket.bind(address)?;
+        let listener = socket.listen(LISTEN_BACKLOG)?;
+        // The sockets after the first share the port picked for it when binding port 0
+        address = listener.local_addr()?;
+        listeners.push(listener);
+    }
+    Ok(listeners)
+}
+
+// Logs to stdout at the given level, unless overridden with RUST_LOG, as text or JSON lines
+fn init_logging(level: &str, json: bool, ansi: bool) -> LogFilter {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(level));
+    let (filter, handle) = reload::Layer::new(filter);
+    let subscriber = tracing_subscriber::registry().with(filter);
+    if json {
+        subscriber.with(fmt::layer().json().with_ansi(ansi)).init();
+    } else {
+        subscriber.with(fmt::layer().with_ansi(ansi)).init();
+    }
+    handle
+}
+
+const LOG_LEVELS: [&str; 5] = ["error", "warn", "info", "debug", "trace"];
+
+fn log_level(matches: &ArgMatches) -> &str {
+    if matches.get_flag("debug") { "debug" } else { matches.get_one::<String>("log-level").unwrap() }
+}
+
+// The policy given with the named option, one of reject, evict-lowest and evict-oldest
+fn capacity_policy(matches: &ArgMatches, name: &str) -> CapacityPolicy {
+    match matches.get_one::<String>(name).unwrap().as_str() {
+        "evict-lowest" => CapacityPolicy::EvictLowest,
+        "evict-oldest" => CapacityPolicy::EvictOldest,
+        _ => CapacityPolicy::Reject,
+    }
+}
+
+fn max_clients(matches: &ArgMatches) -> Option<usize> {
+    matches.get_one::<usize>("max-clients").copied().filter(|&max_clients| max_clients > 0)
+}
+
+// Applies the settings that can change while running from the command line and --config file each
+// time the server gets SIGHUP. Clients stay connected, and stay authenticated under the old credentials.
+#[cfg(unix)]
+async fn reload_on_sighup(server: Arc<Server>) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangups = signal(SignalKind::hangup()).unwrap();
+    while hangups.recv().await.is_some() {
+        let reloaded = config::reload(cli).and_then(|matches| {
+            let auth = Auth::new(&matches)?;
+            Ok((matches, auth))
+        });
+        let (matches, auth) = match reloaded {
+            Ok(reloaded) => reloaded,
+            Err(e) => {
+                error!("Failed to reload the configuration, keeping the current one: {}", e);
+                continue;
+            },
+        };
+        // RUST_LOG still wins over the configured level
+        if std::env::var_os(EnvFilter::DEFAULT_ENV).is_none() {
+            server.config.set_log_level(log_level(&matches));
+        }
+        *server.config.auth.write().unwrap() = auth;
+        *server.config.limits.write().unwrap() = Limits::new(&matches);
+        *server.config.max_clients.write().unwrap() = max_clients(&matches);
+        server.config.idle_timeout.store(*matches.get_one::<u64>("idle-timeout").unwrap(), Ordering::Relaxed);
+        server.config.save_interval.store(*matches.get_one::<u64>("save-interval").unwrap(), Ordering::Relaxed);
+        server.reloaded.notify_waiters();
+        info!("Reloaded the configuration");
+    }
+}
+
+// Drains the server and shuts it down once empty when it gets SIGUSR1, as DRAIN EXIT does
+#[cfg(unix)]
+async fn drain_on_sigusr1(server: Arc<Server>) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut signals = signal(SignalKind::user_defined1()).unwrap();
+    while signals.recv().await.is_some() {
+        info!("Draining before shutting down");
+        server.drain.start(true);
+    }
+}
+
+// State shared by every connection, whichever transport it arrives over
+struct Server {
+    pqueue: PQueue<String>,
+    config: ServerConfig,
+    metrics: Metrics,
+    // Held while a snapshot is written
+    saving: tokio::sync::Mutex<()>,
+    last_save: std::sync::Mutex<Option<LastSave>>,
+    slowlog: SlowLog,
+    clients: Clients,
+    delayed: Delayed,
+    leases: Leases,
+    payloads: Payloads,
+    // Every command run, for clients running MONITOR
+    monitor: broadcast::Sender<Response>,
+    // Notified by SHUTDOWN to stop the server
+    shutdown: tokio::sync::Notify,
+    // Notified after SIGHUP reloads the configuration
+    reloaded: tokio::sync::Notify,
+    // Set with --read-only or READONLY ON to reject the commands in WRITE_COMMANDS
+    read_only: AtomicBool,
+    drain: Drain,
+    // The bound set with --max-memory, if any
+    memory: Option<MemoryLimit>,
+    // The database the queue is written to, with --storage persistent
+    storage: Option<Storage>,
+}
+
+impl Server {
+    // The error refusing the command, for commands changing the queue while the server is read only, and
+    // commands adding items while it drains or is over its memory limit
+    fn refuses(&self, name: &str) -> Option<&'static str> {
+        if self.read_only.load(Ordering::Relaxed) && WRITE_COMMANDS.contains(&name) {
+            Some(READ_ONLY_ERROR)
+        } else if self.drain.is_draining() && INSERT_COMMANDS.contains(&name) {
+            Some(DRAINING_ERROR)
+        } else if self.memory.as_ref().is_some_and(MemoryLimit::is_full) && (INSERT_COMMANDS.contains(&name) || name == "SETDATA") {
+            Some(OUT_OF_MEMORY_ERROR)
+        } else {
+            None
+        }
+    }
+}
+
+// Sent in reply to commands adding items or data while over --max-memory under the reject policy
+const OUT_OF_MEMORY_ERROR: &str = "OOM Over the memory limit, not accepting new items";
+
+// Commands that change the queue, popping included
+const WRITE_COMMANDS: &[&str] = &[
+    "UPDATE", "MUPDATE", "SETSCORE", "NEXT", "BNEXT", "NEXTSCORE", "RESERVE", "ACK", "NACK", "REMOVE", "SETDATA", "DELAY", "EXPIRE", "PERSIST",
+    "RESTORE", "CLEAR", "EVAL", "CONSUME",
+];
+
+// Sent in reply to commands in WRITE_COMMANDS while the server is read only
+const READ_ONLY_ERROR: &str = "Server is read only";
+
+// Commands that may add items to the queue
+const INSERT_COMMANDS: &[&str] = &["UPDATE", "MUPDATE", "SETSCORE", "DELAY", "RESTORE", "EVAL"];
+
+// Sent in reply to commands in INSERT_COMMANDS while the server drains
+const DRAINING_ERROR: &str = "Server is draining, not accepting new items";
+
+// Connections waiting to be accepted on each socket opened for --acceptors
+const LISTEN_BACKLOG: u32 = 1024;
+
+// How long a client has to complete the TLS handshake
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// How often WAITEMPTY checks whether the queue has emptied
+const EMPTY_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+// Commands buffered for each monitoring client before it lags
+const MONITOR_CAPACITY: usize = 1024;
+
+// Sent to clients connecting past --max-clients
+const MAX_CLIENTS_ERROR: &str = "Max number of clients reached";
+
+// Settings shared by every connection
+struct ServerConfig {
+    // Reloaded on SIGHUP, like the limits and the save interval
+    auth: RwLock<Auth>,
+    // Changed by CONFIG SET too, like the save interval and the log level
+    max_clients: RwLock<Option<usize>>,
+    // Seconds a client may go without sending a request, 0 for no limit
+    idle_timeout: AtomicU64,
+    log_level: RwLock<String>,
+    log_filter: LogFilter,
+    // Where snapshots are kept, if anywhere
+    data_dir: Option<PathBuf>,
+    // TLS for the TCP protocol, if enabled
+    tls: Option<Tls>,
+    // The cluster this server is a node of, if any
+    cluster: Option<Cluster>,
+    // The queue events pushed to subscribed clients
+    notify_events: NotifyEvents,
+    limits: RwLock<Limits>,
+    // Seconds between snapshots, 0 to only save on SAVE
+    save_interval: AtomicU64,
+    // Whether the HTTP API serves the web admin dashboard
+    dashboard: bool,
+    read_buffer_size: usize,
+    // How long replies may wait to be sent together, and how many may
+    flush_interval: Duration,
+    flush_responses: usize,
+    // Whether clients speak protocol version 1 until they HELLO another
+    legacy_replies: bool,
+    // Whether clients are greeted on connecting, identifying the server
+    greeting: bool,
+    command_names: CommandNames,
+}
 
+// The credentials clients authenticate with
+struct Auth {
+    requirepass: Option<String>,
+    acl: Option<Acl>,
+}
+
+impl Auth {
+    fn new(matches: &ArgMatches) -> Result<Self, String> {
+        Ok(Auth {
+            requirepass: matches.get_one::<String>("requirepass").cloned(),
+            acl: matches.get_one::<String>("acl-file").map(|path| Acl::load(path.as_ref())).transpose()?,
+        })
+    }
+}
+
+// Bounds on the requests clients send, so a client can't make the server buffer without end
+#[derive(Clone, Copy)]
+struct Limits {
+    // The longest line of the text and JSON protocols, in bytes
+    max_line_len: usize,
+    // The longest item id, in bytes
+    max_item_len: usize,
+    // The most a connection buffers for one request, binary frames included
+    max_buffer: usize,
+    // Whether clients sending an oversized request are disconnected, rather than only sent an error
+    disconnect: bool,
+}
+
+impl Limits {
+    fn new(matches: &ArgMatches) -> Self {
+        Limits {
+            max_line_len: *matches.get_one::<usize>("max-line-length").unwrap(),
+            max_item_len: *matches.get_one::<usize>("max-item-length").unwrap(),
+            max_buffer: *matches.get_one::<usize>("max-buffer-size").unwrap(),
+            disconnect: matches.get_flag("disconnect-oversized"),
+        }
+    }
+
+    // The longest request accepted in the protocol
+    fn max_request(&self, protocol: Protocol) -> usize {
+        match protocol {
+            Protocol::Binary => self.max_buffer,
+            _ => self.max_line_len.min(self.max_buffer),
+        }
+    }
+
+    fn too_long(&self, protocol: Protocol) -> Response {
+        Response::Error(format!("Request longer than the limit of {} bytes", self.max_request(protocol)))
+    }
+
+    // Whether the command names an item longer than allowed
+    fn rejects(&self, command: &Command) -> bool {
+        match command {
+            Command::MultiUpdate { updates } => updates.iter().any(|(item, _)| item.len() > self.max_item_len),
+            command => command.item().is_some_and(|item| item.len() > self.max_item_len),
+        }
+    }
+}
+
+impl ServerConfig {
+    // The client limit, None when unlimited
+    fn max_clients(&self) -> Option<usize> {
+        *self.max_clients.read().unwrap()
+    }
+
+    // Changes the log level, one of LOG_LEVELS
+    fn set_log_level(&self, level: &str) {
+        let _ = self.log_filter.reload(EnvFilter::new(level));
+        *self.log_level.write().unwrap() = level.to_string();
+    }
+
+    // Whether clients must authenticate before running commands
+    fn requires_auth(&self) -> bool {
+        let auth = self.auth.read().unwrap();
+        auth.requirepass.is_some() || auth.acl.is_some()
+    }
+
+    fn limits(&self) -> Limits {
+        *self.limits.read().unwrap()
+    }
+
+    // Features reported by HELLO, so clients can tell what they may use
+    fn capabilities(&self) -> Vec<&'static str> {
+        let mut capabilities = vec!["json", "binary", "quoting", "transactions", "scripting", "payloads", "reserve", "consume", "subscribe", "monitor", "seq"];
+        if self.requires_auth() {
+            capabilities.push("auth");
+        }
+        if self.auth.read().unwrap().acl.is_some() {
+            capabilities.push("acl");
+        }
+        if self.cluster.is_some() {
+            // Scripts are refused by cluster nodes
+            capabilities.retain(|&capability| capability != "scripting");
+            capabilities.push("cluster");
+        }
+        capabilities
+    }
+
+    // Checks AUTH credentials: a password alone against --requirepass, granting every command, or a
+    // user and password against the ACL file
+    fn authenticate(&self, user: Option<&str>, password: &str) -> Result<Access, String> {
+        let auth = self.auth.read().unwrap();
+        match (user, &auth.requirepass, &auth.acl) {
+            (None, Some(requirepass), _) if acl::passwords_match(requirepass, password) => Ok(None),
+            (None, Some(_), _) => Err("Invalid password".to_string()),
+            (None, None, _) => Err("AUTH called without a password configured".to_string()),
+            (Some(user), _, Some(acl)) => acl.authenticate(user, password).map(Some).ok_or_else(|| "Invalid username or password".to_string()),
+            (Some(_), _, None) => Err("AUTH with a username requires --acl-file".to_string()),
+        }
+    }
+
+    // The ACL user named by the certificate a client authenticated with, for --tls-cert-users
+    fn certificate_user(&self, name: &str) -> Option<Arc<acl::User>> {
+        self.auth.read().unwrap().acl.as_ref().and_then(|acl| acl.user(name))
+    }
+
+    // Checks the token of an HTTP or gRPC request: the --requirepass password or "<user>:<password>"
+    // of an ACL user
+    fn authenticate_token(&self, token: Option<&str>) -> Result<Access, String> {
+        if !self.requires_auth() {
+            return Ok(None);
+        }
+        let token = token.ok_or_else(|| "Authentication required".to_string())?;
+        self.authenticate(None, token)
+            .or_else(|e| token.split_once(':').map_or(Err(e), |(user, password)| self.authenticate(Some(user), password)))
+    }
+}
+
+// State kept for each client connection, whichever transport it arrives over
+struct Session {
+    authenticated: bool,
+    // The commands the client may run once authenticated
+    access: Access,
+    protocol: Protocol,
+    // The protocol version replies follow, see PROTOCOL_VERSION
+    version: u32,
+    // Queue events pushed to the client, once subscribed
+    events: Option<broadcast::Receiver<QueueEvent<String>>>,
+    // Items the client will still accept while consuming, None when not consuming
+    credit: Option<usize>,
+    // The client's entry in CLIENT LIST
+    client: Option<Arc<Client>>,
+    // Commands queued since MULTI, None outside a transaction
+    transaction: Option<Vec<Command>>,
+    // Commands run by every client, while monitoring
+    monitor: Option<broadcast::Receiver<Response>>,
+    // The requests read so far, numbering each one in the debug log and, with SEQ ON, in its reply
+    requests: u64,
+    numbered: bool,
+    // The item BNEXT popped, with its score and data, until its reply is sent
+    popped: Option<(String, i64, Option<String>)>,
+}
+
+impl Session {
+    fn new(config: &ServerConfig) -> Self {
+        let version = if config.legacy_replies { 1 } else { PROTOCOL_VERSION };
+        Self { authenticated: !config.requires_auth(), access: None, protocol: Protocol::default(), version, events: None, credit: None, client: None, transaction: None, monitor: None, requests: 0, numbered: false, popped: None }
+    }
+
+    // Settles the item BNEXT popped once its reply was sent, or failed to be: an item whose reply
+    // didn't reach the client goes back on the queue with its score and data
+    fn reply_sent(&mut self, server: &Server, sent: bool) {
+        let Some((item, score, data)) = self.popped.take() else {
+            return;
+        };
+        if !sent && server.pqueue.insert_if_absent(item.clone(), score) {
+            debug!(item, "putting back the item of an unsent reply");
+            if let Some(data) = data {
+                server.payloads.set(item, data);
+            }
+        }
+    }
+
+    // Waits for the next message to push to the client: a queue event once subscribed, a command run
+    // while monitoring, or a popped item while consuming with credit left. Nothing is pushed before
+    // the session has authenticated.
+    async fn next_push(&mut self, server: &Server) -> Response {
+        if !self.authenticated {
+            return std::future::pending().await;
+        }
+        let consuming = self.credit.is_some_and(|credit| credit > 0) && !server.read_only.load(Ordering::Relaxed);
+        tokio::select! {
+            event = next_event(&mut self.events, server.config.notify_events) => event,
+            monitored = next_monitored(&mut self.monitor) => monitored,
+            (item, score) = server.pqueue.next_with_score_async(), if consuming => {
+                self.credit = self.credit.map(|credit| credit - 1);
+                let data = server.payloads.take(&item);
+                Response::Consumed { item, score, data }
+            },
+        }
+    }
+}
+
+// Waits for the next queue event of the enabled kinds, which never comes without a subscription
+async fn next_event(events: &mut Option<broadcast::Receiver<QueueEvent<String>>>, notify_events: NotifyEvents) -> Response {
+    let Some(events) = events else {
+        return std::future::pending().await;
+    };
     loop {
-        let (socket, _) = listener.accept().await.unwrap();
-        let pqueue_clone = pqueue.clone();
+        match events.recv().await {
+            Ok(event) => match notify_events.push(event) {
+                Some(push) => return push,
+                None => continue,
+            },
+            Err(RecvError::Lagged(missed)) => return Response::Lagged(missed),
+            // The queue outlives every session, so its events never close
+            Err(RecvError::Closed) => return std::future::pending().await,
+        }
+    }
+}
+
+// Waits for the next command run by any client, which never comes while not monitoring
+async fn next_monitored(monitor: &mut Option<broadcast::Receiver<Response>>) -> Response {
+    let Some(monitor) = monitor else {
+        return std::future::pending().await;
+    };
+    match monitor.recv().await {
+        Ok(monitored) => monitored,
+        Err(RecvError::Lagged(missed)) => Response::Lagged(missed),
+        // The server outlives every session, so the monitor never closes
+        Err(RecvError::Closed) => std::future::pending().await,
+    }
+}
+
+
+// Serves the connections made to one of the listeners
+async fn accept(listener: TcpListener, server: Arc<Server>) {
+    loop {
+        let (socket, address) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                error!("Failed to accept a connection: {}", e);
+                continue;
+            }
+        };
+        let server = server.clone();
 
         tokio::spawn(async move {
-            handle_connection(socket, pqueue_clone, debug).await;
+            let Some(tls) = &server.config.tls else {
+                return handle_connection(socket, address.to_string(), "tcp", None, server.clone(), Uuid::new_v4()).await;
+            };
+            match tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, tls.acceptor.accept(socket)).await {
+                Ok(Ok(stream)) => {
+                    let user = tls.cert_users.then(|| tls::peer_common_name(&stream)).flatten();
+                    handle_connection(stream, address.to_string(), "tls", user, server.clone(), Uuid::new_v4()).await
+                },
+                Ok(Err(e)) => debug!("TLS handshake with {} failed: {}", address, e),
+                Err(_) => debug!("TLS handshake with {} timed out", address),
+            }
         });
     }
 }
 
+// Binds a Unix socket at path, first removing a socket left there by a server that didn't shut down
+// cleanly, though not any other kind of file
+#[cfg(unix)]
+fn bind_unix(path: &str) -> std::io::Result<tokio::net::UnixListener> {
+    use std::os::unix::fs::FileTypeExt as _;
+    if std::fs::symlink_metadata(path).is_ok_and(|metadata| metadata.file_type().is_socket()) {
+        std::fs::remove_file(path)?;
+    }
+    tokio::net::UnixListener::bind(path)
+}
 
-async fn handle_connection(mut socket: TcpStream, pqueue: Arc<PQueue<String>>, debug: bool) {
-    let client_id = Uuid::new_v4();
-    if debug { println!("[{}] client connected", client_id)}
+// Serves the connections made to the Unix socket at path, in the clear as they never leave the host
+#[cfg(unix)]
+async fn accept_unix(listener: tokio::net::UnixListener, path: String, server: Arc<Server>) {
+    loop {
+        match listener.accept().await {
+            Ok((socket, _)) => {
+                tokio::spawn(handle_connection(socket, path.clone(), "unix", None, server.clone(), Uuid::new_v4()));
+            },
+            Err(e) => error!("Failed to accept a connection: {}", e),
+        }
+    }
+}
+
+// Serves a client over socket, arriving over the transport ("tcp", "tls" or "unix") from address,
+// authenticated as the ACL user named by its certificate, if any
+#[tracing::instrument(name = "connection", skip_all, fields(client_id = %id))]
+async fn handle_connection<S>(mut socket: S, address: String, transport: &'static str, certificate_user: Option<String>, server: Arc<Server>, id: Uuid)
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut session = Session::new(&server.config);
+    if let Some(name) = certificate_user {
+        match server.config.certificate_user(&name) {
+            Some(user) => {
+                session.authenticated = true;
+                session.access = Some(user);
+            },
+            None => debug!("no ACL user named {} by the client certificate", name),
+        }
+    }
+    let Some(_client) = server.metrics.client_connected(server.config.max_clients()) else {
+        warn!("refusing client, max clients reached");
+        let _ = socket.write_all(&session.protocol.render(&Response::Error(MAX_CLIENTS_ERROR.to_string()))).await;
+        return;
+    };
+    let client = server.clients.register(id, address, transport);
+    session.client = Some(client.clone());
+    debug!("client connected");
+    if server.config.greeting {
+        let greeting = Response::Line(format!("PQUEUE {} {}", env!("CARGO_PKG_VERSION"), session.version));
+        if let Err(e) = socket.write_all(&session.protocol.render(&greeting)).await {
+            warn!("Failed to write to socket: {}", e);
+            return;
+        }
+    }
+    let (reader, writer) = tokio::io::split(socket);
+    let mut reader = BufReader::with_capacity(server.config.read_buffer_size, reader);
+    // Replies are buffered so those to pipelined requests go out in one write, sent once no request is
+    // ready to be served, or sooner as --flush-interval and --flush-responses allow
+    let mut writer = BufWriter::new(writer);
+    let mut unflushed = 0;
+    let mut flush_at = None;
+    let mut last_request = Instant::now();
     let mut buffer = Vec::new();
-    let mut char_buffer = [0; 1];
+    let mut skip = Skip::default();
 
     loop {
-        // Read one byte (character) at a time
-        match socket.read_exact(&mut char_buffer).await {
-            Ok(_) => {
-                // Check for CRLF
-                if char_buffer == [b'\n'] && buffer.last() == Some(&b'\r') {
-                    // Remove the last character (CR)
-                    buffer.pop();
-
-                    // Convert buffer to string
-                    let command_string = String::from_utf8_lossy(&buffer);
-
-                    if debug { println!("[{}] rcv: {}", client_id, &command_string); }
+        let protocol = session.protocol;
+        let limits = server.config.limits();
+        let flush_due = async {
+            match flush_at {
+                Some(deadline) => tokio::time::sleep_until(deadline).await,
+                None => std::future::pending().await,
+            }
+        };
+        // Clients waiting for pushes are expected to go quiet
+        let idle_timeout = server.config.idle_timeout.load(Ordering::Relaxed);
+        let awaits_pushes = session.events.is_some() || session.monitor.is_some() || session.credit.is_some();
+        let idle_at = (idle_timeout > 0 && !awaits_pushes).then(|| last_request.checked_add(Duration::from_secs(idle_timeout))).flatten();
+        let idle = async {
+            match idle_at {
+                Some(deadline) => tokio::time::sleep_until(deadline.into()).await,
+                None => std::future::pending().await,
+            }
+        };
+        // The number of the request replied to, None for pushes
+        let mut seq = None;
+        // Biased so requests that are ready are served before the replies waiting on them are sent
+        let result = tokio::select! {
+            biased;
+            request = read_request(&mut reader, protocol, limits.max_request(protocol), &mut buffer, &mut skip) => match request {
+                Ok(Read::Request) => {
+                    session.requests += 1;
+                    seq = Some(session.requests);
+                    debug!(seq = session.requests, request = %String::from_utf8_lossy(&buffer), "rcv");
+                    last_request = Instant::now();
                     // Process the command
-                    let command = Command::from(command_string.as_ref());
-                    let result = process_command(command, &pqueue);
+                    let command = session.protocol.parse(&buffer, &server.config.command_names);
+                    buffer.clear();
+                    if !command.blocks() {
+                        execute(command, &server, &mut session).await
+                    } else {
+                        // A blocking command may not reply for a while, so the replies before it go out first
+                        if unflushed > 0 {
+                            if let Err(e) = writer.flush().await {
+                                warn!("Failed to write to socket: {}", e);
+                                return;
+                            }
+                            unflushed = 0;
+                            flush_at = None;
+                        }
+                        // Nothing is taken off the queue until a blocking command completes, so one
+                        // given up on for the client going away loses nothing
+                        tokio::select! {
+                            response = execute(command, &server, &mut session) => response,
+                            _ = hung_up(&mut reader) => {
+                                debug!("client disconnected");
+                                return;
+                            },
+                            _ = client.killed() => {
+                                debug!("client killed");
+                                return;
+                            },
+                        }
+                    }
+                }
+                Ok(Read::Oversized) if !limits.disconnect => {
+                    session.requests += 1;
+                    seq = Some(session.requests);
+                    limits.too_long(protocol)
+                },
+                Ok(Read::Oversized) => {
+                    warn!("disconnecting client, request over the limit");
+                    let _ = writer.write_all(&protocol.render(&limits.too_long(protocol))).await;
+                    let _ = writer.flush().await;
+                    return;
+                }
+                Ok(Read::Closed) | Err(_) => {
+                    debug!("client disconnected");
+                    // The client may only have stopped sending, and still read the replies
+                    let _ = writer.flush().await;
+                    return;
+                }
+            },
+            _ = flush_due => {
+                if let Err(e) = writer.flush().await {
+                    warn!("Failed to write to socket: {}", e);
+                    return;
+                }
+                unflushed = 0;
+                flush_at = None;
+                continue;
+            },
+            _ = idle => {
+                debug!("disconnecting idle client");
+                let _ = writer.flush().await;
+                return;
+            },
+            push = session.next_push(&server) => push,
+            _ = client.killed() => {
+                debug!("client killed");
+                return;
+            },
+        };
 
-                    let resp = result.to_string();
+        let resp = match seq {
+            Some(seq) if session.numbered => session.protocol.render_numbered(seq, &result),
+            _ => session.protocol.render(&result),
+        };
 
-                    if debug { println!("[{}]snd: {}", client_id, &resp); }
+        match seq {
+            Some(seq) => debug!(seq, response = %String::from_utf8_lossy(&resp), "snd"),
+            None => debug!(response = %String::from_utf8_lossy(&resp), "push"),
+        }
 
-                    // Send response
-                    if let Err(e) = socket.write_all(resp.as_bytes()).await {
-                        println!("[{}] Failed to write to socket: {}", client_id, e);
-                        return;
-                    }
+        // Send response, at once for an item popped by BNEXT so it can be put back if that fails
+        unflushed += 1;
+        let flush = unflushed >= server.config.flush_responses || session.popped.is_some();
+        let sent = match writer.write_all(&resp).await {
+            Ok(()) if flush => writer.flush().await,
+            result => result,
+        };
+        session.reply_sent(&server, sent.is_ok());
+        if let Err(e) = sent {
+            warn!("Failed to write to socket: {}", e);
+            return;
+        }
+        if flush {
+            unflushed = 0;
+            flush_at = None;
+        } else if flush_at.is_none() {
+            flush_at = Some(tokio::time::Instant::now() + server.config.flush_interval);
+        }
+    }
+}
 
-                    // Clear buffer for next command
-                    buffer.clear();
-                } else {
-                    // Not CRLF, keep collecting characters
-                    buffer.push(char_buffer[0]);
-                }
+// The outcome of reading a request
+enum Read {
+    Request,
+    // The request outgrew the limit and is being skipped
+    Oversized,
+    Closed,
+}
+
+// What is left of an oversized request to skip before reading the next one
+#[derive(Default)]
+enum Skip {
+    #[default]
+    Nothing,
+    // Up to the end of the line
+    Line,
+    Bytes(usize),
+}
+
+// Resolves once the client hangs up while nothing it sent is left to read, such as while a blocking
+// command waits. With requests left to read it never does, as the hang-up is only seen past them.
+async fn hung_up<R>(reader: &mut R)
+where
+    R: AsyncBufRead + Unpin,
+{
+    match reader.fill_buf().await {
+        Ok(buffered) if !buffered.is_empty() => std::future::pending().await,
+        _ => {},
+    }
+}
+
+// Reads the next request into buffer: a line without its line ending (CRLF or a bare LF), or the
+// body of a binary frame, as long as it is no longer than limit. Bytes read so far are kept in buffer
+// and what is left of an oversized request in skip, so a read interrupted to push an event resumes
+// where it left off; the caller clears buffer once it has taken the request.
+async fn read_request<R>(reader: &mut R, protocol: Protocol, limit: usize, buffer: &mut Vec<u8>, skip: &mut Skip) -> std::io::Result<Read>
+where
+    R: AsyncBufRead + Unpin,
+{
+    if !skip_oversized(reader, skip).await? {
+        return Ok(Read::Closed);
+    }
+    if protocol == Protocol::Binary {
+        if !fill_to(reader, buffer, 4).await? {
+            return Ok(Read::Closed);
+        }
+        let len = u32::from_be_bytes(buffer[..4].try_into().unwrap()) as usize;
+        if len > limit {
+            buffer.clear();
+            *skip = Skip::Bytes(len);
+            return Ok(Read::Oversized);
+        }
+        if !fill_to(reader, buffer, 4 + len).await? {
+            return Ok(Read::Closed);
+        }
+        buffer.drain(..4);
+        return Ok(Read::Request);
+    }
+    loop {
+        let available = reader.fill_buf().await?;
+        // A line cut off by the client disconnecting is dropped
+        if available.is_empty() {
+            return Ok(Read::Closed);
+        }
+        let (taken, complete) = match available.iter().position(|&byte| byte == b'\n') {
+            Some(end) => (end + 1, true),
+            None => (available.len(), false),
+        };
+        buffer.extend_from_slice(&available[..taken]);
+        reader.consume(taken);
+        if complete {
+            buffer.pop();
+            if buffer.last() == Some(&b'\r') {
+                buffer.pop();
             }
-            Err(_) => {
-                if debug { println!("[{}] client disconnected", client_id); }
-                return;
+            if buffer.len() > limit {
+                buffer.clear();
+                return Ok(Read::Oversized);
             }
+            return Ok(Read::Request);
+        }
+        if buffer.len() > limit {
+            buffer.clear();
+            *skip = Skip::Line;
+            return Ok(Read::Oversized);
         }
     }
 }
 
-fn process_command(command: Command, pqueue: &Arc<PQueue<String>>) -> Response {
-    match command {
-        Command::Update { item_id, value } => {
-            pqueue.update(item_id.into(), value);
+// Discards what is left of an oversized request, returning false if the client disconnects first
+async fn skip_oversized<R>(reader: &mut R, skip: &mut Skip) -> std::io::Result<bool>
+where
+    R: AsyncBufRead + Unpin,
+{
+    loop {
+        let left = match skip {
+            Skip::Nothing => return Ok(true),
+            Skip::Line => usize::MAX,
+            Skip::Bytes(left) => *left,
+        };
+        if left == 0 {
+            *skip = Skip::Nothing;
+            continue;
+        }
+        let available = reader.fill_buf().await?;
+        if available.is_empty() {
+            return Ok(false);
+        }
+        match available.iter().position(|&byte| byte == b'\n') {
+            Some(end) if matches!(skip, Skip::Line) => {
+                reader.consume(end + 1);
+                *skip = Skip::Nothing;
+            }
+            _ => {
+                let taken = available.len().min(left);
+                reader.consume(taken);
+                if let Skip::Bytes(left) = skip {
+                    *left -= taken;
+                }
+            }
+        }
+    }
+}
+
+// Reads into buffer until it holds len bytes, returning false if the client disconnects first
+async fn fill_to<R>(reader: &mut R, buffer: &mut Vec<u8>, len: usize) -> std::io::Result<bool>
+where
+    R: AsyncBufRead + Unpin,
+{
+    while buffer.len() < len {
+        let available = reader.fill_buf().await?;
+        if available.is_empty() {
+            return Ok(false);
+        }
+        let taken = available.len().min(len - buffer.len());
+        buffer.extend_from_slice(&available[..taken]);
+        reader.consume(taken);
+    }
+    Ok(true)
+}
+
+// Runs a command for a client session, handling the commands that act on the session itself, and
+// records its latency and, when slow, logs it to the slowlog. BNEXT waiting for an item isn't slow,
+// so it is left out of the slowlog.
+async fn execute(command: Command, server: &Server, session: &mut Session) -> Response {
+    let name = command.name();
+    let logged = (!command.blocks()).then(|| command.clone());
+    let started = Instant::now();
+    if let Some(client) = &session.client {
+        client.record_command(name);
+    }
+    if server.monitor.receiver_count() > 0 {
+        let _ = server.monitor.send(Response::Monitored {
+            timestamp: chrono::Utc::now().timestamp_micros() as f64 / 1e6,
+            client: session.client.as_ref().map_or("-".to_string(), |client| client.id.to_string()),
+            command: command.to_string(),
+        });
+    }
+    let response = match command {
+        Command::Auth { user, password } => match server.config.authenticate(user.as_deref(), &password) {
+            Ok(access) => {
+                session.authenticated = true;
+                session.access = access;
+                Response::Ok
+            },
+            Err(e) => Response::Error(e),
+        },
+        Command::Protocol { protocol } => {
+            session.protocol = protocol;
+            Response::Ok
+        },
+        Command::Hello { version: Some(version) } if !(1..=PROTOCOL_VERSION).contains(&version) => {
+            Response::Error(format!("Unsupported protocol version {}, this server speaks 1 to {}", version, PROTOCOL_VERSION))
+        },
+        Command::Hello { version } => {
+            session.version = version.unwrap_or(session.version);
+            Response::Hello { protocol: session.protocol, capabilities: server.config.capabilities() }
+        },
+        Command::Seq { enabled } => {
+            session.numbered = enabled;
+            Response::Ok
+        },
+        Command::Ping => Response::Line("PONG".to_string()),
+        _ if !session.authenticated => Response::Error("Authentication required".to_string()),
+        Command::Help | Command::Error { .. } => process_command(command, server).await,
+        _ if !acl::allows(&session.access, name) => Response::Error(format!("No permission to run {}", name)),
+        _ if server.refuses(name).is_some() => Response::Error(server.refuses(name).unwrap().to_string()),
+        _ if server.config.limits().rejects(&command) => Response::Error(format!("Item longer than the limit of {} bytes", server.config.limits().max_item_len)),
+        Command::Multi => match session.transaction {
+            Some(_) => Response::Error("MULTI calls can not be nested".to_string()),
+            None => {
+                session.transaction = Some(Vec::new());
+                Response::Ok
+            },
+        },
+        Command::Exec => match session.transaction.take() {
+            Some(commands) => Response::Multi(exec(commands, server)),
+            None => Response::Error("EXEC without MULTI".to_string()),
+        },
+        Command::Discard => match session.transaction.take() {
+            Some(_) => Response::Ok,
+            None => Response::Error("DISCARD without MULTI".to_string()),
+        },
+        _ if session.transaction.is_some() && !matches!(command, Command::Update { .. } | Command::Remove { .. }) => {
+            Response::Error(format!("{} can not be used in MULTI, only UPDATE and REMOVE", name))
+        },
+        Command::Subscribe => {
+            session.events.get_or_insert_with(|| server.pqueue.subscribe());
+            Response::Ok
+        },
+        Command::Unsubscribe => {
+            session.events = None;
+            Response::Ok
+        },
+        Command::Consume { credit } => {
+            session.credit = Some(credit);
+            Response::Ok
+        },
+        Command::Credit { count } => match &mut session.credit {
+            Some(credit) => {
+                *credit = credit.saturating_add(count);
+                Response::Count(*credit)
+            },
+            None => Response::Error("Not consuming, see CONSUME".to_string()),
+        },
+        Command::ConsumeStop => {
+            session.credit = None;
+            Response::Ok
+        },
+        Command::Monitor => {
+            session.monitor.get_or_insert_with(|| server.monitor.subscribe());
             Response::Ok
         },
+        Command::MonitorStop => {
+            session.monitor = None;
+            Response::Ok
+        },
+        Command::BlockingNext { timeout } => {
+            let entry = if timeout.is_zero() {
+                Some(server.pqueue.next_with_score_async().await)
+            } else {
+                tokio::time::timeout(timeout, server.pqueue.next_with_score_async()).await.ok()
+            };
+            match entry {
+                Some((item, score)) => {
+                    let data = server.payloads.take(&item);
+                    session.popped = Some((item.clone(), score, data.clone()));
+                    match data {
+                        Some(data) => Response::ItemData { item, data },
+                        None => Response::Item(item),
+                    }
+                },
+                None => Response::Empty,
+            }
+        },
+        // Commands for an item another cluster node owns are redirected there
+        command => match server.config.cluster.as_ref().zip(command.item()).and_then(|(cluster, item)| cluster.redirect(item)) {
+            Some(redirect) => Response::Error(redirect),
+            None => match &mut session.transaction {
+                Some(queued) => {
+                    queued.push(command);
+                    Response::Queued
+                },
+                None => process_command(command, server).await,
+            },
+        },
+    };
+    let response = response.for_version(session.version);
+    let elapsed = started.elapsed();
+    server.metrics.record_command(name, elapsed, matches!(response, Response::Error(_)));
+    if let Some(command) = logged {
+        server.slowlog.record(command, elapsed);
+    }
+    response
+}
+
+// Applies the commands queued in a transaction under a single lock, replying to each in order
+fn exec(commands: Vec<Command>, server: &Server) -> Vec<Response> {
+    server.pqueue.transaction(|tx| commands.into_iter().map(|command| match command {
+        Command::Update { item_id, value, data } => update(tx, server, item_id, value, data),
+        Command::Remove { item_id } => {
+            server.payloads.take(&item_id);
+            tx.remove(&item_id).map_or(Response::NotFound, Response::Score)
+        },
+        command => Response::Error(format!("{} can not be used in MULTI", command.name())),
+    }).collect())
+}
+
+// Updates the item's score, attaching data if given. An item added without data is cleared of any
+// left over from before it was added.
+fn update<S: BuildHasher>(tx: &mut Transaction<'_, String, S>, server: &Server, item_id: String, value: i64, data: Option<String>) -> Response {
+    match tx.update(item_id.clone(), value) {
+        Ok((previous, score)) => {
+            match data {
+                Some(data) if score.is_some() => server.payloads.set(item_id, data),
+                None if previous.is_none() && score.is_some() => {
+                    server.payloads.take(&item_id);
+                },
+                _ => {},
+            }
+            Response::Updated(score)
+        },
+        Err(e) => Response::Error(e.to_string()),
+    }
+}
+
+// Replies with a popped item, along with its data if it had any
+fn popped(server: &Server, item: String) -> Response {
+    match server.payloads.take(&item) {
+        Some(data) => Response::ItemData { item, data },
+        None => Response::Item(item),
+    }
+}
+
+async fn process_command(command: Command, server: &Server) -> Response {
+    let pqueue = &server.pqueue;
+    match command {
+        Command::SetScore { item_id, score } => match pqueue.set_score(item_id, score) {
+            Ok(_) => Response::Ok,
+            Err(e) => Response::Error(e.to_string()),
+        },
+        // Updates of items another cluster node owns are answered with where to send them instead
+        Command::MultiUpdate { updates } => {
+            let redirects: Vec<_> = updates.iter()
+                .map(|(item_id, _)| server.config.cluster.as_ref().and_then(|cluster| cluster.redirect(item_id)))
+                .collect();
+            let local = updates.into_iter().zip(&redirects)
+                .filter(|(_, redirect)| redirect.is_none())
+                .map(|((item_id, value), _)| Command::Update { item_id, value, data: None })
+                .collect();
+            let mut applied = exec(local, server).into_iter();
+            Response::Multi(redirects.into_iter().map(|redirect| redirect.map_or_else(|| applied.next().unwrap(), Response::Error)).collect())
+        },
+        Command::Update { item_id, value, data } => pqueue.transaction(|tx| update(tx, server, item_id, value, data)),
         Command::Next => {
-            pqueue.next().map_or(Response::Item("-1".to_string()), |item| Response::Item(item))
+            pqueue.next().map_or(Response::Empty, |item| popped(server, item))
+        },
+        Command::NextBatch { count } => {
+            let items = server.payloads.take_each(pqueue.next_batch(count));
+            // Replied as before when none of the items had data
+            match items.iter().any(|(_, data)| data.is_some()) {
+                true => Response::ItemsData(items),
+                false => Response::Items(items.into_iter().map(|(item, _)| item).collect()),
+            }
+        },
+        Command::WaitEmpty { timeout } => {
+            let deadline = match timeout.is_zero() {
+                true => None,
+                false => match Instant::now().checked_add(timeout) {
+                    Some(deadline) => Some(deadline),
+                    None => return Response::Error("Invalid timeout for WAITEMPTY".to_string()),
+                },
+            };
+            loop {
+                let left = pqueue.stats().items as usize + server.leases.len() + server.delayed.len();
+                if left == 0 || deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                    break Response::Count(left);
+                }
+                let wait = deadline.map_or(EMPTY_CHECK_INTERVAL, |deadline| EMPTY_CHECK_INTERVAL.min(deadline.saturating_duration_since(Instant::now())));
+                tokio::time::sleep(wait).await;
+            }
         },
         Command::Peek => {
-            pqueue.peek().map_or(Response::Item("-1".to_string()), |item| Response::Item(item))
+            pqueue.peek().map_or(Response::Empty, Response::Item)
+        },
+        Command::PeekMany { count } => {
+            Response::Entries(pqueue.peek_n(count))
+        },
+        Command::ScoreRange { min, max, count } => {
+            Response::Entries(pqueue.score_range(min, max, count.unwrap_or(usize::MAX)))
+        },
+        Command::Scan { cursor, pattern, count } => {
+            let (cursor, mut entries) = pqueue.scan(cursor, count);
+            if let Some(pattern) = pattern {
+                entries.retain(|(item, _)| glob::matches(&pattern, item));
+            }
+            Response::Scanned { cursor, entries }
+        },
+        Command::NextScore => {
+            pqueue.next_with_score().map_or(Response::Empty, |(item, score)| match server.payloads.take(&item) {
+                Some(data) => Response::EntryData { item, score, data },
+                None => Response::Entry(item, score),
+            })
+        },
+        Command::PeekScore => {
+            pqueue.peek_with_score().map_or(Response::Empty, |(item, score)| Response::Entry(item, score))
+        },
+        Command::Reserve { timeout } => match server.leases.reserve(pqueue, &server.payloads, timeout) {
+            Ok(Some(leases::Reservation { token, item, score, data })) => Response::Reserved { token: token.to_string(), item, score, data },
+            Ok(None) => Response::Empty,
+            Err(e) => Response::Error(e),
+        },
+        Command::Ack { token } => {
+            match token.parse() {
+                Ok(token) if server.leases.ack(&token) => Response::Ok,
+                _ => Response::Error("No such reservation".to_string()),
+            }
+        },
+        Command::Nack { token } => {
+            match token.parse() {
+                Ok(token) if server.leases.nack(pqueue, &server.payloads, &token) => Response::Ok,
+                _ => Response::Error("No such reservation".to_string()),
+            }
         },
         Command::Score { item_id } => {
-            pqueue.score(&item_id).map_or(Response::Score(-1), Response::Score)
+            pqueue.score(&item_id).map_or(Response::NotFound, Response::Score)
+        },
+        Command::Remove { item_id } => {
+            server.payloads.take(&item_id);
+            pqueue.remove(&item_id).map_or(Response::NotFound, Response::Score)
+        },
+        Command::SetData { item_id, data } => {
+            if pqueue.contains(&item_id) {
+                server.payloads.set(item_id, data);
+                Response::Ok
+            } else {
+                Response::NotFound
+            }
+        },
+        Command::GetData { item_id } => {
+            server.payloads.get(&item_id).map_or(Response::NotFound, Response::Item)
+        },
+        Command::Auth { .. } | Command::Protocol { .. } | Command::Hello { .. } | Command::Seq { .. } | Command::Ping | Command::Subscribe | Command::Unsubscribe
+            | Command::Consume { .. } | Command::Credit { .. } | Command::ConsumeStop | Command::Monitor | Command::MonitorStop | Command::Multi | Command::Exec | Command::Discard
+            | Command::BlockingNext { .. } => {
+            // Handled per connection, before commands are processed
+            Response::Ok
+        },
+        Command::Delay { item_id, value, delay } => {
+            if server.delayed.delay(item_id, value, delay) {
+                Response::Ok
+            } else {
+                Response::Error("Invalid delay for DELAY".to_string())
+            }
+        },
+        Command::Expire { item_id, seconds } => {
+            let ttl = chrono::Duration::seconds(seconds as i64);
+            Response::Count(pqueue.expire(&item_id, ttl) as usize)
+        },
+        Command::Ttl { item_id } => {
+            let ttl = match pqueue.item_info(&item_id) {
+                None => -2,
+                Some(info) => info.expires_at.map_or(-1, |expires_at| {
+                    // Rounded up, so an item only reports 0 seconds left once it has expired
+                    let left = expires_at - chrono::Utc::now().naive_utc();
+                    (left.num_milliseconds().max(0) + 999) / 1000
+                }),
+            };
+            Response::Score(ttl)
+        },
+        Command::Persist { item_id } => {
+            Response::Count(pqueue.persist(&item_id) as usize)
+        },
+        Command::Info { section } => {
+            info::report(server, section.as_deref()).map_or_else(Response::Error, Response::Info)
+        },
+        Command::ResetStats => {
+            pqueue.reset_stats();
+            Response::Ok
+        },
+        Command::Clear => {
+            server.payloads.clear();
+            Response::Count(pqueue.clear())
         },
-        Command::Info => {
-            Response::Stats(pqueue.stats())
+        Command::ReadOnly { enabled } => {
+            server.read_only.store(enabled, Ordering::Relaxed);
+            Response::Ok
+        },
+        Command::Drain { exit } => {
+            server.drain.start(exit);
+            Response::Ok
+        },
+        Command::DrainStop => {
+            server.drain.stop();
+            Response::Ok
+        },
+        Command::Save => {
+            match snapshot::save(server).await {
+                Ok(_) => Response::Ok,
+                Err(e) => Response::Error(e.to_string()),
+            }
+        },
+        Command::Eval { script } => {
+            scripting::eval(server, &script)
+        },
+        Command::Shutdown { save } => {
+            let saved = if save.unwrap_or(server.config.data_dir.is_some()) {
+                snapshot::save(server).await.map(|_| ())
+            } else {
+                Ok(())
+            };
+            match saved {
+                Ok(()) => {
+                    server.shutdown.notify_one();
+                    Response::Ok
+                },
+                Err(e) => Response::Error(format!("Failed to save before shutting down: {}", e)),
+            }
+        },
+        Command::ClusterNodes => match &server.config.cluster {
+            Some(cluster) => Response::Lines(cluster.describe()),
+            None => Response::Error("Cluster mode is not enabled".to_string()),
+        },
+        Command::ClusterNode { item_id } => match &server.config.cluster {
+            Some(cluster) => Response::Line(cluster.describe_owner(&item_id)),
+            None => Response::Error("Cluster mode is not enabled".to_string()),
+        },
+        Command::ClientList => {
+            Response::Lines(server.clients.list())
+        },
+        Command::ClientKill { id } => {
+            match id.parse() {
+                Ok(id) if server.clients.kill(&id) => Response::Ok,
+                _ => Response::Error("No such client".to_string()),
+            }
+        },
+        Command::ConfigGet { pattern } => {
+            Response::Lines(config::get(server, &pattern))
+        },
+        Command::ConfigSet { name, value } => match config::set(server, &name, &value) {
+            Ok(()) => Response::Ok,
+            Err(e) => Response::Error(e),
+        },
+        Command::Dump { item_id } => {
+            dump::dump(server, &item_id).map_or(Response::NotFound, Response::Item)
+        },
+        Command::Export { format } => {
+            Response::Exported { format, entries: pqueue.peek_n(usize::MAX) }
+        },
+        Command::Restore { item_id, dump, replace } => match dump::restore(server, item_id, &dump, replace) {
+            Ok(()) => Response::Ok,
+            Err(e) => Response::Error(e),
+        },
+        Command::MemoryUsage { item_id: None } => {
+            Response::Count(pqueue.memory_usage(String::capacity) + server.payloads.total_memory_usage())
+        },
+        Command::MemoryUsage { item_id: Some(item_id) } => match pqueue.item_memory_usage(&item_id, String::capacity) {
+            Some(bytes) => Response::Count(bytes + server.payloads.memory_usage(&item_id)),
+            None => Response::NotFound,
+        },
+        Command::Latency { command } => {
+            let mut latencies = server.metrics.latencies();
+            if let Some(command) = command {
+                latencies.retain(|latency| latency.command.eq_ignore_ascii_case(&command));
+            }
+            Response::Latency(latencies)
+        },
+        Command::SlowlogGet { count } => {
+            Response::SlowLog(server.slowlog.get(count))
+        },
+        Command::SlowlogLen => {
+            Response::Count(server.slowlog.len())
+        },
+        Command::SlowlogReset => {
+            server.slowlog.reset();
+            Response::Ok
         },
         Command::Error { msg } => {
             Response::Error(msg)
@@ -0,0 +1,79 @@
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::Duration;
+
+use pqueue::CapacityPolicy;
+use tracing::warn;
+
+use crate::Server;
+
+// How often the queue's footprint is estimated, which takes time in proportion to its length
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The bound set with --max-memory on the estimated bytes taken up by the queue and the data attached
+/// to its items. Past it, the policy either refuses commands adding items until the queue shrinks
+/// again, or evicts items until it is back under the bound. The estimate is refreshed every second, so
+/// the queue can overshoot the bound by what is added in between.
+pub struct MemoryLimit {
+    pub max_memory: usize,
+    pub policy: CapacityPolicy,
+    // The latest estimate
+    used: AtomicUsize,
+    // Whether commands adding items are refused, under the Reject policy
+    full: AtomicBool,
+}
+
+impl MemoryLimit {
+    pub fn new(max_memory: usize, policy: CapacityPolicy) -> Self {
+        Self { max_memory, policy, used: AtomicUsize::new(0), full: AtomicBool::new(false) }
+    }
+
+    pub fn used(&self) -> usize {
+        self.used.load(Ordering::Relaxed)
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.full.load(Ordering::Relaxed)
+    }
+
+    /// Keeps the estimate up to date, evicting items while over the bound unless the policy rejects
+    pub async fn run(&self, server: &Server) {
+        loop {
+            let mut used = estimate(server);
+            if used > self.max_memory && self.policy != CapacityPolicy::Reject {
+                // As many as should free the excess, given the average footprint of an item
+                let items = server.pqueue.stats().items.max(1) as usize;
+                let count = (used - self.max_memory).div_ceil((used / items).max(1));
+                for item in server.pqueue.evict(count, self.policy) {
+                    server.payloads.take(&item);
+                }
+                used = estimate(server);
+            }
+            let full = used > self.max_memory && self.policy == CapacityPolicy::Reject;
+            if full && !self.is_full() {
+                warn!("Over the memory limit of {} bytes, refusing new items", self.max_memory);
+            }
+            self.used.store(used, Ordering::Relaxed);
+            self.full.store(full, Ordering::Relaxed);
+            tokio::time::sleep(CHECK_INTERVAL).await;
+        }
+    }
+}
+
+fn estimate(server: &Server) -> usize {
+    server.pqueue.memory_usage(String::capacity) + server.payloads.total_memory_usage()
+}
+
+/// Parses a number of bytes, optionally followed by a unit: k, m or g (or kb, mb or gb), in powers of
+/// 1024
+pub fn parse_bytes(s: &str) -> Result<usize, String> {
+    let lower = s.to_ascii_lowercase();
+    let digits = lower.trim_end_matches(|c: char| c.is_ascii_alphabetic());
+    let multiplier: usize = match &lower[digits.len()..] {
+        "" | "b" => 1,
+        "k" | "kb" => 1 << 10,
+        "m" | "mb" => 1 << 20,
+        "g" | "gb" => 1 << 30,
+        unit => return Err(format!("unknown unit {}, expected k, m or g", unit)),
+    };
+    digits.parse::<usize>().ok().and_then(|n| n.checked_mul(multiplier)).ok_or_else(|| format!("invalid size {}", s))
+}
@@ -0,0 +1,217 @@
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use axum::{extract::State, http::header, response::IntoResponse, routing::get, Router};
+use tokio::net::TcpListener;
+
+use pqueue::PQueueStats;
+
+use crate::Server;
+
+// Upper bounds of the command latency histogram buckets, in seconds
+const LATENCY_BUCKETS: [f64; 12] = [0.00001, 0.00005, 0.0001, 0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0];
+
+/// Server metrics that aren't part of the queue's own stats
+#[derive(Default)]
+pub struct Metrics {
+    connected_clients: Arc<AtomicI64>,
+    commands: Mutex<BTreeMap<&'static str, Histogram>>,
+}
+
+// Latencies up to this many microseconds are kept exactly for percentiles, longer ones in 8 buckets
+// per power of two, so percentiles are reported within an eighth of the true latency
+const EXACT_MICROS: u64 = 16;
+
+#[derive(Default)]
+struct Histogram {
+    // Observations per bucket (not cumulative), with the last one past the largest bucket
+    buckets: [u64; LATENCY_BUCKETS.len() + 1],
+    count: u64,
+    sum: f64,
+    // Commands that replied with an error
+    errors: u64,
+    // Observations per fine grained bucket, see micros_bucket, for percentiles
+    micros: Vec<u64>,
+    max_micros: u64,
+}
+
+/// A command's calls, failed calls and the time they took in total
+#[derive(Clone, Debug)]
+pub struct CommandStats {
+    pub command: &'static str,
+    pub calls: u64,
+    pub errors: u64,
+    pub total: Duration,
+}
+
+/// A command's latency percentiles, in microseconds
+#[derive(Clone, Debug)]
+pub struct Latency {
+    pub command: &'static str,
+    pub calls: u64,
+    pub p50: u64,
+    pub p95: u64,
+    pub p99: u64,
+    pub max: u64,
+}
+
+impl Histogram {
+    fn percentile(&self, quantile: f64) -> u64 {
+        let rank = ((self.count as f64 * quantile).ceil() as u64).max(1);
+        let mut cumulative = 0;
+        for (bucket, observations) in self.micros.iter().enumerate() {
+            cumulative += observations;
+            if cumulative >= rank {
+                return micros_bucket_bound(bucket).min(self.max_micros);
+            }
+        }
+        self.max_micros
+    }
+}
+
+// The fine grained bucket holding a latency
+fn micros_bucket(micros: u64) -> usize {
+    if micros < EXACT_MICROS {
+        return micros as usize;
+    }
+    let power = 63 - micros.leading_zeros() as usize;
+    EXACT_MICROS as usize + (power - 4) * 8 + ((micros >> (power - 3)) & 7) as usize
+}
+
+// The largest latency in a fine grained bucket
+fn micros_bucket_bound(bucket: usize) -> u64 {
+    if bucket < EXACT_MICROS as usize {
+        return bucket as u64;
+    }
+    let power = 4 + (bucket - EXACT_MICROS as usize) / 8;
+    let step = (8 + (bucket - EXACT_MICROS as usize) % 8) as u64;
+    ((step + 1) << (power - 3)) - 1
+}
+
+/// Counts a client as connected until dropped
+pub struct ClientGuard {
+    connected_clients: Arc<AtomicI64>,
+}
+
+impl Drop for ClientGuard {
+    fn drop(&mut self) {
+        self.connected_clients.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Metrics {
+    /// Counts a client as connected, unless max_clients are connected already
+    pub fn client_connected(&self, max_clients: Option<usize>) -> Option<ClientGuard> {
+        let max_clients = max_clients.map_or(i64::MAX, |max| max as i64);
+        self.connected_clients
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |clients| (clients < max_clients).then_some(clients + 1))
+            .ok()?;
+        Some(ClientGuard { connected_clients: self.connected_clients.clone() })
+    }
+
+    pub fn connected_clients(&self) -> i64 {
+        self.connected_clients.load(Ordering::Relaxed)
+    }
+
+    /// The number of commands run since the server started
+    pub fn commands_processed(&self) -> u64 {
+        self.commands.lock().unwrap().values().map(|histogram| histogram.count).sum()
+    }
+
+    /// Records a command run, that failed when it replied with an error
+    pub fn record_command(&self, name: &'static str, elapsed: Duration, failed: bool) {
+        let secs = elapsed.as_secs_f64();
+        let mut commands = self.commands.lock().unwrap();
+        let histogram = commands.entry(name).or_default();
+        let bucket = LATENCY_BUCKETS.iter().position(|&bound| secs <= bound).unwrap_or(LATENCY_BUCKETS.len());
+        histogram.buckets[bucket] += 1;
+        histogram.count += 1;
+        histogram.sum += secs;
+        histogram.errors += failed as u64;
+        let micros = elapsed.as_micros().min(u64::MAX as u128) as u64;
+        let bucket = micros_bucket(micros);
+        if histogram.micros.len() <= bucket {
+            histogram.micros.resize(bucket + 1, 0);
+        }
+        histogram.micros[bucket] += 1;
+        histogram.max_micros = histogram.max_micros.max(micros);
+    }
+
+    /// Latency percentiles of every command run so far, by command name
+    pub fn latencies(&self) -> Vec<Latency> {
+        self.commands.lock().unwrap().iter().map(|(&command, histogram)| Latency {
+            command,
+            calls: histogram.count,
+            p50: histogram.percentile(0.5),
+            p95: histogram.percentile(0.95),
+            p99: histogram.percentile(0.99),
+            max: histogram.max_micros,
+        }).collect()
+    }
+
+    /// The calls of every command run so far, by command name
+    pub fn command_stats(&self) -> Vec<CommandStats> {
+        self.commands.lock().unwrap().iter().map(|(&command, histogram)| CommandStats {
+            command,
+            calls: histogram.count,
+            errors: histogram.errors,
+            total: Duration::from_secs_f64(histogram.sum),
+        }).collect()
+    }
+
+    /// Renders the metrics along with the queue's stats in the Prometheus text format
+    pub fn render(&self, stats: &PQueueStats) -> String {
+        let mut out = String::new();
+        let gauges = [
+            ("pqueue_items", "Items in the queue", stats.items as f64),
+            ("pqueue_pools", "Distinct scores in the queue", stats.pools as f64),
+            ("pqueue_uptime_seconds", "Time since the queue was created", stats.uptime.num_seconds() as f64),
+            ("pqueue_oldest_item_age_seconds", "Time since the oldest item in the queue was inserted", stats.oldest_item_age.map_or(0, |age| age.num_seconds()) as f64),
+            ("pqueue_connected_clients", "Connected clients", self.connected_clients.load(Ordering::Relaxed) as f64),
+        ];
+        for (name, help, value) in gauges {
+            let _ = write!(out, "# HELP {name} {help}\n# TYPE {name} gauge\n{name} {value}\n");
+        }
+        let _ = write!(out, "# HELP pqueue_updates_total Updates applied to the queue\n# TYPE pqueue_updates_total counter\npqueue_updates_total {}\n", stats.updates);
+        for (name, help, rates) in [
+            ("pqueue_enqueue_rate", "Updates per second over the window", &stats.enqueue_rate),
+            ("pqueue_dequeue_rate", "Pops per second over the window", &stats.dequeue_rate),
+        ] {
+            let _ = write!(out, "# HELP {name} {help}\n# TYPE {name} gauge\n");
+            for (window, rate) in [("1s", rates.last_1s), ("1m", rates.last_1m), ("5m", rates.last_5m)] {
+                let _ = writeln!(out, "{name}{{window=\"{window}\"}} {rate}");
+            }
+        }
+
+        let commands = self.commands.lock().unwrap();
+        out.push_str("# HELP pqueue_command_duration_seconds Time taken to run commands\n# TYPE pqueue_command_duration_seconds histogram\n");
+        for (command, histogram) in commands.iter() {
+            let mut cumulative = 0;
+            for (bound, observations) in LATENCY_BUCKETS.iter().zip(histogram.buckets) {
+                cumulative += observations;
+                let _ = writeln!(out, "pqueue_command_duration_seconds_bucket{{command=\"{command}\",le=\"{bound}\"}} {cumulative}");
+            }
+            let _ = writeln!(out, "pqueue_command_duration_seconds_bucket{{command=\"{command}\",le=\"+Inf\"}} {}", histogram.count);
+            let _ = writeln!(out, "pqueue_command_duration_seconds_sum{{command=\"{command}\"}} {}", histogram.sum);
+            let _ = writeln!(out, "pqueue_command_duration_seconds_count{{command=\"{command}\"}} {}", histogram.count);
+        }
+        out
+    }
+}
+
+/// Serves the metrics at /metrics on listener. Command latencies cover commands of the TCP and
+/// WebSocket protocols.
+pub async fn serve(listener: TcpListener, server: Arc<Server>) -> std::io::Result<()> {
+    let app = Router::new()
+        .route("/metrics", get(metrics))
+        .with_state(server);
+    axum::serve(listener, app).await
+}
+
+async fn metrics(State(server): State<Arc<Server>>) -> impl IntoResponse {
+    let body = server.metrics.render(&server.pqueue.stats());
+    ([(header::CONTENT_TYPE, "text/plain; version=0.0.4")], body)
+}
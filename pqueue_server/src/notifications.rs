@@ -0,0 +1,55 @@
+use std::str::FromStr;
+
+use pqueue::QueueEvent;
+
+use crate::protocol::Response;
+
+/// The kinds of queue events pushed to subscribed clients, chosen with --notify-events
+#[derive(Clone, Copy, Debug, Default)]
+pub struct NotifyEvents {
+    added: bool,
+    updated: bool,
+    popped: bool,
+    expired: bool,
+    removed: bool,
+}
+
+impl NotifyEvents {
+    // The names accepted by --notify-events
+    const KINDS: &'static [&'static str] = &["added", "updated", "popped", "expired", "removed"];
+
+    /// The push for a queue event, or None if its kind is not enabled
+    pub fn push(&self, event: QueueEvent<String>) -> Option<Response> {
+        let (kind, item, score) = match event {
+            QueueEvent::Updated { item, previous: None, score } if self.added => ("added", item, score),
+            QueueEvent::Updated { item, previous: Some(_), score } if self.updated => ("updated", item, score),
+            QueueEvent::Popped { item, score } if self.popped => ("popped", item, score),
+            QueueEvent::Expired { item, score } if self.expired => ("expired", item, score),
+            QueueEvent::Removed { item, score } if self.removed => ("removed", item, score),
+            QueueEvent::Cleared { count } if self.removed => return Some(Response::Cleared(count)),
+            _ => return None,
+        };
+        Some(Response::Event { kind, item: (*item).clone(), score })
+    }
+}
+
+impl FromStr for NotifyEvents {
+    type Err = String;
+
+    // Parses a comma separated list of event kinds, or "all"
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut events = NotifyEvents::default();
+        for kind in s.split(',').map(str::trim).filter(|kind| !kind.is_empty()) {
+            match kind.to_ascii_lowercase().as_str() {
+                "added" => events.added = true,
+                "updated" => events.updated = true,
+                "popped" => events.popped = true,
+                "expired" => events.expired = true,
+                "removed" => events.removed = true,
+                "all" => events = NotifyEvents { added: true, updated: true, popped: true, expired: true, removed: true },
+                _ => return Err(format!("unknown event {}, expected all or any of {}", kind, Self::KINDS.join(", "))),
+            }
+        }
+        Ok(events)
+    }
+}
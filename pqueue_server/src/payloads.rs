@@ -0,0 +1,147 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+
+use pqueue::QueueEvent;
+use tokio::sync::broadcast::{error::{RecvError, TryRecvError}, Receiver};
+use tracing::warn;
+
+use crate::Server;
+
+/// Opaque data attached to items with UPDATE or SETDATA, handed out along with the item when it is
+/// popped. A reserved item's data is held by its lease (see `Leases`) until it is acknowledged or put
+/// back. The data of items that leave the queue any other way, expiring, evicted or auto removed, is
+/// dropped by `run` as it follows the queue's events, and an item added again without data starts
+/// without any. Data is not part of snapshots.
+#[derive(Default)]
+pub struct Payloads {
+    data: Mutex<HashMap<String, String>>,
+}
+
+impl Payloads {
+    /// Attaches data to the item, replacing any it had
+    pub fn set(&self, item: String, data: String) {
+        self.data.lock().unwrap().insert(item, data);
+    }
+
+    pub fn get(&self, item: &str) -> Option<String> {
+        self.data.lock().unwrap().get(item).cloned()
+    }
+
+    /// Detaches and returns the item's data, for when the item leaves the queue
+    pub fn take(&self, item: &str) -> Option<String> {
+        self.data.lock().unwrap().remove(item)
+    }
+
+    /// Detaches the data of each of a batch of popped items, pairing every item with its data
+    pub fn take_each(&self, items: Vec<String>) -> Vec<(String, Option<String>)> {
+        let mut data = self.data.lock().unwrap();
+        items.into_iter().map(|item| {
+            let taken = data.remove(&item);
+            (item, taken)
+        }).collect()
+    }
+
+    /// Estimates the bytes the item's data takes up, 0 if it has none
+    pub fn memory_usage(&self, item: &str) -> usize {
+        self.data.lock().unwrap().get_key_value(item).map_or(0, |(item, data)| entry_size(item, data))
+    }
+
+    /// Estimates the bytes every item's data takes up
+    pub fn total_memory_usage(&self) -> usize {
+        self.data.lock().unwrap().iter().map(|(item, data)| entry_size(item, data)).sum()
+    }
+
+    pub fn clear(&self) {
+        self.data.lock().unwrap().clear();
+    }
+
+    /// Drops the data of items as they leave the queue, following its events, forever. Each batch of
+    /// events is handled under the queue's lock, so an item gone according to them but back in the
+    /// queue since, or popped by a command yet to take its data, keeps it.
+    pub async fn run(&self, server: &Server, mut events: Receiver<QueueEvent<String>>) {
+        loop {
+            let first = match events.recv().await {
+                Ok(event) => Some(event),
+                Err(RecvError::Lagged(missed)) => {
+                    warn!("Fell {} changes behind following the queue for item data, dropping the data of every item not in it", missed);
+                    None
+                },
+                Err(RecvError::Closed) => return,
+            };
+            server.pqueue.transaction(|tx| {
+                // Items that left the queue, and those popped, whose data their command takes
+                let mut gone = HashSet::new();
+                let mut popped = HashSet::new();
+                let mut lagged = first.is_none();
+                let mut next = first;
+                loop {
+                    match next {
+                        Some(QueueEvent::Removed { item, .. } | QueueEvent::Expired { item, .. }) => {
+                            gone.insert(item);
+                        },
+                        Some(QueueEvent::Popped { item, .. }) => {
+                            gone.remove(&item);
+                            popped.insert(item);
+                        },
+                        Some(QueueEvent::Updated { item, previous: None, .. }) => {
+                            gone.remove(&item);
+                        },
+                        _ => {},
+                    }
+                    next = match events.try_recv() {
+                        Ok(event) => Some(event),
+                        Err(TryRecvError::Lagged(_)) => {
+                            lagged = true;
+                            None
+                        },
+                        Err(_) => break,
+                    };
+                }
+                let mut data = self.data.lock().unwrap();
+                if lagged {
+                    data.retain(|item, _| tx.score(item).is_some() || popped.contains(item));
+                } else {
+                    for item in gone.iter().filter(|item| tx.score(item.as_str()).is_none()) {
+                        data.remove(item.as_str());
+                    }
+                }
+            });
+        }
+    }
+}
+
+// The bytes taken up by an entry's strings, headers included
+fn entry_size(item: &String, data: &String) -> usize {
+    2 * std::mem::size_of::<String>() + item.capacity() + data.capacity()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::Response;
+    use pqueue::PQueue;
+
+    #[test]
+    fn test_take_each_pops_batch_with_data() {
+        let pqueue = PQueue::<String>::new();
+        let payloads = Payloads::default();
+        pqueue.update("item1".to_string(), 3).unwrap();
+        pqueue.update("item2".to_string(), 2).unwrap();
+        pqueue.update("item3".to_string(), 1).unwrap();
+        payloads.set("item1".to_string(), "one".to_string());
+        payloads.set("item3".to_string(), "three".to_string());
+
+        let items = payloads.take_each(pqueue.next_batch(3));
+        assert_eq!(items, vec![
+            ("item1".to_string(), Some("one".to_string())),
+            ("item2".to_string(), None),
+            ("item3".to_string(), Some("three".to_string())),
+        ]);
+        assert_eq!(payloads.get("item1"), None);
+        assert_eq!(payloads.get("item3"), None);
+
+        let response = Response::ItemsData(items);
+        assert_eq!(response.to_string(), "*3\r\n+item1 one\r\n+item2\r\n+item3 three\r\n");
+        assert_eq!(response.to_json()["items"][2], serde_json::json!({ "item": "item3", "data": "three" }));
+    }
+}
@@ -1,51 +1,805 @@
 use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+use serde_json::{json, Value};
 
 use pqueue::PQueueStats;
 
+use crate::binary;
+use crate::info::InfoSection;
+use crate::load;
+use crate::metrics::Latency;
+use crate::rename::CommandNames;
+use crate::slowlog::SlowLogEntry;
+
 
 #[derive(Clone, Debug)]
 pub enum Command {
-    Update { item_id: String, value: i64 },
+    Update { item_id: String, value: i64, data: Option<String> },
+    SetScore { item_id: String, score: i64 },
+    // Many updates applied atomically
+    MultiUpdate { updates: Vec<(String, i64)> },
     Next,
+    NextBatch { count: usize },
+    BlockingNext { timeout: Duration },
+    // Waits for the queue to empty, forever with a zero timeout
+    WaitEmpty { timeout: Duration },
     Peek,
+    PeekMany { count: usize },
+    NextScore,
+    PeekScore,
+    ScoreRange { min: i64, max: i64, count: Option<usize> },
+    Scan { cursor: u64, pattern: Option<String>, count: usize },
+    Reserve { timeout: Duration },
+    Ack { token: String },
+    Nack { token: String },
     Score { item_id: String },
-    Info,
+    Remove { item_id: String },
+    SetData { item_id: String, data: String },
+    GetData { item_id: String },
+    Delay { item_id: String, value: i64, delay: Duration },
+    Expire { item_id: String, seconds: u64 },
+    Ttl { item_id: String },
+    Persist { item_id: String },
+    Info { section: Option<String> },
+    Dump { item_id: String },
+    // Every item with its score, in priority order
+    Export { format: ExportFormat },
+    Restore { item_id: String, dump: String, replace: bool },
+    // The item to report on is None for the whole queue
+    MemoryUsage { item_id: Option<String> },
+    ResetStats,
+    Auth { user: Option<String>, password: String },
+    Clear,
+    Protocol { protocol: Protocol },
+    Hello { version: Option<u32> },
+    // Numbers replies with the sequence number of the request they answer, or stops to
+    Seq { enabled: bool },
+    Ping,
+    Save,
+    ReadOnly { enabled: bool },
+    // Refuses new items, shutting down once the queue is empty if exit is set
+    Drain { exit: bool },
+    DrainStop,
+    // Save is None when neither SAVE nor NOSAVE was given
+    Shutdown { save: Option<bool> },
+    Eval { script: String },
+    Multi,
+    Exec,
+    Discard,
+    Subscribe,
+    Unsubscribe,
+    Consume { credit: usize },
+    Credit { count: usize },
+    ConsumeStop,
+    Monitor,
+    MonitorStop,
+    ClusterNodes,
+    ClusterNode { item_id: String },
+    ClientList,
+    ClientKill { id: String },
+    ConfigGet { pattern: String },
+    ConfigSet { name: String, value: String },
+    SlowlogGet { count: usize },
+    SlowlogLen,
+    SlowlogReset,
+    // The command to report on is None for every command
+    Latency { command: Option<String> },
     Error { msg: String },
     Help,
 }
 
 
-impl From<&str> for Command {
-    fn from(s: &str) -> Self {
-        let parts: Vec<&str> = s.split_whitespace().collect();
-        match parts.as_slice() {
-            [command, item_id, value] if command.eq_ignore_ascii_case("UPDATE") => {
+impl Command {
+    /// Parses a line of the text protocol, knowing commands by the names they go by on this server
+    pub fn from_text(s: &str, names: &CommandNames) -> Self {
+        // A script is taken as written, as its quotes are Lua's
+        if let Some((command, script)) = s.trim().split_once(char::is_whitespace) {
+            if names.renamed_from(command).unwrap_or(command).eq_ignore_ascii_case("EVAL") {
+                return Command::from_named_parts(&[command, script.trim_start()], names);
+            }
+        }
+        match split_args(s) {
+            Ok(args) => Command::from_named_parts(&args.iter().map(String::as_str).collect::<Vec<_>>(), names),
+            Err(msg) => Command::Error { msg },
+        }
+    }
+
+    /// Parses a command's name and arguments, where the name is the one the command goes by on this
+    /// server, see the rename module
+    pub fn from_named_parts(parts: &[&str], names: &CommandNames) -> Self {
+        if let Some((command, args)) = parts.split_first().and_then(|(name, args)| Some((names.renamed_from(name)?, args))) {
+            let mut parts = vec![command];
+            parts.extend_from_slice(args);
+            return Command::from_parts(&parts);
+        }
+        Command::from_parts(parts).unless_hidden(names)
+    }
+
+    /// The command, unless it only runs under a new name or not at all, when it is as unknown as a
+    /// misspelt one
+    pub fn unless_hidden(self, names: &CommandNames) -> Self {
+        if names.hides(self.name()) {
+            return Command::Error { msg: "Invalid command or arguments".to_string() };
+        }
+        self
+    }
+}
+
+// Splits a line of the text protocol into its arguments. Arguments are separated by whitespace, and
+// one holding whitespace is quoted: in double quotes, where \" \\ \n \r \t and \xHH are escapes, or in
+// single quotes, taken as is. Quotes only start an argument at its beginning.
+fn split_args(s: &str) -> Result<Vec<String>, String> {
+    let mut args = Vec::new();
+    let mut chars = s.chars().peekable();
+    loop {
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        let Some(&first) = chars.peek() else {
+            return Ok(args);
+        };
+        let mut arg = String::new();
+        if first == '"' || first == '\'' {
+            chars.next();
+            loop {
+                match chars.next() {
+                    None => return Err("Unbalanced quotes".to_string()),
+                    Some(c) if c == first => break,
+                    Some('\\') if first == '"' => arg.push(unescape(&mut chars)?),
+                    Some(c) => arg.push(c),
+                }
+            }
+            if chars.peek().is_some_and(|c| !c.is_whitespace()) {
+                return Err("Closing quote must be followed by a space".to_string());
+            }
+        } else {
+            while let Some(c) = chars.next_if(|c| !c.is_whitespace()) {
+                arg.push(c);
+            }
+        }
+        args.push(arg);
+    }
+}
+
+// Reads the escape following a backslash in a double quoted argument
+fn unescape(chars: &mut impl Iterator<Item = char>) -> Result<char, String> {
+    match chars.next() {
+        Some('n') => Ok('\n'),
+        Some('r') => Ok('\r'),
+        Some('t') => Ok('\t'),
+        Some('x') => {
+            let hex: String = chars.take(2).collect();
+            u8::from_str_radix(&hex, 16).map(char::from).map_err(|_| format!("Invalid escape \\x{}", hex))
+        },
+        Some(c @ ('"' | '\\')) => Ok(c),
+        Some(c) => Err(format!("Invalid escape \\{}", c)),
+        None => Err("Unbalanced quotes".to_string()),
+    }
+}
+
+// An item as written in the text protocol: as is, or double quoted and escaped if it would otherwise
+// be read back differently
+fn quote(item: &str) -> std::borrow::Cow<'_, str> {
+    let plain = !item.is_empty()
+        && !item.starts_with(['"', '\''])
+        && !item.chars().any(|c| c.is_whitespace() || c.is_control());
+    if plain {
+        return item.into();
+    }
+    let mut quoted = String::with_capacity(item.len() + 2);
+    quoted.push('"');
+    for c in item.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if c.is_control() && (c as u32) < 0x80 => quoted.push_str(&format!("\\x{:02x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted.into()
+}
+
+impl Command {
+    /// Whether the command may wait a while before replying
+    pub fn blocks(&self) -> bool {
+        matches!(self, Command::BlockingNext { .. } | Command::WaitEmpty { .. })
+    }
+
+    /// The command's name, as used in metrics
+    pub fn name(&self) -> &'static str {
+        match self {
+            Command::Update { .. } => "UPDATE",
+            Command::MultiUpdate { .. } => "MUPDATE",
+            Command::SetScore { .. } => "SETSCORE",
+            Command::Next | Command::NextBatch { .. } => "NEXT",
+            Command::BlockingNext { .. } => "BNEXT",
+            Command::WaitEmpty { .. } => "WAITEMPTY",
+            Command::Peek | Command::PeekMany { .. } => "PEEK",
+            Command::NextScore => "NEXTSCORE",
+            Command::PeekScore => "PEEKSCORE",
+            Command::ScoreRange { .. } => "SCORERANGE",
+            Command::Scan { .. } => "SCAN",
+            Command::Reserve { .. } => "RESERVE",
+            Command::Ack { .. } => "ACK",
+            Command::Nack { .. } => "NACK",
+            Command::Score { .. } => "SCORE",
+            Command::Remove { .. } => "REMOVE",
+            Command::SetData { .. } => "SETDATA",
+            Command::GetData { .. } => "GETDATA",
+            Command::Delay { .. } => "DELAY",
+            Command::Expire { .. } => "EXPIRE",
+            Command::Ttl { .. } => "TTL",
+            Command::Persist { .. } => "PERSIST",
+            Command::Info { .. } => "INFO",
+            Command::MemoryUsage { .. } => "MEMORY",
+            Command::Dump { .. } => "DUMP",
+            Command::Export { .. } => "EXPORT",
+            Command::Restore { .. } => "RESTORE",
+            Command::ResetStats => "RESETSTATS",
+            Command::Auth { .. } => "AUTH",
+            Command::Clear => "CLEAR",
+            Command::Protocol { .. } => "PROTOCOL",
+            Command::Hello { .. } => "HELLO",
+            Command::Seq { .. } => "SEQ",
+            Command::Ping => "PING",
+            Command::Save => "SAVE",
+            Command::ReadOnly { .. } => "READONLY",
+            Command::Drain { .. } | Command::DrainStop => "DRAIN",
+            Command::Shutdown { .. } => "SHUTDOWN",
+            Command::Eval { .. } => "EVAL",
+            Command::Multi => "MULTI",
+            Command::Exec => "EXEC",
+            Command::Discard => "DISCARD",
+            Command::Subscribe => "SUBSCRIBE",
+            Command::Unsubscribe => "UNSUBSCRIBE",
+            Command::Consume { .. } | Command::ConsumeStop => "CONSUME",
+            Command::Monitor | Command::MonitorStop => "MONITOR",
+            Command::Credit { .. } => "CREDIT",
+            Command::ClusterNodes | Command::ClusterNode { .. } => "CLUSTER",
+            Command::ClientList | Command::ClientKill { .. } => "CLIENT",
+            Command::ConfigGet { .. } | Command::ConfigSet { .. } => "CONFIG",
+            Command::SlowlogGet { .. } | Command::SlowlogLen | Command::SlowlogReset => "SLOWLOG",
+            Command::Latency { .. } => "LATENCY",
+            Command::Error { .. } => "INVALID",
+            Command::Help => "HELP",
+        }
+    }
+
+    /// The item the command acts on, for commands that act on a single item
+    pub fn item(&self) -> Option<&str> {
+        match self {
+            Command::Update { item_id, .. } | Command::SetScore { item_id, .. } | Command::Score { item_id } | Command::Remove { item_id } | Command::SetData { item_id, .. }
+            | Command::GetData { item_id } | Command::Delay { item_id, .. } | Command::Expire { item_id, .. }
+            | Command::Ttl { item_id } | Command::Persist { item_id } | Command::MemoryUsage { item_id: Some(item_id) }
+            | Command::Dump { item_id } | Command::Restore { item_id, .. } => Some(item_id),
+            _ => None,
+        }
+    }
+
+    // Parses a command from its name followed by its arguments
+    pub fn from_parts(parts: &[&str]) -> Self {
+        match parts {
+            [command, item_id, value, data @ ..] if command.eq_ignore_ascii_case("UPDATE") => {
                 value.parse().map(|val| Command::Update {
                     item_id: item_id.to_string(),
                     value: val,
+                    data: (!data.is_empty()).then(|| data.join(" ")),
                 }).unwrap_or(Command::Error {
                     msg: "Invalid value for UPDATE".to_string(),
                 })
             },
+            [command, item_id, score] if command.eq_ignore_ascii_case("SETSCORE") => {
+                score.parse().map(|score| Command::SetScore { item_id: item_id.to_string(), score }).unwrap_or(Command::Error {
+                    msg: "Invalid score for SETSCORE".to_string(),
+                })
+            },
+            [command, pairs @ ..] if command.eq_ignore_ascii_case("MUPDATE") => {
+                let updates = pairs.chunks(2).map(|pair| match pair {
+                    [item_id, value] => value.parse().ok().map(|value| (item_id.to_string(), value)),
+                    _ => None,
+                }).collect::<Option<Vec<_>>>();
+                match updates {
+                    Some(updates) if !updates.is_empty() => Command::MultiUpdate { updates },
+                    _ => Command::Error { msg: "Invalid arguments for MUPDATE, expected <identifier> <score> pairs".to_string() },
+                }
+            },
             [command] if command.eq_ignore_ascii_case("NEXT") => Command::Next,
+            [command, count] if command.eq_ignore_ascii_case("NEXT") => {
+                count.parse().map(|count| Command::NextBatch { count }).unwrap_or(Command::Error {
+                    msg: "Invalid count for NEXT".to_string(),
+                })
+            },
+            [command, timeout] if command.eq_ignore_ascii_case("BNEXT") => {
+                timeout.parse().ok()
+                    .and_then(|secs| Duration::try_from_secs_f64(secs).ok())
+                    .map(|timeout| Command::BlockingNext { timeout })
+                    .unwrap_or(Command::Error { msg: "Invalid timeout for BNEXT".to_string() })
+            },
+            [command, timeout] if command.eq_ignore_ascii_case("WAITEMPTY") => {
+                timeout.parse().ok()
+                    .and_then(|secs| Duration::try_from_secs_f64(secs).ok())
+                    .filter(|timeout| timeout.as_secs() <= MAX_SECONDS)
+                    .map(|timeout| Command::WaitEmpty { timeout })
+                    .unwrap_or(Command::Error { msg: "Invalid timeout for WAITEMPTY".to_string() })
+            },
             [command] if command.eq_ignore_ascii_case("PEEK") => Command::Peek,
+            [command, count] if command.eq_ignore_ascii_case("PEEK") => {
+                count.parse().map(|count| Command::PeekMany { count }).unwrap_or(Command::Error {
+                    msg: "Invalid count for PEEK".to_string(),
+                })
+            },
+            [command] if command.eq_ignore_ascii_case("NEXTSCORE") => Command::NextScore,
+            [command] if command.eq_ignore_ascii_case("PEEKSCORE") => Command::PeekScore,
+            [command, min, max, rest @ ..] if command.eq_ignore_ascii_case("SCORERANGE") => {
+                let count = match rest {
+                    [] => Some(None),
+                    [keyword, count] if keyword.eq_ignore_ascii_case("COUNT") => count.parse().ok().map(Some),
+                    _ => None,
+                };
+                match (parse_bound(min), parse_bound(max), count) {
+                    (Some(min), Some(max), Some(count)) => Command::ScoreRange { min, max, count },
+                    _ => Command::Error { msg: "Invalid arguments for SCORERANGE, expected <min> <max> [COUNT <n>]".to_string() },
+                }
+            },
+            [command, cursor, options @ ..] if command.eq_ignore_ascii_case("SCAN") => {
+                let mut scan = cursor.parse().ok().map(|cursor| Command::Scan { cursor, pattern: None, count: SCAN_COUNT });
+                for option in options.chunks(2) {
+                    scan = match (scan, option) {
+                        (Some(Command::Scan { cursor, count, .. }), [keyword, pattern]) if keyword.eq_ignore_ascii_case("MATCH") => {
+                            Some(Command::Scan { cursor, pattern: Some(pattern.to_string()), count })
+                        },
+                        (Some(Command::Scan { cursor, pattern, .. }), [keyword, count]) if keyword.eq_ignore_ascii_case("COUNT") => {
+                            count.parse().ok().filter(|&count| count > 0).map(|count| Command::Scan { cursor, pattern, count })
+                        },
+                        _ => None,
+                    };
+                }
+                scan.unwrap_or(Command::Error { msg: "Invalid arguments for SCAN, expected <cursor> [MATCH <pattern>] [COUNT <n>]".to_string() })
+            },
+            [command, timeout] if command.eq_ignore_ascii_case("RESERVE") => {
+                timeout.parse().ok()
+                    .and_then(|secs| Duration::try_from_secs_f64(secs).ok())
+                    .filter(|timeout| timeout.as_secs() <= MAX_SECONDS)
+                    .map(|timeout| Command::Reserve { timeout })
+                    .unwrap_or(Command::Error { msg: "Invalid timeout for RESERVE".to_string() })
+            },
+            [command, token] if command.eq_ignore_ascii_case("ACK") => Command::Ack {
+                token: token.to_string(),
+            },
+            [command, token] if command.eq_ignore_ascii_case("NACK") => Command::Nack {
+                token: token.to_string(),
+            },
             [command, item_id] if command.eq_ignore_ascii_case("SCORE") => Command::Score {
                 item_id: item_id.to_string(),
             },
-            [command] if command.eq_ignore_ascii_case("INFO") => Command::Info,
-            [command] if command.eq_ignore_ascii_case("INFO") => Command::Help,
+            [command, item_id] if command.eq_ignore_ascii_case("REMOVE") => Command::Remove {
+                item_id: item_id.to_string(),
+            },
+            [command, item_id, data @ ..] if command.eq_ignore_ascii_case("SETDATA") && !data.is_empty() => Command::SetData {
+                item_id: item_id.to_string(),
+                data: data.join(" "),
+            },
+            [command, item_id] if command.eq_ignore_ascii_case("GETDATA") => Command::GetData {
+                item_id: item_id.to_string(),
+            },
+            [command, item_id, value, delay] if command.eq_ignore_ascii_case("DELAY") => {
+                let delay = delay.parse().ok()
+                    .and_then(|secs| Duration::try_from_secs_f64(secs).ok())
+                    .filter(|delay| delay.as_secs() <= MAX_SECONDS);
+                match (value.parse(), delay) {
+                    (Ok(value), Some(delay)) => Command::Delay { item_id: item_id.to_string(), value, delay },
+                    _ => Command::Error { msg: "Invalid score or delay for DELAY".to_string() },
+                }
+            },
+            [command, item_id, seconds] if command.eq_ignore_ascii_case("EXPIRE") => {
+                seconds.parse().ok().filter(|&seconds| seconds <= MAX_SECONDS).map(|seconds| Command::Expire {
+                    item_id: item_id.to_string(),
+                    seconds,
+                }).unwrap_or(Command::Error {
+                    msg: "Invalid seconds for EXPIRE".to_string(),
+                })
+            },
+            [command, item_id] if command.eq_ignore_ascii_case("TTL") => Command::Ttl {
+                item_id: item_id.to_string(),
+            },
+            [command, item_id] if command.eq_ignore_ascii_case("PERSIST") => Command::Persist {
+                item_id: item_id.to_string(),
+            },
+            [command] if command.eq_ignore_ascii_case("INFO") => Command::Info { section: None },
+            [command, section] if command.eq_ignore_ascii_case("INFO") => Command::Info {
+                section: Some(section.to_string()),
+            },
+            [command] if command.eq_ignore_ascii_case("RESETSTATS") => Command::ResetStats,
+            [command, password] if command.eq_ignore_ascii_case("AUTH") => Command::Auth {
+                user: None,
+                password: password.to_string(),
+            },
+            [command, user, password] if command.eq_ignore_ascii_case("AUTH") => Command::Auth {
+                user: Some(user.to_string()),
+                password: password.to_string(),
+            },
+            [command] if command.eq_ignore_ascii_case("SHUTDOWN") => Command::Shutdown { save: None },
+            [command, mode] if command.eq_ignore_ascii_case("SHUTDOWN") => match mode {
+                mode if mode.eq_ignore_ascii_case("SAVE") => Command::Shutdown { save: Some(true) },
+                mode if mode.eq_ignore_ascii_case("NOSAVE") => Command::Shutdown { save: Some(false) },
+                _ => Command::Error { msg: "Invalid mode for SHUTDOWN, expected SAVE or NOSAVE".to_string() },
+            },
+            [command, script @ ..] if command.eq_ignore_ascii_case("EVAL") && !script.is_empty() => Command::Eval {
+                script: script.join(" "),
+            },
+            [command] if command.eq_ignore_ascii_case("MULTI") => Command::Multi,
+            [command] if command.eq_ignore_ascii_case("EXEC") => Command::Exec,
+            [command] if command.eq_ignore_ascii_case("DISCARD") => Command::Discard,
+            [command] if command.eq_ignore_ascii_case("CLEAR") || command.eq_ignore_ascii_case("FLUSH") => Command::Clear,
+            [command, protocol] if command.eq_ignore_ascii_case("PROTOCOL") => {
+                protocol.parse().map(|protocol| Command::Protocol { protocol }).unwrap_or(Command::Error {
+                    msg: "Invalid protocol, expected TEXT, JSON or BINARY".to_string(),
+                })
+            },
+            [command, item_id] if command.eq_ignore_ascii_case("DUMP") => Command::Dump { item_id: item_id.to_string() },
+            [command] if command.eq_ignore_ascii_case("EXPORT") => Command::Export { format: ExportFormat::Json },
+            [command, format] if command.eq_ignore_ascii_case("EXPORT") => {
+                format.parse().map(|format| Command::Export { format }).unwrap_or(Command::Error {
+                    msg: "Invalid format for EXPORT, expected JSON or CSV".to_string(),
+                })
+            },
+            [command, item_id, dump] if command.eq_ignore_ascii_case("RESTORE") => {
+                Command::Restore { item_id: item_id.to_string(), dump: dump.to_string(), replace: false }
+            },
+            [command, item_id, dump, replace] if command.eq_ignore_ascii_case("RESTORE") && replace.eq_ignore_ascii_case("REPLACE") => {
+                Command::Restore { item_id: item_id.to_string(), dump: dump.to_string(), replace: true }
+            },
+            [command, subcommand] if command.eq_ignore_ascii_case("MEMORY") && subcommand.eq_ignore_ascii_case("USAGE") => {
+                Command::MemoryUsage { item_id: None }
+            },
+            [command, subcommand, item_id] if command.eq_ignore_ascii_case("MEMORY") && subcommand.eq_ignore_ascii_case("USAGE") => {
+                Command::MemoryUsage { item_id: Some(item_id.to_string()) }
+            },
+            [command] if command.eq_ignore_ascii_case("HELLO") => Command::Hello { version: None },
+            [command, version] if command.eq_ignore_ascii_case("HELLO") => {
+                version.parse().map(|version| Command::Hello { version: Some(version) }).unwrap_or(Command::Error {
+                    msg: "Invalid version for HELLO".to_string(),
+                })
+            },
+            [command, mode] if command.eq_ignore_ascii_case("SEQ") => match mode {
+                mode if mode.eq_ignore_ascii_case("ON") => Command::Seq { enabled: true },
+                mode if mode.eq_ignore_ascii_case("OFF") => Command::Seq { enabled: false },
+                _ => Command::Error { msg: "Invalid mode for SEQ, expected ON or OFF".to_string() },
+            },
+            [command] if command.eq_ignore_ascii_case("PING") => Command::Ping,
+            [command] if command.eq_ignore_ascii_case("SAVE") => Command::Save,
+            [command, mode] if command.eq_ignore_ascii_case("READONLY") => match mode {
+                mode if mode.eq_ignore_ascii_case("ON") => Command::ReadOnly { enabled: true },
+                mode if mode.eq_ignore_ascii_case("OFF") => Command::ReadOnly { enabled: false },
+                _ => Command::Error { msg: "Invalid mode for READONLY, expected ON or OFF".to_string() },
+            },
+            [command] if command.eq_ignore_ascii_case("DRAIN") => Command::Drain { exit: false },
+            [command, mode] if command.eq_ignore_ascii_case("DRAIN") => match mode {
+                mode if mode.eq_ignore_ascii_case("EXIT") => Command::Drain { exit: true },
+                mode if mode.eq_ignore_ascii_case("OFF") => Command::DrainStop,
+                _ => Command::Error { msg: "Invalid mode for DRAIN, expected EXIT or OFF".to_string() },
+            },
+            [command, channel] if command.eq_ignore_ascii_case("SUBSCRIBE") => match channel {
+                channel if channel.eq_ignore_ascii_case("updates") => Command::Subscribe,
+                _ => Command::Error { msg: "Unknown channel, expected updates".to_string() },
+            },
+            [command] if command.eq_ignore_ascii_case("UNSUBSCRIBE") => Command::Unsubscribe,
+            [command, channel] if command.eq_ignore_ascii_case("UNSUBSCRIBE") => match channel {
+                channel if channel.eq_ignore_ascii_case("updates") => Command::Unsubscribe,
+                _ => Command::Error { msg: "Unknown channel, expected updates".to_string() },
+            },
+            [command, stop] if command.eq_ignore_ascii_case("CONSUME") && stop.eq_ignore_ascii_case("STOP") => Command::ConsumeStop,
+            [command] if command.eq_ignore_ascii_case("MONITOR") => Command::Monitor,
+            [command, stop] if command.eq_ignore_ascii_case("MONITOR") && stop.eq_ignore_ascii_case("STOP") => Command::MonitorStop,
+            [command, credit] if command.eq_ignore_ascii_case("CONSUME") => {
+                credit.parse().map(|credit| Command::Consume { credit }).unwrap_or(Command::Error {
+                    msg: "Invalid credit for CONSUME".to_string(),
+                })
+            },
+            [command, count] if command.eq_ignore_ascii_case("CREDIT") => {
+                count.parse().map(|count| Command::Credit { count }).unwrap_or(Command::Error {
+                    msg: "Invalid count for CREDIT".to_string(),
+                })
+            },
+            [command, subcommand] if command.eq_ignore_ascii_case("CLUSTER") && subcommand.eq_ignore_ascii_case("NODES") => Command::ClusterNodes,
+            [command, subcommand, item_id] if command.eq_ignore_ascii_case("CLUSTER") && subcommand.eq_ignore_ascii_case("NODE") => Command::ClusterNode {
+                item_id: item_id.to_string(),
+            },
+            [command, subcommand] if command.eq_ignore_ascii_case("CLIENT") && subcommand.eq_ignore_ascii_case("LIST") => Command::ClientList,
+            [command, subcommand, pattern] if command.eq_ignore_ascii_case("CONFIG") && subcommand.eq_ignore_ascii_case("GET") => Command::ConfigGet {
+                pattern: pattern.to_string(),
+            },
+            [command, subcommand, name, value] if command.eq_ignore_ascii_case("CONFIG") && subcommand.eq_ignore_ascii_case("SET") => Command::ConfigSet {
+                name: name.to_string(),
+                value: value.to_string(),
+            },
+            [command, subcommand, id] if command.eq_ignore_ascii_case("CLIENT") && subcommand.eq_ignore_ascii_case("KILL") => Command::ClientKill {
+                id: id.to_string(),
+            },
+            [command, subcommand] if command.eq_ignore_ascii_case("SLOWLOG") && subcommand.eq_ignore_ascii_case("GET") => {
+                Command::SlowlogGet { count: DEFAULT_SLOWLOG_COUNT }
+            },
+            [command, subcommand, count] if command.eq_ignore_ascii_case("SLOWLOG") && subcommand.eq_ignore_ascii_case("GET") => {
+                count.parse().map(|count| Command::SlowlogGet { count }).unwrap_or(Command::Error {
+                    msg: "Invalid count for SLOWLOG GET".to_string(),
+                })
+            },
+            [command, subcommand] if command.eq_ignore_ascii_case("SLOWLOG") && subcommand.eq_ignore_ascii_case("LEN") => Command::SlowlogLen,
+            [command, subcommand] if command.eq_ignore_ascii_case("SLOWLOG") && subcommand.eq_ignore_ascii_case("RESET") => Command::SlowlogReset,
+            [command] if command.eq_ignore_ascii_case("LATENCY") => Command::Latency { command: None },
+            [command, name] if command.eq_ignore_ascii_case("LATENCY") => Command::Latency { command: Some(name.to_string()) },
+            [command] if command.eq_ignore_ascii_case("HELP") => Command::Help,
             _ => Command::Error { msg: "Invalid command or arguments".to_string() },
         }
     }
+
+    // Parses a command sent as a JSON object, e.g. {"command": "UPDATE", "args": ["item id", 5]}.
+    // Arguments may be strings or numbers, so unlike the text protocol item ids can hold whitespace.
+    pub fn from_json(s: &str, names: &CommandNames) -> Self {
+        let Ok(Value::Object(request)) = serde_json::from_str::<Value>(s) else {
+            return Command::Error { msg: "Invalid JSON request".to_string() };
+        };
+        let Some(command) = request.get("command").and_then(Value::as_str) else {
+            return Command::Error { msg: "Missing command".to_string() };
+        };
+        let args = match request.get("args") {
+            None => Some(Vec::new()),
+            Some(Value::Array(args)) => args.iter().map(|arg| match arg {
+                Value::String(arg) => Some(arg.clone()),
+                Value::Number(arg) => Some(arg.to_string()),
+                _ => None,
+            }).collect(),
+            Some(_) => None,
+        };
+        let Some(args) = args else {
+            return Command::Error { msg: "Arguments must be an array of strings and numbers".to_string() };
+        };
+        let mut parts = vec![command];
+        parts.extend(args.iter().map(String::as_str));
+        Command::from_named_parts(&parts, names)
+    }
+}
+
+// Renders the command as it would be sent over the text protocol, with passwords left out
+impl fmt::Display for Command {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Command::Update { item_id, value, data: None } => write!(f, "UPDATE {} {}", quote(item_id), value),
+            Command::SetScore { item_id, score } => write!(f, "SETSCORE {} {}", quote(item_id), score),
+            Command::MultiUpdate { updates } => {
+                write!(f, "MUPDATE")?;
+                updates.iter().try_for_each(|(item_id, value)| write!(f, " {} {}", quote(item_id), value))
+            },
+            Command::Update { item_id, value, data: Some(data) } => write!(f, "UPDATE {} {} {}", quote(item_id), value, data),
+            Command::SetData { item_id, data } => write!(f, "SETDATA {} {}", quote(item_id), data),
+            Command::GetData { item_id } => write!(f, "GETDATA {}", quote(item_id)),
+            Command::NextBatch { count } => write!(f, "NEXT {}", count),
+            Command::BlockingNext { timeout } => write!(f, "BNEXT {}", timeout.as_secs_f64()),
+            Command::WaitEmpty { timeout } => write!(f, "WAITEMPTY {}", timeout.as_secs_f64()),
+            Command::PeekMany { count } => write!(f, "PEEK {}", count),
+            Command::ScoreRange { min, max, count: None } => write!(f, "SCORERANGE {} {}", min, max),
+            Command::ScoreRange { min, max, count: Some(count) } => write!(f, "SCORERANGE {} {} COUNT {}", min, max, count),
+            Command::Scan { cursor, pattern, count } => {
+                write!(f, "SCAN {}", cursor)?;
+                if let Some(pattern) = pattern {
+                    write!(f, " MATCH {}", quote(pattern))?;
+                }
+                write!(f, " COUNT {}", count)
+            },
+            Command::Reserve { timeout } => write!(f, "RESERVE {}", timeout.as_secs_f64()),
+            Command::Ack { token } => write!(f, "ACK {}", token),
+            Command::Nack { token } => write!(f, "NACK {}", token),
+            Command::Score { item_id } => write!(f, "SCORE {}", quote(item_id)),
+            Command::Info { section: Some(section) } => write!(f, "INFO {}", section),
+            Command::Dump { item_id } => write!(f, "DUMP {}", quote(item_id)),
+            Command::Export { format } => write!(f, "EXPORT {}", format),
+            Command::Restore { item_id, dump, replace } => {
+                write!(f, "RESTORE {} {}{}", quote(item_id), quote(dump), if *replace { " REPLACE" } else { "" })
+            },
+            Command::MemoryUsage { item_id: None } => write!(f, "MEMORY USAGE"),
+            Command::MemoryUsage { item_id: Some(item_id) } => write!(f, "MEMORY USAGE {}", quote(item_id)),
+            Command::Remove { item_id } => write!(f, "REMOVE {}", quote(item_id)),
+            Command::Delay { item_id, value, delay } => write!(f, "DELAY {} {} {}", quote(item_id), value, delay.as_secs_f64()),
+            Command::Expire { item_id, seconds } => write!(f, "EXPIRE {} {}", quote(item_id), seconds),
+            Command::Ttl { item_id } => write!(f, "TTL {}", quote(item_id)),
+            Command::Persist { item_id } => write!(f, "PERSIST {}", quote(item_id)),
+            Command::Auth { user: Some(user), .. } => write!(f, "AUTH {} (redacted)", user),
+            Command::Auth { user: None, .. } => write!(f, "AUTH (redacted)"),
+            Command::Protocol { protocol } => write!(f, "PROTOCOL {}", protocol),
+            Command::Hello { version: Some(version) } => write!(f, "HELLO {}", version),
+            Command::Seq { enabled: true } => write!(f, "SEQ ON"),
+            Command::Seq { enabled: false } => write!(f, "SEQ OFF"),
+            Command::Subscribe => write!(f, "SUBSCRIBE updates"),
+            Command::Consume { credit } => write!(f, "CONSUME {}", credit),
+            Command::Credit { count } => write!(f, "CREDIT {}", count),
+            Command::ConsumeStop => write!(f, "CONSUME STOP"),
+            Command::MonitorStop => write!(f, "MONITOR STOP"),
+            Command::Eval { script } => write!(f, "EVAL {}", script),
+            Command::ReadOnly { enabled: true } => write!(f, "READONLY ON"),
+            Command::ReadOnly { enabled: false } => write!(f, "READONLY OFF"),
+            Command::Drain { exit: true } => write!(f, "DRAIN EXIT"),
+            Command::DrainStop => write!(f, "DRAIN OFF"),
+            Command::Shutdown { save: Some(true) } => write!(f, "SHUTDOWN SAVE"),
+            Command::Shutdown { save: Some(false) } => write!(f, "SHUTDOWN NOSAVE"),
+            Command::ClusterNodes => write!(f, "CLUSTER NODES"),
+            Command::ClusterNode { item_id } => write!(f, "CLUSTER NODE {}", quote(item_id)),
+            Command::ClientList => write!(f, "CLIENT LIST"),
+            Command::ClientKill { id } => write!(f, "CLIENT KILL {}", id),
+            Command::ConfigGet { pattern } => write!(f, "CONFIG GET {}", quote(pattern)),
+            Command::ConfigSet { name, value } => write!(f, "CONFIG SET {} {}", name, quote(value)),
+            Command::SlowlogGet { count } => write!(f, "SLOWLOG GET {}", count),
+            Command::Latency { command: Some(command) } => write!(f, "LATENCY {}", command),
+            Command::SlowlogLen => write!(f, "SLOWLOG LEN"),
+            Command::SlowlogReset => write!(f, "SLOWLOG RESET"),
+            _ => write!(f, "{}", self.name()),
+        }
+    }
+}
+
+pub const EMPTY_ERROR: &str = "Queue is empty";
+pub const NOT_FOUND_ERROR: &str = "No such item";
+
+/// Version of the command protocol, reported by HELLO. Raised whenever a change could break existing
+/// clients, which keep the behavior they know by asking for the version they speak.
+///
+/// 1: the original replies, with +-1 for both an empty queue and a missing item
+/// 2: -EMPTY and -NOTFOUND replace +-1, which could be mistaken for a score of -1
+/// 3: UPDATE replies with the item's new score rather than OK
+pub const PROTOCOL_VERSION: u32 = 3;
+
+/// Wire format of a connection, switched with the PROTOCOL command
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Protocol {
+    /// Whitespace separated commands, answered with the positional replies documented in HELP
+    #[default]
+    Text,
+    /// A JSON object per line both ways
+    Json,
+    /// Length prefixed binary frames, see the binary module
+    Binary,
+}
+
+impl FromStr for Protocol {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            s if s.eq_ignore_ascii_case("TEXT") => Ok(Protocol::Text),
+            s if s.eq_ignore_ascii_case("JSON") => Ok(Protocol::Json),
+            s if s.eq_ignore_ascii_case("BINARY") => Ok(Protocol::Binary),
+            _ => Err(()),
+        }
+    }
+}
+
+impl fmt::Display for Protocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Protocol::Text => write!(f, "TEXT"),
+            Protocol::Json => write!(f, "JSON"),
+            Protocol::Binary => write!(f, "BINARY"),
+        }
+    }
+}
+
+impl Protocol {
+    /// Parses a request: a line without its line ending, or the body of a binary frame
+    pub fn parse(&self, request: &[u8], names: &CommandNames) -> Command {
+        match self {
+            Protocol::Text => Command::from_text(&String::from_utf8_lossy(request), names),
+            Protocol::Json => Command::from_json(&String::from_utf8_lossy(request), names),
+            Protocol::Binary => binary::decode_command(request, names),
+        }
+    }
+
+    pub fn render(&self, response: &Response) -> Vec<u8> {
+        match self {
+            Protocol::Text => response.to_string().into_bytes(),
+            Protocol::Json => format!("{}\n", response.to_json()).into_bytes(),
+            Protocol::Binary => binary::encode_response(response),
+        }
+    }
+
+    /// Renders the reply to the request numbered seq along with that number, see SEQ
+    pub fn render_numbered(&self, seq: u64, response: &Response) -> Vec<u8> {
+        match self {
+            Protocol::Text => format!("#{}\r\n{}", seq, response).into_bytes(),
+            Protocol::Json => {
+                let mut json = response.to_json();
+                if let Some(fields) = json.as_object_mut() {
+                    fields.insert("seq".to_string(), json!(seq));
+                }
+                format!("{}\n", json).into_bytes()
+            },
+            Protocol::Binary => {
+                let mut frames = binary::encode_seq(seq);
+                frames.extend(binary::encode_response(response));
+                frames
+            },
+        }
+    }
+}
+
+/// Format of the queue's contents written by EXPORT, the formats --load reads
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExportFormat {
+    Json,
+    Csv,
+}
+
+impl FromStr for ExportFormat {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            s if s.eq_ignore_ascii_case("JSON") => Ok(ExportFormat::Json),
+            s if s.eq_ignore_ascii_case("CSV") => Ok(ExportFormat::Csv),
+            _ => Err(()),
+        }
+    }
+}
+
+impl fmt::Display for ExportFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExportFormat::Json => write!(f, "JSON"),
+            ExportFormat::Csv => write!(f, "CSV"),
+        }
+    }
 }
 
 #[derive(Clone, Debug)]
 pub enum Response {
     Ok,
+    // No value, as returned by a script, and what Empty and NotFound are sent as to version 1 clients
+    Nil,
+    // No item to return
+    Empty,
+    // No such item
+    NotFound,
+    // The score an item was updated to, None when it left the queue (auto removed or evicted)
+    Updated(Option<i64>),
     Score(i64),
+    Count(usize),
     Item(String),
+    Entry(String, i64),
+    // A popped item along with the data attached to it
+    ItemData { item: String, data: String },
+    EntryData { item: String, score: i64, data: String },
+    Reserved { token: String, item: String, score: i64, data: Option<String> },
+    Items(Vec<String>),
+    // A batch of popped items, along with the data attached to those that had any
+    ItemsData(Vec<(String, Option<String>)>),
+    // Descriptions rather than items, so never quoted
+    Line(String),
+    Lines(Vec<String>),
+    Entries(Vec<(String, i64)>),
+    // A batch of SCAN, with the cursor to continue from, 0 once done
+    Scanned { cursor: u64, entries: Vec<(String, i64)> },
+    // The queue's contents for EXPORT, in priority order
+    Exported { format: ExportFormat, entries: Vec<(String, i64)> },
     Error(String),
-    Stats(PQueueStats),
+    // A command was queued until EXEC
+    Queued,
+    // The responses to the commands run by EXEC, in order
+    Multi(Vec<Response>),
+    Info(Vec<InfoSection>),
+    // The reply to HELLO: the connection's wire format and the features the server offers
+    Hello { protocol: Protocol, capabilities: Vec<&'static str> },
+    SlowLog(Vec<SlowLogEntry>),
+    Latency(Vec<Latency>),
+    // Pushed to clients that asked for notifications, rather than sent in reply to a command
+    // A queue event of the given kind, see NotifyEvents
+    Event { kind: &'static str, item: String, score: i64 },
+    Cleared(usize),
+    Lagged(u64),
+    Consumed { item: String, score: i64, data: Option<String> },
+    // A command run by any client, pushed while monitoring. The timestamp is in seconds since the epoch.
+    Monitored { timestamp: f64, client: String, command: String },
     Help,
 }
 
@@ -53,24 +807,289 @@ impl fmt::Display for Response {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Response::Ok => write!(f, "+OK\r\n"),
-            Response::Score(score) => write!(f, "+{}\r\n", score),
-            Response::Item(item) => write!(f, "+{}\r\n", item),
+            Response::Nil => write!(f, "+-1\r\n"),
+            Response::Empty => write!(f, "-EMPTY {}\r\n", EMPTY_ERROR),
+            Response::NotFound | Response::Updated(None) => write!(f, "-NOTFOUND {}\r\n", NOT_FOUND_ERROR),
+            Response::Score(score) | Response::Updated(Some(score)) => write!(f, "+{}\r\n", score),
+            Response::Count(count) => write!(f, "+{}\r\n", count),
+            Response::Item(item) => write!(f, "+{}\r\n", quote(item)),
+            Response::Line(line) => write!(f, "+{}\r\n", line),
+            Response::Entry(item, score) => write!(f, "+{} {}\r\n", quote(item), score),
+            Response::ItemData { item, data } => write!(f, "+{} {}\r\n", quote(item), data),
+            Response::EntryData { item, score, data } => write!(f, "+{} {} {}\r\n", quote(item), score, data),
+            Response::Reserved { token, item, score, data } => write!(f, "+{} {} {}{}\r\n", token, quote(item), score, data_suffix(data)),
+            Response::Items(items) => {
+                write!(f, "*{}\r\n", items.len())?;
+                items.iter().try_for_each(|item| write!(f, "+{}\r\n", quote(item)))
+            },
+            Response::ItemsData(items) => {
+                write!(f, "*{}\r\n", items.len())?;
+                items.iter().try_for_each(|(item, data)| write!(f, "+{}{}\r\n", quote(item), data_suffix(data)))
+            },
+            Response::Lines(lines) => {
+                write!(f, "*{}\r\n", lines.len())?;
+                lines.iter().try_for_each(|line| write!(f, "+{}\r\n", line))
+            },
+            Response::Entries(entries) => {
+                write!(f, "*{}\r\n", entries.len())?;
+                entries.iter().try_for_each(|(item, score)| write!(f, "+{} {}\r\n", quote(item), score))
+            },
+            Response::Scanned { cursor, entries } => {
+                write!(f, "*2\r\n+{}\r\n*{}\r\n", cursor, entries.len())?;
+                entries.iter().try_for_each(|(item, score)| write!(f, "+{} {}\r\n", quote(item), score))
+            },
+            Response::Exported { format: ExportFormat::Json, entries } => write!(f, "+{}\r\n", load::to_json(entries)),
+            Response::Exported { format: ExportFormat::Csv, entries } => {
+                let lines = load::to_csv(entries);
+                write!(f, "*{}\r\n", lines.len())?;
+                lines.iter().try_for_each(|line| write!(f, "+{}\r\n", line))
+            },
             Response::Error(msg) => write!(f, "-{}\r\n", msg),
-            Response::Stats(stats) => write!(f,
-                "+INFO\r\n+uptime:{}\r\n+version:{}\r\n+updates:{}\r\n+items:{}\r\n+pools:{}\r\n",
-                stats.uptime.num_seconds(),
-                stats.version,
-                stats.updates,
-                stats.items,
-                stats.pools),
-            Response::Help => write!(f,
-                "USAGE (note: commands are case insensitive, identifiers are case sensitive): \r\n\
-                 +UPDATE <identifier> <score> [Updates the priority of <identifier> by adding <score> to its priority or inserts it with priority of <score>]\r\n \
-                 +NEXT                        [Pops the highest priority item (item that has had that priority the longest if multiple) off the queue]\r\n \
-                 +SCORE <identifier>          [Fetch the current priority score for <identifier>]\r\n \
-                 +INFO                        [Fetch statistics about the server]\r\n \
-                 +HELP                        [Get this help]\r\n"
-            )
+            Response::Queued => write!(f, "+QUEUED\r\n"),
+            Response::Multi(responses) => {
+                write!(f, "*{}\r\n", responses.len())?;
+                responses.iter().try_for_each(|response| write!(f, "{}", response))
+            },
+            Response::Event { kind, item, score } => write!(f, ">{} {} {}\r\n", kind, quote(item), score),
+            Response::Cleared(count) => write!(f, ">cleared {}\r\n", count),
+            Response::Lagged(missed) => write!(f, ">lagged {}\r\n", missed),
+            Response::Monitored { timestamp, client, command } => write!(f, ">monitor {:.6} {} {}\r\n", timestamp, client, command),
+            Response::Consumed { item, score, data } => write!(f, ">consumed {} {}{}\r\n", quote(item), score, data_suffix(data)),
+            Response::Info(sections) => {
+                write!(f, "+INFO\r\n")?;
+                sections.iter().try_for_each(|section| {
+                    write!(f, "+# {}\r\n", section.name)?;
+                    section.fields.iter().try_for_each(|(name, value)| match value {
+                        Value::String(value) => write!(f, "+{}:{}\r\n", name, value),
+                        Value::Null => write!(f, "+{}:\r\n", name),
+                        value => write!(f, "+{}:{}\r\n", name, value),
+                    })
+                })
+            },
+            Response::Hello { protocol, capabilities } => {
+                write!(f, "+HELLO\r\n+server:pqueue\r\n+version:{}\r\n", env!("CARGO_PKG_VERSION"))?;
+                write!(f, "+proto:{}\r\n+protocol:{}\r\n+capabilities:{}\r\n", PROTOCOL_VERSION, protocol, capabilities.join(","))
+            },
+            Response::SlowLog(entries) => {
+                write!(f, "*{}\r\n", entries.len())?;
+                entries.iter().try_for_each(|entry| {
+                    write!(f, "+{} {} {} {}\r\n", entry.id, entry.timestamp, entry.duration.as_micros(), entry.command)
+                })
+            },
+            Response::Latency(latencies) => {
+                write!(f, "*{}\r\n", latencies.len())?;
+                latencies.iter().try_for_each(|latency| {
+                    write!(f, "+{} calls={} p50={} p95={} p99={} max={}\r\n", latency.command, latency.calls, latency.p50, latency.p95, latency.p99, latency.max)
+                })
+            },
+            Response::Help => {
+                write!(f, "USAGE (note: commands are case insensitive, identifiers are case sensitive; quote identifiers holding spaces, e.g. \"my item\", escaping \\\" and \\\\ inside): \r\n")?;
+                HELP.iter().try_for_each(|(usage, description)| write!(f, "+{:<27} [{}]\r\n", usage, description))
+            },
+        }
+    }
+}
+
+impl Response {
+    /// The response as the given protocol version replies it, see PROTOCOL_VERSION
+    pub fn for_version(self, version: u32) -> Response {
+        match self {
+            Response::Updated(_) if version < 3 => Response::Ok,
+            Response::Updated(Some(score)) => Response::Score(score),
+            Response::Updated(None) => Response::NotFound,
+            Response::Empty | Response::NotFound if version < 2 => Response::Nil,
+            Response::Multi(responses) => Response::Multi(responses.into_iter().map(|response| response.for_version(version)).collect()),
+            response => response,
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        match self {
+            Response::Ok => json!({ "ok": true }),
+            Response::Nil => Value::Null,
+            Response::Empty => json!({ "error": EMPTY_ERROR, "code": "EMPTY" }),
+            Response::NotFound | Response::Updated(None) => json!({ "error": NOT_FOUND_ERROR, "code": "NOTFOUND" }),
+            Response::Score(score) | Response::Updated(Some(score)) => json!({ "score": score }),
+            Response::Count(count) => json!({ "count": count }),
+            Response::Item(item) | Response::Line(item) => json!({ "item": item }),
+            Response::Entry(item, score) => json!({ "item": item, "score": score }),
+            Response::ItemData { item, data } => json!({ "item": item, "data": data }),
+            Response::EntryData { item, score, data } => json!({ "item": item, "score": score, "data": data }),
+            Response::Reserved { token, item, score, data } => json!({ "token": token, "item": item, "score": score, "data": data }),
+            Response::Items(items) | Response::Lines(items) => json!({ "items": items }),
+            Response::ItemsData(items) => json!({
+                "items": items.iter().map(|(item, data)| json!({ "item": item, "data": data })).collect::<Vec<_>>(),
+            }),
+            Response::Entries(entries) => json!({
+                "items": entries.iter().map(|(item, score)| json!({ "item": item, "score": score })).collect::<Vec<_>>(),
+            }),
+            Response::Scanned { cursor, entries } => json!({
+                "cursor": cursor,
+                "items": entries.iter().map(|(item, score)| json!({ "item": item, "score": score })).collect::<Vec<_>>(),
+            }),
+            Response::Exported { entries, .. } => json!({
+                "items": entries.iter().map(|(item, score)| json!({ "item": item, "score": score })).collect::<Vec<_>>(),
+            }),
+            Response::Error(msg) => json!({ "error": msg }),
+            Response::Queued => json!({ "queued": true }),
+            Response::Multi(responses) => json!({ "results": responses.iter().map(Response::to_json).collect::<Vec<_>>() }),
+            Response::Event { kind, item, score } => json!({ "event": kind, "item": item, "score": score }),
+            Response::Cleared(count) => json!({ "event": "cleared", "count": count }),
+            Response::Lagged(missed) => json!({ "event": "lagged", "missed": missed }),
+            Response::Monitored { timestamp, client, command } => json!({ "event": "monitor", "timestamp": timestamp, "client": client, "command": command }),
+            Response::Consumed { item, score, data } => json!({ "event": "consumed", "item": item, "score": score, "data": data }),
+            Response::Info(sections) => json!({
+                "info": sections.iter()
+                    .map(|section| (section.name.to_string(), section.fields.iter().map(|(name, value)| (name.to_string(), value.clone())).collect()))
+                    .collect::<serde_json::Map<_, _>>(),
+            }),
+            Response::Hello { protocol, capabilities } => json!({
+                "server": "pqueue",
+                "version": env!("CARGO_PKG_VERSION"),
+                "proto": PROTOCOL_VERSION,
+                "protocol": protocol.to_string(),
+                "capabilities": capabilities,
+            }),
+            Response::Latency(latencies) => json!({
+                "latency": latencies.iter().map(|latency| json!({
+                    "command": latency.command,
+                    "calls": latency.calls,
+                    "p50_us": latency.p50,
+                    "p95_us": latency.p95,
+                    "p99_us": latency.p99,
+                    "max_us": latency.max,
+                })).collect::<Vec<_>>(),
+            }),
+            Response::SlowLog(entries) => json!({
+                "slowlog": entries.iter().map(|entry| json!({
+                    "id": entry.id,
+                    "timestamp": entry.timestamp,
+                    "duration_us": entry.duration.as_micros() as u64,
+                    "command": entry.command,
+                })).collect::<Vec<_>>(),
+            }),
+            Response::Help => json!({
+                "help": HELP.iter().map(|(usage, description)| json!({ "usage": usage, "description": description })).collect::<Vec<_>>(),
+            }),
         }
     }
 }
+
+// Items SCAN looks at without COUNT
+const SCAN_COUNT: usize = 10;
+
+// The longest TTL, delay or timeout accepted, in seconds: about a hundred years, well short of
+// overflowing once added to the current time
+const MAX_SECONDS: u64 = 100 * 365 * 24 * 60 * 60;
+
+// Parses a SCORERANGE bound, where -inf and +inf stand for the lowest and highest scores
+fn parse_bound(bound: &str) -> Option<i64> {
+    match bound {
+        "-inf" => Some(i64::MIN),
+        "+inf" | "inf" => Some(i64::MAX),
+        bound => bound.parse().ok(),
+    }
+}
+
+// The data attached to an item as the last field of a reply line, when it has any
+fn data_suffix(data: &Option<String>) -> String {
+    data.as_ref().map_or(String::new(), |data| format!(" {}", data))
+}
+
+pub fn stats_json(stats: &PQueueStats) -> Value {
+    json!({
+        "uptime": stats.uptime.num_seconds(),
+        "version": stats.version,
+        "updates": stats.updates,
+        "items": stats.items,
+        "pools": stats.pools,
+        "evicted": stats.evicted,
+        "rejected": stats.rejected,
+        "expired": stats.expired,
+        "enqueue_rate": {
+            "last_1s": stats.enqueue_rate.last_1s,
+            "last_1m": stats.enqueue_rate.last_1m,
+            "last_5m": stats.enqueue_rate.last_5m,
+        },
+        "dequeue_rate": {
+            "last_1s": stats.dequeue_rate.last_1s,
+            "last_1m": stats.dequeue_rate.last_1m,
+            "last_5m": stats.dequeue_rate.last_5m,
+        },
+        "oldest_item_age": stats.oldest_item_age.map_or(0, |age| age.num_seconds()),
+    })
+}
+
+// Entries listed by SLOWLOG GET without a count
+const DEFAULT_SLOWLOG_COUNT: usize = 10;
+
+/// The name of every command, as HELP lists them
+pub fn command_names() -> impl Iterator<Item = &'static str> {
+    HELP.iter().filter_map(|(usage, _)| usage.split_whitespace().next())
+}
+
+// Usage and description of every command, listed by HELP
+const HELP: &[(&str, &str)] = &[
+    ("UPDATE <identifier> <score> [<data>]", "Updates the priority of <identifier> by adding <score> to its priority or inserts it with priority of <score>, attaching <data> if given; replies with the new priority, or -NOTFOUND if the item left the queue (auto removal or eviction)"),
+    ("SETSCORE <identifier> <score>", "Sets the priority of <identifier> to <score> rather than adding to it, inserting it if needed"),
+    ("MUPDATE <identifier> <score> ...", "Like UPDATE for each pair, applied atomically, replying with \"*<n>\" followed by the reply to each update"),
+    ("SETDATA <identifier> <data>", "Attaches <data> to <identifier>, returned along with it by NEXT, BNEXT, NEXTSCORE, RESERVE and CONSUME; replies -NOTFOUND if it is not in the queue"),
+    ("GETDATA <identifier>", "Fetch the data attached to <identifier>"),
+    ("NEXT", "Pops the highest priority item (item that has had that priority the longest if multiple) off the queue"),
+    ("NEXT <count>", "Pops up to <count> items, replying with \"*<n>\" followed by one line per item"),
+    ("BNEXT <timeout>", "Like NEXT, but waits up to <timeout> seconds (0 waits forever) for an item to become available"),
+    ("WAITEMPTY <timeout>", "Waits up to <timeout> seconds (0 waits forever) for the queue to empty, with no item reserved or delayed left to come back into it, replying with the number of items left: 0 once empty"),
+    ("RESERVE <timeout>", "Pops the next item, replying with \"<token> <identifier> <score>\"; unless acknowledged within <timeout> seconds the item is put back"),
+    ("ACK <token>", "Completes the reservation with the given token"),
+    ("NACK <token>", "Puts the item reserved with the given token back on the queue with its score"),
+    ("SCORE <identifier>", "Fetch the current priority score for <identifier>"),
+    ("REMOVE <identifier>", "Removes <identifier> from the queue, replying with the score it had"),
+    ("EVAL <script>", "Runs a Lua script atomically, with pqueue.update, remove, score, next and peek; replies with what the script returns; refused in cluster mode"),
+    ("MULTI", "Starts a transaction: UPDATE and REMOVE are queued, replying with QUEUED, until EXEC"),
+    ("EXEC", "Applies the queued commands atomically, replying with \"*<n>\" followed by the reply to each"),
+    ("DISCARD", "Drops the queued commands and ends the transaction"),
+    ("DELAY <identifier> <score> <seconds>", "Like UPDATE, but only once <seconds> have passed; delaying an item again replaces its earlier delay"),
+    ("EXPIRE <identifier> <seconds>", "Removes <identifier> from the queue once <seconds> have passed, replying with 1, or 0 if it is not in the queue"),
+    ("TTL <identifier>", "Replies with the seconds left until <identifier> expires, -1 if it doesn't expire or -2 if it is not in the queue"),
+    ("PERSIST <identifier>", "Removes the expiry of <identifier>, replying with 1, or 0 if it had none"),
+    ("PEEK", "Returns the highest priority item without removing it from the queue"),
+    ("PEEK <count>", "Lists up to <count> of the highest priority items as \"<identifier> <score>\" lines without removing them"),
+    ("SCORERANGE <min> <max> [COUNT <n>]", "Lists the items scored between <min> and <max> (inclusive, -inf and +inf allowed) as \"<identifier> <score>\" lines, highest first, up to <n> of them"),
+    ("SCAN <cursor> [MATCH <pattern>] [COUNT <n>]", "Lists about <n> (default 10) items as \"<identifier> <score>\" lines in the order they were added, starting at <cursor> (0 to start over), after the cursor to continue from (0 once done); <pattern> may use *, ? and [...]"),
+    ("NEXTSCORE", "Like NEXT, but replies with \"<identifier> <score>\""),
+    ("PEEKSCORE", "Like PEEK, but replies with \"<identifier> <score>\""),
+    ("DUMP <identifier>", "Serializes <identifier> with its score, timestamps, expiry and data, for RESTORE on another server; replies -NOTFOUND if it is not in the queue"),
+    ("EXPORT [JSON|CSV]", "Replies with every item and its score in priority order, as a JSON array (the default) on one line or as \"<identifier>,<score>\" CSV lines after a header, the formats --load reads"),
+    ("RESTORE <identifier> <dump> [REPLACE]", "Recreates <identifier> from the output of DUMP, failing if it is in the queue already unless REPLACE is given"),
+    ("MEMORY USAGE [<identifier>]", "Estimates the bytes the queue takes up, data included, or only <identifier> (-NOTFOUND if it is not in the queue); the whole queue is walked, so this is slow on long queues"),
+    ("INFO [<section>]", "Fetch statistics about the server, or only the given section: server, clients, memory, persistence, stats, commandstats, latency or queue"),
+    ("RESETSTATS", "Zeroes the update count and rates reported by INFO"),
+    ("CLEAR", "Removes every item from the queue, returning how many were removed (alias: FLUSH)"),
+    ("SAVE", "Writes a snapshot of the queue to the data directory, restored when the server starts"),
+    ("DRAIN [EXIT|OFF]", "Refuses commands adding items, such as UPDATE and DELAY, while NEXT keeps serving; with EXIT (or on SIGUSR1) shuts down once the queue is empty, with OFF accepts items again"),
+    ("READONLY <ON|OFF>", "Rejects (or accepts again) every command that changes the queue, such as UPDATE, NEXT, REMOVE and CLEAR, as --read-only does at startup"),
+    ("SHUTDOWN [SAVE|NOSAVE]", "Stops the server, first saving a snapshot with SAVE or by default when there is a data directory; the server keeps running if saving fails"),
+    ("SUBSCRIBE updates", "Pushes \"><event> <identifier> <score>\" for the events enabled with --notify-events: added, updated, popped, expired or removed (\">cleared <n>\" for CLEAR, \">lagged <n>\" if <n> were missed)"),
+    ("UNSUBSCRIBE [updates]", "Stops the pushes started by SUBSCRIBE"),
+    ("CONSUME <credit>", "Pops items as they become available, pushing each as \">consumed <identifier> <score>\" until <credit> items were sent"),
+    ("CREDIT <count>", "Lets CONSUME push <count> more items, replying with the credit left"),
+    ("CONSUME STOP", "Stops pushing items"),
+    ("MONITOR", "Pushes every command any client runs as \">monitor <timestamp> <client id> <command>\" (\">lagged <n>\" if <n> were missed)"),
+    ("MONITOR STOP", "Stops the pushes started by MONITOR"),
+    ("CLIENT LIST", "Lists connected clients as \"id=<id> addr=<address> type=<tcp|ws> age=<seconds> idle=<seconds> cmd=<last command>\" lines"),
+    ("CLIENT KILL <id>", "Disconnects the client with the given id"),
+    ("CONFIG GET <pattern>", "Lists the settings matching <pattern> (* for all) as \"<name> <value>\" lines: log-level, idle-timeout, max-clients and save-interval"),
+    ("CONFIG SET <name> <value>", "Changes a setting until the server restarts or reloads its configuration on SIGHUP; 0 turns off idle-timeout, max-clients and save-interval"),
+    ("SLOWLOG GET [<count>]", "Lists up to <count> (default 10) of the latest slow commands as \"<id> <timestamp> <microseconds> <command>\" lines"),
+    ("LATENCY [<command>]", "Lists the latency percentiles of every command run (or just <command>) as \"<command> calls=<n> p50=<us> p95=<us> p99=<us> max=<us>\" lines, in microseconds"),
+    ("SLOWLOG LEN", "Replies with the number of commands in the slowlog"),
+    ("SLOWLOG RESET", "Empties the slowlog"),
+    ("CLUSTER NODES", "Lists the cluster's nodes as \"<index> <address>\" lines, marking this one with \"myself\""),
+    ("CLUSTER NODE <identifier>", "Replies with \"<index> <address>\" of the cluster node owning <identifier>"),
+    ("AUTH [<user>] <password>", "Authenticates the connection with the server's password, or as a user of its ACL file"),
+    ("HELLO [<version>]", "Reports the server's version, the protocol version, the connection's wire format and the server's capabilities; with <version>, switches the connection to that protocol version (1 replies +-1 rather than -EMPTY or -NOTFOUND, 1 and 2 reply OK to UPDATE), failing if the server doesn't speak it"),
+    ("SEQ <ON|OFF>", "Precedes every reply with the sequence number of the request it answers, counted from 1 per connection, as logged at the debug level: a #<number> line in TEXT mode, a \"seq\" field in JSON mode and a SEQ frame in BINARY mode; pushes aren't numbered"),
+    ("PING", "Replies with PONG, without changing anything and before AUTH, for health checks"),
+    ("PROTOCOL <TEXT|JSON|BINARY>", "Switches the connection to the given wire format; in JSON mode requests are objects like {\"command\": \"UPDATE\", \"args\": [\"id\", 5]}, in BINARY mode length prefixed frames"),
+    ("HELP", "Get this help"),
+];
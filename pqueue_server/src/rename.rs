@@ -0,0 +1,55 @@
+// Commands renamed or disabled with --rename-command, like Redis's rename-command, so clients that
+// shouldn't run a dangerous command can't guess their way to it:
+//
+//   --rename-command CONFIG=CONFIG-8f2a6c   CONFIG only runs as CONFIG-8f2a6c
+//   --rename-command CLEAR=                 CLEAR doesn't run at all
+//
+// Under its own name (or a synonym, like FLUSH for CLEAR) a renamed or disabled command is as unknown
+// as a misspelt one, over every protocol of the TCP port, WebSockets and the original protocol's
+// port, and the opcode the binary protocol has for it is refused as well. The HTTP and gRPC APIs are
+// routed by endpoint rather than by command name, so they keep theirs.
+
+use std::collections::HashMap;
+
+use crate::protocol;
+
+/// The commands renamed or disabled
+#[derive(Default)]
+pub struct CommandNames {
+    // The new name of every renamed command, None for disabled ones, by the command's own name
+    renamed: HashMap<String, Option<String>>,
+}
+
+impl CommandNames {
+    pub fn new(renames: impl IntoIterator<Item = (String, Option<String>)>) -> Self {
+        Self { renamed: renames.into_iter().collect() }
+    }
+
+    /// The command a new name stands for, None for any other name
+    pub fn renamed_from(&self, name: &str) -> Option<&str> {
+        self.renamed.iter()
+            .find(|(_, new_name)| new_name.as_ref().is_some_and(|new_name| new_name.eq_ignore_ascii_case(name)))
+            .map(|(command, _)| command.as_str())
+    }
+
+    /// Whether the command, by its own name, only runs under a new name or not at all
+    pub fn hides(&self, command: &str) -> bool {
+        self.renamed.contains_key(command)
+    }
+}
+
+/// Parses a --rename-command value: a command's name, =, then its new name, or nothing to disable it
+pub fn parse_rename(s: &str) -> Result<(String, Option<String>), String> {
+    let (command, new_name) = s.split_once('=').ok_or("expected <command>=<new name>, or <command>= to disable it")?;
+    let command = command.to_ascii_uppercase();
+    if !protocol::command_names().any(|name| name == command) {
+        return Err(format!("unknown command {}", command));
+    }
+    if new_name.contains(char::is_whitespace) {
+        return Err(format!("invalid name {:?}, names can't hold whitespace", new_name));
+    }
+    if protocol::command_names().any(|name| name.eq_ignore_ascii_case(new_name)) {
+        return Err(format!("{} already names a command", new_name));
+    }
+    Ok((command, (!new_name.is_empty()).then(|| new_name.to_string())))
+}
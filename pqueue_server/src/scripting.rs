@@ -0,0 +1,107 @@
+// Lua scripts run with EVAL. A script runs inside a queue transaction, so other clients see either none
+// or all of its changes, and reaches the queue through a `pqueue` table:
+//
+//   pqueue.update(item, delta)   adds delta to the item's score, returning the new score (nil if removed)
+//   pqueue.remove(item)          removes the item, returning the score it had
+//   pqueue.score(item)           the item's score
+//   pqueue.next()                pops the next item, returning it and its score
+//   pqueue.peek()                the next item and its score, without popping it
+//
+// Changes made before a script fails are kept. Only the table, string and math libraries are loaded.
+// The queue is locked while a script runs, so scripts are stopped once they run longer than
+// SCRIPT_TIMEOUT, and run off the async workers so the other connections they serve carry on. A
+// cluster node refuses scripts, as the items they touch can't be redirected to the nodes owning them.
+
+use std::cell::RefCell;
+use std::time::{Duration, Instant};
+
+use mlua::{HookTriggers, Lua, LuaOptions, StdLib, Value};
+use tokio::runtime::{Handle, RuntimeFlavor};
+
+use crate::protocol::Response;
+use crate::Server;
+
+// The longest a script may hold the queue's lock
+const SCRIPT_TIMEOUT: Duration = Duration::from_secs(1);
+
+// Instructions run between checks of the script's running time
+const INSTRUCTIONS_PER_CHECK: u32 = 10_000;
+
+/// Runs the script atomically, replying with what it returns: nil as -1, an integer as a score, a
+/// string as an item, true as OK and a table as a list of its values
+pub fn eval(server: &Server, script: &str) -> Response {
+    if server.config.cluster.is_some() {
+        return Response::Error("EVAL is not available in cluster mode".to_string());
+    }
+    // Hands the worker's other tasks to the rest meanwhile, which a current-thread runtime has none of
+    let result = match Handle::current().runtime_flavor() {
+        RuntimeFlavor::MultiThread => tokio::task::block_in_place(|| run(server, script)),
+        _ => run(server, script),
+    };
+    match result {
+        Ok(response) => response,
+        // Only the message, as errors carry a multi-line stack traceback
+        Err(e) => Response::Error(format!("Script failed: {}", e.to_string().lines().next().unwrap_or_default())),
+    }
+}
+
+fn run(server: &Server, script: &str) -> mlua::Result<Response> {
+    let lua = Lua::new_with(StdLib::TABLE | StdLib::STRING | StdLib::MATH, LuaOptions::default())?;
+    let started = Instant::now();
+    lua.set_hook(HookTriggers::new().every_nth_instruction(INSTRUCTIONS_PER_CHECK), move |_, _| {
+        if started.elapsed() > SCRIPT_TIMEOUT {
+            return Err(mlua::Error::runtime("script timed out"));
+        }
+        Ok(())
+    });
+    server.pqueue.transaction(|tx| {
+        let tx = RefCell::new(tx);
+        lua.scope(|scope| {
+            let pqueue = lua.create_table()?;
+            pqueue.set("update", scope.create_function(|_, (item, delta): (String, i64)| {
+                tx.borrow_mut().update(item, delta).map(|(_, score)| score).map_err(mlua::Error::external)
+            })?)?;
+            pqueue.set("remove", scope.create_function(|_, item: String| {
+                server.payloads.take(&item);
+                Ok(tx.borrow_mut().remove(&item))
+            })?)?;
+            pqueue.set("score", scope.create_function(|_, item: String| Ok(tx.borrow().score(&item)))?)?;
+            pqueue.set("next", scope.create_function(|_, ()| {
+                let entry = tx.borrow_mut().next_with_score();
+                if let Some((item, _)) = &entry {
+                    server.payloads.take(item);
+                }
+                Ok(entry.unzip())
+            })?)?;
+            pqueue.set("peek", scope.create_function(|_, ()| Ok(tx.borrow().peek_with_score().unzip()))?)?;
+            lua.globals().set("pqueue", pqueue)?;
+            let result: Value = lua.load(script).set_name("EVAL").eval()?;
+            to_response(result)
+        })
+    })
+}
+
+fn to_response(value: Value) -> mlua::Result<Response> {
+    Ok(match value {
+        Value::Nil | Value::Boolean(false) => Response::Nil,
+        Value::Boolean(true) => Response::Ok,
+        Value::Integer(score) => Response::Score(score),
+        Value::Number(number) => Response::Item(number.to_string()),
+        Value::String(string) => Response::Item(string.to_str()?.to_string()),
+        Value::Table(table) => Response::Items(
+            table.sequence_values::<Value>()
+                .map(|value| value.and_then(|value| to_item(&value)))
+                .collect::<mlua::Result<_>>()?,
+        ),
+        value => return Err(mlua::Error::runtime(format!("can not return a {}", value.type_name()))),
+    })
+}
+
+fn to_item(value: &Value) -> mlua::Result<String> {
+    match value {
+        Value::Integer(integer) => Ok(integer.to_string()),
+        Value::Number(number) => Ok(number.to_string()),
+        Value::String(string) => Ok(string.to_str()?.to_string()),
+        value => Err(mlua::Error::runtime(format!("can not return a {} in a table", value.type_name()))),
+    }
+}
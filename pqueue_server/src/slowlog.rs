@@ -0,0 +1,63 @@
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// A command that took longer than the slowlog threshold
+#[derive(Clone, Debug)]
+pub struct SlowLogEntry {
+    pub id: u64,
+    // Seconds since the Unix epoch when the command finished
+    pub timestamp: u64,
+    pub duration: Duration,
+    pub command: String,
+}
+
+/// The most recent commands that took longer than a threshold to run, newest first
+pub struct SlowLog {
+    threshold: Duration,
+    max_len: usize,
+    entries: Mutex<SlowLogEntries>,
+}
+
+#[derive(Default)]
+struct SlowLogEntries {
+    next_id: u64,
+    entries: VecDeque<SlowLogEntry>,
+}
+
+impl SlowLog {
+    /// Creates a slowlog keeping up to max_len commands that took longer than threshold
+    pub fn new(threshold: Duration, max_len: usize) -> Self {
+        Self { threshold, max_len, entries: Mutex::default() }
+    }
+
+    fn is_slow(&self, duration: Duration) -> bool {
+        self.max_len > 0 && duration > self.threshold
+    }
+
+    /// Records a command that took duration to run, if it is slow
+    pub fn record(&self, command: impl ToString, duration: Duration) {
+        if !self.is_slow(duration) {
+            return;
+        }
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |since| since.as_secs());
+        let mut log = self.entries.lock().unwrap();
+        let id = log.next_id;
+        log.next_id += 1;
+        log.entries.push_front(SlowLogEntry { id, timestamp, duration, command: command.to_string() });
+        log.entries.truncate(self.max_len);
+    }
+
+    /// Up to count of the most recent entries, newest first
+    pub fn get(&self, count: usize) -> Vec<SlowLogEntry> {
+        self.entries.lock().unwrap().entries.iter().take(count).cloned().collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap().entries.len()
+    }
+
+    pub fn reset(&self) {
+        self.entries.lock().unwrap().entries.clear();
+    }
+}
@@ -0,0 +1,95 @@
+// Snapshots of the queue's contents, written to <data dir>/pqueue.snapshot. A snapshot is a magic
+// line followed by one record per item, in the order `PQueue::snapshot` returns them:
+//
+//   score: i64 (big endian), length: u32 (big endian), then the item's bytes
+//
+// Snapshots are written to a temporary file that is renamed over the previous one once complete, so a
+// crash while saving leaves the last complete snapshot in place.
+
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, Utc};
+
+use crate::Server;
+
+const MAGIC: &[u8] = b"PQSNAP1\n";
+const FILE_NAME: &str = "pqueue.snapshot";
+
+/// Path of the snapshot file within the data directory
+pub fn path(data_dir: &Path) -> PathBuf {
+    data_dir.join(FILE_NAME)
+}
+
+/// The outcome of the latest save, reported by INFO persistence
+pub struct LastSave {
+    pub at: DateTime<Utc>,
+    // The number of items saved, or why saving failed
+    pub result: Result<usize, String>,
+}
+
+/// Saves the queue to the server's data directory, returning the number of items saved. Saves are
+/// serialized so a periodic save and a SAVE command can't write the file at the same time.
+pub async fn save(server: &Server) -> io::Result<usize> {
+    let Some(data_dir) = server.config.data_dir.clone() else {
+        return Err(io::Error::other("No data directory configured, see --data-dir"));
+    };
+    let _saving = server.saving.lock().await;
+    let entries = server.pqueue.snapshot();
+    let saved = entries.len();
+    let result = tokio::task::spawn_blocking(move || write(&path(&data_dir), &entries))
+        .await
+        .map_err(io::Error::other)
+        .and_then(|written| written)
+        .map(|_| saved);
+    *server.last_save.lock().unwrap() = Some(LastSave {
+        at: Utc::now(),
+        result: result.as_ref().map(|&saved| saved).map_err(|e| e.to_string()),
+    });
+    result
+}
+
+/// Writes (score, item) pairs to the snapshot file at path
+pub fn write(path: &Path, entries: &[(i64, String)]) -> io::Result<()> {
+    let temp_path = path.with_extension("tmp");
+    let mut file = BufWriter::new(File::create(&temp_path)?);
+    file.write_all(MAGIC)?;
+    for (score, item) in entries {
+        file.write_all(&score.to_be_bytes())?;
+        file.write_all(&(item.len() as u32).to_be_bytes())?;
+        file.write_all(item.as_bytes())?;
+    }
+    file.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+    fs::rename(temp_path, path)
+}
+
+/// Reads the (score, item) pairs of the snapshot file at path, or None if there is no snapshot yet
+pub fn read(path: &Path) -> io::Result<Option<Vec<(i64, String)>>> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let mut file = BufReader::new(file);
+    let mut magic = [0; MAGIC.len()];
+    file.read_exact(&mut magic)?;
+    if magic != MAGIC {
+        return Err(io::Error::new(ErrorKind::InvalidData, "not a pqueue snapshot"));
+    }
+    let mut entries = Vec::new();
+    let mut score = [0; 8];
+    loop {
+        match file.read_exact(&mut score) {
+            Ok(()) => {},
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(Some(entries)),
+            Err(e) => return Err(e),
+        }
+        let mut len = [0; 4];
+        file.read_exact(&mut len)?;
+        let mut item = vec![0; u32::from_be_bytes(len) as usize];
+        file.read_exact(&mut item)?;
+        let item = String::from_utf8(item).map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+        entries.push((i64::from_be_bytes(score), item));
+    }
+}
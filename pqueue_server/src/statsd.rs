@@ -0,0 +1,82 @@
+// Pushes metrics to a StatsD server (--statsd-addr) over UDP, for setups scraping nothing. Every
+// --statsd-interval seconds it sends, under --statsd-prefix:
+//
+//   <prefix>.items, .pools, .connected_clients, .oldest_item_age_seconds   gauges
+//   <prefix>.enqueue_rate, .dequeue_rate                                   gauges, per second over the last minute
+//   <prefix>.updates                                                       counter
+//   <prefix>.commands.<command>.calls                                      counter
+//   <prefix>.commands.<command>.latency                                    timer, the mean in ms over the interval
+//
+// Counters are sent as the change since the previous push. The names are dotted paths, so they land
+// in Graphite as they are when StatsD forwards there.
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use tokio::net::UdpSocket;
+use tracing::warn;
+
+use crate::Server;
+
+// Lines are packed into datagrams of at most this many bytes, which fit in an Ethernet frame
+const MAX_DATAGRAM: usize = 1432;
+
+/// Sends the server's metrics to address every interval, until the server stops
+pub async fn run(server: &Server, address: &str, prefix: &str, interval: Duration) -> std::io::Result<()> {
+    let socket = UdpSocket::bind(if address.starts_with('[') { "[::]:0" } else { "0.0.0.0:0" }).await?;
+    socket.connect(address).await?;
+    let mut updates = server.pqueue.stats().updates;
+    let mut commands: HashMap<&'static str, (u64, Duration)> = server.metrics.command_stats()
+        .into_iter()
+        .map(|stats| (stats.command, (stats.calls, stats.total)))
+        .collect();
+    let mut ticker = tokio::time::interval(interval);
+    ticker.tick().await;
+    loop {
+        ticker.tick().await;
+        let stats = server.pqueue.stats();
+        let mut lines = vec![
+            format!("{prefix}.items:{}|g", stats.items),
+            format!("{prefix}.pools:{}|g", stats.pools),
+            format!("{prefix}.connected_clients:{}|g", server.metrics.connected_clients()),
+            format!("{prefix}.oldest_item_age_seconds:{}|g", stats.oldest_item_age.map_or(0, |age| age.num_seconds())),
+            format!("{prefix}.enqueue_rate:{}|g", stats.enqueue_rate.last_1m),
+            format!("{prefix}.dequeue_rate:{}|g", stats.dequeue_rate.last_1m),
+            format!("{prefix}.updates:{}|c", (stats.updates - updates).max(0)),
+        ];
+        updates = stats.updates;
+        for stats in server.metrics.command_stats() {
+            let (previous_calls, previous_total) = commands.insert(stats.command, (stats.calls, stats.total)).unwrap_or_default();
+            let calls = stats.calls.saturating_sub(previous_calls);
+            if calls > 0 {
+                let mean = stats.total.saturating_sub(previous_total).as_secs_f64() / calls as f64 * 1000.0;
+                let command = stats.command.to_ascii_lowercase();
+                lines.push(format!("{prefix}.commands.{}.calls:{}|c", command, calls));
+                lines.push(format!("{prefix}.commands.{}.latency:{:.3}|ms", command, mean));
+            }
+        }
+        for datagram in pack(&lines) {
+            if let Err(e) = socket.send(datagram.as_bytes()).await {
+                warn!("Failed to send metrics to StatsD at {}: {}", address, e);
+                break;
+            }
+        }
+    }
+}
+
+// Joins the lines into as few datagrams as fit under MAX_DATAGRAM
+fn pack(lines: &[String]) -> Vec<String> {
+    let mut datagrams = vec![String::new()];
+    for line in lines {
+        let datagram = datagrams.last_mut().unwrap();
+        if !datagram.is_empty() && datagram.len() + 1 + line.len() > MAX_DATAGRAM {
+            datagrams.push(line.clone());
+        } else {
+            if !datagram.is_empty() {
+                datagram.push('\n');
+            }
+            datagram.push_str(line);
+        }
+    }
+    datagrams
+}
@@ -0,0 +1,139 @@
+// Persistent storage, enabled with --storage persistent: the queue is kept in a sled database in the
+// data directory as well as in memory. Every change to the queue is written to the database behind
+// it, in the order made, and on startup the queue is restored from the database instead of from the
+// snapshot. sled flushes its writes to disk every half second and on shutdown, so a crash loses at
+// most the changes of the last moments, where snapshots lose everything since the last save.
+//
+// The database follows the queue through its events, written in batches on a blocking thread so disk
+// writes never hold up the async workers. Should the writer fall so far behind that events are
+// dropped, it copies the whole queue over again and carries on from there. Items still
+// have to fit in memory: the database makes the queue durable, not larger.
+
+use std::path::{Path, PathBuf};
+
+use pqueue::{PQueueError, QueueEvent, SledPQueue};
+use tokio::sync::broadcast::{error::{RecvError, TryRecvError}, Receiver};
+use tokio::sync::Notify;
+use tracing::{error, warn};
+
+use crate::Server;
+
+const DIR_NAME: &str = "pqueue.db";
+
+// The most events written in one trip to a blocking thread
+const WRITE_BATCH: usize = 1024;
+
+/// Path of the database within the data directory
+pub fn path(data_dir: &Path) -> PathBuf {
+    data_dir.join(DIR_NAME)
+}
+
+/// The database the queue is written to under --storage persistent
+pub struct Storage {
+    database: SledPQueue<String>,
+    // Notified at shutdown to write the remaining events, then by the writer once it has
+    stop: Notify,
+    stopped: Notify,
+}
+
+impl Storage {
+    /// Opens (or creates) the database at path, returning it with the entries it holds in the order
+    /// `PQueue::load_sorted` takes
+    pub fn open(path: &Path) -> Result<(Self, Vec<(i64, String)>), PQueueError> {
+        let database = SledPQueue::open(path)?;
+        let entries = database.snapshot()?;
+        Ok((Storage { database, stop: Notify::new(), stopped: Notify::new() }, entries))
+    }
+
+    /// Writes the queue's events to the database until stop is called
+    pub async fn run(&self, server: &Server, mut events: Receiver<QueueEvent<String>>) {
+        loop {
+            tokio::select! {
+                biased;
+                event = events.recv() => match event {
+                    Ok(event) => {
+                        // Along with the events that came in meanwhile
+                        let mut batch = vec![event];
+                        let mut missed = None;
+                        while batch.len() < WRITE_BATCH {
+                            match events.try_recv() {
+                                Ok(event) => batch.push(event),
+                                Err(TryRecvError::Lagged(count)) => {
+                                    missed = Some(count);
+                                    break;
+                                },
+                                Err(_) => break,
+                            }
+                        }
+                        match missed {
+                            Some(missed) => self.copy(server, missed).await,
+                            None => self.write(batch).await,
+                        }
+                    },
+                    Err(RecvError::Lagged(missed)) => self.copy(server, missed).await,
+                    Err(RecvError::Closed) => break,
+                },
+                _ = self.stop.notified() => {
+                    let mut batch = Vec::new();
+                    while let Ok(event) = events.try_recv() {
+                        batch.push(event);
+                    }
+                    self.write(batch).await;
+                    break;
+                },
+            }
+        }
+        let database = self.database.clone();
+        match tokio::task::spawn_blocking(move || database.flush()).await {
+            Ok(Ok(())) => {},
+            Ok(Err(e)) => error!("Failed to flush the queue's database: {}", e),
+            Err(e) => error!("Failed to flush the queue's database: {}", e),
+        }
+        self.stopped.notify_one();
+    }
+
+    /// Writes the changes made so far and waits for them to be flushed
+    pub async fn stop(&self) {
+        self.stop.notify_one();
+        self.stopped.notified().await;
+    }
+
+    // Writes the events to the database in order, on a blocking thread
+    async fn write(&self, events: Vec<QueueEvent<String>>) {
+        let database = self.database.clone();
+        let written = tokio::task::spawn_blocking(move || events.into_iter().for_each(|event| write(&database, event))).await;
+        if let Err(e) = written {
+            error!("Failed to write to the queue's database: {}", e);
+        }
+    }
+
+    // Replaces the database's contents with the queue's, after missing changes to it
+    async fn copy(&self, server: &Server, missed: u64) {
+        warn!("Fell {} changes behind writing the queue to its database, copying it over again", missed);
+        let database = self.database.clone();
+        let entries = server.pqueue.snapshot();
+        let copied = tokio::task::spawn_blocking(move || {
+            database.clear()?;
+            entries.into_iter().try_for_each(|(score, item)| database.set_score(item, score).map(|_| ()))
+        }).await;
+        match copied {
+            Ok(Ok(())) => {},
+            Ok(Err(e)) => error!("Failed to copy the queue to its database: {}", e),
+            Err(e) => error!("Failed to copy the queue to its database: {}", e),
+        }
+    }
+}
+
+fn write(database: &SledPQueue<String>, event: QueueEvent<String>) {
+    let written = match event {
+        QueueEvent::Updated { item, score, .. } => database.set_score((*item).clone(), score).map(|_| ()),
+        QueueEvent::Popped { item, .. } | QueueEvent::Removed { item, .. } | QueueEvent::Expired { item, .. } => {
+            database.remove(&item).map(|_| ())
+        },
+        QueueEvent::Cleared { .. } => database.clear().map(|_| ()),
+        _ => return,
+    };
+    if let Err(e) = written {
+        error!("Failed to write to the queue's database: {}", e);
+    }
+}
@@ -0,0 +1,61 @@
+// systemd integration: socket activation (the listening sockets are passed in as LISTEN_FDS describes)
+// and readiness notification over $NOTIFY_SOCKET. Both are no-ops when not run by systemd.
+
+use std::env;
+use std::io;
+
+// The first file descriptor passed with socket activation
+#[cfg(unix)]
+const LISTEN_FDS_START: i32 = 3;
+
+/// Takes the TCP listeners passed in by systemd socket activation, none if the server wasn't started
+/// that way
+#[cfg(unix)]
+pub fn listeners() -> io::Result<Vec<std::net::TcpListener>> {
+    use std::os::fd::FromRawFd;
+
+    let for_us = env::var("LISTEN_PID").ok().and_then(|pid| pid.parse::<u32>().ok()) == Some(std::process::id());
+    let fds = env::var("LISTEN_FDS").ok().and_then(|fds| fds.parse::<i32>().ok()).unwrap_or(0);
+    // Unset so the variables aren't inherited by anything the server starts
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDS");
+    env::remove_var("LISTEN_FDNAMES");
+    if !for_us {
+        return Ok(Vec::new());
+    }
+    (LISTEN_FDS_START..LISTEN_FDS_START + fds).map(|fd| {
+        // SAFETY: systemd hands the process ownership of the descriptors from LISTEN_FDS_START on
+        let listener = unsafe { std::net::TcpListener::from_raw_fd(fd) };
+        listener.set_nonblocking(true)?;
+        Ok(listener)
+    }).collect()
+}
+
+#[cfg(not(unix))]
+pub fn listeners() -> io::Result<Vec<std::net::TcpListener>> {
+    Ok(Vec::new())
+}
+
+/// Sends a state such as "READY=1" to systemd, if it is waiting for notifications
+#[cfg(target_os = "linux")]
+pub fn notify(state: &str) -> io::Result<()> {
+    use std::os::linux::net::SocketAddrExt;
+    use std::os::unix::net::{SocketAddr, UnixDatagram};
+
+    let Some(path) = env::var_os("NOTIFY_SOCKET") else {
+        return Ok(());
+    };
+    let path = path.to_string_lossy();
+    // A leading @ names a socket in the abstract namespace
+    let address = match path.strip_prefix('@') {
+        Some(name) => SocketAddr::from_abstract_name(name)?,
+        None => SocketAddr::from_pathname(path.as_ref())?,
+    };
+    UnixDatagram::unbound()?.send_to_addr(state.as_bytes(), &address)?;
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn notify(_state: &str) -> io::Result<()> {
+    Ok(())
+}
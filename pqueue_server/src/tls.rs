@@ -0,0 +1,73 @@
+// TLS for the TCP protocol, enabled with --tls-cert and --tls-key. With --tls-ca the certificates
+// clients present are verified against that CA, and with --tls-require-client-cert clients without one
+// are turned away during the handshake. With --tls-cert-users, a client whose certificate's common
+// name is an ACL user is authenticated as that user without AUTH.
+
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use std::sync::Arc;
+
+use tokio::net::TcpStream;
+use tokio_rustls::rustls::crypto::ring;
+use tokio_rustls::rustls::pki_types::CertificateDer;
+use tokio_rustls::rustls::server::WebPkiClientVerifier;
+use tokio_rustls::rustls::{RootCertStore, ServerConfig};
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+
+pub struct Tls {
+    pub acceptor: TlsAcceptor,
+    // Whether clients are authenticated as the ACL user named by their certificate
+    pub cert_users: bool,
+}
+
+impl Tls {
+    /// Loads the server's certificate chain and key, and the CA client certificates are verified
+    /// against if given, all PEM files
+    pub fn load(cert: &Path, key: &Path, ca: Option<&Path>, require_client_cert: bool, cert_users: bool) -> Result<Self, String> {
+        let provider = Arc::new(ring::default_provider());
+        let builder = ServerConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()
+            .map_err(|e| e.to_string())?;
+        let builder = match ca {
+            Some(ca) => {
+                let mut roots = RootCertStore::empty();
+                for cert in certificates(ca)? {
+                    roots.add(cert).map_err(|e| format!("{}: {}", ca.display(), e))?;
+                }
+                let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider);
+                let verifier = if require_client_cert { verifier } else { verifier.allow_unauthenticated() };
+                builder.with_client_cert_verifier(verifier.build().map_err(|e| format!("{}: {}", ca.display(), e))?)
+            },
+            None => builder.with_no_client_auth(),
+        };
+        let private_key = rustls_pemfile::private_key(&mut open(key)?)
+            .map_err(|e| format!("{}: {}", key.display(), e))?
+            .ok_or_else(|| format!("{}: no private key found", key.display()))?;
+        let config = builder.with_single_cert(certificates(cert)?, private_key).map_err(|e| format!("{}: {}", cert.display(), e))?;
+        Ok(Tls { acceptor: TlsAcceptor::from(Arc::new(config)), cert_users })
+    }
+}
+
+/// The common name of the verified certificate the client presented, if it presented one
+pub fn peer_common_name(stream: &TlsStream<TcpStream>) -> Option<String> {
+    let certificate = stream.get_ref().1.peer_certificates()?.first()?;
+    let (_, certificate) = x509_parser::parse_x509_certificate(certificate).ok()?;
+    let common_name = certificate.subject().iter_common_name().next()?.as_str().ok()?;
+    Some(common_name.to_string())
+}
+
+fn certificates(path: &Path) -> Result<Vec<CertificateDer<'static>>, String> {
+    let certificates = rustls_pemfile::certs(&mut open(path)?)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| format!("{}: {}", path.display(), e))?;
+    if certificates.is_empty() {
+        return Err(format!("{}: no certificate found", path.display()));
+    }
+    Ok(certificates)
+}
+
+fn open(path: &Path) -> Result<BufReader<File>, String> {
+    File::open(path).map(BufReader::new).map_err(|e| format!("{}: {}", path.display(), e))
+}